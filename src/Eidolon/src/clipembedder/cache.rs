@@ -0,0 +1,127 @@
+use anyhow::{Error as E, Result};
+use std::collections::HashMap;
+
+use crate::clipembedder::encoder::DualEncoder;
+
+/// Upper bound on cached prompt embeddings before the cache is reset.
+const PROMPT_CACHE_CAPACITY: usize = 4096;
+
+/// Text embeddings for classification prompts, which tend to repeat across requests.
+#[derive(Default)]
+pub struct PromptCache {
+    embeddings: HashMap<String, Vec<f32>>,
+}
+
+impl PromptCache {
+    /// Returns the embedding for each prompt, embedding only those not seen before.
+    pub fn embed(&mut self, model: &dyn DualEncoder, prompts: &[String]) -> Result<Vec<Vec<f32>>> {
+        // Copy this call's hits out first, so making room for the misses can't evict them.
+        let mut found: HashMap<String, Vec<f32>> = HashMap::new();
+        let mut missing: Vec<String> = vec![];
+        for prompt in prompts {
+            match self.embeddings.get(prompt) {
+                Some(embedding) => {
+                    found.insert(prompt.clone(), embedding.clone());
+                }
+                None => missing.push(prompt.clone()),
+            }
+        }
+        missing.sort();
+        missing.dedup();
+
+        if !missing.is_empty() {
+            let embeddings = model.embed_texts(&missing)?;
+            if embeddings.len() != missing.len() {
+                return Err(E::msg("Model returned the wrong number of prompt embeddings"));
+            }
+            if self.embeddings.len() + missing.len() > PROMPT_CACHE_CAPACITY {
+                self.embeddings.clear();
+            }
+            for (prompt, embedding) in missing.into_iter().zip(embeddings) {
+                found.insert(prompt.clone(), embedding.clone());
+                self.embeddings.insert(prompt, embedding);
+            }
+        }
+
+        prompts
+            .iter()
+            .map(|prompt| {
+                found
+                    .get(prompt.as_str())
+                    .cloned()
+                    .ok_or_else(|| E::msg("Prompt embedding missing from cache"))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipembedder::encoder::Scoring;
+    use crate::clipembedder::processor::ImageProcessor;
+    use crate::clipembedder::text::{LongTextMode, TextEmbedding};
+    use candle_core::{Device, Tensor};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Embeds each text as its length, counting the texts it is asked for.
+    #[derive(Default)]
+    struct LengthEncoder {
+        processor: ImageProcessor,
+        embedded: AtomicUsize,
+    }
+
+    impl DualEncoder for LengthEncoder {
+        fn device(&self) -> &Device {
+            &Device::Cpu
+        }
+
+        fn embed_texts_with(&self, texts: &[String], _mode: LongTextMode) -> Result<Vec<TextEmbedding>> {
+            self.embedded.fetch_add(texts.len(), Ordering::Relaxed);
+            Ok(texts
+                .iter()
+                .map(|text| TextEmbedding {
+                    values: vec![text.len() as f32],
+                    truncated: false,
+                })
+                .collect())
+        }
+
+        fn image_processor(&self) -> &ImageProcessor {
+            &self.processor
+        }
+
+        fn embed_images(&self, _pixels: &[Tensor]) -> Result<Vec<Vec<f32>>> {
+            unimplemented!()
+        }
+
+        fn scoring(&self) -> Scoring {
+            Scoring::Softmax { logit_scale: 1.0 }
+        }
+    }
+
+    fn prompts(range: std::ops::Range<usize>) -> Vec<String> {
+        range.map(|i| "x".repeat(i + 1)).collect()
+    }
+
+    #[test]
+    fn embeds_only_unseen_prompts() {
+        let model = LengthEncoder::default();
+        let mut cache = PromptCache::default();
+        cache.embed(&model, &prompts(0..3)).unwrap();
+        let embeddings = cache.embed(&model, &prompts(1..5)).unwrap();
+        assert_eq!(embeddings, vec![vec![2.0], vec![3.0], vec![4.0], vec![5.0]]);
+        assert_eq!(model.embedded.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn keeps_hits_when_the_cache_overflows() {
+        let model = LengthEncoder::default();
+        let mut cache = PromptCache::default();
+        cache.embed(&model, &prompts(0..PROMPT_CACHE_CAPACITY)).unwrap();
+        let request = prompts(PROMPT_CACHE_CAPACITY - 2..PROMPT_CACHE_CAPACITY + 2);
+        let embeddings = cache.embed(&model, &request).unwrap();
+        let expected: Vec<Vec<f32>> = request.iter().map(|p| vec![p.len() as f32]).collect();
+        assert_eq!(embeddings, expected);
+    }
+}
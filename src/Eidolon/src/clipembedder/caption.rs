@@ -0,0 +1,281 @@
+use anyhow::{Error as E, Result};
+use candle_core::{D, DType, Device, IndexOp, Module, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::generation::LogitsProcessor;
+use candle_transformers::models::blip;
+use tokenizers::Tokenizer;
+
+use crate::clipembedder::config::ModelSource;
+use crate::clipembedder::processor::ImageProcessor;
+
+/// BLIP's `[DEC]` token starts every caption and `[SEP]` ends it.
+const BOS_TOKEN_ID: u32 = 30522;
+const SEP_TOKEN_ID: u32 = 102;
+const DEFAULT_SEED: u64 = 299_792_458;
+
+#[derive(Debug, Clone, Copy)]
+pub enum CaptionDecoding {
+    Greedy,
+    /// Samples each token at `temperature`.
+    Sample { temperature: f64 },
+    /// Keeps the `width` most likely captions at each step and returns the best one,
+    /// scored by mean token log-probability.
+    Beam { width: usize },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CaptionOptions {
+    /// Maximum number of generated tokens.
+    pub max_length: usize,
+    pub decoding: CaptionDecoding,
+    /// Seed for sampled decoding, so repeated calls can be reproducible.
+    pub seed: Option<u64>,
+}
+
+/// Parses a BLIP `config.json`. The hub files do not always carry every field candle
+/// expects, so any field missing from the file is taken from the large captioning model.
+fn blip_config(bytes: &[u8]) -> Result<blip::Config> {
+    let defaults = blip::Config::image_captioning_large();
+    let (text, vision) = (&defaults.text_config, &defaults.vision_config);
+    let mut config = serde_json::json!({
+        "text_config": {
+            "vocab_size": text.vocab_size,
+            "hidden_size": text.hidden_size,
+            "encoder_hidden_size": text.encoder_hidden_size,
+            "intermediate_size": text.intermediate_size,
+            "projection_dim": text.projection_dim,
+            "num_hidden_layers": text.num_hidden_layers,
+            "num_attention_heads": text.num_attention_heads,
+            "max_position_embeddings": text.max_position_embeddings,
+            "hidden_act": text.hidden_act,
+            "layer_norm_eps": text.layer_norm_eps,
+            "is_decoder": text.is_decoder,
+        },
+        "vision_config": {
+            "hidden_size": vision.hidden_size,
+            "intermediate_size": vision.intermediate_size,
+            "projection_dim": vision.projection_dim,
+            "num_hidden_layers": vision.num_hidden_layers,
+            "num_attention_heads": vision.num_attention_heads,
+            "image_size": vision.image_size,
+            "patch_size": vision.patch_size,
+            "hidden_act": vision.hidden_act,
+            "layer_norm_eps": vision.layer_norm_eps,
+        },
+        "projection_dim": defaults.projection_dim,
+        "image_text_hidden_size": defaults.image_text_hidden_size,
+    });
+    let overrides: serde_json::Value = serde_json::from_slice(bytes)
+        .map_err(|e| E::msg(format!("Invalid BLIP config.json: {}", e)))?;
+    merge(&mut config, &overrides);
+    serde_json::from_value(config).map_err(|e| E::msg(format!("Invalid BLIP config.json: {}", e)))
+}
+
+/// Overwrites `target` with every field in `overrides`, recursing into nested objects.
+fn merge(target: &mut serde_json::Value, overrides: &serde_json::Value) {
+    match (target.as_object_mut(), overrides.as_object()) {
+        (Some(target), Some(overrides)) => {
+            for (key, value) in overrides {
+                match target.get_mut(key) {
+                    Some(existing) if existing.is_object() && value.is_object() => merge(existing, value),
+                    _ => {
+                        target.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        _ => *target = overrides.clone(),
+    }
+}
+
+/// A BLIP image-to-text model.
+pub struct Captioner {
+    model: blip::BlipForConditionalGeneration,
+    tokenizer: Tokenizer,
+    processor: ImageProcessor,
+    pub device: Device,
+}
+
+impl Captioner {
+    /// Creates a new model on `device` from the HuggingFace hub or a local directory.
+    pub fn new(source: &ModelSource, device: &Device) -> Result<Self> {
+        let device = device.clone();
+
+        let repo = source.files()?;
+        let model_filename = repo.get("model.safetensors")?;
+        let tokenizer_filename = repo.get("tokenizer.json")?;
+        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
+
+        let config = match repo.get("config.json") {
+            Ok(path) => blip_config(&std::fs::read(path)?)?,
+            Err(_) => blip::Config::image_captioning_large(),
+        };
+
+        let image_size = config.vision_config.image_size;
+        let defaults = ImageProcessor::blip(image_size as u32);
+        let processor = match repo.get("preprocessor_config.json") {
+            Ok(path) => ImageProcessor::from_file(path, defaults)?,
+            Err(_) => defaults,
+        };
+        if processor.output_size() != (image_size, image_size) {
+            return Err(E::msg(format!(
+                "Processor output size {:?} does not match model image size {}",
+                processor.output_size(),
+                image_size
+            )));
+        }
+
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[model_filename], DType::F32, &device)? };
+        let model = blip::BlipForConditionalGeneration::new(&config, vb)?;
+
+        Ok(Self {
+            model,
+            tokenizer,
+            processor,
+            device,
+        })
+    }
+
+    /// The preprocessing the vision encoder expects.
+    pub fn image_processor(&self) -> &ImageProcessor {
+        &self.processor
+    }
+
+    /// Generates a caption for an image preprocessed with `image_processor`.
+    pub fn caption(&mut self, pixels: &Tensor, options: &CaptionOptions) -> Result<String> {
+        let pixels = pixels.unsqueeze(0)?.to_device(&self.device)?;
+        let image_embeds = self.model.vision_model().forward(&pixels)?;
+
+        let tokens = match options.decoding {
+            CaptionDecoding::Greedy => self.generate(&image_embeds, options, None)?,
+            CaptionDecoding::Sample { temperature } => {
+                self.generate(&image_embeds, options, Some(temperature))?
+            }
+            CaptionDecoding::Beam { width } => self.beam_search(&image_embeds, options, width)?,
+        };
+        let caption = self.tokenizer.decode(&tokens, true).map_err(E::msg)?;
+        Ok(caption.trim().to_string())
+    }
+
+    /// Greedy or sampled decoding, one token at a time on top of the decoder's KV cache.
+    fn generate(
+        &mut self,
+        image_embeds: &Tensor,
+        options: &CaptionOptions,
+        temperature: Option<f64>,
+    ) -> Result<Vec<u32>> {
+        let mut logits_processor =
+            LogitsProcessor::new(options.seed.unwrap_or(DEFAULT_SEED), temperature, None);
+        self.model.reset_kv_cache();
+
+        let mut tokens = vec![BOS_TOKEN_ID];
+        for index in 0..options.max_length {
+            let context = if index > 0 { &tokens[tokens.len() - 1..] } else { &tokens[..] };
+            let input_ids = Tensor::new(context, &self.device)?.unsqueeze(0)?;
+            let logits = self.model.text_decoder().forward(&input_ids, image_embeds)?;
+            let logits = logits.squeeze(0)?;
+            let logits = logits.get(logits.dim(0)? - 1)?;
+            let token = logits_processor.sample(&logits)?;
+            if token == SEP_TOKEN_ID {
+                break;
+            }
+            tokens.push(token);
+        }
+        self.model.reset_kv_cache();
+        Ok(tokens[1..].to_vec())
+    }
+
+    /// Beam search. The decoder's KV cache can't be reordered between beams, so every step
+    /// re-runs the full prefixes as one batch; captions are short enough for this to be cheap.
+    fn beam_search(
+        &mut self,
+        image_embeds: &Tensor,
+        options: &CaptionOptions,
+        width: usize,
+    ) -> Result<Vec<u32>> {
+        let width = width.max(1);
+        // Tokens include the leading BOS, which carries no score.
+        let mean_score = |tokens: &[u32], score: f32| {
+            score / tokens.len().saturating_sub(1).max(1) as f32
+        };
+        let mut beams: Vec<(Vec<u32>, f32)> = vec![(vec![BOS_TOKEN_ID], 0.0)];
+        let mut finished: Vec<(Vec<u32>, f32)> = vec![];
+
+        let (_, patches, hidden) = image_embeds.dims3()?;
+        for _ in 0..options.max_length {
+            self.model.reset_kv_cache();
+            let input: Vec<Vec<u32>> = beams.iter().map(|(tokens, _)| tokens.clone()).collect();
+            let input_ids = Tensor::new(input, &self.device)?;
+            let embeds = image_embeds
+                .broadcast_as((beams.len(), patches, hidden))?
+                .contiguous()?;
+            let logits = self.model.text_decoder().forward(&input_ids, &embeds)?;
+            let last = logits.i((.., logits.dim(1)? - 1, ..))?;
+            let log_probs = candle_nn::ops::log_softmax(&last, D::Minus1)?.to_vec2::<f32>()?;
+
+            // Each beam can only contribute its `width` best continuations to the next step.
+            let mut candidates = vec![];
+            for ((tokens, score), log_probs) in beams.iter().zip(&log_probs) {
+                let mut ranked: Vec<(u32, f32)> = log_probs
+                    .iter()
+                    .enumerate()
+                    .map(|(token, lp)| (token as u32, *lp))
+                    .collect();
+                ranked.select_nth_unstable_by(width - 1, |a, b| b.1.total_cmp(&a.1));
+                for &(token, lp) in &ranked[..width] {
+                    candidates.push((tokens, token, score + lp));
+                }
+            }
+            candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+            let mut next = vec![];
+            for (tokens, token, score) in candidates {
+                if token == SEP_TOKEN_ID {
+                    finished.push((tokens.clone(), score));
+                } else {
+                    let mut tokens = tokens.clone();
+                    tokens.push(token);
+                    next.push((tokens, score));
+                }
+                if next.len() == width {
+                    break;
+                }
+            }
+            beams = next;
+            if beams.is_empty() || finished.len() >= width {
+                break;
+            }
+        }
+        self.model.reset_kv_cache();
+
+        finished.extend(beams);
+        let best = finished
+            .into_iter()
+            .max_by(|a, b| mean_score(&a.0, a.1).total_cmp(&mean_score(&b.0, b.1)))
+            .map(|(tokens, _)| tokens)
+            .unwrap_or_default();
+        Ok(best.into_iter().skip(1).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_in_missing_config_fields() {
+        let config = blip_config(br#"{"text_config": {"vocab_size": 100}, "vision_config": {"image_size": 224}}"#)
+            .unwrap();
+        assert_eq!(config.text_config.vocab_size, 100);
+        assert_eq!(config.text_config.hidden_size, 768);
+        assert_eq!(config.vision_config.image_size, 224);
+        assert_eq!(config.vision_config.hidden_size, 1024);
+        assert_eq!(config.image_text_hidden_size, 256);
+    }
+
+    #[test]
+    fn rejects_malformed_configs() {
+        assert!(blip_config(b"{not json").is_err());
+        assert!(blip_config(br#"{"vision_config": {"image_size": "large"}}"#).is_err());
+    }
+}
@@ -0,0 +1,484 @@
+//! The CLIP text and vision towers, adapted from `candle_transformers::models::clip`
+//! (MIT/Apache-2.0). candle's version only implements `quick_gelu` and ignores the text
+//! tower's configured activation; this one runs whichever activation each tower declares.
+
+use candle_core::{D, DType, Device, IndexOp, Result, Tensor};
+use candle_nn::{Conv2dConfig, Module, VarBuilder};
+
+/// The MLP activations CLIP checkpoints on the hub declare in `hidden_act`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activation {
+    QuickGelu,
+    /// The exact, erf-based GELU.
+    Gelu,
+    /// The tanh approximation of GELU (`gelu_pytorch_tanh`, `gelu_new`).
+    GeluPytorchTanh,
+}
+
+impl Module for Activation {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Activation::QuickGelu => xs * candle_nn::ops::sigmoid(&(xs * 1.702f64)?)?,
+            Activation::Gelu => xs.gelu_erf(),
+            Activation::GeluPytorchTanh => xs.gelu(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClipTextConfig {
+    pub vocab_size: usize,
+    pub embed_dim: usize,
+    pub activation: Activation,
+    pub intermediate_size: usize,
+    pub max_position_embeddings: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub projection_dim: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClipVisionConfig {
+    pub embed_dim: usize,
+    pub activation: Activation,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub projection_dim: usize,
+    pub num_channels: usize,
+    pub image_size: usize,
+    pub patch_size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClipConfig {
+    pub text_config: ClipTextConfig,
+    pub vision_config: ClipVisionConfig,
+    pub logit_scale_init_value: f32,
+    pub image_size: usize,
+}
+
+/// The parts of a tower's config its encoder layers need.
+#[derive(Debug, Clone, Copy)]
+struct EncoderConfig {
+    embed_dim: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    activation: Activation,
+}
+
+impl From<&ClipTextConfig> for EncoderConfig {
+    fn from(c: &ClipTextConfig) -> Self {
+        Self {
+            embed_dim: c.embed_dim,
+            intermediate_size: c.intermediate_size,
+            num_hidden_layers: c.num_hidden_layers,
+            num_attention_heads: c.num_attention_heads,
+            activation: c.activation,
+        }
+    }
+}
+
+impl From<&ClipVisionConfig> for EncoderConfig {
+    fn from(c: &ClipVisionConfig) -> Self {
+        Self {
+            embed_dim: c.embed_dim,
+            intermediate_size: c.intermediate_size,
+            num_hidden_layers: c.num_hidden_layers,
+            num_attention_heads: c.num_attention_heads,
+            activation: c.activation,
+        }
+    }
+}
+
+struct ClipAttention {
+    k_proj: candle_nn::Linear,
+    v_proj: candle_nn::Linear,
+    q_proj: candle_nn::Linear,
+    out_proj: candle_nn::Linear,
+    head_dim: usize,
+    scale: f64,
+    num_attention_heads: usize,
+}
+
+impl ClipAttention {
+    fn new(vb: VarBuilder, c: &EncoderConfig) -> Result<Self> {
+        let embed_dim = c.embed_dim;
+        let head_dim = embed_dim / c.num_attention_heads;
+        Ok(Self {
+            k_proj: candle_nn::linear(embed_dim, embed_dim, vb.pp("k_proj"))?,
+            v_proj: candle_nn::linear(embed_dim, embed_dim, vb.pp("v_proj"))?,
+            q_proj: candle_nn::linear(embed_dim, embed_dim, vb.pp("q_proj"))?,
+            out_proj: candle_nn::linear(embed_dim, embed_dim, vb.pp("out_proj"))?,
+            head_dim,
+            scale: (head_dim as f64).powf(-0.5),
+            num_attention_heads: c.num_attention_heads,
+        })
+    }
+
+    /// (batch, seq, embed) to (batch * heads, seq, head_dim), in f32 for the softmax.
+    fn heads(&self, xs: &Tensor, bsz: usize, seq_len: usize) -> Result<Tensor> {
+        xs.reshape((bsz, seq_len, self.num_attention_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?
+            .reshape((bsz * self.num_attention_heads, seq_len, self.head_dim))?
+            .to_dtype(DType::F32)
+    }
+
+    fn forward(&self, xs: &Tensor, causal_attention_mask: Option<&Tensor>) -> Result<Tensor> {
+        let in_dtype = xs.dtype();
+        let (bsz, seq_len, embed_dim) = xs.dims3()?;
+
+        let query = self.heads(&(self.q_proj.forward(xs)? * self.scale)?, bsz, seq_len)?;
+        let key = self.heads(&self.k_proj.forward(xs)?, bsz, seq_len)?;
+        let value = self.heads(&self.v_proj.forward(xs)?, bsz, seq_len)?;
+        let mut weights = query.matmul(&key.transpose(1, 2)?)?;
+        if let Some(mask) = causal_attention_mask {
+            weights = weights
+                .reshape((bsz, self.num_attention_heads, seq_len, seq_len))?
+                .broadcast_add(mask)?
+                .reshape((bsz * self.num_attention_heads, seq_len, seq_len))?;
+        }
+        let weights = candle_nn::ops::softmax(&weights, D::Minus1)?;
+
+        let output = weights
+            .matmul(&value)?
+            .to_dtype(in_dtype)?
+            .reshape((bsz, self.num_attention_heads, seq_len, self.head_dim))?
+            .transpose(1, 2)?
+            .reshape((bsz, seq_len, embed_dim))?;
+        self.out_proj.forward(&output)
+    }
+}
+
+struct ClipEncoderLayer {
+    self_attn: ClipAttention,
+    layer_norm1: candle_nn::LayerNorm,
+    fc1: candle_nn::Linear,
+    fc2: candle_nn::Linear,
+    activation: Activation,
+    layer_norm2: candle_nn::LayerNorm,
+}
+
+impl ClipEncoderLayer {
+    fn new(vb: VarBuilder, c: &EncoderConfig) -> Result<Self> {
+        Ok(Self {
+            self_attn: ClipAttention::new(vb.pp("self_attn"), c)?,
+            layer_norm1: candle_nn::layer_norm(c.embed_dim, 1e-5, vb.pp("layer_norm1"))?,
+            fc1: candle_nn::linear(c.embed_dim, c.intermediate_size, vb.pp("mlp").pp("fc1"))?,
+            fc2: candle_nn::linear(c.intermediate_size, c.embed_dim, vb.pp("mlp").pp("fc2"))?,
+            activation: c.activation,
+            layer_norm2: candle_nn::layer_norm(c.embed_dim, 1e-5, vb.pp("layer_norm2"))?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, causal_attention_mask: Option<&Tensor>) -> Result<Tensor> {
+        let attended = self
+            .self_attn
+            .forward(&self.layer_norm1.forward(xs)?, causal_attention_mask)?;
+        let xs = (attended + xs)?;
+        let mlp = self
+            .layer_norm2
+            .forward(&xs)?
+            .apply(&self.fc1)?
+            .apply(&self.activation)?
+            .apply(&self.fc2)?;
+        mlp + xs
+    }
+}
+
+struct ClipEncoder {
+    layers: Vec<ClipEncoderLayer>,
+}
+
+impl ClipEncoder {
+    fn new(vb: VarBuilder, c: &EncoderConfig) -> Result<Self> {
+        let vb = vb.pp("layers");
+        let layers = (0..c.num_hidden_layers)
+            .map(|index| ClipEncoderLayer::new(vb.pp(index.to_string()), c))
+            .collect::<Result<_>>()?;
+        Ok(Self { layers })
+    }
+
+    fn forward(&self, xs: &Tensor, causal_attention_mask: Option<&Tensor>) -> Result<Tensor> {
+        let mut xs = xs.clone();
+        for layer in &self.layers {
+            xs = layer.forward(&xs, causal_attention_mask)?;
+        }
+        Ok(xs)
+    }
+}
+
+struct ClipTextTransformer {
+    token_embedding: candle_nn::Embedding,
+    position_embedding: candle_nn::Embedding,
+    encoder: ClipEncoder,
+    final_layer_norm: candle_nn::LayerNorm,
+}
+
+impl ClipTextTransformer {
+    fn new(vb: VarBuilder, c: &ClipTextConfig) -> Result<Self> {
+        let embeddings = vb.pp("embeddings");
+        Ok(Self {
+            token_embedding: candle_nn::embedding(
+                c.vocab_size,
+                c.embed_dim,
+                embeddings.pp("token_embedding"),
+            )?,
+            position_embedding: candle_nn::embedding(
+                c.max_position_embeddings,
+                c.embed_dim,
+                embeddings.pp("position_embedding"),
+            )?,
+            encoder: ClipEncoder::new(vb.pp("encoder"), &c.into())?,
+            final_layer_norm: candle_nn::layer_norm(c.embed_dim, 1e-5, vb.pp("final_layer_norm"))?,
+        })
+    }
+
+    fn causal_attention_mask(bsz: usize, seq_len: usize, device: &Device) -> Result<Tensor> {
+        let mask: Vec<f32> = (0..seq_len)
+            .flat_map(|i| (0..seq_len).map(move |j| if j > i { f32::MIN } else { 0.0 }))
+            .collect();
+        Tensor::from_slice(&mask, (seq_len, seq_len), device)?
+            .broadcast_as((bsz, 1, seq_len, seq_len))
+    }
+}
+
+impl Module for ClipTextTransformer {
+    fn forward(&self, input_ids: &Tensor) -> Result<Tensor> {
+        let (bsz, seq_len) = input_ids.dims2()?;
+        let position_ids =
+            Tensor::arange(0u32, seq_len as u32, input_ids.device())?.unsqueeze(0)?;
+        let xs = self
+            .token_embedding
+            .forward(input_ids)?
+            .broadcast_add(&self.position_embedding.forward(&position_ids)?)?;
+        let mask = Self::causal_attention_mask(bsz, seq_len, input_ids.device())?;
+        let xs = self
+            .final_layer_norm
+            .forward(&self.encoder.forward(&xs, Some(&mask))?)?;
+
+        // Pool at the end-of-text token, which has the highest id in CLIP's vocabulary.
+        let eos_positions = input_ids
+            .argmax(D::Minus1)?
+            .to_dtype(DType::I64)?
+            .to_vec1::<i64>()?;
+        let pooled = eos_positions
+            .iter()
+            .enumerate()
+            .map(|(batch, &position)| xs.i((batch, position as usize))?.unsqueeze(0))
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::cat(&pooled, 0)
+    }
+}
+
+struct ClipVisionTransformer {
+    patch_embedding: candle_nn::Conv2d,
+    class_embedding: Tensor,
+    position_embedding: candle_nn::Embedding,
+    num_positions: usize,
+    pre_layer_norm: candle_nn::LayerNorm,
+    encoder: ClipEncoder,
+    post_layer_norm: candle_nn::LayerNorm,
+}
+
+impl ClipVisionTransformer {
+    fn new(vb: VarBuilder, c: &ClipVisionConfig) -> Result<Self> {
+        let embeddings = vb.pp("embeddings");
+        let num_positions = (c.image_size / c.patch_size).pow(2) + 1;
+        let conv_config = Conv2dConfig {
+            stride: c.patch_size,
+            ..Default::default()
+        };
+        Ok(Self {
+            patch_embedding: candle_nn::conv2d_no_bias(
+                c.num_channels,
+                c.embed_dim,
+                c.patch_size,
+                conv_config,
+                embeddings.pp("patch_embedding"),
+            )?,
+            class_embedding: embeddings.get(c.embed_dim, "class_embedding")?,
+            position_embedding: candle_nn::embedding(
+                num_positions,
+                c.embed_dim,
+                embeddings.pp("position_embedding"),
+            )?,
+            num_positions,
+            // The misspelling matches the `transformers` weight names.
+            pre_layer_norm: candle_nn::layer_norm(c.embed_dim, 1e-5, vb.pp("pre_layrnorm"))?,
+            encoder: ClipEncoder::new(vb.pp("encoder"), &c.into())?,
+            post_layer_norm: candle_nn::layer_norm(c.embed_dim, 1e-5, vb.pp("post_layernorm"))?,
+        })
+    }
+}
+
+impl Module for ClipVisionTransformer {
+    fn forward(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        let bsz = pixel_values.dim(0)?;
+        let patches = self
+            .patch_embedding
+            .forward(pixel_values)?
+            .flatten_from(2)?
+            .transpose(1, 2)?;
+        let class = self
+            .class_embedding
+            .expand((bsz, 1, self.class_embedding.dim(0)?))?;
+        let position_ids = Tensor::arange(0u32, self.num_positions as u32, pixel_values.device())?;
+        let xs = Tensor::cat(&[class, patches], 1)?
+            .broadcast_add(&self.position_embedding.forward(&position_ids)?)?
+            .apply(&self.pre_layer_norm)?;
+        let xs = self.encoder.forward(&xs, None)?;
+        // Pool at the class token.
+        self.post_layer_norm.forward(&xs.i((.., 0, ..))?)
+    }
+}
+
+pub struct ClipModel {
+    text_model: ClipTextTransformer,
+    vision_model: ClipVisionTransformer,
+    visual_projection: candle_nn::Linear,
+    text_projection: candle_nn::Linear,
+}
+
+impl ClipModel {
+    pub fn new(vb: VarBuilder, c: &ClipConfig) -> Result<Self> {
+        Ok(Self {
+            text_model: ClipTextTransformer::new(vb.pp("text_model"), &c.text_config)?,
+            vision_model: ClipVisionTransformer::new(vb.pp("vision_model"), &c.vision_config)?,
+            visual_projection: candle_nn::linear_no_bias(
+                c.vision_config.embed_dim,
+                c.vision_config.projection_dim,
+                vb.pp("visual_projection"),
+            )?,
+            text_projection: candle_nn::linear_no_bias(
+                c.text_config.embed_dim,
+                c.text_config.projection_dim,
+                vb.pp("text_projection"),
+            )?,
+        })
+    }
+
+    pub fn get_text_features(&self, input_ids: &Tensor) -> Result<Tensor> {
+        input_ids
+            .apply(&self.text_model)?
+            .apply(&self.text_projection)
+    }
+
+    pub fn get_image_features(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        pixel_values
+            .apply(&self.vision_model)?
+            .apply(&self.visual_projection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_nn::VarMap;
+    use candle_transformers::models::clip as reference;
+
+    fn config(activation: Activation) -> ClipConfig {
+        ClipConfig {
+            text_config: ClipTextConfig {
+                vocab_size: 50,
+                embed_dim: 16,
+                activation,
+                intermediate_size: 32,
+                max_position_embeddings: 8,
+                num_hidden_layers: 2,
+                num_attention_heads: 4,
+                projection_dim: 8,
+            },
+            vision_config: ClipVisionConfig {
+                embed_dim: 16,
+                activation,
+                intermediate_size: 32,
+                num_hidden_layers: 2,
+                num_attention_heads: 4,
+                projection_dim: 8,
+                num_channels: 3,
+                image_size: 8,
+                patch_size: 4,
+            },
+            logit_scale_init_value: 2.6592,
+            image_size: 8,
+        }
+    }
+
+    fn max_difference(a: &Tensor, b: &Tensor) -> f32 {
+        (a - b)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar()
+            .unwrap()
+    }
+
+    #[test]
+    fn matches_candle_for_quick_gelu() {
+        let device = Device::Cpu;
+        let weights = VarMap::new();
+        let vb = VarBuilder::from_varmap(&weights, DType::F32, &device);
+        let model = ClipModel::new(vb.clone(), &config(Activation::QuickGelu)).unwrap();
+
+        let c = config(Activation::QuickGelu);
+        let reference_config = reference::ClipConfig {
+            text_config: reference::text_model::ClipTextConfig {
+                vocab_size: c.text_config.vocab_size,
+                embed_dim: c.text_config.embed_dim,
+                activation: reference::text_model::Activation::QuickGelu,
+                intermediate_size: c.text_config.intermediate_size,
+                max_position_embeddings: c.text_config.max_position_embeddings,
+                pad_with: None,
+                num_hidden_layers: c.text_config.num_hidden_layers,
+                num_attention_heads: c.text_config.num_attention_heads,
+                projection_dim: c.text_config.projection_dim,
+            },
+            vision_config: reference::vision_model::ClipVisionConfig {
+                embed_dim: c.vision_config.embed_dim,
+                activation: reference::text_model::Activation::QuickGelu,
+                intermediate_size: c.vision_config.intermediate_size,
+                num_hidden_layers: c.vision_config.num_hidden_layers,
+                num_attention_heads: c.vision_config.num_attention_heads,
+                projection_dim: c.vision_config.projection_dim,
+                num_channels: c.vision_config.num_channels,
+                image_size: c.vision_config.image_size,
+                patch_size: c.vision_config.patch_size,
+            },
+            logit_scale_init_value: c.logit_scale_init_value,
+            image_size: c.image_size,
+        };
+        let expected = reference::ClipModel::new(vb, &reference_config).unwrap();
+
+        let input_ids = Tensor::new(&[[3u32, 7, 49, 0, 0], [5, 9, 11, 49, 0]], &device).unwrap();
+        let text = model.get_text_features(&input_ids).unwrap();
+        assert_eq!(text.dims(), &[2, 8]);
+        assert!(max_difference(&text, &expected.get_text_features(&input_ids).unwrap()) < 1e-5);
+
+        let pixels = Tensor::randn(0f32, 1f32, (2, 3, 8, 8), &device).unwrap();
+        let images = model.get_image_features(&pixels).unwrap();
+        assert_eq!(images.dims(), &[2, 8]);
+        assert!(max_difference(&images, &expected.get_image_features(&pixels).unwrap()) < 1e-5);
+    }
+
+    #[test]
+    fn runs_the_configured_activation() {
+        let device = Device::Cpu;
+        let weights = VarMap::new();
+        let vb = VarBuilder::from_varmap(&weights, DType::F32, &device);
+        let quick = ClipModel::new(vb.clone(), &config(Activation::QuickGelu)).unwrap();
+        let gelu = ClipModel::new(vb, &config(Activation::Gelu)).unwrap();
+        let pixels = Tensor::randn(0f32, 1f32, (1, 3, 8, 8), &device).unwrap();
+        let difference = max_difference(
+            &quick.get_image_features(&pixels).unwrap(),
+            &gelu.get_image_features(&pixels).unwrap(),
+        );
+        assert!(difference > 1e-6);
+    }
+}
@@ -0,0 +1,90 @@
+use image::{DynamicImage, Rgb, RgbImage};
+
+/// The background transparent images are composited onto, white by default so product
+/// shots on transparent backgrounds look the way they do in a browser.
+pub const DEFAULT_BACKGROUND: Rgb<u8> = Rgb([255, 255, 255]);
+
+/// Parses a six-digit hex color, with or without a leading `#`.
+pub fn parse_hex_color(value: &str) -> anyhow::Result<Rgb<u8>> {
+    let hex = value.trim().trim_start_matches('#');
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(anyhow::Error::msg(format!("Invalid hex color: {}", value)));
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16);
+    Ok(Rgb([channel(0)?, channel(2)?, channel(4)?]))
+}
+
+/// Converts any decoded image to 8-bit RGB before it is resized. Grayscale is replicated
+/// across channels, 16-bit and float samples are scaled down, and alpha is composited
+/// onto `background` instead of being dropped.
+pub fn to_rgb8(img: &DynamicImage, background: Rgb<u8>) -> RgbImage {
+    if !img.color().has_alpha() {
+        return img.to_rgb8();
+    }
+
+    let rgba = img.to_rgba32f();
+    let background = background.0.map(|c| c as f32 / 255.0);
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let a = a.clamp(0.0, 1.0);
+        let blend = |c: f32, bg: f32| ((c * a + bg * (1.0 - a)).clamp(0.0, 1.0) * 255.0).round() as u8;
+        Rgb([
+            blend(r, background[0]),
+            blend(g, background[1]),
+            blend(b, background[2]),
+        ])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayAlphaImage, GrayImage, ImageBuffer, Luma, LumaA, Rgba, RgbaImage};
+
+    #[test]
+    fn composites_alpha_onto_the_background() {
+        let img = RgbaImage::from_fn(3, 1, |x, _| match x {
+            0 => Rgba([255, 0, 0, 255]),
+            1 => Rgba([255, 0, 0, 128]),
+            _ => Rgba([255, 0, 0, 0]),
+        });
+        let rgb = to_rgb8(&DynamicImage::ImageRgba8(img), DEFAULT_BACKGROUND);
+        assert_eq!(rgb.get_pixel(0, 0), &Rgb([255, 0, 0]));
+        assert_eq!(rgb.get_pixel(1, 0), &Rgb([255, 127, 127]));
+        assert_eq!(rgb.get_pixel(2, 0), &DEFAULT_BACKGROUND);
+    }
+
+    #[test]
+    fn composites_16_bit_alpha() {
+        let img: ImageBuffer<Rgba<u16>, Vec<u16>> =
+            ImageBuffer::from_pixel(1, 1, Rgba([0, 65535, 0, 32768]));
+        let background = parse_hex_color("#000000").unwrap();
+        let rgb = to_rgb8(&DynamicImage::ImageRgba16(img), background);
+        assert_eq!(rgb.get_pixel(0, 0), &Rgb([0, 128, 0]));
+    }
+
+    #[test]
+    fn composites_gray_alpha() {
+        let img = GrayAlphaImage::from_fn(2, 1, |x, _| LumaA([200, if x == 0 { 255 } else { 0 }]));
+        let background = parse_hex_color("#336699").unwrap();
+        let rgb = to_rgb8(&DynamicImage::ImageLumaA8(img), background);
+        assert_eq!(rgb.get_pixel(0, 0), &Rgb([200, 200, 200]));
+        assert_eq!(rgb.get_pixel(1, 0), &Rgb([0x33, 0x66, 0x99]));
+    }
+
+    #[test]
+    fn scales_16_bit_gray_without_alpha() {
+        let img: ImageBuffer<Luma<u16>, Vec<u16>> = ImageBuffer::from_pixel(1, 1, Luma([65535 / 5]));
+        let rgb = to_rgb8(&DynamicImage::ImageLuma16(img), DEFAULT_BACKGROUND);
+        assert_eq!(rgb.get_pixel(0, 0), &Rgb([51, 51, 51]));
+        let gray = GrayImage::from_pixel(1, 1, Luma([51]));
+        assert_eq!(to_rgb8(&DynamicImage::ImageLuma8(gray), DEFAULT_BACKGROUND), rgb);
+    }
+
+    #[test]
+    fn rejects_malformed_colors() {
+        assert!(parse_hex_color("#12345").is_err());
+        assert!(parse_hex_color("zzzzzz").is_err());
+        assert_eq!(parse_hex_color(" ffffff ").unwrap(), DEFAULT_BACKGROUND);
+    }
+}
@@ -0,0 +1,242 @@
+use anyhow::{Error as E, Result};
+use hf_hub::api::sync::{Api, ApiRepo};
+use hf_hub::{Repo, RepoType};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use crate::clipembedder::clip::{Activation, ClipConfig, ClipTextConfig, ClipVisionConfig};
+
+const DEFAULT_MODEL_ID: &str = "openai/clip-vit-base-patch32";
+/// The default repo only ships safetensors weights on this PR branch.
+const DEFAULT_MODEL_REVISION: &str = "refs/pr/15";
+
+const DEFAULT_CAPTION_MODEL_ID: &str = "Salesforce/blip-image-captioning-large";
+/// As above, safetensors weights live on a PR branch.
+const DEFAULT_CAPTION_MODEL_REVISION: &str = "refs/pr/18";
+
+/// Where the model weights, tokenizer and configs are loaded from.
+#[derive(Debug, Clone)]
+pub struct ModelSource {
+    pub model_id: String,
+    pub revision: String,
+    /// A local directory holding the model files. Takes precedence over the hub.
+    pub local_path: Option<PathBuf>,
+}
+
+impl Default for ModelSource {
+    fn default() -> Self {
+        Self {
+            model_id: DEFAULT_MODEL_ID.to_string(),
+            revision: DEFAULT_MODEL_REVISION.to_string(),
+            local_path: None,
+        }
+    }
+}
+
+impl ModelSource {
+    /// The BLIP model behind captioning.
+    pub fn default_captioner() -> Self {
+        Self {
+            model_id: DEFAULT_CAPTION_MODEL_ID.to_string(),
+            revision: DEFAULT_CAPTION_MODEL_REVISION.to_string(),
+            local_path: None,
+        }
+    }
+
+    /// Overrides parts of `default`. A different model id without an explicit revision
+    /// uses `main`, since the default's pinned revision belongs to the default repo.
+    pub fn with_overrides(
+        default: Self,
+        model_id: Option<&str>,
+        revision: Option<&str>,
+        local_path: Option<&Path>,
+    ) -> Self {
+        let mut source = default;
+        if let Some(model_id) = model_id
+            && model_id != source.model_id
+        {
+            source.model_id = model_id.to_string();
+            source.revision = "main".to_string();
+        }
+        if let Some(revision) = revision {
+            source.revision = revision.to_string();
+        }
+        source.local_path = local_path.map(Path::to_path_buf);
+        source
+    }
+
+    /// Opens the source for file lookups.
+    pub fn files(&self) -> Result<ModelFiles> {
+        match &self.local_path {
+            Some(path) => Ok(ModelFiles::Local(path.clone())),
+            None => {
+                let api = Api::new()?;
+                Ok(ModelFiles::Hub(Box::new(api.repo(Repo::with_revision(
+                    self.model_id.clone(),
+                    RepoType::Model,
+                    self.revision.clone(),
+                )))))
+            }
+        }
+    }
+
+    /// A human readable description for logging.
+    pub fn describe(&self) -> String {
+        match &self.local_path {
+            Some(path) => format!("{}", path.display()),
+            None => format!("{}@{}", self.model_id, self.revision),
+        }
+    }
+}
+
+pub enum ModelFiles {
+    Local(PathBuf),
+    Hub(Box<ApiRepo>),
+}
+
+impl ModelFiles {
+    /// Resolves a file, downloading it from the hub if necessary.
+    pub fn get(&self, filename: &str) -> Result<PathBuf> {
+        match self {
+            ModelFiles::Local(dir) => {
+                let path = dir.join(filename);
+                if path.exists() {
+                    Ok(path)
+                } else {
+                    Err(E::msg(format!("{} not found", path.display())))
+                }
+            }
+            ModelFiles::Hub(repo) => Ok(repo.get(filename)?),
+        }
+    }
+}
+
+/// The subset of a `transformers` CLIP `config.json` needed to build the model.
+/// Missing fields fall back to the `transformers` defaults.
+#[derive(Debug, Deserialize)]
+struct HfClipConfig {
+    #[serde(default = "default_projection_dim")]
+    projection_dim: usize,
+    #[serde(default = "default_logit_scale_init_value")]
+    logit_scale_init_value: f32,
+    #[serde(default)]
+    text_config: HfTextConfig,
+    #[serde(default)]
+    vision_config: HfVisionConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct HfTextConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    max_position_embeddings: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    hidden_act: String,
+}
+
+impl Default for HfTextConfig {
+    fn default() -> Self {
+        Self {
+            vocab_size: 49408,
+            hidden_size: 512,
+            intermediate_size: 2048,
+            max_position_embeddings: 77,
+            num_hidden_layers: 12,
+            num_attention_heads: 8,
+            hidden_act: "quick_gelu".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct HfVisionConfig {
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_channels: usize,
+    image_size: usize,
+    patch_size: usize,
+    hidden_act: String,
+}
+
+impl Default for HfVisionConfig {
+    fn default() -> Self {
+        Self {
+            hidden_size: 768,
+            intermediate_size: 3072,
+            num_hidden_layers: 12,
+            num_attention_heads: 12,
+            num_channels: 3,
+            image_size: 224,
+            patch_size: 32,
+            hidden_act: "quick_gelu".to_string(),
+        }
+    }
+}
+
+fn default_projection_dim() -> usize {
+    512
+}
+
+fn default_logit_scale_init_value() -> f32 {
+    2.6592
+}
+
+fn activation(hidden_act: &str) -> Result<Activation> {
+    match hidden_act {
+        "quick_gelu" => Ok(Activation::QuickGelu),
+        "gelu" => Ok(Activation::Gelu),
+        "gelu_pytorch_tanh" | "gelu_new" => Ok(Activation::GeluPytorchTanh),
+        other => Err(E::msg(format!("Unsupported CLIP activation: {}", other))),
+    }
+}
+
+/// Builds a `ClipConfig` from the repo's `config.json`.
+pub fn load_clip_config(path: impl AsRef<Path>) -> Result<ClipConfig> {
+    let hf: HfClipConfig = serde_json::from_slice(&std::fs::read(path)?)?;
+    let text_config = ClipTextConfig {
+        vocab_size: hf.text_config.vocab_size,
+        embed_dim: hf.text_config.hidden_size,
+        activation: activation(&hf.text_config.hidden_act)?,
+        intermediate_size: hf.text_config.intermediate_size,
+        max_position_embeddings: hf.text_config.max_position_embeddings,
+        num_hidden_layers: hf.text_config.num_hidden_layers,
+        num_attention_heads: hf.text_config.num_attention_heads,
+        projection_dim: hf.projection_dim,
+    };
+    let vision_config = ClipVisionConfig {
+        embed_dim: hf.vision_config.hidden_size,
+        activation: activation(&hf.vision_config.hidden_act)?,
+        intermediate_size: hf.vision_config.intermediate_size,
+        num_hidden_layers: hf.vision_config.num_hidden_layers,
+        num_attention_heads: hf.vision_config.num_attention_heads,
+        projection_dim: hf.projection_dim,
+        num_channels: hf.vision_config.num_channels,
+        image_size: hf.vision_config.image_size,
+        patch_size: hf.vision_config.patch_size,
+    };
+    Ok(ClipConfig {
+        image_size: vision_config.image_size,
+        text_config,
+        vision_config,
+        logit_scale_init_value: hf.logit_scale_init_value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_hub_activations() {
+        assert!(matches!(activation("quick_gelu").unwrap(), Activation::QuickGelu));
+        assert!(matches!(activation("gelu").unwrap(), Activation::Gelu));
+        assert!(matches!(activation("gelu_pytorch_tanh").unwrap(), Activation::GeluPytorchTanh));
+        assert!(activation("relu").is_err());
+    }
+}
@@ -0,0 +1,622 @@
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::metadata::Orientation;
+use image::{AnimationDecoder, ColorType, DynamicImage, Frames, ImageDecoder, ImageFormat, ImageReader};
+use std::fmt;
+use std::io::Cursor;
+
+/// Limits applied to untrusted image bytes before and during decoding.
+#[derive(Debug, Clone)]
+pub struct DecodeLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Upper bound on the memory the decoder may allocate, in bytes.
+    pub max_alloc: u64,
+    /// Upper bound on the size of the encoded input, in bytes.
+    pub max_encoded_bytes: usize,
+    /// Formats accepted from clients. AVIF is only available when Eidolon is built with the
+    /// opt-in `avif` feature, which links the system dav1d library.
+    pub allowed_formats: Vec<ImageFormat>,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_width: 8192,
+            max_height: 8192,
+            max_alloc: 512 * 1024 * 1024,
+            max_encoded_bytes: 20 * 1024 * 1024,
+            allowed_formats: vec![
+                ImageFormat::Jpeg,
+                ImageFormat::Png,
+                ImageFormat::WebP,
+                ImageFormat::Gif,
+                ImageFormat::Bmp,
+                ImageFormat::Tiff,
+                #[cfg(feature = "avif")]
+                ImageFormat::Avif,
+            ],
+        }
+    }
+}
+
+impl DecodeLimits {
+    fn image_limits(&self) -> image::Limits {
+        let mut limits = image::Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_alloc);
+        limits
+    }
+}
+
+/// Parses a comma-separated list of image file extensions into formats.
+pub fn parse_formats(value: &str) -> anyhow::Result<Vec<ImageFormat>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|extension| !extension.is_empty())
+        .map(|extension| {
+            ImageFormat::from_extension(extension)
+                .ok_or_else(|| anyhow::Error::msg(format!("Unknown image format: {}", extension)))
+        })
+        .collect()
+}
+
+/// Why client-supplied image bytes were rejected.
+#[derive(Debug)]
+pub enum ImageDecodeError {
+    Empty,
+    TooLarge { size: usize, limit: usize },
+    UnknownFormat,
+    FormatNotAllowed(ImageFormat),
+    LimitsExceeded(String),
+    Malformed(String),
+}
+
+impl fmt::Display for ImageDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageDecodeError::Empty => write!(f, "Image bytes cannot be empty"),
+            ImageDecodeError::TooLarge { size, limit } => {
+                write!(f, "Image is {} bytes, exceeding the limit of {} bytes", size, limit)
+            }
+            ImageDecodeError::UnknownFormat => write!(f, "Image format could not be detected"),
+            ImageDecodeError::FormatNotAllowed(format) => {
+                write!(f, "Image format {:?} is not allowed", format)
+            }
+            ImageDecodeError::LimitsExceeded(e) => write!(f, "Image exceeds decoding limits: {}", e),
+            ImageDecodeError::Malformed(e) => write!(f, "Image could not be decoded: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ImageDecodeError {}
+
+/// Frames decoded from an image along with the format they were detected as.
+pub struct DecodedImage {
+    pub format: ImageFormat,
+    /// The color type the decoder reported, before animation frames were composited
+    /// into RGBA.
+    pub color_type: ColorType,
+    pub frames: Vec<DynamicImage>,
+}
+
+/// Decodes client-supplied bytes, enforcing the size limits and format allow-list, and
+/// rotates the result upright according to its EXIF orientation. Animated images yield
+/// their first frame.
+pub fn decode_image(bytes: &[u8], limits: &DecodeLimits) -> Result<DynamicImage, ImageDecodeError> {
+    let reader = open(bytes, limits)?;
+    decode_still(reader, limits)
+}
+
+/// Like `decode_image`, but samples up to `max_frames` evenly spaced frames from animated
+/// GIF, WebP and PNG images, each rotated by the image's EXIF orientation. Still images
+/// yield a single frame.
+pub fn decode_frames(
+    bytes: &[u8],
+    limits: &DecodeLimits,
+    max_frames: usize,
+) -> Result<DecodedImage, ImageDecodeError> {
+    let reader = open(bytes, limits)?;
+    let format = reader.format().ok_or(ImageDecodeError::UnknownFormat)?;
+    if max_frames > 1
+        && let Some(animation) = animation_frames(bytes, format, limits)?
+    {
+        let frames = sample_frames(animation.frames, limits)?;
+        if !frames.is_empty() {
+            let mut frames = pick_evenly(frames, max_frames);
+            if let Some(orientation) = animation.orientation {
+                frames.iter_mut().for_each(|frame| frame.apply_orientation(orientation));
+            }
+            return Ok(DecodedImage {
+                format,
+                color_type: animation.color_type,
+                frames,
+            });
+        }
+    }
+    let still = decode_still(reader, limits)?;
+    Ok(DecodedImage {
+        format,
+        color_type: still.color(),
+        frames: vec![still],
+    })
+}
+
+/// A short lowercase name for a format, as reported to clients (e.g. `jpeg`, `gif`).
+pub fn format_name(format: ImageFormat) -> String {
+    format!("{:?}", format).to_lowercase()
+}
+
+/// Validates the encoded bytes and detects their format against the allow-list.
+fn open<'a>(
+    bytes: &'a [u8],
+    limits: &DecodeLimits,
+) -> Result<ImageReader<Cursor<&'a [u8]>>, ImageDecodeError> {
+    if bytes.is_empty() {
+        return Err(ImageDecodeError::Empty);
+    }
+    if bytes.len() > limits.max_encoded_bytes {
+        return Err(ImageDecodeError::TooLarge {
+            size: bytes.len(),
+            limit: limits.max_encoded_bytes,
+        });
+    }
+
+    let reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| ImageDecodeError::Malformed(e.to_string()))?;
+    let format = reader.format().ok_or(ImageDecodeError::UnknownFormat)?;
+    if !limits.allowed_formats.contains(&format) {
+        return Err(ImageDecodeError::FormatNotAllowed(format));
+    }
+    Ok(reader)
+}
+
+fn decode_still(
+    mut reader: ImageReader<Cursor<&[u8]>>,
+    limits: &DecodeLimits,
+) -> Result<DynamicImage, ImageDecodeError> {
+    reader.limits(limits.image_limits());
+    let mut decoder = reader.into_decoder().map_err(decode_error)?;
+    // Unlike `ImageReader::decode`, `into_decoder` doesn't check that the decoded image
+    // fits the allocation limit, so a small, highly compressed file could still decode
+    // into a huge buffer.
+    limits
+        .image_limits()
+        .reserve(decoder.total_bytes())
+        .map_err(decode_error)?;
+    // A missing or unreadable orientation tag leaves the image as stored.
+    let orientation = decoder.orientation().ok();
+    let mut img = DynamicImage::from_decoder(decoder).map_err(decode_error)?;
+    if let Some(orientation) = orientation {
+        img.apply_orientation(orientation);
+    }
+    Ok(img)
+}
+
+/// The frames of an animated image, with what its decoder reported about the image.
+struct Animation<'a> {
+    frames: Frames<'a>,
+    orientation: Option<Orientation>,
+    color_type: ColorType,
+}
+
+/// Returns the frames of animated images, or `None` for still ones.
+fn animation_frames<'a>(
+    bytes: &'a [u8],
+    format: ImageFormat,
+    limits: &DecodeLimits,
+) -> Result<Option<Animation<'a>>, ImageDecodeError> {
+    let animation = match format {
+        ImageFormat::Gif => {
+            let mut decoder = GifDecoder::new(Cursor::new(bytes)).map_err(decode_error)?;
+            decoder.set_limits(limits.image_limits()).map_err(decode_error)?;
+            Some(Animation {
+                orientation: decoder.orientation().ok(),
+                color_type: decoder.color_type(),
+                frames: decoder.into_frames(),
+            })
+        }
+        ImageFormat::WebP => {
+            let mut decoder = WebPDecoder::new(Cursor::new(bytes)).map_err(decode_error)?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder.set_limits(limits.image_limits()).map_err(decode_error)?;
+            Some(Animation {
+                orientation: decoder.orientation().ok(),
+                color_type: decoder.color_type(),
+                frames: decoder.into_frames(),
+            })
+        }
+        ImageFormat::Png => {
+            let mut decoder = PngDecoder::with_limits(Cursor::new(bytes), limits.image_limits())
+                .map_err(decode_error)?;
+            if !decoder.is_apng().map_err(decode_error)? {
+                return Ok(None);
+            }
+            Some(Animation {
+                orientation: decoder.orientation().ok(),
+                color_type: decoder.color_type(),
+                frames: decoder.apng().map_err(decode_error)?.into_frames(),
+            })
+        }
+        _ => None,
+    };
+    Ok(animation)
+}
+
+/// Collects composited frames, counting them against the allocation limit since the
+/// decoders only bound a single frame.
+fn sample_frames(frames: Frames, limits: &DecodeLimits) -> Result<Vec<DynamicImage>, ImageDecodeError> {
+    let mut allocated = 0u64;
+    let mut collected = vec![];
+    for frame in frames {
+        let buffer = frame.map_err(decode_error)?.into_buffer();
+        allocated += buffer.as_raw().len() as u64;
+        if allocated > limits.max_alloc {
+            return Err(ImageDecodeError::LimitsExceeded(format!(
+                "animation frames exceed {} bytes",
+                limits.max_alloc
+            )));
+        }
+        collected.push(DynamicImage::ImageRgba8(buffer));
+    }
+    Ok(collected)
+}
+
+/// Keeps `count` frames spread evenly across the animation, always including the first.
+fn pick_evenly(frames: Vec<DynamicImage>, count: usize) -> Vec<DynamicImage> {
+    if frames.len() <= count {
+        return frames;
+    }
+    let total = frames.len();
+    let mut frames: Vec<Option<DynamicImage>> = frames.into_iter().map(Some).collect();
+    (0..count)
+        .filter_map(|i| frames[i * total / count].take())
+        .collect()
+}
+
+fn decode_error(e: image::ImageError) -> ImageDecodeError {
+    match e {
+        image::ImageError::Limits(e) => ImageDecodeError::LimitsExceeded(e.to_string()),
+        e => ImageDecodeError::Malformed(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::GifEncoder;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::codecs::png::PngEncoder;
+    use image::{Delay, Frame, ImageEncoder, Rgb, RgbImage, Rgba, RgbaImage};
+
+    const RED: [u8; 3] = [255, 0, 0];
+    const GREEN: [u8; 3] = [0, 255, 0];
+    const BLUE: [u8; 3] = [0, 0, 255];
+    const WHITE: [u8; 3] = [255, 255, 255];
+
+    /// A 64x32 JPEG with red, green, blue and white quadrants (clockwise from the top
+    /// left) and an EXIF APP1 segment carrying `orientation`.
+    fn quadrant_jpeg(orientation: u16) -> Vec<u8> {
+        let img = RgbImage::from_fn(64, 32, |x, y| {
+            Rgb(match (x < 32, y < 16) {
+                (true, true) => RED,
+                (false, true) => GREEN,
+                (false, false) => WHITE,
+                (true, false) => BLUE,
+            })
+        });
+        let mut jpeg = vec![];
+        JpegEncoder::new_with_quality(&mut jpeg, 95).encode_image(&img).unwrap();
+
+        // A big-endian TIFF header followed by one IFD holding only the orientation tag.
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+        exif.extend_from_slice(&orientation.to_be_bytes());
+        exif.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(&exif);
+        jpeg.splice(2..2, segment);
+        jpeg
+    }
+
+    /// The colors at the centers of the four quadrants, clockwise from the top left.
+    fn quadrants(img: &DynamicImage) -> [[u8; 3]; 4] {
+        let rgb = img.to_rgb8();
+        let (w, h) = (rgb.width(), rgb.height());
+        let nearest = |x: u32, y: u32| {
+            let pixel = rgb.get_pixel(x, y).0;
+            let distance = |color: &[u8; 3]| -> i32 {
+                (0..3).map(|i| (color[i] as i32 - pixel[i] as i32).abs()).sum()
+            };
+            *[RED, GREEN, BLUE, WHITE].iter().min_by_key(|color| distance(color)).unwrap()
+        };
+        [
+            nearest(w / 4, h / 4),
+            nearest(3 * w / 4, h / 4),
+            nearest(3 * w / 4, 3 * h / 4),
+            nearest(w / 4, 3 * h / 4),
+        ]
+    }
+
+    #[test]
+    fn keeps_upright_jpegs_as_stored() {
+        let img = decode_image(&quadrant_jpeg(1), &DecodeLimits::default()).unwrap();
+        assert_eq!((img.width(), img.height()), (64, 32));
+        assert_eq!(quadrants(&img), [RED, GREEN, WHITE, BLUE]);
+    }
+
+    #[test]
+    fn rotates_180_for_orientation_3() {
+        let img = decode_image(&quadrant_jpeg(3), &DecodeLimits::default()).unwrap();
+        assert_eq!((img.width(), img.height()), (64, 32));
+        assert_eq!(quadrants(&img), [WHITE, BLUE, RED, GREEN]);
+    }
+
+    #[test]
+    fn rotates_clockwise_for_orientation_6() {
+        let img = decode_image(&quadrant_jpeg(6), &DecodeLimits::default()).unwrap();
+        assert_eq!((img.width(), img.height()), (32, 64));
+        assert_eq!(quadrants(&img), [BLUE, RED, GREEN, WHITE]);
+    }
+
+    #[test]
+    fn rotates_counterclockwise_for_orientation_8() {
+        let img = decode_image(&quadrant_jpeg(8), &DecodeLimits::default()).unwrap();
+        assert_eq!((img.width(), img.height()), (32, 64));
+        assert_eq!(quadrants(&img), [GREEN, WHITE, BLUE, RED]);
+    }
+
+    fn encode(img: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Cursor::new(vec![]);
+        img.write_to(&mut bytes, format).unwrap();
+        bytes.into_inner()
+    }
+
+    fn solid(color: [u8; 3]) -> RgbaImage {
+        RgbaImage::from_pixel(4, 4, Rgba([color[0], color[1], color[2], 255]))
+    }
+
+    fn animated_gif(colors: &[[u8; 3]]) -> Vec<u8> {
+        let mut bytes = vec![];
+        let mut encoder = GifEncoder::new(&mut bytes);
+        let frames = colors
+            .iter()
+            .map(|&color| Frame::from_parts(solid(color), 0, 0, Delay::from_numer_denom_ms(100, 1)));
+        encoder.encode_frames(frames).unwrap();
+        drop(encoder);
+        bytes
+    }
+
+    fn crc32(bytes: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for &byte in bytes {
+            crc ^= byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            }
+        }
+        !crc
+    }
+
+    fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = out.len();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(data);
+        let crc = crc32(&out[start..]);
+        out.extend_from_slice(&crc.to_be_bytes());
+    }
+
+    fn frame_control(sequence: u32, width: u32, height: u32) -> Vec<u8> {
+        let mut data = vec![];
+        for value in [sequence, width, height, 0, 0] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        data.extend_from_slice(&[0, 1, 0, 10, 0, 0]);
+        data
+    }
+
+    /// A two-frame APNG of `img`, with an eXIf chunk carrying `orientation`.
+    fn apng(img: &DynamicImage, orientation: u16) -> Vec<u8> {
+        let mut png = vec![];
+        PngEncoder::new(&mut png)
+            .write_image(img.as_bytes(), img.width(), img.height(), img.color().into())
+            .unwrap();
+
+        let (mut header, mut data) = (vec![], vec![]);
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            match &rest[4..8] {
+                b"IHDR" => header = rest[8..8 + len].to_vec(),
+                b"IDAT" => data.extend_from_slice(&rest[8..8 + len]),
+                _ => {}
+            }
+            rest = &rest[12 + len..];
+        }
+
+        let mut exif = b"MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+        exif.extend_from_slice(&orientation.to_be_bytes());
+        exif.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+        let mut out = png[..8].to_vec();
+        chunk(&mut out, b"IHDR", &header);
+        chunk(&mut out, b"acTL", &[0, 0, 0, 2, 0, 0, 0, 0]);
+        chunk(&mut out, b"eXIf", &exif);
+        chunk(&mut out, b"fcTL", &frame_control(0, img.width(), img.height()));
+        chunk(&mut out, b"IDAT", &data);
+        chunk(&mut out, b"fcTL", &frame_control(1, img.width(), img.height()));
+        let mut frame_data = 2u32.to_be_bytes().to_vec();
+        frame_data.extend_from_slice(&data);
+        chunk(&mut out, b"fdAT", &frame_data);
+        chunk(&mut out, b"IEND", &[]);
+        out
+    }
+
+    #[test]
+    fn decodes_webp_and_tiff() {
+        let img = DynamicImage::ImageRgba8(solid(GREEN));
+        for format in [ImageFormat::WebP, ImageFormat::Tiff] {
+            let decoded = decode_frames(&encode(&img, format), &DecodeLimits::default(), 4).unwrap();
+            assert_eq!(decoded.format, format);
+            assert_eq!(decoded.frames.len(), 1);
+            assert_eq!(decoded.frames[0].to_rgb8().get_pixel(1, 1).0, GREEN);
+        }
+        assert_eq!(format_name(ImageFormat::WebP), "webp");
+    }
+
+    #[test]
+    fn samples_animated_gif_frames_evenly() {
+        let bytes = animated_gif(&[RED, GREEN, BLUE, WHITE]);
+        let decoded = decode_frames(&bytes, &DecodeLimits::default(), 2).unwrap();
+        assert_eq!(format_name(decoded.format), "gif");
+        let colors: Vec<[u8; 3]> = decoded
+            .frames
+            .iter()
+            .map(|frame| frame.to_rgb8().get_pixel(0, 0).0)
+            .collect();
+        assert_eq!(colors, [RED, BLUE]);
+
+        let first = decode_image(&bytes, &DecodeLimits::default()).unwrap();
+        assert_eq!(first.to_rgb8().get_pixel(0, 0).0, RED);
+    }
+
+    #[test]
+    fn rotates_animated_frames_by_their_orientation() {
+        let img = RgbaImage::from_fn(4, 2, |x, _| {
+            let [r, g, b] = if x < 2 { RED } else { BLUE };
+            Rgba([r, g, b, 255])
+        });
+        let decoded = decode_frames(&apng(&DynamicImage::ImageRgba8(img), 6), &DecodeLimits::default(), 4).unwrap();
+        assert_eq!(decoded.frames.len(), 2);
+        for frame in decoded.frames {
+            let rgb = frame.to_rgb8();
+            assert_eq!((rgb.width(), rgb.height()), (2, 4));
+            assert_eq!(rgb.get_pixel(0, 0).0, RED);
+            assert_eq!(rgb.get_pixel(0, 3).0, BLUE);
+        }
+    }
+
+    /// A PNG whose header claims `width` x `height` RGB pixels, followed by a single
+    /// tiny data chunk instead of the pixels themselves.
+    fn png_header(width: u32, height: u32) -> Vec<u8> {
+        let mut header = width.to_be_bytes().to_vec();
+        header.extend_from_slice(&height.to_be_bytes());
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+        chunk(&mut out, b"IHDR", &header);
+        chunk(&mut out, b"IDAT", &[0x78, 0x9c, 0x03, 0x00, 0x00, 0x00, 0x00, 0x01]);
+        chunk(&mut out, b"IEND", &[]);
+        out
+    }
+
+    #[test]
+    fn rejects_empty_and_oversized_input() {
+        let limits = DecodeLimits {
+            max_encoded_bytes: 64,
+            ..DecodeLimits::default()
+        };
+        assert!(matches!(decode_image(&[], &limits), Err(ImageDecodeError::Empty)));
+        let bytes = encode(&DynamicImage::ImageRgba8(solid(RED)), ImageFormat::Png);
+        assert!(bytes.len() > 64);
+        assert!(matches!(
+            decode_frames(&bytes, &limits, 1),
+            Err(ImageDecodeError::TooLarge { limit: 64, .. })
+        ));
+        assert!(decode_image(&bytes, &DecodeLimits::default()).is_ok());
+    }
+
+    #[test]
+    fn rejects_headers_claiming_huge_dimensions() {
+        // Nowhere near enough data for 100000x100000 pixels; the header alone is refused.
+        for (width, height) in [(100_000, 1), (1, 100_000), (100_000, 100_000)] {
+            let result = decode_image(&png_header(width, height), &DecodeLimits::default());
+            assert!(
+                matches!(result, Err(ImageDecodeError::LimitsExceeded(_))),
+                "{}x{}: {:?}",
+                width,
+                height,
+                result.err()
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_decompression_bombs() {
+        // 4000x4000 black pixels compress to a few kilobytes but decode to 16 MB.
+        let img = image::GrayImage::new(4000, 4000);
+        let mut bytes = vec![];
+        PngEncoder::new(&mut bytes)
+            .write_image(img.as_raw(), 4000, 4000, image::ExtendedColorType::L8)
+            .unwrap();
+        assert!(bytes.len() < 100_000);
+
+        let limits = DecodeLimits {
+            max_alloc: 4 * 1024 * 1024,
+            ..DecodeLimits::default()
+        };
+        assert!(matches!(decode_image(&bytes, &limits), Err(ImageDecodeError::LimitsExceeded(_))));
+        assert!(decode_image(&bytes, &DecodeLimits::default()).is_ok());
+    }
+
+    #[test]
+    fn rejects_animations_whose_frames_exceed_the_allocation_limit() {
+        // Each 4x4 RGBA frame is 64 bytes, so three frames are over a 150 byte budget.
+        let limits = DecodeLimits {
+            max_alloc: 150,
+            ..DecodeLimits::default()
+        };
+        let bytes = animated_gif(&[RED, GREEN, BLUE]);
+        assert!(matches!(
+            decode_frames(&bytes, &limits, 3),
+            Err(ImageDecodeError::LimitsExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_unrecognized_bytes() {
+        let result = decode_image(b"definitely not an image", &DecodeLimits::default());
+        assert!(matches!(result, Err(ImageDecodeError::UnknownFormat)));
+    }
+
+    #[test]
+    fn parses_format_lists() {
+        let formats = parse_formats("jpg, png,,webp").unwrap();
+        assert_eq!(formats, [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP]);
+        assert!(parse_formats("png,psd2").is_err());
+    }
+
+    #[test]
+    fn reports_the_color_type_before_compositing() {
+        let rgb = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb(BLUE)));
+        let decoded = decode_frames(&apng(&rgb, 1), &DecodeLimits::default(), 4).unwrap();
+        assert_eq!(decoded.frames.len(), 2);
+        assert_eq!(decoded.frames[0].color(), image::ColorType::Rgba8);
+        assert_eq!(decoded.color_type, image::ColorType::Rgb8);
+
+        let gray = DynamicImage::ImageLuma8(image::GrayImage::new(4, 4));
+        let decoded = decode_frames(&encode(&gray, ImageFormat::Png), &DecodeLimits::default(), 4).unwrap();
+        assert_eq!(decoded.color_type, image::ColorType::L8);
+    }
+
+    #[test]
+    fn rejects_formats_outside_the_allow_list() {
+        let limits = DecodeLimits {
+            allowed_formats: vec![ImageFormat::Png],
+            ..DecodeLimits::default()
+        };
+        let bytes = animated_gif(&[RED]);
+        assert!(matches!(
+            decode_image(&bytes, &limits),
+            Err(ImageDecodeError::FormatNotAllowed(ImageFormat::Gif))
+        ));
+        let png = encode(&DynamicImage::ImageRgba8(solid(RED)), ImageFormat::Png);
+        assert!(decode_image(&png, &limits).is_ok());
+    }
+}
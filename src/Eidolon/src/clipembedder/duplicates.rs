@@ -0,0 +1,156 @@
+use crate::clipembedder::hashing::{hamming_distance, ImageHashes};
+use crate::utils::cosine_similarity;
+
+/// When two images count as duplicates: identical bytes always do, otherwise their DCT
+/// hashes must be within `max_hash_distance` bits and their embeddings at least
+/// `min_similarity` apart in cosine similarity.
+#[derive(Debug, Clone, Copy)]
+pub struct DuplicateThresholds {
+    pub max_hash_distance: u32,
+    pub min_similarity: f32,
+}
+
+impl Default for DuplicateThresholds {
+    fn default() -> Self {
+        Self {
+            max_hash_distance: 10,
+            min_similarity: 0.92,
+        }
+    }
+}
+
+pub struct DuplicateCandidate {
+    pub document_id: String,
+    pub hashes: ImageHashes,
+    pub embedding: Vec<f32>,
+}
+
+impl DuplicateThresholds {
+    fn matches(&self, a: &DuplicateCandidate, b: &DuplicateCandidate) -> bool {
+        a.hashes.sha256 == b.hashes.sha256
+            || (hamming_distance(a.hashes.phash, b.hashes.phash) <= self.max_hash_distance
+                && cosine_similarity(&a.embedding, &b.embedding) >= self.min_similarity)
+    }
+}
+
+/// Groups candidates transitively, so a chain of near-duplicates ends up in one group.
+/// Only groups with more than one member are returned, in order of first appearance.
+pub fn group_duplicates(
+    candidates: &[DuplicateCandidate],
+    thresholds: &DuplicateThresholds,
+) -> Vec<Vec<usize>> {
+    let mut parents: Vec<usize> = (0..candidates.len()).collect();
+    fn root(parents: &mut [usize], mut i: usize) -> usize {
+        while parents[i] != i {
+            parents[i] = parents[parents[i]];
+            i = parents[i];
+        }
+        i
+    }
+
+    for i in 0..candidates.len() {
+        for j in i + 1..candidates.len() {
+            if thresholds.matches(&candidates[i], &candidates[j]) {
+                let (a, b) = (root(&mut parents, i), root(&mut parents, j));
+                if a != b {
+                    parents[a.max(b)] = a.min(b);
+                }
+            }
+        }
+    }
+
+    let mut groups: Vec<Vec<usize>> = vec![];
+    let mut group_of_root = std::collections::HashMap::new();
+    for i in 0..candidates.len() {
+        let r = root(&mut parents, i);
+        let group = *group_of_root.entry(r).or_insert_with(|| {
+            groups.push(vec![]);
+            groups.len() - 1
+        });
+        groups[group].push(i);
+    }
+    groups.retain(|group| group.len() > 1);
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(document_id: &str, phash: u64, sha256: &str, embedding: [f32; 2]) -> DuplicateCandidate {
+        DuplicateCandidate {
+            document_id: document_id.to_string(),
+            hashes: ImageHashes {
+                ahash: 0,
+                dhash: 0,
+                phash,
+                sha256: sha256.to_string(),
+            },
+            embedding: embedding.to_vec(),
+        }
+    }
+
+    /// An embedding at `degrees` from the x axis; cos(10°) ≈ 0.985 and cos(25°) ≈ 0.906.
+    fn angle(degrees: f32) -> [f32; 2] {
+        let radians = degrees.to_radians();
+        [radians.cos(), radians.sin()]
+    }
+
+    #[test]
+    fn needs_both_the_hash_and_the_embedding_to_match() {
+        let thresholds = DuplicateThresholds::default();
+        let candidates = [
+            candidate("original", 0, "a", angle(0.0)),
+            // Three bits away and nearly the same embedding: a duplicate.
+            candidate("resized", 0b111, "b", angle(10.0)),
+            // Close hash, but the embeddings disagree.
+            candidate("lookalike", 0b1, "c", angle(60.0)),
+            // Same embedding as the original, but the hashes are too far apart.
+            candidate("cropped", 0xFFE0, "d", angle(0.0)),
+        ];
+        assert_eq!(group_duplicates(&candidates, &thresholds), [vec![0, 1]]);
+    }
+
+    #[test]
+    fn identical_bytes_are_always_duplicates() {
+        let candidates = [
+            candidate("a", 0, "same", angle(0.0)),
+            candidate("b", u64::MAX, "same", angle(90.0)),
+        ];
+        assert_eq!(group_duplicates(&candidates, &DuplicateThresholds::default()), [vec![0, 1]]);
+    }
+
+    /// Each neighbour is within the thresholds, but the ends of the chain are 25° and
+    /// 12 bits apart.
+    fn chain() -> Vec<DuplicateCandidate> {
+        vec![
+            candidate("a", 0, "a", angle(0.0)),
+            candidate("unrelated", u64::MAX, "x", angle(-90.0)),
+            candidate("b", 0x3F, "b", angle(12.5)),
+            candidate("c", 0xFFF, "c", angle(25.0)),
+        ]
+    }
+
+    #[test]
+    fn groups_chains_transitively() {
+        let thresholds = DuplicateThresholds::default();
+        let candidates = chain();
+        assert!(!thresholds.matches(&candidates[0], &candidates[3]));
+        assert_eq!(group_duplicates(&candidates, &thresholds), [vec![0, 2, 3]]);
+
+        // The same group comes back whatever order the chain arrives in.
+        let mut reversed = chain();
+        reversed.reverse();
+        assert_eq!(group_duplicates(&reversed, &thresholds), [vec![0, 1, 3]]);
+    }
+
+    #[test]
+    fn leaves_out_singletons() {
+        let candidates = [
+            candidate("a", 0, "a", angle(0.0)),
+            candidate("b", u64::MAX, "b", angle(90.0)),
+        ];
+        assert!(group_duplicates(&candidates, &DuplicateThresholds::default()).is_empty());
+        assert!(group_duplicates(&[], &DuplicateThresholds::default()).is_empty());
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::{Error as E, Result};
+use candle_core::{Device, Tensor};
+use serde::Deserialize;
+
+use crate::clipembedder::config::ModelSource;
+use crate::clipembedder::model::ClipEmbeddingModel;
+use crate::clipembedder::processor::ImageProcessor;
+use crate::clipembedder::siglip::SiglipEmbeddingModel;
+use crate::clipembedder::text::{LongTextMode, TextEmbedding};
+use crate::utils::softmax;
+
+/// How cosine scores between the two towers are turned into probabilities.
+#[derive(Debug, Clone, Copy)]
+pub enum Scoring {
+    /// CLIP: a softmax over all candidates of `logit_scale * cos`.
+    Softmax { logit_scale: f32 },
+    /// SigLIP: an independent sigmoid per candidate of `logit_scale * cos + logit_bias`.
+    Sigmoid { logit_scale: f32, logit_bias: f32 },
+}
+
+impl Scoring {
+    pub fn probabilities(&self, scores: &[f32]) -> Vec<f32> {
+        match *self {
+            Scoring::Softmax { logit_scale } => softmax(scores, logit_scale),
+            Scoring::Sigmoid {
+                logit_scale,
+                logit_bias,
+            } => scores
+                .iter()
+                .map(|s| 1.0 / (1.0 + (-(s * logit_scale + logit_bias)).exp()))
+                .collect(),
+        }
+    }
+}
+
+/// A model with aligned text and image towers producing L2-normalized embeddings
+/// in a shared space.
+pub trait DualEncoder: Send {
+    fn device(&self) -> &Device;
+
+    /// Generates embeddings for a batch of text, handling texts longer than the
+    /// encoder's context according to `mode`.
+    fn embed_texts_with(&self, texts: &[String], mode: LongTextMode) -> Result<Vec<TextEmbedding>>;
+
+    /// Generates embeddings for a batch of text, truncating long texts.
+    fn embed_texts(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        Ok(self
+            .embed_texts_with(texts, LongTextMode::Truncate)?
+            .into_iter()
+            .map(|embedding| embedding.values)
+            .collect())
+    }
+
+    /// The preprocessing the image tower expects.
+    fn image_processor(&self) -> &ImageProcessor;
+
+    /// Generates embeddings for a batch of images preprocessed with `image_processor`.
+    fn embed_images(&self, pixels: &[Tensor]) -> Result<Vec<Vec<f32>>>;
+
+    /// The scoring function the model was trained with.
+    fn scoring(&self) -> Scoring;
+}
+
+#[derive(Deserialize)]
+struct ModelType {
+    #[serde(default)]
+    model_type: String,
+}
+
+/// Loads the dual encoder matching the `model_type` in the source's `config.json`.
+pub fn load_dual_encoder(source: &ModelSource, device: &Device) -> Result<Box<dyn DualEncoder>> {
+    let config_filename = source.files()?.get("config.json")?;
+    let ModelType { model_type } = serde_json::from_slice(&std::fs::read(config_filename)?)?;
+    match model_type.as_str() {
+        "clip" | "" => Ok(Box::new(ClipEmbeddingModel::new(source, device)?)),
+        "siglip" => Ok(Box::new(SiglipEmbeddingModel::new(source, device)?)),
+        other => Err(E::msg(format!("Unsupported model type: {}", other))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn softmax_probabilities_compete() {
+        let scoring = Scoring::Softmax { logit_scale: 100.0 };
+        let probabilities = scoring.probabilities(&[0.30, 0.25, 0.10]);
+        assert!((probabilities.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        assert!(probabilities[0] > 0.99);
+
+        // Adding a better candidate lowers the others' probabilities.
+        let crowded = scoring.probabilities(&[0.30, 0.25, 0.10, 0.35]);
+        assert!(crowded[0] < probabilities[0]);
+    }
+
+    #[test]
+    fn sigmoid_scores_each_candidate_alone() {
+        let scoring = Scoring::Sigmoid {
+            logit_scale: 10.0,
+            logit_bias: -5.0,
+        };
+        let probabilities = scoring.probabilities(&[0.5, 0.8, 0.2]);
+        let sigmoid = |x: f32| 1.0 / (1.0 + (-x).exp());
+        for (actual, expected) in probabilities.iter().zip([sigmoid(0.0), sigmoid(3.0), sigmoid(-3.0)]) {
+            assert!((actual - expected).abs() < 1e-6, "{} != {}", actual, expected);
+        }
+        assert_eq!(probabilities[0], 0.5);
+
+        // Unlike softmax, the probabilities don't have to sum to one, and another
+        // candidate doesn't change them.
+        assert!((probabilities.iter().sum::<f32>() - 1.0).abs() > 0.1);
+        assert_eq!(scoring.probabilities(&[0.5]), [0.5]);
+    }
+}
@@ -0,0 +1,199 @@
+use image::DynamicImage;
+use image::imageops::FilterType;
+use sha2::{Digest, Sha256};
+
+/// Perceptual hashes of an image and the SHA-256 of its encoded bytes. Perceptual hashes
+/// survive re-encoding and resizing; compare them with `hamming_distance`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageHashes {
+    /// Average hash: each bit is whether an 8x8 grayscale pixel is above the mean.
+    pub ahash: u64,
+    /// Difference hash: each bit is whether a pixel is brighter than its right neighbour.
+    pub dhash: u64,
+    /// DCT hash: each bit is whether a low-frequency coefficient is above the median.
+    pub phash: u64,
+    pub sha256: String,
+}
+
+impl ImageHashes {
+    pub fn compute(img: &DynamicImage, bytes: &[u8]) -> Self {
+        Self {
+            ahash: average_hash(img),
+            dhash: difference_hash(img),
+            phash: dct_hash(img),
+            sha256: content_hash(bytes),
+        }
+    }
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Hex-encoded SHA-256 of the encoded bytes.
+pub fn content_hash(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn grayscale(img: &DynamicImage, width: u32, height: u32) -> Vec<f32> {
+    img.resize_exact(width, height, FilterType::Triangle)
+        .to_luma8()
+        .into_raw()
+        .into_iter()
+        .map(f32::from)
+        .collect()
+}
+
+fn bits(values: impl Iterator<Item = bool>) -> u64 {
+    values
+        .take(64)
+        .enumerate()
+        .fold(0, |hash, (i, set)| if set { hash | (1 << i) } else { hash })
+}
+
+fn average_hash(img: &DynamicImage) -> u64 {
+    let pixels = grayscale(img, 8, 8);
+    let mean = pixels.iter().sum::<f32>() / pixels.len() as f32;
+    bits(pixels.iter().map(|&p| p > mean))
+}
+
+fn difference_hash(img: &DynamicImage) -> u64 {
+    let pixels = grayscale(img, 9, 8);
+    bits((0..8).flat_map(|y| {
+        let row = &pixels[y * 9..(y + 1) * 9];
+        (0..8).map(move |x| row[x] < row[x + 1])
+    }))
+}
+
+fn dct_hash(img: &DynamicImage) -> u64 {
+    const SIZE: usize = 32;
+    const LOW: usize = 8;
+    let pixels = grayscale(img, SIZE as u32, SIZE as u32);
+
+    // Only the top-left LOW x LOW block of the 2D DCT-II is needed.
+    let cosines: Vec<f32> = (0..LOW)
+        .flat_map(|k| {
+            (0..SIZE).map(move |n| {
+                (std::f32::consts::PI / SIZE as f32 * (n as f32 + 0.5) * k as f32).cos()
+            })
+        })
+        .collect();
+    let mut rows = vec![0f32; SIZE * LOW];
+    for y in 0..SIZE {
+        for k in 0..LOW {
+            rows[y * LOW + k] = (0..SIZE)
+                .map(|x| pixels[y * SIZE + x] * cosines[k * SIZE + x])
+                .sum();
+        }
+    }
+    let mut coefficients = vec![0f32; LOW * LOW];
+    for v in 0..LOW {
+        for u in 0..LOW {
+            coefficients[v * LOW + u] = (0..SIZE)
+                .map(|y| rows[y * LOW + u] * cosines[v * SIZE + y])
+                .sum();
+        }
+    }
+
+    // The DC term only reflects overall brightness, so it is left out of the median.
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(f32::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    bits(coefficients.iter().map(|&c| c > median))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::{Rgb, RgbImage};
+
+    /// `DuplicateThresholds`' default hash distance.
+    const MAX_DISTANCE: u32 = 10;
+
+    /// A smooth scene with a bright disc on a diagonal gradient, like a product shot.
+    fn scene(width: u32, height: u32) -> DynamicImage {
+        let (w, h) = (width as f32, height as f32);
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let (u, v) = (x as f32 / w, y as f32 / h);
+            let disc = ((u - 0.35).powi(2) + (v - 0.55).powi(2)).sqrt() < 0.2;
+            let base = (40.0 + 150.0 * (u + v) / 2.0) as u8;
+            if disc {
+                Rgb([250, 220, 90])
+            } else {
+                Rgb([base, base / 2, 255 - base])
+            }
+        }))
+    }
+
+    /// An unrelated image: a checkerboard of large light and dark tiles.
+    fn tiles(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            if (x * 5 / width + y * 3 / height).is_multiple_of(2) {
+                Rgb([230, 230, 230])
+            } else {
+                Rgb([20, 30, 60])
+            }
+        }))
+    }
+
+    fn reencoded(img: &DynamicImage, quality: u8) -> DynamicImage {
+        let mut jpeg = vec![];
+        JpegEncoder::new_with_quality(&mut jpeg, quality)
+            .encode_image(img)
+            .unwrap();
+        image::load_from_memory(&jpeg).unwrap()
+    }
+
+    fn distances(a: &ImageHashes, b: &ImageHashes) -> [u32; 3] {
+        [
+            hamming_distance(a.ahash, b.ahash),
+            hamming_distance(a.dhash, b.dhash),
+            hamming_distance(a.phash, b.phash),
+        ]
+    }
+
+    #[test]
+    fn resized_and_reencoded_copies_stay_close() {
+        let original = scene(320, 240);
+        let hashes = ImageHashes::compute(&original, b"original");
+        for copy in [
+            original.resize_exact(160, 120, FilterType::Triangle),
+            original.resize_exact(640, 480, FilterType::CatmullRom),
+            reencoded(&original, 40),
+        ] {
+            let distances = distances(&hashes, &ImageHashes::compute(&copy, b"copy"));
+            assert!(distances.iter().all(|&d| d <= MAX_DISTANCE), "{:?}", distances);
+        }
+    }
+
+    #[test]
+    fn unrelated_images_are_far_apart() {
+        let hashes = ImageHashes::compute(&scene(320, 240), b"scene");
+        let distances = distances(&hashes, &ImageHashes::compute(&tiles(320, 240), b"tiles"));
+        assert!(distances.iter().all(|&d| d > MAX_DISTANCE), "{:?}", distances);
+    }
+
+    #[test]
+    fn hashes_the_encoded_bytes() {
+        assert_eq!(
+            content_hash(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let img = scene(32, 32);
+        let a = ImageHashes::compute(&img, b"one encoding");
+        let b = ImageHashes::compute(&img, b"another encoding");
+        assert_eq!((a.ahash, a.dhash, a.phash), (b.ahash, b.dhash, b.phash));
+        assert_ne!(a.sha256, b.sha256);
+    }
+
+    #[test]
+    fn counts_differing_bits() {
+        assert_eq!(hamming_distance(0, 0), 0);
+        assert_eq!(hamming_distance(0b1011, 0b0001), 2);
+        assert_eq!(hamming_distance(u64::MAX, 0), 64);
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{Error, Result};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+/// An exact in-memory nearest-neighbour index over L2-normalized vectors, scored by
+/// dot product (cosine similarity). Re-indexing a document replaces its vector, and every
+/// vector must have the same dimension.
+#[derive(Debug, Default)]
+pub struct VectorIndex {
+    ids: Vec<String>,
+    vectors: Vec<Vec<f32>>,
+    positions: HashMap<String, usize>,
+}
+
+/// A candidate in the bounded top-k heap, ordered so the heap's top is the worst kept hit.
+struct Hit {
+    score: f32,
+    position: usize,
+}
+
+impl PartialEq for Hit {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Hit {}
+
+impl PartialOrd for Hit {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Hit {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.score.total_cmp(&self.score)
+    }
+}
+
+impl VectorIndex {
+    /// The dimension every vector in the index has, once it holds any.
+    pub fn dimension(&self) -> Option<usize> {
+        self.vectors.first().map(Vec::len)
+    }
+
+    fn check_dimension(&self, len: usize) -> Result<()> {
+        match self.dimension() {
+            Some(dimension) if dimension != len => Err(Error::msg(format!(
+                "Vector has {} dimensions but the index holds {}",
+                len, dimension
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn upsert(&mut self, id: String, vector: Vec<f32>) -> Result<()> {
+        self.check_dimension(vector.len())?;
+        match self.positions.get(&id) {
+            Some(&position) => self.vectors[position] = vector,
+            None => {
+                self.positions.insert(id.clone(), self.ids.len());
+                self.ids.push(id);
+                self.vectors.push(vector);
+            }
+        }
+        Ok(())
+    }
+
+    /// The `k` most similar documents, best first.
+    pub fn search(&self, query: &[f32], k: usize) -> Result<Vec<(String, f32)>> {
+        self.check_dimension(query.len())?;
+        if k == 0 {
+            return Ok(vec![]);
+        }
+        let mut heap = BinaryHeap::with_capacity(k + 1);
+        for (position, vector) in self.vectors.iter().enumerate() {
+            let score = vector.iter().zip(query).map(|(a, b)| a * b).sum();
+            heap.push(Hit { score, position });
+            if heap.len() > k {
+                heap.pop();
+            }
+        }
+        Ok(heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|hit| (self.ids[hit.position].clone(), hit.score))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(vectors: &[(&str, [f32; 2])]) -> VectorIndex {
+        let mut index = VectorIndex::default();
+        for (id, vector) in vectors {
+            index.upsert(id.to_string(), vector.to_vec()).unwrap();
+        }
+        index
+    }
+
+    #[test]
+    fn returns_the_top_k_best_first() {
+        let index = index(&[
+            ("east", [1.0, 0.0]),
+            ("north", [0.0, 1.0]),
+            ("northeast", [0.6, 0.8]),
+            ("west", [-1.0, 0.0]),
+        ]);
+        let hits = index.search(&[0.8, 0.6], 3).unwrap();
+        let expected = [("northeast", 0.96), ("east", 0.8), ("north", 0.6)];
+        assert_eq!(hits.len(), expected.len());
+        for ((id, score), (expected_id, expected_score)) in hits.iter().zip(expected) {
+            assert_eq!(id, expected_id);
+            assert!((score - expected_score).abs() < 1e-6, "{} != {}", score, expected_score);
+        }
+    }
+
+    #[test]
+    fn returns_everything_when_k_exceeds_the_size() {
+        let index = index(&[("a", [1.0, 0.0]), ("b", [0.0, 1.0])]);
+        let hits = index.search(&[0.0, 1.0], 10).unwrap();
+        assert_eq!(hits, [("b".to_string(), 1.0), ("a".to_string(), 0.0)]);
+        assert!(index.search(&[0.0, 1.0], 0).unwrap().is_empty());
+        assert!(VectorIndex::default().search(&[0.0, 1.0], 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn upserting_an_id_replaces_its_vector() {
+        let mut index = index(&[("a", [1.0, 0.0]), ("b", [0.0, 1.0])]);
+        index.upsert("a".to_string(), vec![0.0, -1.0]).unwrap();
+        let hits = index.search(&[0.0, 1.0], 10).unwrap();
+        assert_eq!(hits, [("b".to_string(), 1.0), ("a".to_string(), -1.0)]);
+    }
+
+    #[test]
+    fn rejects_vectors_of_another_dimension() {
+        let mut index = index(&[("a", [1.0, 0.0])]);
+        assert!(index.upsert("b".to_string(), vec![1.0, 0.0, 0.0]).is_err());
+        assert!(index.upsert("a".to_string(), vec![1.0]).is_err());
+        assert!(index.search(&[1.0, 0.0, 0.0], 5).is_err());
+        assert_eq!(index.dimension(), Some(2));
+        assert_eq!(index.search(&[1.0, 0.0], 5).unwrap().len(), 1);
+    }
+}
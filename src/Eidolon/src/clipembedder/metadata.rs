@@ -0,0 +1,230 @@
+use exif::{In, Tag, Value};
+use image::imageops::FilterType;
+use image::{ColorType, DynamicImage, ImageFormat};
+use std::io::Cursor;
+
+/// What is known about an image from decoding it, so clients need not decode it again.
+#[derive(Debug, Clone)]
+pub struct ImageMetadata {
+    /// Dimensions of the upright image, after applying the EXIF orientation.
+    pub width: u32,
+    pub height: u32,
+    pub format: ImageFormat,
+    pub byte_size: usize,
+    pub color_type: ColorType,
+    pub exif: ExifInfo,
+    pub dominant_colors: Vec<DominantColor>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExifInfo {
+    /// `DateTimeOriginal` as `YYYY-MM-DDTHH:MM:SS`, without a time zone.
+    pub capture_date: Option<String>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    /// The raw orientation tag, 1 to 8.
+    pub orientation: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DominantColor {
+    pub rgb: [u8; 3],
+    /// Share of the image's pixels close to this color.
+    pub fraction: f32,
+}
+
+const DOMINANT_COLORS: usize = 5;
+
+impl ImageMetadata {
+    /// `color_type` is what the decoder reported, and `upright` the first frame rotated
+    /// upright and converted to RGB.
+    pub fn extract(
+        bytes: &[u8],
+        format: ImageFormat,
+        color_type: ColorType,
+        upright: &DynamicImage,
+    ) -> Self {
+        Self {
+            width: upright.width(),
+            height: upright.height(),
+            format,
+            byte_size: bytes.len(),
+            color_type,
+            exif: read_exif(bytes),
+            dominant_colors: dominant_colors(upright, DOMINANT_COLORS),
+        }
+    }
+}
+
+/// Reads the EXIF fields we report. Images without EXIF, or with EXIF we can't parse,
+/// yield empty fields rather than an error.
+fn read_exif(bytes: &[u8]) -> ExifInfo {
+    let Ok(exif) = exif::Reader::new().read_from_container(&mut Cursor::new(bytes)) else {
+        return ExifInfo::default();
+    };
+    let ascii = |tag: Tag| match exif.get_field(tag, In::PRIMARY).map(|f| &f.value) {
+        Some(Value::Ascii(values)) => values
+            .first()
+            .map(|v| String::from_utf8_lossy(v).trim_end_matches(['\0', ' ']).to_string())
+            .filter(|v| !v.is_empty()),
+        _ => None,
+    };
+
+    let capture_date = match exif.get_field(Tag::DateTimeOriginal, In::PRIMARY).map(|f| &f.value) {
+        Some(Value::Ascii(values)) => values
+            .first()
+            .and_then(|v| exif::DateTime::from_ascii(v).ok())
+            .map(|d| {
+                format!(
+                    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+                    d.year, d.month, d.day, d.hour, d.minute, d.second
+                )
+            }),
+        _ => None,
+    };
+    ExifInfo {
+        capture_date,
+        camera_make: ascii(Tag::Make),
+        camera_model: ascii(Tag::Model),
+        orientation: exif
+            .get_field(Tag::Orientation, In::PRIMARY)
+            .and_then(|f| f.value.get_uint(0)),
+    }
+}
+
+/// The most common colors of a downscaled copy, quantized to 4 bits per channel and
+/// reported as the mean of the pixels in each bucket.
+fn dominant_colors(img: &DynamicImage, count: usize) -> Vec<DominantColor> {
+    let small = img.resize(64, 64, FilterType::Triangle).to_rgb8();
+    let total = (small.width() * small.height()) as f32;
+    if total == 0.0 {
+        return vec![];
+    }
+
+    // Per bucket: pixel count and channel sums.
+    let mut buckets = vec![(0u32, [0u32; 3]); 4096];
+    for pixel in small.pixels() {
+        let [r, g, b] = pixel.0;
+        let index = (r as usize >> 4) << 8 | (g as usize >> 4) << 4 | b as usize >> 4;
+        let bucket = &mut buckets[index];
+        bucket.0 += 1;
+        for (sum, c) in bucket.1.iter_mut().zip(pixel.0) {
+            *sum += c as u32;
+        }
+    }
+
+    buckets.retain(|(n, _)| *n > 0);
+    buckets.sort_by_key(|(n, _)| std::cmp::Reverse(*n));
+    buckets
+        .into_iter()
+        .take(count)
+        .map(|(n, sums)| DominantColor {
+            rgb: sums.map(|sum| (sum / n) as u8),
+            fraction: n as f32 / total,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::experimental::Writer;
+    use exif::Field;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::{Rgb, RgbImage};
+
+    fn ascii(tag: Tag, value: &str) -> Field {
+        Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![value.as_bytes().to_vec()]),
+        }
+    }
+
+    /// A small JPEG with an EXIF APP1 segment holding `fields`.
+    fn jpeg_with_exif(fields: &[Field]) -> Vec<u8> {
+        let mut writer = Writer::new();
+        fields.iter().for_each(|field| writer.push_field(field));
+        let mut tiff = Cursor::new(vec![]);
+        writer.write(&mut tiff, false).unwrap();
+
+        let mut jpeg = vec![];
+        JpegEncoder::new(&mut jpeg)
+            .encode_image(&RgbImage::new(8, 8))
+            .unwrap();
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&(tiff.get_ref().len() as u16 + 8).to_be_bytes());
+        segment.extend_from_slice(b"Exif\0\0");
+        segment.extend_from_slice(tiff.get_ref());
+        jpeg.splice(2..2, segment);
+        jpeg
+    }
+
+    #[test]
+    fn reads_capture_date_camera_and_orientation() {
+        let bytes = jpeg_with_exif(&[
+            ascii(Tag::Make, "Acme"),
+            ascii(Tag::Model, "Shooter 3000 "),
+            ascii(Tag::DateTimeOriginal, "2024:03:15 14:30:05"),
+            Field {
+                tag: Tag::Orientation,
+                ifd_num: In::PRIMARY,
+                value: Value::Short(vec![6]),
+            },
+        ]);
+        let exif = read_exif(&bytes);
+        assert_eq!(exif.capture_date.as_deref(), Some("2024-03-15T14:30:05"));
+        assert_eq!(exif.camera_make.as_deref(), Some("Acme"));
+        assert_eq!(exif.camera_model.as_deref(), Some("Shooter 3000"));
+        assert_eq!(exif.orientation, Some(6));
+    }
+
+    #[test]
+    fn leaves_missing_or_unreadable_exif_empty() {
+        let exif = read_exif(&jpeg_with_exif(&[ascii(Tag::DateTimeOriginal, "not a date")]));
+        assert_eq!(exif.capture_date, None);
+        assert_eq!(exif.camera_make, None);
+
+        let mut plain = vec![];
+        JpegEncoder::new(&mut plain)
+            .encode_image(&RgbImage::new(8, 8))
+            .unwrap();
+        for bytes in [plain.as_slice(), b"not an image"] {
+            let exif = read_exif(bytes);
+            assert_eq!(
+                (exif.capture_date, exif.camera_make, exif.camera_model, exif.orientation),
+                (None, None, None, None)
+            );
+        }
+    }
+
+    #[test]
+    fn ranks_dominant_colors_by_share() {
+        // Three quarters red, one quarter blue, with a few white pixels.
+        let img = RgbImage::from_fn(64, 64, |x, y| match (x, y) {
+            (0, 0..4) => Rgb([255, 255, 255]),
+            (0..48, _) => Rgb([200, 10, 10]),
+            _ => Rgb([10, 10, 200]),
+        });
+        let colors = dominant_colors(&DynamicImage::ImageRgb8(img), DOMINANT_COLORS);
+        assert_eq!(colors.len(), 3);
+        assert_eq!(colors[0].rgb, [200, 10, 10]);
+        assert!((colors[0].fraction - (48.0 * 64.0 - 4.0) / 4096.0).abs() < 1e-6);
+        assert_eq!(colors[1].rgb, [10, 10, 200]);
+        assert!((colors[1].fraction - 0.25).abs() < 1e-6);
+        assert_eq!(colors[2].rgb, [255, 255, 255]);
+
+        assert_eq!(dominant_colors(&DynamicImage::ImageRgb8(RgbImage::new(64, 64)), 1).len(), 1);
+    }
+
+    #[test]
+    fn extracts_dimensions_and_the_decoded_color_type() {
+        let bytes = jpeg_with_exif(&[ascii(Tag::Make, "Acme")]);
+        let upright = DynamicImage::ImageRgb8(RgbImage::new(8, 6));
+        let metadata = ImageMetadata::extract(&bytes, ImageFormat::Jpeg, ColorType::L8, &upright);
+        assert_eq!((metadata.width, metadata.height), (8, 6));
+        assert_eq!(metadata.byte_size, bytes.len());
+        assert_eq!(metadata.color_type, ColorType::L8);
+        assert_eq!(metadata.exif.camera_make.as_deref(), Some("Acme"));
+    }
+}
@@ -1,4 +1,20 @@
+pub mod cache;
+pub mod caption;
+pub mod clip;
+pub mod color;
+pub mod config;
+pub mod decode;
+pub mod duplicates;
+pub mod encoder;
+pub mod hashing;
+pub mod index;
+pub mod metadata;
 pub mod model;
+pub mod pipeline;
+pub mod processor;
+pub mod regions;
 pub mod service;
 pub mod proto;
-
+pub mod siglip;
+pub mod text;
+pub mod thumbnails;
@@ -1,123 +1,111 @@
 use anyhow::{Error as E, Result};
 use candle_core::{DType, Device, Tensor};
 use candle_nn::VarBuilder;
-use candle_transformers::models::clip;
-use hf_hub::api::sync::Api;
-use hf_hub::{Repo, RepoType};
 use tokenizers::Tokenizer;
 
+use crate::clipembedder::clip::ClipModel;
+use crate::clipembedder::config::{load_clip_config, ModelSource};
+use crate::clipembedder::encoder::{DualEncoder, Scoring};
+use crate::clipembedder::processor::ImageProcessor;
+use crate::clipembedder::text::{LongTextMode, TextEmbedding, TextProcessor};
+use crate::utils::normalize_l2;
+
 pub struct ClipEmbeddingModel {
-    model: clip::ClipModel,
-    tokenizer: Tokenizer,
+    model: ClipModel,
+    text_processor: TextProcessor,
     pub device: Device,
-    image_size: usize,
+    processor: ImageProcessor,
+    logit_scale: f32,
 }
 
 impl ClipEmbeddingModel {
-    /// Creates a new model from the HuggingFace hub.
-    pub fn new(model_id: &str) -> Result<Self> {
-        let device = if candle_core::utils::metal_is_available() {
-            Device::new_metal(0)?
-        } else {
-            Device::Cpu
-        };
+    /// Creates a new model on `device` from the HuggingFace hub or a local directory.
+    pub fn new(source: &ModelSource, device: &Device) -> Result<Self> {
+        let device = device.clone();
 
-        // Use the exact repository details from the working example
-        let api = Api::new()?;
-        let repo = api.repo(Repo::with_revision(
-            model_id.to_string(),
-            RepoType::Model,
-            "refs/pr/15".to_string(), // Specific revision from the example
-        ));
+        let repo = source.files()?;
 
+        let config_filename = repo.get("config.json")?;
         let model_filename = repo.get("model.safetensors")?;
         let tokenizer_filename = repo.get("tokenizer.json")?;
 
         let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
+        let tokenizer_config = repo.get("tokenizer_config.json").ok();
 
-        // Use the hardcoded config from the example, which is simpler and more reliable
-        let config = clip::ClipConfig::vit_base_patch32();
-        let image_size = config.image_size;
+        // Older repos ship without a processor config, in which case the CLIP defaults apply.
+        let processor = match repo.get("preprocessor_config.json") {
+            Ok(path) => ImageProcessor::from_file(path, ImageProcessor::default())?,
+            Err(_) => ImageProcessor::default(),
+        };
+
+        let config = load_clip_config(config_filename)?;
+        let text_processor = TextProcessor::new(
+            tokenizer,
+            tokenizer_config.as_deref(),
+            config.text_config.max_position_embeddings,
+            false,
+        )?;
+        if processor.output_size() != (config.image_size, config.image_size) {
+            return Err(E::msg(format!(
+                "Processor output size {:?} does not match model image size {}",
+                processor.output_size(),
+                config.image_size
+            )));
+        }
 
         let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[model_filename], DType::F32, &device)? };
-        // Use the simpler constructor from the example
-        let model = clip::ClipModel::new(vb, &config)?;
+
+        // The model keeps its logit scale private, so read it from the weights ourselves.
+        let logit_scale = if vb.contains_tensor("logit_scale") {
+            vb.get(&[], "logit_scale")?.to_dtype(DType::F32)?.to_scalar::<f32>()?
+        } else {
+            config.logit_scale_init_value
+        }
+        .exp();
+
+        let model = ClipModel::new(vb, &config)?;
 
         Ok(Self {
             model,
-            tokenizer,
+            text_processor,
             device,
-            image_size,
+            processor,
+            logit_scale,
         })
     }
+}
 
-    /// Generates embeddings for a batch of text.
-    pub fn embed_texts(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
-        let pad_id = *self
-            .tokenizer
-            .get_vocab(true)
-            .get("<|endoftext|>")
-            .ok_or(E::msg("No pad token"))?;
-
-        let mut tokens = vec![];
-        for text in texts {
-            let encoding = self.tokenizer.encode(text.clone(), true).map_err(E::msg)?;
-            tokens.push(encoding.get_ids().to_vec());
-        }
+impl DualEncoder for ClipEmbeddingModel {
+    fn device(&self) -> &Device {
+        &self.device
+    }
 
-        let max_len = tokens.iter().map(|v| v.len()).max().unwrap_or(0);
-        for token_vec in tokens.iter_mut() {
-            let len_diff = max_len - token_vec.len();
-            if len_diff > 0 {
-                token_vec.extend(vec![pad_id; len_diff]);
-            }
-        }
+    fn embed_texts_with(&self, texts: &[String], mode: LongTextMode) -> Result<Vec<TextEmbedding>> {
+        self.text_processor.embed(texts, mode, |tokens| {
+            let token_ids = Tensor::new(tokens, &self.device)?;
+            let embeddings = self.model.get_text_features(&token_ids)?;
 
-        let token_ids = Tensor::new(tokens, &self.device)?;
-        let embeddings = self.model.get_text_features(&token_ids)?;
+            // Normalize embeddings, which is crucial for similarity search
+            let embeddings = normalize_l2(&embeddings)?;
+            Ok(embeddings.to_vec2()?)
+        })
+    }
 
-        // Normalize embeddings, which is crucial for similarity search
-        let embeddings = normalize_l2(&embeddings)?;
-        Ok(embeddings.to_vec2()?)
+    fn image_processor(&self) -> &ImageProcessor {
+        &self.processor
     }
 
-    /// Generates embeddings for a batch of images provided as raw bytes.
-    pub fn embed_images(&self, image_bytes_batch: &[Vec<u8>]) -> Result<Vec<Vec<f32>>> {
-        let mut image_tensors = vec![];
-        for image_bytes in image_bytes_batch {
-            let tensor = self.preprocess_image(image_bytes)?;
-            image_tensors.push(tensor);
-        }
-        let image_tensors = Tensor::stack(&image_tensors, 0)?.to_device(&self.device)?;
+    fn embed_images(&self, pixels: &[Tensor]) -> Result<Vec<Vec<f32>>> {
+        let image_tensors = Tensor::stack(pixels, 0)?.to_device(&self.device)?;
 
         let embeddings = self.model.get_image_features(&image_tensors)?;
         let embeddings = normalize_l2(&embeddings)?;
         Ok(embeddings.to_vec2()?)
     }
 
-    /// Preprocesses a single image from bytes into a tensor.
-    /// This logic is taken directly from the working example you provided.
-    fn preprocess_image(&self, image_bytes: &[u8]) -> Result<Tensor> {
-        let img = image::load_from_memory(image_bytes)?;
-        let (height, width) = (self.image_size, self.image_size);
-
-        let img = img.resize_to_fill(
-            width as u32,
-            height as u32,
-            image::imageops::FilterType::Triangle,
-        );
-        let img = img.to_rgb8();
-        let img_data = img.into_raw();
-
-        let tensor = Tensor::from_vec(img_data, (height, width, 3), &Device::Cpu)?
-            .permute((2, 0, 1))?
-            .to_dtype(DType::F32)?
-            .affine(2. / 255., -1.)?; // Normalization
-        Ok(tensor)
+    fn scoring(&self) -> Scoring {
+        Scoring::Softmax {
+            logit_scale: self.logit_scale,
+        }
     }
 }
-
-/// Helper function to normalize the embeddings.
-fn normalize_l2(v: &Tensor) -> Result<Tensor> {
-    Ok(v.broadcast_div(&v.sqr()?.sum_keepdim(1)?.sqrt()?)?)
-}
\ No newline at end of file
@@ -0,0 +1,214 @@
+use anyhow::Result;
+use candle_core::Tensor;
+use image::{DynamicImage, ImageFormat, Rgb};
+use tracing::warn;
+
+use crate::clipembedder::color::to_rgb8;
+use crate::clipembedder::decode::{decode_frames, decode_image, DecodeLimits, ImageDecodeError};
+use crate::clipembedder::hashing::ImageHashes;
+use crate::clipembedder::metadata::ImageMetadata;
+use crate::clipembedder::processor::ImageProcessor;
+use crate::clipembedder::regions::{Region, RegionLayout};
+use crate::clipembedder::thumbnails::{Thumbnail, ThumbnailSpec};
+
+/// What to extract from an image besides its first frame.
+#[derive(Debug, Clone)]
+pub struct PrepareOptions {
+    /// Frames to sample from animated images; zero prepares none, for requests that only
+    /// need the caption input, hashes or the like.
+    pub max_frames: usize,
+    pub layout: Option<RegionLayout>,
+    pub hashes: bool,
+    pub metadata: bool,
+    pub thumbnails: Vec<ThumbnailSpec>,
+    /// Also preprocess the first frame for the captioning model, if one is loaded.
+    pub caption: bool,
+}
+
+impl Default for PrepareOptions {
+    fn default() -> Self {
+        Self {
+            max_frames: 1,
+            layout: None,
+            hashes: false,
+            metadata: false,
+            thumbnails: vec![],
+            caption: false,
+        }
+    }
+}
+
+/// The model-ready views of one image: its sampled frames and any regions cropped
+/// from the first frame.
+pub struct PreparedImage {
+    pub format: ImageFormat,
+    pub frames: Vec<Tensor>,
+    pub regions: Vec<(Region, Tensor)>,
+    pub hashes: Option<ImageHashes>,
+    pub metadata: Option<ImageMetadata>,
+    pub thumbnails: Vec<Thumbnail>,
+    pub caption_pixels: Option<Tensor>,
+}
+
+impl PreparedImage {
+    /// Every view in embedding order, frames first.
+    pub fn views(&self) -> impl Iterator<Item = &Tensor> {
+        self.frames.iter().chain(self.regions.iter().map(|(_, pixels)| pixels))
+    }
+
+    pub fn view_count(&self) -> usize {
+        self.frames.len() + self.regions.len()
+    }
+}
+
+/// Turns client image bytes into model-ready tensors. It holds no model state, so
+/// decoding and preprocessing run without holding the model lock.
+pub struct ImagePipeline {
+    pub limits: DecodeLimits,
+    pub processor: ImageProcessor,
+    /// The color transparent regions are flattened onto.
+    pub background: Rgb<u8>,
+    /// Preprocessing for the captioning model, when one is loaded.
+    pub caption_processor: Option<ImageProcessor>,
+}
+
+impl ImagePipeline {
+    /// Decodes an image upright and converted to 8-bit RGB.
+    pub fn decode(&self, image_bytes: &[u8]) -> Result<DynamicImage, ImageDecodeError> {
+        let img = decode_image(image_bytes, &self.limits)?;
+        Ok(DynamicImage::ImageRgb8(to_rgb8(&img, self.background)))
+    }
+
+    /// Decodes and preprocesses a single image.
+    pub fn prepare(&self, image_bytes: &[u8]) -> Result<Tensor> {
+        let img = self.decode(image_bytes)?;
+        self.processor.preprocess(&img)
+    }
+
+    /// Decodes and preprocesses the frames sampled from an image, plus whatever else
+    /// `options` asks for, all taken from the first frame.
+    pub fn prepare_image(&self, image_bytes: &[u8], options: &PrepareOptions) -> Result<PreparedImage> {
+        let decoded = decode_frames(image_bytes, &self.limits, options.max_frames.max(1))?;
+        let frames: Vec<DynamicImage> = decoded
+            .frames
+            .iter()
+            .map(|frame| DynamicImage::ImageRgb8(to_rgb8(frame, self.background)))
+            .collect();
+
+        let regions = match (&options.layout, frames.first()) {
+            (Some(layout), Some(first)) => layout
+                .regions(first.width(), first.height())
+                .into_iter()
+                .map(|region| {
+                    let crop = first.crop_imm(region.x, region.y, region.width, region.height);
+                    Ok((region, self.processor.preprocess(&crop)?))
+                })
+                .collect::<Result<_>>()?,
+            _ => vec![],
+        };
+        let hashes = frames
+            .first()
+            .filter(|_| options.hashes)
+            .map(|first| ImageHashes::compute(first, image_bytes));
+        let metadata = frames
+            .first()
+            .filter(|_| options.metadata)
+            .map(|upright| {
+                ImageMetadata::extract(image_bytes, decoded.format, decoded.color_type, upright)
+            });
+        // A thumbnail that fails to encode is left out rather than failing the embedding.
+        let thumbnails = match frames.first() {
+            Some(first) => options
+                .thumbnails
+                .iter()
+                .filter_map(|spec| {
+                    spec.render(first)
+                        .inspect_err(|e| warn!("Skipping {:?} thumbnail: {}", spec, e))
+                        .ok()
+                })
+                .collect(),
+            None => vec![],
+        };
+        let caption_pixels = match (&self.caption_processor, frames.first()) {
+            (Some(processor), Some(first)) if options.caption => Some(processor.preprocess(first)?),
+            _ => None,
+        };
+        let frames = frames
+            .iter()
+            .take(options.max_frames)
+            .map(|frame| self.processor.preprocess(frame))
+            .collect::<Result<_>>()?;
+
+        Ok(PreparedImage {
+            format: decoded.format,
+            frames,
+            regions,
+            hashes,
+            metadata,
+            thumbnails,
+            caption_pixels,
+        })
+    }
+
+    /// Decodes and preprocesses a batch of images, failing on the first rejected image.
+    pub fn prepare_batch(&self, image_bytes_batch: &[Vec<u8>]) -> Result<Vec<Tensor>> {
+        image_bytes_batch
+            .iter()
+            .map(|image_bytes| self.prepare(image_bytes))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipembedder::color::DEFAULT_BACKGROUND;
+    use crate::clipembedder::thumbnails::ThumbnailFormat;
+    use image::RgbImage;
+    use std::io::Cursor;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = Cursor::new(vec![]);
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut bytes, ImageFormat::Png)
+            .unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn skips_thumbnails_that_fail_to_encode() {
+        let pipeline = ImagePipeline {
+            limits: DecodeLimits {
+                max_width: 70_000,
+                ..DecodeLimits::default()
+            },
+            // Hand the extremely wide test image to the model as it is.
+            processor: ImageProcessor {
+                do_resize: false,
+                do_center_crop: false,
+                ..ImageProcessor::default()
+            },
+            background: DEFAULT_BACKGROUND,
+            caption_processor: None,
+        };
+        let thumbnail = |max_edge, format| ThumbnailSpec {
+            max_edge,
+            format,
+            quality: 80,
+        };
+        let options = PrepareOptions {
+            // JPEG tops out at 65535 pixels a side and WebP at 16383, so the full-size
+            // thumbnails fail.
+            thumbnails: vec![
+                thumbnail(u32::MAX, ThumbnailFormat::Jpeg),
+                thumbnail(u32::MAX, ThumbnailFormat::WebP),
+                thumbnail(700, ThumbnailFormat::Jpeg),
+            ],
+            ..PrepareOptions::default()
+        };
+        let prepared = pipeline.prepare_image(&png(70_000, 2), &options).unwrap();
+        assert_eq!(prepared.frames.len(), 1);
+        assert_eq!(prepared.thumbnails.len(), 1);
+        assert_eq!(prepared.thumbnails[0].width, 700);
+    }
+}
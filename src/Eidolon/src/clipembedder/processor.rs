@@ -0,0 +1,389 @@
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use image::imageops::FilterType;
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// OpenAI CLIP normalization constants, used when the model repo does not provide its own.
+const CLIP_IMAGE_MEAN: [f32; 3] = [0.481_454_66, 0.457_827_5, 0.408_210_73];
+const CLIP_IMAGE_STD: [f32; 3] = [0.268_629_54, 0.261_302_6, 0.275_777_1];
+
+/// Image size as written by `transformers`: either a bare integer or an object with
+/// `shortest_edge` or `height`/`width`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SizeSpec {
+    Square(u32),
+    Dims {
+        shortest_edge: Option<u32>,
+        height: Option<u32>,
+        width: Option<u32>,
+    },
+}
+
+/// Image processor settings loaded from a model repo's `preprocessor_config.json`.
+/// Any field missing from the file falls back to the model family's defaults.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImageProcessor {
+    pub do_resize: bool,
+    pub size: SizeSpec,
+    /// PIL resampling filter code (0 nearest, 1 lanczos, 2 bilinear, 3 bicubic, 4 box, 5 hamming).
+    pub resample: u32,
+    pub do_center_crop: bool,
+    pub crop_size: SizeSpec,
+    pub do_rescale: bool,
+    pub rescale_factor: f32,
+    pub do_normalize: bool,
+    pub image_mean: Vec<f32>,
+    pub image_std: Vec<f32>,
+}
+
+impl Default for ImageProcessor {
+    fn default() -> Self {
+        Self {
+            do_resize: true,
+            size: SizeSpec::Dims {
+                shortest_edge: Some(224),
+                height: None,
+                width: None,
+            },
+            resample: 3,
+            do_center_crop: true,
+            crop_size: SizeSpec::Dims {
+                shortest_edge: None,
+                height: Some(224),
+                width: Some(224),
+            },
+            do_rescale: true,
+            rescale_factor: 1.0 / 255.0,
+            do_normalize: true,
+            image_mean: CLIP_IMAGE_MEAN.to_vec(),
+            image_std: CLIP_IMAGE_STD.to_vec(),
+        }
+    }
+}
+
+impl ImageProcessor {
+    /// BLIP defaults: a plain square resize with CLIP normalization and no center crop.
+    pub fn blip(image_size: u32) -> Self {
+        Self {
+            size: SizeSpec::Dims {
+                shortest_edge: None,
+                height: Some(image_size),
+                width: Some(image_size),
+            },
+            do_center_crop: false,
+            ..Self::default()
+        }
+    }
+
+    /// SigLIP defaults: a plain square resize, mean/std of 0.5 and no center crop.
+    pub fn siglip(image_size: u32) -> Self {
+        Self {
+            size: SizeSpec::Dims {
+                shortest_edge: None,
+                height: Some(image_size),
+                width: Some(image_size),
+            },
+            do_center_crop: false,
+            image_mean: vec![0.5; 3],
+            image_std: vec![0.5; 3],
+            ..Self::default()
+        }
+    }
+
+    /// Loads the processor settings from a `preprocessor_config.json` file, taking any
+    /// setting the file does not specify from `defaults`.
+    pub fn from_file(path: impl AsRef<Path>, defaults: Self) -> Result<Self> {
+        let mut settings = serde_json::to_value(defaults)?;
+        let overrides: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+        if let (Some(settings), Some(overrides)) = (settings.as_object_mut(), overrides.as_object()) {
+            for (key, value) in overrides {
+                settings.insert(key.clone(), value.clone());
+            }
+        }
+        let processor: Self = serde_json::from_value(settings)?;
+        processor.validate()?;
+        Ok(processor)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.image_mean.len() != 3 || self.image_std.len() != 3 {
+            anyhow::bail!("image_mean and image_std must have exactly 3 channels");
+        }
+        if self.image_std.contains(&0.0) {
+            anyhow::bail!("image_std must not contain zeros");
+        }
+        if self.resample > 5 {
+            anyhow::bail!("Unknown resample filter: {}", self.resample);
+        }
+        Ok(())
+    }
+
+    /// The (height, width) of the tensors produced by `preprocess`.
+    pub fn output_size(&self) -> (usize, usize) {
+        if self.do_center_crop {
+            return Self::fixed_dims(&self.crop_size);
+        }
+        match self.size {
+            SizeSpec::Dims {
+                height: Some(height),
+                width: Some(width),
+                ..
+            } => (height as usize, width as usize),
+            _ => Self::fixed_dims(&self.size),
+        }
+    }
+
+    fn fixed_dims(spec: &SizeSpec) -> (usize, usize) {
+        match *spec {
+            SizeSpec::Square(size) => (size as usize, size as usize),
+            SizeSpec::Dims {
+                shortest_edge,
+                height,
+                width,
+            } => {
+                let fallback = shortest_edge.or(height).or(width).unwrap_or(224);
+                (
+                    height.unwrap_or(fallback) as usize,
+                    width.unwrap_or(fallback) as usize,
+                )
+            }
+        }
+    }
+
+    fn filter(&self) -> FilterType {
+        match self.resample {
+            0 => FilterType::Nearest,
+            1 => FilterType::Lanczos3,
+            3 => FilterType::CatmullRom,
+            // 2 is bilinear. `image` has no box (4) or hamming (5) filter; both are short,
+            // non-negative kernels that Triangle approximates more closely than anything
+            // else it offers. `validate` rejects any other code.
+            _ => FilterType::Triangle,
+        }
+    }
+
+    /// Resizes so the shortest side matches the configured size, keeping the aspect ratio,
+    /// or to the exact size when the config specifies both height and width.
+    fn resize(&self, img: &DynamicImage) -> DynamicImage {
+        let (width, height) = (img.width(), img.height());
+        let (new_width, new_height) = match self.size {
+            SizeSpec::Dims {
+                height: Some(h),
+                width: Some(w),
+                ..
+            } => (w, h),
+            SizeSpec::Square(edge)
+            | SizeSpec::Dims {
+                shortest_edge: Some(edge),
+                ..
+            } => {
+                let (short, long) = if width <= height {
+                    (width, height)
+                } else {
+                    (height, width)
+                };
+                let new_long = ((edge as u64 * long as u64) / short.max(1) as u64) as u32;
+                if width <= height {
+                    (edge, new_long)
+                } else {
+                    (new_long, edge)
+                }
+            }
+            SizeSpec::Dims { .. } => return img.clone(),
+        };
+        img.resize_exact(new_width.max(1), new_height.max(1), self.filter())
+    }
+
+    /// Crops the center of the image, padding with black if it is smaller than the crop.
+    fn center_crop(&self, img: DynamicImage) -> DynamicImage {
+        let (crop_height, crop_width) = Self::fixed_dims(&self.crop_size);
+        let (crop_height, crop_width) = (crop_height as u32, crop_width as u32);
+        if img.width() == crop_width && img.height() == crop_height {
+            return img;
+        }
+        if img.width() >= crop_width && img.height() >= crop_height {
+            let left = (img.width() - crop_width) / 2;
+            let top = (img.height() - crop_height) / 2;
+            return img.crop_imm(left, top, crop_width, crop_height);
+        }
+
+        let mut canvas = image::RgbImage::new(crop_width, crop_height);
+        let left = (crop_width as i64 - img.width() as i64) / 2;
+        let top = (crop_height as i64 - img.height() as i64) / 2;
+        image::imageops::overlay(&mut canvas, &img.to_rgb8(), left, top);
+        DynamicImage::ImageRgb8(canvas)
+    }
+
+    /// Converts a decoded image into a normalized (3, height, width) tensor on the CPU.
+    pub fn preprocess(&self, img: &DynamicImage) -> Result<Tensor> {
+        let mut img = if self.do_resize {
+            self.resize(img)
+        } else {
+            img.clone()
+        };
+        if self.do_center_crop {
+            img = self.center_crop(img);
+        }
+
+        let img = img.to_rgb8();
+        let (width, height) = (img.width() as usize, img.height() as usize);
+        let mut tensor = Tensor::from_vec(img.into_raw(), (height, width, 3), &Device::Cpu)?
+            .permute((2, 0, 1))?
+            .to_dtype(DType::F32)?;
+
+        if self.do_rescale {
+            tensor = tensor.affine(self.rescale_factor as f64, 0.)?;
+        }
+        if self.do_normalize {
+            let mean = Tensor::from_slice(&self.image_mean, (3, 1, 1), &Device::Cpu)?;
+            let std = Tensor::from_slice(&self.image_std, (3, 1, 1), &Device::Cpu)?;
+            tensor = tensor.broadcast_sub(&mean)?.broadcast_div(&std)?;
+        }
+        Ok(tensor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-5, "{} != {}", actual, expected);
+    }
+
+    /// Expected values follow the CLIPImageProcessor steps by hand: resize the shortest edge,
+    /// center crop, rescale by 1/255, then normalize with the CLIP mean/std.
+    #[test]
+    fn follows_the_clip_preprocessing_steps() {
+        // A 4x2 image whose red channel numbers the pixels 10, 20, ... row by row.
+        let img = RgbImage::from_fn(4, 2, |x, y| Rgb([(10 * (y * 4 + x) + 10) as u8, 0, 255]));
+        let processor = ImageProcessor {
+            size: SizeSpec::Square(4),
+            resample: 0,
+            crop_size: SizeSpec::Square(4),
+            ..ImageProcessor::default()
+        };
+        let tensor = processor.preprocess(&DynamicImage::ImageRgb8(img)).unwrap();
+        assert_eq!(tensor.dims(), &[3, 4, 4]);
+        let channels = tensor.to_vec3::<f32>().unwrap();
+
+        // Resized to 8x4 by doubling every pixel, then cropped to columns 2..6, which come
+        // from source columns 1, 1, 2, 2.
+        let red = [
+            [-1.500_294, -1.500_294, -1.354_31, -1.354_31],
+            [-1.500_294, -1.500_294, -1.354_31, -1.354_31],
+            [-0.916_357, -0.916_357, -0.770_373, -0.770_373],
+            [-0.916_357, -0.916_357, -0.770_373, -0.770_373],
+        ];
+        for (row, expected) in channels[0].iter().zip(red) {
+            for (&actual, expected) in row.iter().zip(expected) {
+                assert_close(actual, expected);
+            }
+        }
+        for &value in channels[1].iter().flatten() {
+            assert_close(value, -1.752_097);
+        }
+        for &value in channels[2].iter().flatten() {
+            assert_close(value, 2.145_897);
+        }
+    }
+
+    /// Reference tensors from `transformers`' CLIPImageProcessor, written by
+    /// `testdata/clip_preprocess.py`.
+    #[derive(Deserialize)]
+    struct Fixture {
+        cases: Vec<FixtureCase>,
+    }
+
+    #[derive(Deserialize)]
+    struct FixtureCase {
+        name: String,
+        width: u32,
+        height: u32,
+        pixels: Vec<u8>,
+        config: serde_json::Value,
+        shape: Vec<usize>,
+        values: Vec<f32>,
+    }
+
+    /// PIL resamples in 8-bit fixed point and `image` in floating point, so single values
+    /// may differ by a pixel level or two. Errors beyond that, or a bias across the whole
+    /// tensor, mean a preprocessing step doesn't match.
+    #[test]
+    #[ignore = "needs testdata/clip_preprocess.json, written by testdata/clip_preprocess.py"]
+    fn matches_transformers_fixtures() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/clip_preprocess.json");
+        let fixture: Fixture = serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+        assert!(!fixture.cases.is_empty());
+        for case in fixture.cases {
+            let processor: ImageProcessor = serde_json::from_value(case.config).unwrap();
+            processor.validate().unwrap();
+            let img = RgbImage::from_raw(case.width, case.height, case.pixels).unwrap();
+            let tensor = processor.preprocess(&DynamicImage::ImageRgb8(img)).unwrap();
+            assert_eq!(tensor.dims(), case.shape.as_slice(), "{}", case.name);
+
+            let actual = tensor.flatten_all().unwrap().to_vec1::<f32>().unwrap();
+            let plane = actual.len() / 3;
+            let mut total_error = 0.0;
+            for (i, (&actual, &expected)) in actual.iter().zip(&case.values).enumerate() {
+                let std = if processor.do_normalize { processor.image_std[i / plane] } else { 1.0 };
+                let levels = (actual - expected).abs() * std / processor.rescale_factor;
+                assert!(
+                    levels <= 2.0,
+                    "{}: value {} is {} but transformers gives {}",
+                    case.name,
+                    i,
+                    actual,
+                    expected
+                );
+                total_error += levels;
+            }
+            let mean_error = total_error / actual.len() as f32;
+            assert!(mean_error < 0.5, "{}: off by {} levels on average", case.name, mean_error);
+        }
+    }
+
+    #[test]
+    fn resizes_and_crops_to_the_model_input() {
+        let img = RgbImage::from_pixel(300, 200, Rgb([128, 64, 32]));
+        let tensor = ImageProcessor::default()
+            .preprocess(&DynamicImage::ImageRgb8(img))
+            .unwrap();
+        assert_eq!(tensor.dims(), &[3, 224, 224]);
+        let channels = tensor.to_vec3::<f32>().unwrap();
+        for (channel, expected) in channels.iter().zip([0.076_336, -0.791_6, -1.025_178]) {
+            for &value in channel.iter().flatten() {
+                assert_close(value, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn pads_images_smaller_than_the_crop() {
+        let img = RgbImage::from_pixel(2, 2, Rgb([255, 255, 255]));
+        let processor = ImageProcessor {
+            do_resize: false,
+            crop_size: SizeSpec::Square(4),
+            do_normalize: false,
+            ..ImageProcessor::default()
+        };
+        let tensor = processor.preprocess(&DynamicImage::ImageRgb8(img)).unwrap();
+        let red = &tensor.to_vec3::<f32>().unwrap()[0];
+        assert_eq!(red[0], vec![0.0; 4]);
+        assert_eq!(red[1], vec![0.0, 1.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn rejects_unknown_resample_filters() {
+        let processor = ImageProcessor {
+            resample: 6,
+            ..ImageProcessor::default()
+        };
+        assert!(processor.validate().is_err());
+    }
+}
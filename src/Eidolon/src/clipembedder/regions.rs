@@ -0,0 +1,122 @@
+/// A rectangle in pixels of the upright image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// How an image is split into regions embedded alongside the whole image.
+#[derive(Debug, Clone, Copy)]
+pub enum RegionLayout {
+    /// `rows` by `cols` tiles covering the image.
+    Grid { rows: u32, cols: u32 },
+    /// Square windows as tall as the short side, slid along the long side with
+    /// `overlap` (a fraction in `[0, 1)`) shared between neighbours.
+    Sliding { overlap: f32 },
+}
+
+/// The most regions a single image may be split into.
+pub const MAX_REGIONS: usize = 64;
+
+impl RegionLayout {
+    pub fn regions(&self, width: u32, height: u32) -> Vec<Region> {
+        match *self {
+            RegionLayout::Grid { rows, cols } => grid(width, height, rows, cols),
+            RegionLayout::Sliding { overlap } => sliding(width, height, overlap),
+        }
+    }
+}
+
+fn grid(width: u32, height: u32, rows: u32, cols: u32) -> Vec<Region> {
+    let rows = rows.clamp(1, height.max(1));
+    let cols = cols.clamp(1, width.max(1));
+    let edge = |i: u32, n: u32, len: u32| (u64::from(i) * u64::from(len) / u64::from(n)) as u32;
+
+    // The service rejects larger grids; stop at the limit rather than building every tile.
+    (0..rows)
+        .flat_map(|row| (0..cols).map(move |col| (row, col)))
+        .take(MAX_REGIONS)
+        .map(|(row, col)| {
+            let (top, bottom) = (edge(row, rows, height), edge(row + 1, rows, height));
+            let (left, right) = (edge(col, cols, width), edge(col + 1, cols, width));
+            Region {
+                x: left,
+                y: top,
+                width: right - left,
+                height: bottom - top,
+            }
+        })
+        .collect()
+}
+
+fn sliding(width: u32, height: u32, overlap: f32) -> Vec<Region> {
+    let side = width.min(height);
+    let long = width.max(height);
+    if side == 0 {
+        return vec![];
+    }
+    let overlap = overlap.clamp(0.0, 0.95);
+    let step = ((side as f32 * (1.0 - overlap)).round() as u32).max(1);
+
+    let mut offsets: Vec<u32> = (0..=long - side).step_by(step as usize).collect();
+    // Make sure the far edge of the image is covered.
+    if offsets.last() != Some(&(long - side)) {
+        offsets.push(long - side);
+    }
+    if offsets.len() > MAX_REGIONS {
+        // Spread the allowed windows evenly rather than covering only the start.
+        let total = offsets.len();
+        offsets = (0..MAX_REGIONS)
+            .map(|i| offsets[i * (total - 1) / (MAX_REGIONS - 1)])
+            .collect();
+    }
+
+    offsets
+        .into_iter()
+        .map(|offset| {
+            if width >= height {
+                Region {
+                    x: offset,
+                    y: 0,
+                    width: side,
+                    height: side,
+                }
+            } else {
+                Region {
+                    x: 0,
+                    y: offset,
+                    width: side,
+                    height: side,
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_the_whole_image() {
+        let regions = RegionLayout::Grid { rows: 2, cols: 3 }.regions(100, 50);
+        assert_eq!(regions.len(), 6);
+        assert_eq!(regions[0], Region { x: 0, y: 0, width: 33, height: 25 });
+        assert_eq!(regions[5], Region { x: 66, y: 25, width: 34, height: 25 });
+    }
+
+    #[test]
+    fn stops_huge_grids_at_the_limit() {
+        let regions = RegionLayout::Grid { rows: u32::MAX, cols: u32::MAX }.regions(8192, 8192);
+        assert_eq!(regions.len(), MAX_REGIONS);
+    }
+
+    #[test]
+    fn slides_windows_to_the_far_edge() {
+        let regions = RegionLayout::Sliding { overlap: 0.5 }.regions(250, 100);
+        let offsets: Vec<u32> = regions.iter().map(|region| region.x).collect();
+        assert_eq!(offsets, [0, 50, 100, 150]);
+    }
+}
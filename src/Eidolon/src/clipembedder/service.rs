@@ -1,23 +1,676 @@
-use crate::clipembedder::model::ClipEmbeddingModel;
+use crate::clipembedder::cache::PromptCache;
+use crate::clipembedder::caption::{CaptionDecoding, CaptionOptions, Captioner};
+use crate::clipembedder::decode::{format_name, DecodeLimits, ImageDecodeError};
+use crate::clipembedder::duplicates::{group_duplicates, DuplicateCandidate, DuplicateThresholds};
+use crate::clipembedder::encoder::{DualEncoder, Scoring};
+use crate::clipembedder::hashing::ImageHashes;
+use crate::clipembedder::index::VectorIndex;
+use crate::clipembedder::metadata::ImageMetadata;
+use crate::clipembedder::pipeline::{ImagePipeline, PrepareOptions, PreparedImage};
 use crate::clipembedder::proto::{
-    ClipEmbedder, EmbedImageRequest, EmbedResponse, EmbedTextRequest, Embedding, IndexImageRequest,
-    IndexResponse,
+    self, BoundingBox, CaptionRequest, CaptionResponse, ClassifyRequest, ClassifyResponse,
+    ClipEmbedder, DominantColor, DuplicateGroup, DuplicateImage, EmbedImageRequest, EmbedResponse,
+    EmbedTextRequest, Embedding, FindDuplicatesRequest, FindDuplicatesResponse, FrameMode,
+    FrameSampling, FusionOptions, ImageTextSimilarityRequest, IndexImageRequest, IndexItemRequest,
+    IndexItemResponse, IndexResponse, IndexTextRequest, LabelScore, RegionEmbedding, RegionMode,
+    RegionOptions, RegionOutput, ScoringFunction, SearchByImageRequest, SearchByTextRequest,
+    SearchHit, SearchResponse, SimilarityResponse, TextImageSimilarityRequest,
+    find_duplicates_request,
 };
+use crate::clipembedder::regions::{RegionLayout, MAX_REGIONS};
+use crate::clipembedder::text::LongTextMode;
+use crate::clipembedder::thumbnails::{Thumbnail, ThumbnailFormat, ThumbnailSpec, MAX_THUMBNAILS};
+use crate::utils::{cosine_similarity, mean_normalized, weighted_mean_normalized};
 use futures::{Stream, StreamExt};
+use image::Rgb;
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status, Streaming};
+use tracing::{error, warn};
 
 pub struct ClipEmbedderService {
-    pub model: Arc<Mutex<ClipEmbeddingModel>>,
+    pub model: Arc<Mutex<Box<dyn DualEncoder>>>,
+    pub prompt_cache: Arc<Mutex<PromptCache>>,
+    pub images: Arc<ImagePipeline>,
+    /// The optional BLIP model behind `Caption`, locked separately from the dual encoder.
+    pub captioner: Option<Arc<Mutex<Captioner>>>,
+    /// Where `IndexImages` keeps embeddings for `SearchByText`/`SearchByImage`, when enabled.
+    pub index: Option<Arc<RwLock<VectorIndex>>>,
+    pub batching: Batching,
 }
 
-struct ImageBatch {
+/// How the streaming RPCs group requests before running the model.
+#[derive(Debug, Clone, Copy)]
+pub struct Batching {
+    /// Images per forward pass in `IndexImages`, `IndexItems` and `FindDuplicates`.
+    pub image_batch_size: usize,
+    /// Texts per forward pass in `IndexTexts`.
+    pub text_batch_size: usize,
+    /// How long a stream may go quiet before a partial batch is flushed.
+    pub timeout: Duration,
+}
+
+impl Default for Batching {
+    fn default() -> Self {
+        Self {
+            image_batch_size: 16,
+            text_batch_size: 32,
+            timeout: Duration::from_millis(500),
+        }
+    }
+}
+
+impl ClipEmbedderService {
+    pub fn new(
+        model: Box<dyn DualEncoder>,
+        captioner: Option<Captioner>,
+        limits: DecodeLimits,
+        background: Rgb<u8>,
+    ) -> Self {
+        let images = ImagePipeline {
+            limits,
+            processor: model.image_processor().clone(),
+            background,
+            caption_processor: captioner.as_ref().map(|c| c.image_processor().clone()),
+        };
+        Self {
+            model: Arc::new(Mutex::new(model)),
+            prompt_cache: Arc::new(Mutex::new(PromptCache::default())),
+            images: Arc::new(images),
+            captioner: captioner.map(|c| Arc::new(Mutex::new(c))),
+            index: None,
+            batching: Batching::default(),
+        }
+    }
+
+    /// Keeps the embeddings produced by `IndexImages` in `index` for searching.
+    pub fn with_index(mut self, index: VectorIndex) -> Self {
+        self.index = Some(Arc::new(RwLock::new(index)));
+        self
+    }
+
+    pub fn with_batching(mut self, batching: Batching) -> Self {
+        self.batching = batching;
+        self
+    }
+
+    fn search_index(&self) -> Result<Arc<RwLock<VectorIndex>>, Status> {
+        self.index
+            .clone()
+            .ok_or_else(|| Status::failed_precondition("The in-memory index is not enabled on this server"))
+    }
+}
+
+/// Results returned when a search doesn't say, and the most it may ask for.
+const DEFAULT_TOP_K: usize = 10;
+const MAX_TOP_K: usize = 1000;
+
+fn search_response(index: &RwLock<VectorIndex>, query: &[f32], top_k: u32) -> anyhow::Result<SearchResponse> {
+    let top_k = match top_k {
+        0 => DEFAULT_TOP_K,
+        n => (n as usize).min(MAX_TOP_K),
+    };
+    let hits = index
+        .read()
+        .unwrap()
+        .search(query, top_k)?
+        .into_iter()
+        .map(|(document_id, score)| SearchHit { document_id, score })
+        .collect();
+    Ok(SearchResponse { hits })
+}
+
+/// Maps a failed request to a status, reporting rejected images as invalid arguments.
+fn failure_status(context: &str, e: anyhow::Error) -> Status {
+    match e.downcast_ref::<ImageDecodeError>() {
+        Some(decode_error) => Status::invalid_argument(decode_error.to_string()),
+        None => Status::internal(format!("{}: {}", context, e)),
+    }
+}
+
+/// Frames sampled from an animated image when the request doesn't say, and the most it may ask for.
+const DEFAULT_FRAMES: usize = 8;
+const MAX_FRAMES: usize = 32;
+/// Grid tiles per side when the request doesn't say.
+const DEFAULT_GRID: u32 = 2;
+
+/// Which views of an image a request asked to embed, and how to report them.
+struct ImageOptions {
+    frame_mode: FrameMode,
+    region_output: RegionOutput,
+    caption: Option<CaptionOptions>,
+    prepare: PrepareOptions,
+}
+
+impl ImageOptions {
+    fn new(frames: Option<&FrameSampling>, regions: Option<&RegionOptions>) -> Self {
+        let frame_mode = frames.map(|f| f.mode()).unwrap_or(FrameMode::FirstFrame);
+        let max_frames = match frames {
+            Some(f) if frame_mode != FrameMode::FirstFrame => match f.max_frames {
+                0 => DEFAULT_FRAMES,
+                n => (n as usize).min(MAX_FRAMES),
+            },
+            _ => 1,
+        };
+        let or_default = |n: u32| if n == 0 { DEFAULT_GRID } else { n };
+        let layout = regions.and_then(|r| match r.mode() {
+            RegionMode::NoRegions => None,
+            RegionMode::Grid => Some(RegionLayout::Grid {
+                rows: or_default(r.rows),
+                cols: or_default(r.cols),
+            }),
+            RegionMode::Sliding => Some(RegionLayout::Sliding { overlap: r.overlap }),
+        });
+        Self {
+            frame_mode,
+            region_output: regions.map(|r| r.output()).unwrap_or(RegionOutput::Regions),
+            caption: None,
+            prepare: PrepareOptions {
+                max_frames,
+                layout,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Rejects region grids with more tiles than `MAX_REGIONS`, since rows and cols come
+    /// straight from the client.
+    fn validate(&self) -> Result<(), Status> {
+        if let Some(RegionLayout::Grid { rows, cols }) = self.prepare.layout
+            && u64::from(rows) * u64::from(cols) > MAX_REGIONS as u64
+        {
+            return Err(Status::invalid_argument(format!(
+                "A {}x{} region grid exceeds the limit of {} regions",
+                rows, cols, MAX_REGIONS
+            )));
+        }
+        Ok(())
+    }
+
+    fn prepare(&self, images: &ImagePipeline, image_bytes: &[u8]) -> anyhow::Result<PreparedImage> {
+        self.validate()?;
+        images.prepare_image(image_bytes, &self.prepare)
+    }
+}
+
+fn image_hashes(hashes: ImageHashes) -> proto::ImageHashes {
+    proto::ImageHashes {
+        ahash: hashes.ahash,
+        dhash: hashes.dhash,
+        phash: hashes.phash,
+        sha256: hashes.sha256,
+    }
+}
+
+fn image_metadata(metadata: ImageMetadata) -> proto::ImageMetadata {
+    proto::ImageMetadata {
+        width: metadata.width,
+        height: metadata.height,
+        format: format_name(metadata.format),
+        byte_size: metadata.byte_size as u64,
+        color_type: format!("{:?}", metadata.color_type).to_lowercase(),
+        capture_date: metadata.exif.capture_date.unwrap_or_default(),
+        camera_make: metadata.exif.camera_make.unwrap_or_default(),
+        camera_model: metadata.exif.camera_model.unwrap_or_default(),
+        orientation: metadata.exif.orientation.unwrap_or_default(),
+        dominant_colors: metadata
+            .dominant_colors
+            .into_iter()
+            .map(|color| DominantColor {
+                hex: format!("#{:02x}{:02x}{:02x}", color.rgb[0], color.rgb[1], color.rgb[2]),
+                fraction: color.fraction,
+            })
+            .collect(),
+    }
+}
+
+/// Caption length and beam width when the request doesn't say, and the most it may ask for.
+const DEFAULT_CAPTION_LENGTH: usize = 30;
+const MAX_CAPTION_LENGTH: usize = 100;
+const DEFAULT_BEAM_WIDTH: usize = 3;
+const MAX_BEAM_WIDTH: usize = 8;
+
+fn caption_options(options: &proto::CaptionOptions) -> CaptionOptions {
+    let decoding = match options.decoding() {
+        proto::CaptionDecoding::Greedy => CaptionDecoding::Greedy,
+        proto::CaptionDecoding::Sample => CaptionDecoding::Sample {
+            temperature: if options.temperature > 0.0 {
+                options.temperature as f64
+            } else {
+                1.0
+            },
+        },
+        proto::CaptionDecoding::Beam => CaptionDecoding::Beam {
+            width: match options.beam_width {
+                0 => DEFAULT_BEAM_WIDTH,
+                n => (n as usize).min(MAX_BEAM_WIDTH),
+            },
+        },
+    };
+    CaptionOptions {
+        max_length: match options.max_length {
+            0 => DEFAULT_CAPTION_LENGTH,
+            n => (n as usize).min(MAX_CAPTION_LENGTH),
+        },
+        decoding,
+        seed: options.seed,
+    }
+}
+
+/// Captions a prepared image with the captioner, if the server has one.
+fn caption_image(
+    captioner: Option<&Mutex<Captioner>>,
+    prepared: &PreparedImage,
+    options: &CaptionOptions,
+) -> anyhow::Result<String> {
+    let (Some(captioner), Some(pixels)) = (captioner, prepared.caption_pixels.as_ref()) else {
+        return Err(anyhow::Error::msg("Captioning is not enabled on this server"));
+    };
+    captioner.lock().unwrap().caption(pixels, options)
+}
+
+fn thumbnail_spec(spec: &proto::ThumbnailSpec) -> ThumbnailSpec {
+    let format = match spec.format() {
+        proto::ThumbnailFormat::Jpeg => ThumbnailFormat::Jpeg,
+        proto::ThumbnailFormat::Webp => ThumbnailFormat::WebP,
+    };
+    ThumbnailSpec::new(spec.max_edge, format, spec.quality)
+}
+
+fn thumbnail(thumbnail: Thumbnail) -> proto::Thumbnail {
+    let format = match thumbnail.format {
+        ThumbnailFormat::Jpeg => proto::ThumbnailFormat::Jpeg,
+        ThumbnailFormat::WebP => proto::ThumbnailFormat::Webp,
+    };
+    proto::Thumbnail {
+        data: thumbnail.data,
+        format: format.into(),
+        width: thumbnail.width,
+        height: thumbnail.height,
+    }
+}
+
+/// The embeddings reported for one image.
+struct ImageEmbeddings {
+    embedding: Vec<f32>,
+    frame_embeddings: Vec<Embedding>,
+    regions: Vec<RegionEmbedding>,
+}
+
+/// Splits the embeddings of an image's views back up. The image embedding is the normalized
+/// mean of its frames, folded together with the regions when an aggregate was asked for.
+fn reduce_views(
+    options: &ImageOptions,
+    prepared: &PreparedImage,
+    mut views: Vec<Vec<f32>>,
+) -> ImageEmbeddings {
+    let regions = views.split_off(prepared.frames.len().min(views.len()));
+    let mut embedding = mean_normalized(&views);
+    let frame_embeddings = if options.frame_mode == FrameMode::PerFrame {
+        views.into_iter().map(|values| Embedding { values }).collect()
+    } else {
+        vec![]
+    };
+
+    let regions = if options.region_output == RegionOutput::Aggregate {
+        if !regions.is_empty() {
+            let mut all = vec![embedding];
+            all.extend(regions);
+            embedding = mean_normalized(&all);
+        }
+        vec![]
+    } else {
+        prepared
+            .regions
+            .iter()
+            .zip(regions)
+            .map(|((region, _), values)| RegionEmbedding {
+                bounding_box: Some(BoundingBox {
+                    x: region.x,
+                    y: region.y,
+                    width: region.width,
+                    height: region.height,
+                }),
+                embedding: Some(Embedding { values }),
+            })
+            .collect()
+    };
+
+    ImageEmbeddings {
+        embedding,
+        frame_embeddings,
+        regions,
+    }
+}
+
+/// A batch of streamed requests awaiting embedding.
+struct Batch<T> {
     document_ids: Vec<String>,
-    images: Vec<Vec<u8>>,
+    items: Vec<T>,
+}
+
+/// Reads the client stream into batches of up to `batch_size` items, flushing what is
+/// buffered once the stream ends or goes quiet for `timeout`. The returned channel is
+/// small, so a worker that can't keep up stalls the reader and, through it, the client.
+fn spawn_batcher<S, R, T>(
+    mut request_stream: S,
+    batch_size: usize,
+    timeout: Duration,
+    split: fn(R) -> (String, T),
+) -> mpsc::Receiver<Batch<T>>
+where
+    S: Stream<Item = Result<R, Status>> + Unpin + Send + 'static,
+    R: Send + 'static,
+    T: Send + 'static,
+{
+    let (batch_tx, batch_rx) = mpsc::channel::<Batch<T>>(4);
+
+    tokio::spawn(async move {
+        let mut batch_ids = Vec::with_capacity(batch_size);
+        let mut batch_items = Vec::with_capacity(batch_size);
+
+        loop {
+            match tokio::time::timeout(timeout, request_stream.next()).await {
+                Ok(Some(Ok(req))) => {
+                    let (document_id, item) = split(req);
+                    batch_ids.push(document_id);
+                    batch_items.push(item);
+
+                    if batch_ids.len() >= batch_size {
+                        let batch = Batch {
+                            document_ids: batch_ids,
+                            items: batch_items,
+                        };
+                        if batch_tx.send(batch).await.is_err() {
+                            break;
+                        }
+                        batch_ids = Vec::with_capacity(batch_size);
+                        batch_items = Vec::with_capacity(batch_size);
+                    }
+                }
+                Ok(None) | Err(_) => {
+                    if !batch_ids.is_empty() {
+                        let batch = Batch {
+                            document_ids: batch_ids,
+                            items: batch_items,
+                        };
+                        let _ = batch_tx.send(batch).await;
+                    }
+                    break;
+                }
+                Ok(Some(Err(e))) => {
+                    warn!("Client stream error: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    batch_rx
+}
+
+/// Embeds a batch of `IndexTexts` requests, failing every document in the batch if the
+/// model does.
+fn embed_text_batch(model: &Mutex<Box<dyn DualEncoder>>, batch: Batch<String>) -> Vec<IndexResponse> {
+    let embeddings_result = model
+        .lock()
+        .unwrap()
+        .embed_texts_with(&batch.items, LongTextMode::Truncate);
+    match embeddings_result {
+        Ok(embeddings) => batch
+            .document_ids
+            .into_iter()
+            .zip(embeddings)
+            .map(|(document_id, embedding)| IndexResponse {
+                document_id,
+                embedding: Some(Embedding {
+                    values: embedding.values,
+                }),
+                success: true,
+                truncated: embedding.truncated,
+                ..Default::default()
+            })
+            .collect(),
+        Err(e) => {
+            error!("Batch embedding failed: {:?}", e);
+            batch
+                .document_ids
+                .into_iter()
+                .map(|document_id| IndexResponse {
+                    document_id,
+                    success: false,
+                    ..Default::default()
+                })
+                .collect()
+        }
+    }
+}
+
+/// Combines an item's text embedding and mean image embedding into one vector.
+fn fuse(text: Option<&[f32]>, images: &[Vec<f32>], options: &FusionOptions) -> Option<Vec<f32>> {
+    let (text_weight, image_weight) = if options.text_weight == 0.0 && options.image_weight == 0.0 {
+        (1.0, 1.0)
+    } else {
+        (options.text_weight, options.image_weight)
+    };
+    let image_mean = (!images.is_empty()).then(|| mean_normalized(images));
+
+    let mut parts = vec![];
+    if let Some(text) = text {
+        parts.push((text, text_weight));
+    }
+    if let Some(image_mean) = &image_mean {
+        parts.push((image_mean.as_slice(), image_weight));
+    }
+    let fused = weighted_mean_normalized(&parts);
+    (!fused.is_empty()).then_some(fused)
+}
+
+/// Embeds every modality of a batch of items with one text pass and one image pass.
+/// Images are decoded before taking the model lock; an item with a rejected image fails
+/// on its own without affecting the rest of the batch.
+fn embed_items(
+    model: &Mutex<Box<dyn DualEncoder>>,
+    images: &ImagePipeline,
+    item_ids: Vec<String>,
+    items: Vec<IndexItemRequest>,
+) -> anyhow::Result<Vec<IndexItemResponse>> {
+    let pixels: Vec<Option<Vec<_>>> = item_ids
+        .iter()
+        .zip(&items)
+        .map(|(item_id, item)| match images.prepare_batch(&item.images) {
+            Ok(pixels) => Some(pixels),
+            Err(e) => {
+                warn!("Rejected images for item {}: {}", item_id, e);
+                None
+            }
+        })
+        .collect();
+    let texts: Vec<String> = items
+        .iter()
+        .zip(&pixels)
+        .filter(|(item, pixels)| pixels.is_some() && !item.text.is_empty())
+        .map(|(item, _)| item.text.clone())
+        .collect();
+    let all_pixels: Vec<_> = pixels.iter().flatten().flatten().cloned().collect();
+
+    let model = model.lock().unwrap();
+    let text_embeddings = if texts.is_empty() {
+        vec![]
+    } else {
+        model.embed_texts_with(&texts, LongTextMode::Truncate)?
+    };
+    let image_embeddings = if all_pixels.is_empty() {
+        vec![]
+    } else {
+        model.embed_images(&all_pixels)?
+    };
+    drop(model);
+    let mut text_embeddings = text_embeddings.into_iter();
+    let mut image_embeddings = image_embeddings.into_iter();
+
+    Ok(item_ids
+        .into_iter()
+        .zip(items)
+        .zip(pixels)
+        .map(|((item_id, item), pixels)| {
+            let Some(pixels) = pixels else {
+                return IndexItemResponse {
+                    item_id,
+                    success: false,
+                    ..Default::default()
+                };
+            };
+            let text = if item.text.is_empty() {
+                None
+            } else {
+                text_embeddings.next()
+            };
+            let images: Vec<Vec<f32>> = image_embeddings.by_ref().take(pixels.len()).collect();
+            let fused = item
+                .fusion
+                .filter(|fusion| fusion.enabled)
+                .and_then(|fusion| fuse(text.as_ref().map(|t| t.values.as_slice()), &images, &fusion));
+
+            IndexItemResponse {
+                item_id,
+                success: text.is_some() || !images.is_empty(),
+                truncated: text.as_ref().is_some_and(|t| t.truncated),
+                text_embedding: text.map(|t| Embedding { values: t.values }),
+                image_embeddings: images
+                    .into_iter()
+                    .map(|values| Embedding { values })
+                    .collect(),
+                fused_embedding: fused.map(|values| Embedding { values }),
+            }
+        })
+        .collect())
+}
+
+const DEFAULT_PROMPT_TEMPLATE: &str = "a photo of a {label}";
+
+/// Builds the response for an embedded image, captioning it first if that was asked for.
+fn image_response(
+    document_id: String,
+    options: &ImageOptions,
+    prepared: PreparedImage,
+    views: Vec<Vec<f32>>,
+    captioner: Option<&Mutex<Captioner>>,
+) -> IndexResponse {
+    // A failed caption is reported alongside the embedding rather than failing the image.
+    let (caption, caption_error) = match &options.caption {
+        Some(caption_options) => match caption_image(captioner, &prepared, caption_options) {
+            Ok(caption) => (caption, String::new()),
+            Err(e) => {
+                error!("Captioning {} failed: {}", document_id, e);
+                (String::new(), e.to_string())
+            }
+        },
+        None => (String::new(), String::new()),
+    };
+    let reduced = reduce_views(options, &prepared, views);
+
+    IndexResponse {
+        document_id,
+        success: !reduced.embedding.is_empty(),
+        embedding: Some(Embedding {
+            values: reduced.embedding,
+        }),
+        truncated: false,
+        format: format_name(prepared.format),
+        frame_embeddings: reduced.frame_embeddings,
+        regions: reduced.regions,
+        hashes: prepared.hashes.map(image_hashes),
+        metadata: prepared.metadata.map(image_metadata),
+        thumbnails: prepared.thumbnails.into_iter().map(thumbnail).collect(),
+        caption,
+        caption_error,
+    }
+}
+
+/// Hashes and embeds a batch of images for duplicate detection, returning the candidates
+/// and the ids of images that could not be decoded.
+fn duplicate_candidates(
+    model: &Mutex<Box<dyn DualEncoder>>,
+    images: &ImagePipeline,
+    batch: Vec<DuplicateImage>,
+) -> anyhow::Result<(Vec<DuplicateCandidate>, Vec<String>)> {
+    let options = PrepareOptions {
+        hashes: true,
+        ..Default::default()
+    };
+    let mut accepted = vec![];
+    let mut rejected_ids = vec![];
+    for image in batch {
+        match images.prepare_image(&image.image, &options) {
+            Ok(prepared) => accepted.push((image.document_id, prepared)),
+            Err(e) => {
+                warn!("Rejected image {}: {}", image.document_id, e);
+                rejected_ids.push(image.document_id);
+            }
+        }
+    }
+    if accepted.is_empty() {
+        return Ok((vec![], rejected_ids));
+    }
+
+    let pixels: Vec<_> = accepted
+        .iter()
+        .flat_map(|(_, prepared)| prepared.frames.iter().cloned())
+        .collect();
+    let embeddings = model.lock().unwrap().embed_images(&pixels)?;
+    let candidates = accepted
+        .into_iter()
+        .zip(embeddings)
+        .filter_map(|((document_id, prepared), embedding)| {
+            Some(DuplicateCandidate {
+                document_id,
+                hashes: prepared.hashes?,
+                embedding,
+            })
+        })
+        .collect();
+    Ok((candidates, rejected_ids))
+}
+
+fn long_text_mode(mode: proto::LongTextMode) -> LongTextMode {
+    match mode {
+        proto::LongTextMode::Truncate => LongTextMode::Truncate,
+        proto::LongTextMode::SegmentMean => LongTextMode::SegmentMean,
+    }
+}
+
+fn scoring_function(scoring: Scoring) -> ScoringFunction {
+    match scoring {
+        Scoring::Softmax { .. } => ScoringFunction::Softmax,
+        Scoring::Sigmoid { .. } => ScoringFunction::Sigmoid,
+    }
+}
+
+/// Scores every candidate against the source and, if requested, adds the model's probabilities.
+fn similarity_response(
+    source: &[f32],
+    candidates: &[Vec<f32>],
+    scoring: Scoring,
+    probabilities: bool,
+) -> SimilarityResponse {
+    let scores: Vec<f32> = candidates
+        .iter()
+        .map(|candidate| cosine_similarity(source, candidate))
+        .collect();
+    let probabilities = if probabilities {
+        scoring.probabilities(&scores)
+    } else {
+        vec![]
+    };
+    SimilarityResponse {
+        scores,
+        probabilities,
+        scoring: scoring_function(scoring).into(),
+    }
 }
 
 #[tonic::async_trait]
@@ -26,13 +679,17 @@ impl ClipEmbedder for ClipEmbedderService {
         &self,
         request: Request<EmbedTextRequest>,
     ) -> Result<Response<EmbedResponse>, Status> {
-        let text = request.into_inner().text;
+        let request = request.into_inner();
+        let mode = long_text_mode(request.long_text_mode());
+        let text = request.text;
         if text.is_empty() {
             return Err(Status::invalid_argument("Text cannot be empty"));
         }
 
         let model = self.model.clone();
-        let embedding = tokio::task::spawn_blocking(move || model.lock().unwrap().embed_texts(&[text]))
+        let embedding = tokio::task::spawn_blocking(move || {
+            model.lock().unwrap().embed_texts_with(&[text], mode)
+        })
             .await
             .map_err(|e| Status::internal(format!("Task join error: {}", e)))?
             .map_err(|e| Status::internal(format!("Embedding generation failed: {}", e)))?
@@ -40,7 +697,11 @@ impl ClipEmbedder for ClipEmbedderService {
             .ok_or_else(|| Status::internal("Model returned no embedding"))?;
 
         Ok(Response::new(EmbedResponse {
-            embedding: Some(Embedding { values: embedding }),
+            embedding: Some(Embedding {
+                values: embedding.values,
+            }),
+            truncated: embedding.truncated,
+            ..Default::default()
         }))
     }
 
@@ -48,23 +709,38 @@ impl ClipEmbedder for ClipEmbedderService {
         &self,
         request: Request<EmbedImageRequest>,
     ) -> Result<Response<EmbedResponse>, Status> {
-        let image_bytes = request.into_inner().image;
+        let request = request.into_inner();
+        let options = ImageOptions::new(request.frames.as_ref(), request.regions.as_ref());
+        options.validate()?;
+        let image_bytes = request.image;
         if image_bytes.is_empty() {
             return Err(Status::invalid_argument("Image bytes cannot be empty"));
         }
 
         let model = self.model.clone();
-        let embedding = tokio::task::spawn_blocking(move || {
-            model.lock().unwrap().embed_images(&[image_bytes.clone()])
+        let images = self.images.clone();
+        let (prepared, reduced) = tokio::task::spawn_blocking(move || {
+            let prepared = options.prepare(&images, &image_bytes)?;
+            let views: Vec<_> = prepared.views().cloned().collect();
+            let views = model.lock().unwrap().embed_images(&views)?;
+            if views.is_empty() {
+                return Err(anyhow::Error::msg("Model returned no embedding"));
+            }
+            let reduced = reduce_views(&options, &prepared, views);
+            Ok((prepared, reduced))
         })
             .await
             .map_err(|e| Status::internal(format!("Task join error: {}", e)))?
-            .map_err(|e| Status::internal(format!("Embedding generation failed: {}", e)))?
-            .pop()
-            .ok_or_else(|| Status::internal("Model returned no embedding"))?;
+            .map_err(|e| failure_status("Embedding generation failed", e))?;
 
         Ok(Response::new(EmbedResponse {
-            embedding: Some(Embedding { values: embedding }),
+            embedding: Some(Embedding {
+                values: reduced.embedding,
+            }),
+            truncated: false,
+            format: format_name(prepared.format),
+            frame_embeddings: reduced.frame_embeddings,
+            regions: reduced.regions,
         }))
     }
 
@@ -74,40 +750,101 @@ impl ClipEmbedder for ClipEmbedderService {
         &self,
         request: Request<Streaming<IndexImageRequest>>,
     ) -> Result<Response<Self::IndexImagesStream>, Status> {
-        let mut request_stream = request.into_inner();
+        let Batching { image_batch_size, timeout, .. } = self.batching;
         let model = self.model.clone();
-        let (batch_tx, mut batch_rx) = mpsc::channel::<ImageBatch>(4);
+        let images = self.images.clone();
+        let captioner = self.captioner.clone();
+        let index = self.index.clone();
+        let mut batch_rx = spawn_batcher(request.into_inner(), image_batch_size, timeout, |req: IndexImageRequest| {
+            let mut options = ImageOptions::new(req.frames.as_ref(), req.regions.as_ref());
+            options.prepare.hashes = req.compute_hashes;
+            options.prepare.metadata = req.include_metadata;
+            options.prepare.thumbnails = req
+                .thumbnails
+                .iter()
+                .take(MAX_THUMBNAILS)
+                .map(thumbnail_spec)
+                .collect();
+            options.caption = req.caption.as_ref().map(caption_options);
+            options.prepare.caption = options.caption.is_some();
+            (req.document_id, (req.image, options))
+        });
         let (response_tx, response_rx) = mpsc::channel(32);
 
         // Worker task to process image batches
         tokio::spawn(async move {
             while let Some(batch) = batch_rx.recv().await {
                 let model = model.clone();
+                let images = images.clone();
+                let captioner = captioner.clone();
+                let index = index.clone();
                 let response_tx = response_tx.clone();
                 tokio::task::spawn_blocking(move || {
-                    let embeddings_result = model.lock().unwrap().embed_images(&batch.images);
+                    // Decode outside the model lock; rejected images fail individually.
+                    let prepared: Vec<Option<_>> = batch
+                        .document_ids
+                        .iter()
+                        .zip(&batch.items)
+                        .map(|(doc_id, (image_bytes, options))| {
+                            match options.prepare(&images, image_bytes) {
+                                Ok(prepared) => Some(prepared),
+                                Err(e) => {
+                                    warn!("Rejected image {}: {}", doc_id, e);
+                                    None
+                                }
+                            }
+                        })
+                        .collect();
+                    let pixels: Vec<_> = prepared
+                        .iter()
+                        .flatten()
+                        .flat_map(|prepared| prepared.views().cloned())
+                        .collect();
+                    let embeddings_result = if pixels.is_empty() {
+                        Ok(vec![])
+                    } else {
+                        model.lock().unwrap().embed_images(&pixels)
+                    };
                     match embeddings_result {
                         Ok(embeddings) => {
-                            for (i, doc_id) in batch.document_ids.iter().enumerate() {
-                                let response = IndexResponse {
-                                    document_id: doc_id.clone(),
-                                    embedding: embeddings
-                                        .get(i)
-                                        .map(|v| Embedding { values: v.clone() }),
-                                    success: true,
+                            let mut embeddings = embeddings.into_iter();
+                            let items = batch.document_ids.into_iter().zip(batch.items).zip(prepared);
+                            for ((doc_id, (_, options)), prepared) in items {
+                                let response = match prepared {
+                                    Some(prepared) => {
+                                        let views: Vec<_> =
+                                            embeddings.by_ref().take(prepared.view_count()).collect();
+                                        let captioner = captioner.as_deref();
+                                        image_response(doc_id, &options, prepared, views, captioner)
+                                    }
+                                    None => IndexResponse {
+                                        document_id: doc_id,
+                                        success: false,
+                                        ..Default::default()
+                                    },
                                 };
+                                if let (Some(index), true) = (&index, response.success) {
+                                    let embedding = response.embedding.clone().unwrap_or_default();
+                                    let indexed = index
+                                        .write()
+                                        .unwrap()
+                                        .upsert(response.document_id.clone(), embedding.values);
+                                    if let Err(e) = indexed {
+                                        error!("Could not index {}: {}", response.document_id, e);
+                                    }
+                                }
                                 if response_tx.blocking_send(Ok(response)).is_err() {
                                     break;
                                 }
                             }
                         }
                         Err(e) => {
-                            eprintln!("Batch embedding failed: {:?}", e);
+                            error!("Batch embedding failed: {:?}", e);
                             for doc_id in batch.document_ids {
                                 let response = IndexResponse {
                                     document_id: doc_id,
-                                    embedding: None,
                                     success: false,
+                                    ..Default::default()
                                 };
                                 if response_tx.blocking_send(Ok(response)).is_err() {
                                     break;
@@ -119,50 +856,711 @@ impl ClipEmbedder for ClipEmbedderService {
             }
         });
 
-        // Task to create batches from the client stream
+        let output_stream = ReceiverStream::new(response_rx);
+        Ok(Response::new(Box::pin(output_stream)))
+    }
+
+    type IndexTextsStream = Pin<Box<dyn Stream<Item = Result<IndexResponse, Status>> + Send>>;
+
+    async fn index_texts(
+        &self,
+        request: Request<Streaming<IndexTextRequest>>,
+    ) -> Result<Response<Self::IndexTextsStream>, Status> {
+        let Batching { text_batch_size, timeout, .. } = self.batching;
+        let model = self.model.clone();
+        let mut batch_rx = spawn_batcher(request.into_inner(), text_batch_size, timeout, |req: IndexTextRequest| {
+            (req.document_id, req.text)
+        });
+        let (response_tx, response_rx) = mpsc::channel(32);
+
+        // Worker task to process text batches
         tokio::spawn(async move {
-            const BATCH_SIZE: usize = 16;
-            const BATCH_TIMEOUT: Duration = Duration::from_millis(500);
-            let mut batch_ids = Vec::with_capacity(BATCH_SIZE);
-            let mut batch_images = Vec::with_capacity(BATCH_SIZE);
-
-            loop {
-                match tokio::time::timeout(BATCH_TIMEOUT, request_stream.next()).await {
-                    Ok(Some(Ok(req))) => {
-                        batch_ids.push(req.document_id);
-                        batch_images.push(req.image);
-
-                        if batch_ids.len() >= BATCH_SIZE {
-                            let batch = ImageBatch {
-                                document_ids: batch_ids,
-                                images: batch_images,
-                            };
-                            if batch_tx.send(batch).await.is_err() {
-                                break;
-                            }
-                            batch_ids = Vec::with_capacity(BATCH_SIZE);
-                            batch_images = Vec::with_capacity(BATCH_SIZE);
+            while let Some(batch) = batch_rx.recv().await {
+                let model = model.clone();
+                let response_tx = response_tx.clone();
+                tokio::task::spawn_blocking(move || {
+                    for response in embed_text_batch(&model, batch) {
+                        if response_tx.blocking_send(Ok(response)).is_err() {
+                            break;
                         }
                     }
-                    Ok(None) | Err(_) => {
-                        if !batch_ids.is_empty() {
-                            let batch = ImageBatch {
-                                document_ids: batch_ids,
-                                images: batch_images,
-                            };
-                            let _ = batch_tx.send(batch).await;
+                });
+            }
+        });
+
+        let output_stream = ReceiverStream::new(response_rx);
+        Ok(Response::new(Box::pin(output_stream)))
+    }
+
+    type IndexItemsStream = Pin<Box<dyn Stream<Item = Result<IndexItemResponse, Status>> + Send>>;
+
+    async fn index_items(
+        &self,
+        request: Request<Streaming<IndexItemRequest>>,
+    ) -> Result<Response<Self::IndexItemsStream>, Status> {
+        let Batching { image_batch_size, timeout, .. } = self.batching;
+        let model = self.model.clone();
+        let images = self.images.clone();
+        let mut batch_rx = spawn_batcher(request.into_inner(), image_batch_size, timeout, |mut req: IndexItemRequest| {
+            (std::mem::take(&mut req.item_id), req)
+        });
+        let (response_tx, response_rx) = mpsc::channel(32);
+
+        // Worker task to process item batches
+        tokio::spawn(async move {
+            while let Some(batch) = batch_rx.recv().await {
+                let model = model.clone();
+                let images = images.clone();
+                let response_tx = response_tx.clone();
+                tokio::task::spawn_blocking(move || {
+                    let item_ids = batch.document_ids.clone();
+                    let responses_result = embed_items(&model, &images, batch.document_ids, batch.items);
+                    let responses = match responses_result {
+                        Ok(responses) => responses,
+                        Err(e) => {
+                            error!("Batch embedding failed: {:?}", e);
+                            item_ids
+                                .into_iter()
+                                .map(|item_id| IndexItemResponse {
+                                    item_id,
+                                    success: false,
+                                    ..Default::default()
+                                })
+                                .collect()
+                        }
+                    };
+                    for response in responses {
+                        if response_tx.blocking_send(Ok(response)).is_err() {
+                            break;
                         }
-                        break;
-                    }
-                    Ok(Some(Err(e))) => {
-                        eprintln!("Client stream error: {}", e);
-                        break;
                     }
-                }
+                });
             }
         });
 
         let output_stream = ReceiverStream::new(response_rx);
         Ok(Response::new(Box::pin(output_stream)))
     }
-}
\ No newline at end of file
+
+    async fn image_text_similarity(
+        &self,
+        request: Request<ImageTextSimilarityRequest>,
+    ) -> Result<Response<SimilarityResponse>, Status> {
+        let ImageTextSimilarityRequest {
+            image,
+            texts,
+            probabilities,
+        } = request.into_inner();
+        if image.is_empty() {
+            return Err(Status::invalid_argument("Image bytes cannot be empty"));
+        }
+        if texts.is_empty() {
+            return Ok(Response::new(SimilarityResponse::default()));
+        }
+
+        let model = self.model.clone();
+        let images = self.images.clone();
+        let response = tokio::task::spawn_blocking(move || {
+            let pixels = images.prepare(&image)?;
+            let model = model.lock().unwrap();
+            let image_embedding = model
+                .embed_images(&[pixels])?
+                .pop()
+                .ok_or_else(|| anyhow::Error::msg("Model returned no embedding"))?;
+            let text_embeddings = model.embed_texts(&texts)?;
+            Ok::<_, anyhow::Error>(similarity_response(
+                &image_embedding,
+                &text_embeddings,
+                model.scoring(),
+                probabilities,
+            ))
+        })
+            .await
+            .map_err(|e| Status::internal(format!("Task join error: {}", e)))?
+            .map_err(|e| failure_status("Embedding generation failed", e))?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn text_image_similarity(
+        &self,
+        request: Request<TextImageSimilarityRequest>,
+    ) -> Result<Response<SimilarityResponse>, Status> {
+        let TextImageSimilarityRequest {
+            text,
+            images,
+            probabilities,
+        } = request.into_inner();
+        if text.is_empty() {
+            return Err(Status::invalid_argument("Text cannot be empty"));
+        }
+        if images.is_empty() {
+            return Ok(Response::new(SimilarityResponse::default()));
+        }
+        if images.iter().any(|image| image.is_empty()) {
+            return Err(Status::invalid_argument("Image bytes cannot be empty"));
+        }
+
+        let model = self.model.clone();
+        let pipeline = self.images.clone();
+        let response = tokio::task::spawn_blocking(move || {
+            let pixels = pipeline.prepare_batch(&images)?;
+            let model = model.lock().unwrap();
+            let text_embedding = model
+                .embed_texts(&[text])?
+                .pop()
+                .ok_or_else(|| anyhow::Error::msg("Model returned no embedding"))?;
+            let image_embeddings = model.embed_images(&pixels)?;
+            Ok::<_, anyhow::Error>(similarity_response(
+                &text_embedding,
+                &image_embeddings,
+                model.scoring(),
+                probabilities,
+            ))
+        })
+            .await
+            .map_err(|e| Status::internal(format!("Task join error: {}", e)))?
+            .map_err(|e| failure_status("Embedding generation failed", e))?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn classify(
+        &self,
+        request: Request<ClassifyRequest>,
+    ) -> Result<Response<ClassifyResponse>, Status> {
+        let ClassifyRequest {
+            image,
+            labels,
+            prompt_template,
+        } = request.into_inner();
+        if image.is_empty() {
+            return Err(Status::invalid_argument("Image bytes cannot be empty"));
+        }
+        if labels.is_empty() {
+            return Err(Status::invalid_argument("At least one label is required"));
+        }
+
+        let template = if prompt_template.is_empty() {
+            DEFAULT_PROMPT_TEMPLATE.to_string()
+        } else if prompt_template.contains("{label}") {
+            prompt_template
+        } else {
+            return Err(Status::invalid_argument(
+                "Prompt template must contain a {label} placeholder",
+            ));
+        };
+        let prompts: Vec<String> = labels
+            .iter()
+            .map(|label| template.replace("{label}", label))
+            .collect();
+
+        let model = self.model.clone();
+        let images = self.images.clone();
+        let prompt_cache = self.prompt_cache.clone();
+        let response = tokio::task::spawn_blocking(move || {
+            let pixels = images.prepare(&image)?;
+            let model = model.lock().unwrap();
+            let image_embedding = model
+                .embed_images(&[pixels])?
+                .pop()
+                .ok_or_else(|| anyhow::Error::msg("Model returned no embedding"))?;
+            let prompt_embeddings = prompt_cache.lock().unwrap().embed(model.as_ref(), &prompts)?;
+            Ok::<_, anyhow::Error>(similarity_response(
+                &image_embedding,
+                &prompt_embeddings,
+                model.scoring(),
+                true,
+            ))
+        })
+            .await
+            .map_err(|e| Status::internal(format!("Task join error: {}", e)))?
+            .map_err(|e| failure_status("Classification failed", e))?;
+
+        let mut scored: Vec<LabelScore> = labels
+            .into_iter()
+            .zip(response.scores)
+            .zip(response.probabilities)
+            .map(|((label, score), probability)| LabelScore {
+                label,
+                score,
+                probability,
+            })
+            .collect();
+        scored.sort_by(|a, b| b.probability.total_cmp(&a.probability));
+
+        Ok(Response::new(ClassifyResponse {
+            labels: scored,
+            scoring: response.scoring,
+        }))
+    }
+
+    async fn find_duplicates(
+        &self,
+        request: Request<Streaming<FindDuplicatesRequest>>,
+    ) -> Result<Response<FindDuplicatesResponse>, Status> {
+        let batch_size = self.batching.image_batch_size;
+        // Grouping compares every pair, so bound the number of images per call.
+        const MAX_IMAGES: usize = 10_000;
+        let mut stream = request.into_inner();
+        let mut thresholds = DuplicateThresholds::default();
+        let mut candidates = vec![];
+        let mut rejected_ids = vec![];
+        let mut pending = vec![];
+        let mut first = true;
+
+        loop {
+            let message = stream.message().await?;
+            let done = message.is_none();
+            match message.and_then(|m| m.request) {
+                Some(find_duplicates_request::Request::Thresholds(t)) => {
+                    if !first {
+                        return Err(Status::invalid_argument(
+                            "Thresholds must be the first message of the stream",
+                        ));
+                    }
+                    if let Some(max_hash_distance) = t.max_hash_distance {
+                        thresholds.max_hash_distance = max_hash_distance;
+                    }
+                    if let Some(min_similarity) = t.min_similarity {
+                        thresholds.min_similarity = min_similarity;
+                    }
+                }
+                Some(find_duplicates_request::Request::Image(image)) => pending.push(image),
+                None => {}
+            }
+            first = false;
+
+            if candidates.len() + rejected_ids.len() + pending.len() > MAX_IMAGES {
+                return Err(Status::invalid_argument(format!(
+                    "At most {} images can be compared at once",
+                    MAX_IMAGES
+                )));
+            }
+            if pending.len() >= batch_size || (done && !pending.is_empty()) {
+                let batch = std::mem::take(&mut pending);
+                let model = self.model.clone();
+                let images = self.images.clone();
+                let (accepted, rejected) =
+                    tokio::task::spawn_blocking(move || duplicate_candidates(&model, &images, batch))
+                        .await
+                        .map_err(|e| Status::internal(format!("Task join error: {}", e)))?
+                        .map_err(|e| failure_status("Embedding generation failed", e))?;
+                candidates.extend(accepted);
+                rejected_ids.extend(rejected);
+            }
+            if done {
+                break;
+            }
+        }
+
+        let groups = tokio::task::spawn_blocking(move || {
+            group_duplicates(&candidates, &thresholds)
+                .into_iter()
+                .map(|group| DuplicateGroup {
+                    document_ids: group
+                        .into_iter()
+                        .map(|i| candidates[i].document_id.clone())
+                        .collect(),
+                })
+                .collect()
+        })
+            .await
+            .map_err(|e| Status::internal(format!("Task join error: {}", e)))?;
+
+        Ok(Response::new(FindDuplicatesResponse {
+            groups,
+            rejected_ids,
+        }))
+    }
+
+    async fn caption(
+        &self,
+        request: Request<CaptionRequest>,
+    ) -> Result<Response<CaptionResponse>, Status> {
+        let request = request.into_inner();
+        let Some(captioner) = self.captioner.clone() else {
+            return Err(Status::failed_precondition("Captioning is not enabled on this server"));
+        };
+        let image = request.image;
+        if image.is_empty() {
+            return Err(Status::invalid_argument("Image bytes cannot be empty"));
+        }
+        let options = caption_options(&request.options.unwrap_or_default());
+
+        let images = self.images.clone();
+        let caption = tokio::task::spawn_blocking(move || {
+            let prepare = PrepareOptions {
+                max_frames: 0,
+                caption: true,
+                ..Default::default()
+            };
+            let prepared = images.prepare_image(&image, &prepare)?;
+            caption_image(Some(&captioner), &prepared, &options)
+        })
+            .await
+            .map_err(|e| Status::internal(format!("Task join error: {}", e)))?
+            .map_err(|e| failure_status("Caption generation failed", e))?;
+
+        Ok(Response::new(CaptionResponse { caption }))
+    }
+
+    async fn search_by_text(
+        &self,
+        request: Request<SearchByTextRequest>,
+    ) -> Result<Response<SearchResponse>, Status> {
+        let index = self.search_index()?;
+        let request = request.into_inner();
+        let mode = long_text_mode(request.long_text_mode());
+        let top_k = request.top_k;
+        let text = request.text;
+        if text.is_empty() {
+            return Err(Status::invalid_argument("Text cannot be empty"));
+        }
+
+        let model = self.model.clone();
+        let response = tokio::task::spawn_blocking(move || {
+            let query = model
+                .lock()
+                .unwrap()
+                .embed_texts_with(&[text], mode)?
+                .pop()
+                .ok_or_else(|| anyhow::Error::msg("Model returned no embedding"))?;
+            search_response(&index, &query.values, top_k)
+        })
+            .await
+            .map_err(|e| Status::internal(format!("Task join error: {}", e)))?
+            .map_err(|e| Status::internal(format!("Search failed: {}", e)))?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn search_by_image(
+        &self,
+        request: Request<SearchByImageRequest>,
+    ) -> Result<Response<SearchResponse>, Status> {
+        let index = self.search_index()?;
+        let request = request.into_inner();
+        let top_k = request.top_k;
+        let image = request.image;
+        if image.is_empty() {
+            return Err(Status::invalid_argument("Image bytes cannot be empty"));
+        }
+
+        let model = self.model.clone();
+        let images = self.images.clone();
+        let response = tokio::task::spawn_blocking(move || {
+            let pixels = images.prepare(&image)?;
+            let query = model
+                .lock()
+                .unwrap()
+                .embed_images(&[pixels])?
+                .pop()
+                .ok_or_else(|| anyhow::Error::msg("Model returned no embedding"))?;
+            search_response(&index, &query, top_k)
+        })
+            .await
+            .map_err(|e| Status::internal(format!("Task join error: {}", e)))?
+            .map_err(|e| failure_status("Search failed", e))?;
+
+        Ok(Response::new(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipembedder::processor::ImageProcessor;
+    use crate::clipembedder::text::TextEmbedding;
+    use crate::utils::softmax;
+    use candle_core::{DType, Device, Tensor};
+
+    /// Embeds every text as [1, 0] and every image as [0, 1], so tests can predict each
+    /// embedding. Texts over ten characters count as truncated, and a batch containing
+    /// "fail" fails.
+    struct FakeEncoder {
+        device: Device,
+        processor: ImageProcessor,
+    }
+
+    impl DualEncoder for FakeEncoder {
+        fn device(&self) -> &Device {
+            &self.device
+        }
+
+        fn embed_texts_with(&self, texts: &[String], _mode: LongTextMode) -> anyhow::Result<Vec<TextEmbedding>> {
+            if texts.iter().any(|text| text == "fail") {
+                anyhow::bail!("model failure");
+            }
+            Ok(texts
+                .iter()
+                .map(|text| TextEmbedding {
+                    values: vec![1.0, 0.0],
+                    truncated: text.len() > 10,
+                })
+                .collect())
+        }
+
+        fn image_processor(&self) -> &ImageProcessor {
+            &self.processor
+        }
+
+        fn embed_images(&self, pixels: &[Tensor]) -> anyhow::Result<Vec<Vec<f32>>> {
+            Ok(vec![vec![0.0, 1.0]; pixels.len()])
+        }
+
+        fn scoring(&self) -> Scoring {
+            Scoring::Softmax { logit_scale: 100.0 }
+        }
+    }
+
+    fn fake_model() -> Mutex<Box<dyn DualEncoder>> {
+        Mutex::new(Box::new(FakeEncoder {
+            device: Device::Cpu,
+            processor: ImageProcessor::siglip(8),
+        }))
+    }
+
+    fn text_batch(texts: &[&str]) -> Batch<String> {
+        Batch {
+            document_ids: (0..texts.len()).map(|i| format!("doc{}", i)).collect(),
+            items: texts.iter().map(|text| text.to_string()).collect(),
+        }
+    }
+
+    fn grid(rows: u32, cols: u32) -> RegionOptions {
+        RegionOptions {
+            mode: RegionMode::Grid as i32,
+            rows,
+            cols,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn keeps_the_embedding_when_captioning_fails() {
+        let mut options = ImageOptions::new(None, None);
+        options.caption = Some(CaptionOptions {
+            max_length: DEFAULT_CAPTION_LENGTH,
+            decoding: CaptionDecoding::Greedy,
+            seed: None,
+        });
+        let prepared = PreparedImage {
+            format: image::ImageFormat::Png,
+            frames: vec![Tensor::zeros((3, 2, 2), DType::F32, &Device::Cpu).unwrap()],
+            regions: vec![],
+            hashes: None,
+            metadata: None,
+            thumbnails: vec![],
+            caption_pixels: None,
+        };
+        let response = image_response("doc".to_string(), &options, prepared, vec![vec![3.0, 4.0]], None);
+        assert!(response.success);
+        assert_eq!(response.embedding.unwrap().values, [0.6, 0.8]);
+        assert!(response.caption.is_empty());
+        assert!(!response.caption_error.is_empty());
+    }
+
+    #[test]
+    fn similarity_adds_probabilities_only_when_asked() {
+        let source = [1.0, 0.0];
+        let candidates = vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![-1.0, 0.0]];
+        let scoring = Scoring::Softmax { logit_scale: 1.0 };
+
+        let response = similarity_response(&source, &candidates, scoring, false);
+        assert_eq!(response.scores, [1.0, 0.0, -1.0]);
+        assert!(response.probabilities.is_empty());
+        assert_eq!(response.scoring(), ScoringFunction::Softmax);
+
+        let response = similarity_response(&source, &candidates, scoring, true);
+        let expected = softmax(&[1.0, 0.0, -1.0], 1.0);
+        assert_eq!(response.probabilities, expected);
+        assert!((response.probabilities.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn similarity_reports_sigmoid_scoring() {
+        let scoring = Scoring::Sigmoid {
+            logit_scale: 10.0,
+            logit_bias: -10.0,
+        };
+        let response = similarity_response(&[0.0, 1.0], &[vec![0.0, 2.0], vec![1.0, 0.0]], scoring, true);
+        assert_eq!(response.scoring(), ScoringFunction::Sigmoid);
+        assert_eq!(response.scores, [1.0, 0.0]);
+        assert_eq!(response.probabilities[0], 0.5);
+        assert!(response.probabilities[1] < 1e-4);
+    }
+
+    #[tokio::test]
+    async fn batches_streamed_requests_by_size() {
+        let requests: Vec<Result<IndexTextRequest, Status>> = (0..5)
+            .map(|i| {
+                Ok(IndexTextRequest {
+                    document_id: format!("doc{}", i),
+                    text: format!("text {}", i),
+                })
+            })
+            .collect();
+        let mut batch_rx = spawn_batcher(
+            tokio_stream::iter(requests),
+            2,
+            Duration::from_secs(5),
+            |req: IndexTextRequest| (req.document_id, req.text),
+        );
+
+        let mut batches = vec![];
+        while let Some(batch) = batch_rx.recv().await {
+            assert_eq!(batch.document_ids.len(), batch.items.len());
+            batches.push(batch.items);
+        }
+        assert_eq!(batches, [vec!["text 0", "text 1"], vec!["text 2", "text 3"], vec!["text 4"]]);
+    }
+
+    #[test]
+    fn embeds_text_batches_in_order() {
+        let responses = embed_text_batch(&fake_model(), text_batch(&["short", "a much longer text"]));
+        let ids: Vec<_> = responses.iter().map(|r| r.document_id.as_str()).collect();
+        assert_eq!(ids, ["doc0", "doc1"]);
+        assert!(responses.iter().all(|r| r.success));
+        assert_eq!(responses[0].embedding.as_ref().unwrap().values, [1.0, 0.0]);
+        assert!(!responses[0].truncated);
+        assert!(responses[1].truncated);
+    }
+
+    #[test]
+    fn fails_every_text_when_the_batch_fails() {
+        let responses = embed_text_batch(&fake_model(), text_batch(&["ok", "fail"]));
+        assert_eq!(responses.len(), 2);
+        assert!(responses.iter().all(|r| !r.success && r.embedding.is_none()));
+    }
+
+    fn fusion(text_weight: f32, image_weight: f32) -> FusionOptions {
+        FusionOptions {
+            enabled: true,
+            text_weight,
+            image_weight,
+        }
+    }
+
+    fn assert_vec_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn fuses_text_and_images_by_weight() {
+        let text = [1.0, 0.0];
+        let images = vec![vec![0.0, 1.0]];
+        let fused = fuse(Some(&text), &images, &fusion(3.0, 1.0)).unwrap();
+        assert_vec_close(&fused, &[3.0 / 10f32.sqrt(), 1.0 / 10f32.sqrt()]);
+
+        // A zero weight leaves that modality out.
+        let fused = fuse(Some(&text), &images, &fusion(0.0, 1.0)).unwrap();
+        assert_vec_close(&fused, &[0.0, 1.0]);
+    }
+
+    #[test]
+    fn fuses_equally_when_no_weights_are_set() {
+        let fused = fuse(Some(&[1.0, 0.0]), &[vec![0.0, 1.0]], &fusion(0.0, 0.0)).unwrap();
+        assert_vec_close(&fused, &[0.5f32.sqrt(), 0.5f32.sqrt()]);
+    }
+
+    #[test]
+    fn fuses_whichever_modalities_are_present() {
+        let images = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        let fused = fuse(None, &images, &fusion(1.0, 1.0)).unwrap();
+        assert_vec_close(&fused, &[0.5f32.sqrt(), 0.5f32.sqrt()]);
+
+        let fused = fuse(Some(&[2.0, 0.0]), &[], &fusion(1.0, 1.0)).unwrap();
+        assert_vec_close(&fused, &[1.0, 0.0]);
+
+        assert!(fuse(None, &[], &fusion(1.0, 1.0)).is_none());
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = std::io::Cursor::new(vec![]);
+        image::DynamicImage::ImageRgb8(image::RgbImage::new(width, height))
+            .write_to(&mut bytes, image::ImageFormat::Png)
+            .unwrap();
+        bytes.into_inner()
+    }
+
+    fn item(text: &str, images: Vec<Vec<u8>>, fusion: Option<FusionOptions>) -> IndexItemRequest {
+        IndexItemRequest {
+            item_id: String::new(),
+            text: text.to_string(),
+            images,
+            fusion,
+        }
+    }
+
+    #[test]
+    fn embeds_items_with_optional_fusion() {
+        let model = fake_model();
+        let pipeline = ImagePipeline {
+            limits: DecodeLimits::default(),
+            processor: ImageProcessor::siglip(8),
+            background: crate::clipembedder::color::DEFAULT_BACKGROUND,
+            caption_processor: None,
+        };
+        let items = vec![
+            item("a red shirt", vec![png(4, 4)], Some(fusion(1.0, 1.0))),
+            item("", vec![png(4, 4), png(2, 2)], None),
+            item("broken", vec![b"not an image".to_vec()], Some(fusion(1.0, 1.0))),
+            item("text only", vec![], Some(fusion(0.0, 0.0))),
+        ];
+        let ids = vec!["both".into(), "images".into(), "broken".into(), "text".into()];
+        let responses = embed_items(&model, &pipeline, ids, items).unwrap();
+        let ids: Vec<_> = responses.iter().map(|r| r.item_id.as_str()).collect();
+        assert_eq!(ids, ["both", "images", "broken", "text"]);
+
+        let both = &responses[0];
+        assert!(both.success);
+        assert_eq!(both.text_embedding.as_ref().unwrap().values, [1.0, 0.0]);
+        assert_eq!(both.image_embeddings.len(), 1);
+        assert_vec_close(&both.fused_embedding.as_ref().unwrap().values, &[0.5f32.sqrt(), 0.5f32.sqrt()]);
+
+        let images = &responses[1];
+        assert!(images.success);
+        assert!(images.text_embedding.is_none());
+        assert_eq!(images.image_embeddings.len(), 2);
+        assert!(images.fused_embedding.is_none());
+
+        // A bad image fails its item, text included, but not the rest of the batch.
+        let broken = &responses[2];
+        assert!(!broken.success);
+        assert!(broken.text_embedding.is_none() && broken.image_embeddings.is_empty());
+
+        let text = &responses[3];
+        assert!(text.success);
+        assert_eq!(text.fused_embedding.as_ref().unwrap().values, [1.0, 0.0]);
+    }
+
+    #[test]
+    fn reports_rejected_images_as_invalid_arguments() {
+        let rejections = [
+            ImageDecodeError::Empty,
+            ImageDecodeError::TooLarge { size: 2, limit: 1 },
+            ImageDecodeError::UnknownFormat,
+            ImageDecodeError::FormatNotAllowed(image::ImageFormat::Gif),
+            ImageDecodeError::LimitsExceeded("too wide".to_string()),
+            ImageDecodeError::Malformed("truncated".to_string()),
+        ];
+        for rejection in rejections {
+            let status = failure_status("Embedding generation failed", rejection.into());
+            assert_eq!(status.code(), tonic::Code::InvalidArgument, "{}", status.message());
+        }
+        let status = failure_status("Embedding generation failed", anyhow::Error::msg("out of memory"));
+        assert_eq!(status.code(), tonic::Code::Internal);
+    }
+
+    #[test]
+    fn rejects_grids_over_the_region_limit() {
+        let status = ImageOptions::new(None, Some(&grid(100_000, 100_000))).validate().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(ImageOptions::new(None, Some(&grid(8, 8))).validate().is_ok());
+        assert!(ImageOptions::new(None, Some(&grid(0, 0))).validate().is_ok());
+    }
+}
@@ -0,0 +1,111 @@
+use anyhow::{Error as E, Result};
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::siglip;
+use tokenizers::Tokenizer;
+
+use crate::clipembedder::config::ModelSource;
+use crate::clipembedder::encoder::{DualEncoder, Scoring};
+use crate::clipembedder::processor::ImageProcessor;
+use crate::clipembedder::text::{LongTextMode, TextEmbedding, TextProcessor};
+use crate::utils::normalize_l2;
+
+pub struct SiglipEmbeddingModel {
+    model: siglip::Model,
+    text_processor: TextProcessor,
+    pub device: Device,
+    processor: ImageProcessor,
+    logit_scale: f32,
+    logit_bias: f32,
+}
+
+impl SiglipEmbeddingModel {
+    /// Creates a new model on `device` from the HuggingFace hub or a local directory.
+    pub fn new(source: &ModelSource, device: &Device) -> Result<Self> {
+        let device = device.clone();
+
+        let repo = source.files()?;
+
+        let config_filename = repo.get("config.json")?;
+        let model_filename = repo.get("model.safetensors")?;
+        let tokenizer_filename = repo.get("tokenizer.json")?;
+
+        let config: siglip::Config = serde_json::from_slice(&std::fs::read(config_filename)?)?;
+        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
+        let tokenizer_config = repo.get("tokenizer_config.json").ok();
+
+        // SigLIP pools the last position, so every sequence is padded to the full context
+        // length the model was trained with.
+        let text_processor = TextProcessor::new(
+            tokenizer,
+            tokenizer_config.as_deref(),
+            config.text_config.max_position_embeddings,
+            true,
+        )?;
+
+        let image_size = config.vision_config.image_size;
+        let defaults = ImageProcessor::siglip(image_size as u32);
+        let processor = match repo.get("preprocessor_config.json") {
+            Ok(path) => ImageProcessor::from_file(path, defaults)?,
+            Err(_) => defaults,
+        };
+        if processor.output_size() != (image_size, image_size) {
+            return Err(E::msg(format!(
+                "Processor output size {:?} does not match model image size {}",
+                processor.output_size(),
+                image_size
+            )));
+        }
+
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[model_filename], DType::F32, &device)? };
+
+        // The model keeps its logit scale and bias private, so read them from the weights ourselves.
+        let logit_scale = vb.get(&[1], "logit_scale")?.to_vec1::<f32>()?[0].exp();
+        let logit_bias = vb.get(&[1], "logit_bias")?.to_vec1::<f32>()?[0];
+
+        let model = siglip::Model::new(&config, vb)?;
+
+        Ok(Self {
+            model,
+            text_processor,
+            device,
+            processor,
+            logit_scale,
+            logit_bias,
+        })
+    }
+}
+
+impl DualEncoder for SiglipEmbeddingModel {
+    fn device(&self) -> &Device {
+        &self.device
+    }
+
+    fn embed_texts_with(&self, texts: &[String], mode: LongTextMode) -> Result<Vec<TextEmbedding>> {
+        self.text_processor.embed(texts, mode, |tokens| {
+            let token_ids = Tensor::new(tokens, &self.device)?;
+            let embeddings = self.model.get_text_features(&token_ids)?;
+            let embeddings = normalize_l2(&embeddings)?;
+            Ok(embeddings.to_vec2()?)
+        })
+    }
+
+    fn image_processor(&self) -> &ImageProcessor {
+        &self.processor
+    }
+
+    fn embed_images(&self, pixels: &[Tensor]) -> Result<Vec<Vec<f32>>> {
+        let image_tensors = Tensor::stack(pixels, 0)?.to_device(&self.device)?;
+
+        let embeddings = self.model.get_image_features(&image_tensors)?;
+        let embeddings = normalize_l2(&embeddings)?;
+        Ok(embeddings.to_vec2()?)
+    }
+
+    fn scoring(&self) -> Scoring {
+        Scoring::Sigmoid {
+            logit_scale: self.logit_scale,
+            logit_bias: self.logit_bias,
+        }
+    }
+}
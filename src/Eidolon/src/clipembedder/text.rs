@@ -0,0 +1,277 @@
+use anyhow::{Error as E, Result};
+use serde::Deserialize;
+use std::path::Path;
+use tokenizers::Tokenizer;
+
+use crate::utils::mean_normalized;
+
+/// Upper bound on the number of segments a single text is split into.
+const MAX_SEGMENTS: usize = 16;
+
+/// EOS tokens to look for when neither the tokenizer config nor the post-processor names one.
+const FALLBACK_EOS_TOKENS: [&str; 3] = ["<|endoftext|>", "</s>", "<eos>"];
+
+/// What to do with texts that exceed the text encoder's context length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LongTextMode {
+    /// Keep the start of the text and drop the rest.
+    Truncate,
+    /// Split the text into context-sized segments and average their embeddings.
+    SegmentMean,
+}
+
+/// A text embedding along with whether any of the input was dropped.
+#[derive(Debug, Clone)]
+pub struct TextEmbedding {
+    pub values: Vec<f32>,
+    pub truncated: bool,
+}
+
+/// Special tokens in `tokenizer_config.json` are either a bare string or an object
+/// with a `content` field.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SpecialToken {
+    Content(String),
+    Added { content: String },
+}
+
+impl SpecialToken {
+    fn content(&self) -> &str {
+        match self {
+            SpecialToken::Content(content) | SpecialToken::Added { content } => content,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct TokenizerConfig {
+    bos_token: Option<SpecialToken>,
+    eos_token: Option<SpecialToken>,
+    pad_token: Option<SpecialToken>,
+}
+
+/// One text after tokenization: one or more id sequences, each within the context length.
+struct Segments {
+    ids: Vec<Vec<u32>>,
+    truncated: bool,
+}
+
+/// Tokenizes text for a fixed-context text encoder, wrapping every sequence in the
+/// tokenizer's own BOS/EOS tokens and padding with its configured pad token.
+pub struct TextProcessor {
+    tokenizer: Tokenizer,
+    bos_id: Option<u32>,
+    eos_id: u32,
+    pad_id: u32,
+    max_len: usize,
+    /// Pad every sequence to `max_len` rather than to the longest in the batch.
+    pad_to_max_len: bool,
+}
+
+impl TextProcessor {
+    /// Builds the processor from `tokenizer.json` and, if the repo has one, `tokenizer_config.json`.
+    pub fn new(
+        tokenizer: Tokenizer,
+        tokenizer_config: Option<&Path>,
+        max_len: usize,
+        pad_to_max_len: bool,
+    ) -> Result<Self> {
+        let config: TokenizerConfig = match tokenizer_config {
+            Some(path) => serde_json::from_slice(&std::fs::read(path)?)?,
+            None => TokenizerConfig::default(),
+        };
+        let token_id = |token: &Option<SpecialToken>| {
+            token
+                .as_ref()
+                .and_then(|token| tokenizer.token_to_id(token.content()))
+        };
+
+        let (mut bos_id, mut eos_id) = (token_id(&config.bos_token), token_id(&config.eos_token));
+        if eos_id.is_none() {
+            let (template_bos, template_eos) = Self::template_tokens(&tokenizer)?;
+            bos_id = bos_id.or(template_bos);
+            eos_id = template_eos.or_else(|| {
+                FALLBACK_EOS_TOKENS
+                    .iter()
+                    .find_map(|token| tokenizer.token_to_id(token))
+            });
+        }
+        let eos_id = eos_id.ok_or_else(|| E::msg("Tokenizer does not define an EOS token"))?;
+        let pad_id = token_id(&config.pad_token)
+            .or_else(|| tokenizer.get_padding().map(|padding| padding.pad_id))
+            .unwrap_or(eos_id);
+
+        let reserved = 1 + bos_id.is_some() as usize;
+        if max_len <= reserved {
+            return Err(E::msg(format!("Context length {} is too short", max_len)));
+        }
+
+        Ok(Self {
+            tokenizer,
+            bos_id,
+            eos_id,
+            pad_id,
+            max_len,
+            pad_to_max_len,
+        })
+    }
+
+    /// The BOS and EOS tokens the tokenizer's post-processor wraps every sequence in, found
+    /// by encoding an empty string with special tokens. A lone token is taken as the EOS.
+    fn template_tokens(tokenizer: &Tokenizer) -> Result<(Option<u32>, Option<u32>)> {
+        let encoding = tokenizer.encode("", true).map_err(E::msg)?;
+        let ids: Vec<u32> = encoding
+            .get_ids()
+            .iter()
+            .zip(encoding.get_attention_mask())
+            .filter(|&(_, &attended)| attended == 1)
+            .map(|(&id, _)| id)
+            .collect();
+        Ok(match ids.as_slice() {
+            [] => (None, None),
+            [eos] => (None, Some(*eos)),
+            [bos, .., eos] => (Some(*bos), Some(*eos)),
+        })
+    }
+
+    fn wrap(&self, content: &[u32]) -> Vec<u32> {
+        let mut ids = Vec::with_capacity(content.len() + 2);
+        ids.extend(self.bos_id);
+        ids.extend_from_slice(content);
+        ids.push(self.eos_id);
+        ids
+    }
+
+    fn segments(&self, text: &str, mode: LongTextMode) -> Result<Segments> {
+        let encoding = self.tokenizer.encode(text, false).map_err(E::msg)?;
+        let content = encoding.get_ids();
+        let capacity = self.max_len - 1 - self.bos_id.is_some() as usize;
+
+        if content.len() <= capacity {
+            return Ok(Segments {
+                ids: vec![self.wrap(content)],
+                truncated: false,
+            });
+        }
+        match mode {
+            LongTextMode::Truncate => Ok(Segments {
+                ids: vec![self.wrap(&content[..capacity])],
+                truncated: true,
+            }),
+            LongTextMode::SegmentMean => {
+                let chunks: Vec<&[u32]> = content.chunks(capacity).collect();
+                Ok(Segments {
+                    truncated: chunks.len() > MAX_SEGMENTS,
+                    ids: chunks
+                        .into_iter()
+                        .take(MAX_SEGMENTS)
+                        .map(|chunk| self.wrap(chunk))
+                        .collect(),
+                })
+            }
+        }
+    }
+
+    fn pad(&self, mut batch: Vec<Vec<u32>>) -> Vec<Vec<u32>> {
+        let len = if self.pad_to_max_len {
+            self.max_len
+        } else {
+            batch.iter().map(|ids| ids.len()).max().unwrap_or(0)
+        };
+        for ids in batch.iter_mut() {
+            ids.resize(len, self.pad_id);
+        }
+        batch
+    }
+
+    /// Tokenizes `texts`, runs every resulting sequence through `forward` as one padded
+    /// batch, and folds segment embeddings back into one normalized vector per text.
+    pub fn embed<F>(&self, texts: &[String], mode: LongTextMode, forward: F) -> Result<Vec<TextEmbedding>>
+    where
+        F: FnOnce(Vec<Vec<u32>>) -> Result<Vec<Vec<f32>>>,
+    {
+        let segmented = texts
+            .iter()
+            .map(|text| self.segments(text, mode))
+            .collect::<Result<Vec<_>>>()?;
+
+        let batch: Vec<Vec<u32>> = segmented.iter().flat_map(|s| s.ids.iter().cloned()).collect();
+        let mut embeddings = forward(self.pad(batch))?.into_iter();
+
+        segmented
+            .iter()
+            .map(|segments| {
+                let vectors: Vec<Vec<f32>> = embeddings.by_ref().take(segments.ids.len()).collect();
+                let values = if vectors.len() == 1 {
+                    vectors.into_iter().next().unwrap_or_default()
+                } else {
+                    mean_normalized(&vectors)
+                };
+                if values.is_empty() {
+                    return Err(E::msg("Model returned no embedding"));
+                }
+                Ok(TextEmbedding {
+                    values,
+                    truncated: segments.truncated,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::pre_tokenizers::whitespace::Whitespace;
+    use tokenizers::processors::template::TemplateProcessing;
+
+    fn tokenizer(template: Option<&str>) -> Tokenizer {
+        let vocab = [("<unk>", 0), ("<|startoftext|>", 1), ("<|endoftext|>", 2), ("a", 3), ("b", 4)]
+            .into_iter()
+            .map(|(token, id)| (token.to_string(), id))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        if let Some(template) = template {
+            let processor = TemplateProcessing::builder()
+                .try_single(template)
+                .unwrap()
+                .special_tokens(vec![("<|startoftext|>", 1), ("<|endoftext|>", 2)])
+                .build()
+                .unwrap();
+            tokenizer.with_post_processor(Some(processor));
+        }
+        tokenizer
+    }
+
+    fn first_batch(processor: &TextProcessor, text: &str) -> Vec<Vec<u32>> {
+        let mut seen = Vec::new();
+        processor
+            .embed(&[text.to_string()], LongTextMode::Truncate, |batch| {
+                seen = batch.clone();
+                Ok(vec![vec![1.0]; batch.len()])
+            })
+            .unwrap();
+        seen
+    }
+
+    #[test]
+    fn takes_special_tokens_from_the_post_processor() {
+        let tokenizer = tokenizer(Some("<|startoftext|> $A <|endoftext|>"));
+        let processor = TextProcessor::new(tokenizer, None, 8, false).unwrap();
+        assert_eq!(first_batch(&processor, "a b"), vec![vec![1, 3, 4, 2]]);
+    }
+
+    #[test]
+    fn falls_back_to_known_eos_tokens() {
+        let processor = TextProcessor::new(tokenizer(None), None, 8, false).unwrap();
+        assert_eq!(first_batch(&processor, "b a"), vec![vec![4, 3, 2]]);
+    }
+}
@@ -0,0 +1,132 @@
+use anyhow::Result;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbnailFormat {
+    Jpeg,
+    /// Lossy WebP through libwebp; the `image` crate only writes lossless WebP.
+    WebP,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ThumbnailSpec {
+    /// The longest side of the thumbnail. Smaller images are not upscaled.
+    pub max_edge: u32,
+    pub format: ThumbnailFormat,
+    /// Encoder quality from 1 to 100.
+    pub quality: u8,
+}
+
+pub struct Thumbnail {
+    pub data: Vec<u8>,
+    pub format: ThumbnailFormat,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Bounds on what a request may ask for.
+pub const DEFAULT_MAX_EDGE: u32 = 256;
+pub const MAX_EDGE_LIMIT: u32 = 2048;
+pub const DEFAULT_QUALITY: u8 = 80;
+pub const MAX_THUMBNAILS: usize = 4;
+
+impl ThumbnailSpec {
+    /// Fills in defaults for zero values and clamps the rest to the allowed range.
+    pub fn new(max_edge: u32, format: ThumbnailFormat, quality: u32) -> Self {
+        Self {
+            max_edge: match max_edge {
+                0 => DEFAULT_MAX_EDGE,
+                n => n.min(MAX_EDGE_LIMIT),
+            },
+            format,
+            quality: match quality {
+                0 => DEFAULT_QUALITY,
+                n => n.min(100) as u8,
+            },
+        }
+    }
+
+    /// Scales the upright RGB image down to fit `max_edge` and encodes it.
+    pub fn render(&self, img: &DynamicImage) -> Result<Thumbnail> {
+        let resized;
+        let img = if img.width() > self.max_edge || img.height() > self.max_edge {
+            resized = img.resize(self.max_edge, self.max_edge, FilterType::CatmullRom);
+            &resized
+        } else {
+            img
+        };
+        let rgb = img.to_rgb8();
+
+        let data = match self.format {
+            ThumbnailFormat::Jpeg => {
+                let mut data = vec![];
+                JpegEncoder::new_with_quality(&mut data, self.quality).encode_image(&rgb)?;
+                data
+            }
+            // `encode` unwraps libwebp's result, so go through `encode_simple` to get the error.
+            ThumbnailFormat::WebP => webp::Encoder::from_rgb(rgb.as_raw(), rgb.width(), rgb.height())
+                .encode_simple(false, self.quality as f32)
+                .map_err(|e| anyhow::Error::msg(format!("WebP encoding failed: {:?}", e)))?
+                .to_vec(),
+        };
+        Ok(Thumbnail {
+            data,
+            format: self.format,
+            width: rgb.width(),
+            height: rgb.height(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            Rgb([(x % 256) as u8, (y % 256) as u8, 128])
+        }))
+    }
+
+    #[test]
+    fn fills_in_and_clamps_specs() {
+        let spec = ThumbnailSpec::new(0, ThumbnailFormat::Jpeg, 0);
+        assert_eq!((spec.max_edge, spec.quality), (DEFAULT_MAX_EDGE, DEFAULT_QUALITY));
+        let spec = ThumbnailSpec::new(100_000, ThumbnailFormat::WebP, 500);
+        assert_eq!((spec.max_edge, spec.quality), (MAX_EDGE_LIMIT, 100));
+    }
+
+    #[test]
+    fn scales_down_keeping_the_aspect_ratio() {
+        for format in [ThumbnailFormat::Jpeg, ThumbnailFormat::WebP] {
+            let thumbnail = ThumbnailSpec::new(100, format, 80).render(&gradient(400, 200)).unwrap();
+            assert_eq!((thumbnail.width, thumbnail.height), (100, 50));
+            let decoded = image::load_from_memory(&thumbnail.data).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (100, 50));
+        }
+    }
+
+    #[test]
+    fn does_not_upscale() {
+        let thumbnail = ThumbnailSpec::new(256, ThumbnailFormat::WebP, 80).render(&gradient(40, 30)).unwrap();
+        assert_eq!((thumbnail.width, thumbnail.height), (40, 30));
+        assert_eq!(image::guess_format(&thumbnail.data).unwrap(), image::ImageFormat::WebP);
+    }
+
+    #[test]
+    fn reports_encoder_failures() {
+        // Both formats have a size limit below 70000 pixels a side.
+        let img = gradient(70_000, 2);
+        for format in [ThumbnailFormat::Jpeg, ThumbnailFormat::WebP] {
+            let spec = ThumbnailSpec {
+                max_edge: u32::MAX,
+                format,
+                quality: 80,
+            };
+            assert!(spec.render(&img).is_err());
+        }
+    }
+}
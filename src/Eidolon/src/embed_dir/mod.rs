@@ -0,0 +1,267 @@
+pub mod output;
+
+use anyhow::{Error as E, Result};
+use candle_core::Tensor;
+use image::ImageFormat;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+use crate::clipembedder::encoder::load_dual_encoder;
+use crate::clipembedder::pipeline::ImagePipeline;
+use crate::embed_dir::output::{EmbeddingWriter, OutputFormat};
+use crate::settings::{ServeArgs, Settings};
+
+/// Embeds every image under a directory, or listed in a manifest, without the gRPC server.
+#[derive(Debug, Clone, clap::Args)]
+pub struct EmbedDirArgs {
+    /// A directory to walk, or a manifest file listing one image path per line. Relative
+    /// manifest paths are resolved against the manifest's directory.
+    pub path: PathBuf,
+    /// Where to write the embeddings.
+    #[arg(short, long)]
+    pub output: PathBuf,
+    /// Inferred from the output extension when not given.
+    #[arg(long, value_enum)]
+    pub output_format: Option<OutputFormat>,
+    /// Images embedded per forward pass.
+    #[arg(long, default_value_t = 32)]
+    pub batch_size: usize,
+    /// Threads decoding each batch; defaults to the number of CPUs.
+    #[arg(long)]
+    pub decode_threads: Option<usize>,
+    /// Where skipped files are listed with the reason; defaults to `<output>.skipped.jsonl`.
+    #[arg(long)]
+    pub report: Option<PathBuf>,
+    /// The server's TOML settings file, for the model, device and image limits;
+    /// `EIDOLON_CONFIG` when not given.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+}
+
+/// An image to embed: where to read it and the path reported for it.
+struct ImageFile {
+    path: PathBuf,
+    name: String,
+}
+
+/// A file or directory left out of the output, with the reason.
+type Skipped = (String, String);
+
+/// Every file under `root` with an allowed image extension, in a stable order, named
+/// relative to `root`, along with the subdirectories that could not be read and why.
+fn walk(root: &Path, allowed: &[ImageFormat]) -> Result<(Vec<ImageFile>, Vec<Skipped>)> {
+    let name = |path: &Path| path.strip_prefix(root).unwrap_or(path).to_string_lossy().into_owned();
+    let mut files = vec![];
+    let mut unreadable = vec![];
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            // The root itself is the one directory the job can't do without.
+            Err(e) if dir == root => return Err(e.into()),
+            Err(e) => {
+                unreadable.push((name(&dir), e.to_string()));
+                continue;
+            }
+        };
+        for entry in entries {
+            let (path, file_type) = match entry.and_then(|entry| Ok((entry.path(), entry.file_type()?))) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    unreadable.push((name(&dir), e.to_string()));
+                    continue;
+                }
+            };
+            // Symlinked directories aren't followed, so links can't send the walk in circles.
+            if file_type.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            let format = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .and_then(ImageFormat::from_extension);
+            if format.is_some_and(|format| allowed.contains(&format)) {
+                files.push(ImageFile { name: name(&path), path });
+            }
+        }
+    }
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    unreadable.sort();
+    Ok((files, unreadable))
+}
+
+/// The paths in a manifest, skipping blank lines and `#` comments.
+fn read_manifest(manifest: &Path) -> Result<Vec<ImageFile>> {
+    let base = manifest.parent().unwrap_or(Path::new(""));
+    Ok(std::fs::read_to_string(manifest)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| ImageFile {
+            path: base.join(line),
+            name: line.to_string(),
+        })
+        .collect())
+}
+
+/// Reads and preprocesses a batch across `threads` threads, keeping the batch order.
+fn decode_batch(pipeline: &ImagePipeline, files: &[ImageFile], threads: usize) -> Vec<Result<Tensor, String>> {
+    let load = |file: &ImageFile| -> Result<Tensor, String> {
+        let bytes = std::fs::read(&file.path).map_err(|e| e.to_string())?;
+        pipeline.prepare(&bytes).map_err(|e| e.to_string())
+    };
+    let per_thread = files.len().div_ceil(threads.max(1)).max(1);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = files
+            .chunks(per_thread)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(load).collect::<Vec<_>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("decode thread panicked"))
+            .collect()
+    })
+}
+
+pub fn run(args: EmbedDirArgs) -> Result<()> {
+    let output_format = match args.output_format {
+        Some(format) => format,
+        None => OutputFormat::from_path(&args.output)?,
+    };
+    let batch_size = args.batch_size.max(1);
+    let threads = args
+        .decode_threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let settings = Settings::load(&ServeArgs {
+        config: args.config.clone(),
+        ..ServeArgs::default()
+    })?;
+    settings.apply_compute_threads();
+    let limits = settings.decode_limits()?;
+
+    // Directories the walk couldn't read are reported along with the files that fail.
+    let (files, mut skipped) = if args.path.is_dir() {
+        walk(&args.path, &limits.allowed_formats)?
+    } else {
+        (read_manifest(&args.path)?, vec![])
+    };
+    if files.is_empty() {
+        return Err(E::msg(format!("No images found in {}", args.path.display())));
+    }
+
+    let source = settings.model_source();
+    println!("Initializing model {} and device...", source.describe());
+    let model = load_dual_encoder(&source, &settings.device.device()?)?;
+    println!("Model loaded successfully on device: {:?}.", model.device().location());
+    let pipeline = ImagePipeline {
+        limits,
+        processor: model.image_processor().clone(),
+        background: settings.background()?,
+        caption_processor: None,
+    };
+
+    let progress = ProgressBar::new(files.len() as u64);
+    progress.set_style(
+        ProgressStyle::with_template("{bar:40} {pos}/{len} images, {per_sec}, ETA {eta}")
+            .expect("valid progress template"),
+    );
+    let mut writer = EmbeddingWriter::create(&args.output, output_format)?;
+    let mut embedded = 0;
+
+    // Decode the next batch on a separate thread while the current one is embedded.
+    std::thread::scope(|scope| -> Result<()> {
+        let (batch_tx, batch_rx) = mpsc::sync_channel(1);
+        let pipeline = &pipeline;
+        let files = &files;
+        scope.spawn(move || {
+            for batch in files.chunks(batch_size) {
+                if batch_tx.send((batch, decode_batch(pipeline, batch, threads))).is_err() {
+                    break;
+                }
+            }
+        });
+
+        for (batch, decoded) in batch_rx {
+            let mut names = Vec::with_capacity(batch.len());
+            let mut pixels = Vec::with_capacity(batch.len());
+            for (file, result) in batch.iter().zip(decoded) {
+                match result {
+                    Ok(tensor) => {
+                        names.push(file.name.clone());
+                        pixels.push(tensor);
+                    }
+                    Err(e) => skipped.push((file.name.clone(), e)),
+                }
+            }
+            if !pixels.is_empty() {
+                let vectors = model.embed_images(&pixels)?;
+                writer.write(&names, &vectors)?;
+                embedded += names.len();
+            }
+            progress.inc(batch.len() as u64);
+        }
+        Ok(())
+    })?;
+    writer.finish()?;
+    progress.finish();
+
+    println!("Wrote {} embeddings to {}.", embedded, args.output.display());
+    if !skipped.is_empty() {
+        let report = args.report.unwrap_or_else(|| {
+            let mut report = args.output.clone().into_os_string();
+            report.push(".skipped.jsonl");
+            PathBuf::from(report)
+        });
+        let mut out = BufWriter::new(File::create(&report)?);
+        for (path, error) in &skipped {
+            serde_json::to_writer(&mut out, &serde_json::json!({ "path": path, "error": error }))?;
+            out.write_all(b"\n")?;
+        }
+        out.flush()?;
+        println!("Skipped {} unreadable files or directories; see {}.", skipped.len(), report.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn walks_past_unreadable_directories() {
+        let root = std::env::temp_dir().join(format!("eidolon-walk-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("a/nested")).unwrap();
+        std::fs::create_dir_all(root.join("locked")).unwrap();
+        for file in ["b.png", "a/one.JPG", "a/nested/two.webp", "a/notes.txt", "c.bmp"] {
+            std::fs::write(root.join(file), b"").unwrap();
+        }
+        std::fs::set_permissions(root.join("locked"), std::fs::Permissions::from_mode(0o000)).unwrap();
+
+        let allowed = [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::WebP];
+        let result = walk(&root, &allowed);
+        let locked_is_readable = std::fs::read_dir(root.join("locked")).is_ok();
+        std::fs::set_permissions(root.join("locked"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        let (files, unreadable) = result.unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let names: Vec<&str> = files.iter().map(|file| file.name.as_str()).collect();
+        assert_eq!(names, ["a/nested/two.webp", "a/one.JPG", "b.png"]);
+        // Permissions don't stop root, so the locked directory only fails for other users.
+        if !locked_is_readable {
+            assert_eq!(unreadable.len(), 1);
+            assert_eq!(unreadable[0].0, "locked");
+        }
+    }
+
+    #[test]
+    fn fails_when_the_root_is_unreadable() {
+        let root = std::env::temp_dir().join(format!("eidolon-walk-missing-{}", std::process::id()));
+        assert!(walk(&root, &[ImageFormat::Png]).is_err());
+    }
+}
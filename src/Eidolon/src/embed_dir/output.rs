@@ -0,0 +1,110 @@
+use anyhow::{Error as E, Result};
+use arrow_array::{FixedSizeListArray, Float32Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// A `path` string column and an `embedding` fixed-size float list column.
+    Parquet,
+    /// One `{"path": ..., "embedding": [...]}` object per line.
+    Jsonl,
+}
+
+impl OutputFormat {
+    /// Infers the format from a file extension.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+        match extension.to_ascii_lowercase().as_str() {
+            "parquet" => Ok(OutputFormat::Parquet),
+            "jsonl" | "ndjson" => Ok(OutputFormat::Jsonl),
+            _ => Err(E::msg(format!(
+                "Can't infer the output format of {}; pass --output-format",
+                path.display()
+            ))),
+        }
+    }
+}
+
+/// Streams embeddings to the output file batch by batch. The Parquet writer is created
+/// with the first batch, once the dimension is known.
+pub enum EmbeddingWriter {
+    Parquet { file: Option<File>, writer: Option<Box<ArrowWriter<File>>> },
+    Jsonl(BufWriter<File>),
+}
+
+impl EmbeddingWriter {
+    pub fn create(path: &Path, format: OutputFormat) -> Result<Self> {
+        let file = File::create(path)?;
+        Ok(match format {
+            OutputFormat::Parquet => EmbeddingWriter::Parquet {
+                file: Some(file),
+                writer: None,
+            },
+            OutputFormat::Jsonl => EmbeddingWriter::Jsonl(BufWriter::new(file)),
+        })
+    }
+
+    pub fn write(&mut self, paths: &[String], vectors: &[Vec<f32>]) -> Result<()> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+        match self {
+            EmbeddingWriter::Parquet { file, writer } => {
+                let dimension = vectors[0].len();
+                let writer = match writer {
+                    Some(writer) => writer,
+                    None => {
+                        let file = file.take().expect("file is kept until the writer is created");
+                        writer.insert(Box::new(ArrowWriter::try_new(file, schema(dimension), None)?))
+                    }
+                };
+                let values = Float32Array::from_iter_values(vectors.iter().flatten().copied());
+                let item = Arc::new(Field::new("item", DataType::Float32, false));
+                let embeddings = FixedSizeListArray::try_new(item, dimension as i32, Arc::new(values), None)?;
+                let batch = RecordBatch::try_new(
+                    schema(dimension),
+                    vec![Arc::new(StringArray::from_iter_values(paths)), Arc::new(embeddings)],
+                )?;
+                writer.write(&batch)?;
+            }
+            EmbeddingWriter::Jsonl(out) => {
+                for (path, vector) in paths.iter().zip(vectors) {
+                    let line = serde_json::json!({ "path": path, "embedding": vector });
+                    serde_json::to_writer(&mut *out, &line)?;
+                    out.write_all(b"\n")?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        match self {
+            EmbeddingWriter::Parquet { file, writer } => match writer {
+                Some(writer) => {
+                    writer.close()?;
+                }
+                // Nothing was embedded; still leave a valid, empty file behind.
+                None => {
+                    let file = file.expect("file is kept until the writer is created");
+                    ArrowWriter::try_new(file, schema(0), None)?.close()?;
+                }
+            },
+            EmbeddingWriter::Jsonl(mut out) => out.flush()?,
+        }
+        Ok(())
+    }
+}
+
+fn schema(dimension: usize) -> SchemaRef {
+    let item = Arc::new(Field::new("item", DataType::Float32, false));
+    Arc::new(Schema::new(vec![
+        Field::new("path", DataType::Utf8, false),
+        Field::new("embedding", DataType::FixedSizeList(item, dimension as i32), false),
+    ]))
+}
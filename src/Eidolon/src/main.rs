@@ -1,40 +1,105 @@
 mod clipembedder; // Renamed from 'clipembedder'
+mod embed_dir;
+mod settings;
 mod utils;
 
-use crate::clipembedder::model::ClipEmbeddingModel;
-use crate::clipembedder::proto::{clip_embedder_server, ClipEmbedderServer};
+use crate::clipembedder::caption::Captioner;
+use crate::clipembedder::encoder::load_dual_encoder;
+use crate::clipembedder::index::VectorIndex;
+use crate::clipembedder::proto::ClipEmbedderServer;
 use crate::clipembedder::service::ClipEmbedderService;
-use std::sync::{Arc, Mutex};
+use crate::embed_dir::EmbedDirArgs;
+use crate::settings::{ServeArgs, Settings};
+use clap::{Parser, Subcommand};
 use tonic::transport::Server;
+use tracing::info;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Initializing CLIP model and device...");
-    let model = ClipEmbeddingModel::new("openai/clip-vit-base-patch32")?;
-    println!(
-        "CLIP Model loaded successfully on device: {:?}.",
-        model.device.location()
-    );
+#[derive(Parser)]
+#[command(about = "Image and text embedding gRPC server", args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    serve: ServeArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Runs the gRPC server (the default).
+    Serve(ServeArgs),
+    /// Embeds every image under a directory, or listed in a manifest, and writes the vectors to disk.
+    EmbedDir(EmbedDirArgs),
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::EmbedDir(args)) => Ok(embed_dir::run(args)?),
+        Some(Command::Serve(args)) => serve(&args),
+        None => serve(&cli.serve),
+    }
+}
 
-    let shared_model = Arc::new(Mutex::new(model));
+fn serve(args: &ServeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let settings = Settings::load(args)?;
+    if args.print_config {
+        print!("{}", settings.to_toml()?);
+        return Ok(());
+    }
+    settings.init_logging()?;
+    settings.apply_compute_threads();
+    settings.runtime()?.block_on(run(settings))
+}
 
-    let clip_service = ClipEmbedderService {
-        model: shared_model,
+async fn run(settings: Settings) -> Result<(), Box<dyn std::error::Error>> {
+    let device = settings.device.device()?;
+    let source = settings.model_source();
+    info!("Initializing model {} and device...", source.describe());
+    let model = load_dual_encoder(&source, &device)?;
+    info!(
+        "Model loaded successfully on device: {:?}.",
+        model.device().location()
+    );
+
+    let captioner = match settings.captioner_source() {
+        Some(source) => {
+            info!("Initializing captioning model {}...", source.describe());
+            Some(Captioner::new(&source, &device)?)
+        }
+        None => None,
     };
 
-    let addr = "[::1]:50051".parse()?;
-    println!("gRPC ClipEmbedderServer listening on {}", addr);
+    let mut clip_service = ClipEmbedderService::new(
+        model,
+        captioner,
+        settings.decode_limits()?,
+        settings.background()?,
+    )
+    .with_batching(settings.batching());
+    if settings.in_memory_index {
+        info!("Keeping indexed image embeddings in memory for search.");
+        clip_service = clip_service.with_index(VectorIndex::default());
+    }
+
+    let addr = settings.listen;
+    info!("gRPC ClipEmbedderServer listening on {}", addr);
 
     let (health_reporter, health_service) = tonic_health::server::health_reporter();
     health_reporter
         .set_serving::<ClipEmbedderServer<ClipEmbedderService>>()
         .await;
 
-    Server::builder()
-        .add_service(ClipEmbedderServer::new(clip_service))
+    let mut server = Server::builder();
+    if let Some(limit) = settings.limits.max_concurrent_requests {
+        server = server.concurrency_limit_per_connection(limit);
+    }
+    server
+        .add_service(
+            ClipEmbedderServer::new(clip_service).max_decoding_message_size(settings.limits.max_message_bytes),
+        )
         .add_service(health_service)
         .serve(addr)
         .await?;
 
     Ok(())
-}
\ No newline at end of file
+}
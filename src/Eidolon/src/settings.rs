@@ -0,0 +1,603 @@
+use anyhow::{Error as E, Result};
+use candle_core::Device;
+use image::Rgb;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::clipembedder::color::{DEFAULT_BACKGROUND, parse_hex_color};
+use crate::clipembedder::config::ModelSource;
+use crate::clipembedder::decode::{DecodeLimits, parse_formats};
+use crate::clipembedder::service::Batching;
+
+/// Where the models run. `auto` prefers Metal, then CUDA, then the CPU.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    #[default]
+    Auto,
+    Cpu,
+    Metal,
+    Cuda,
+}
+
+impl DeviceKind {
+    pub fn device(self) -> Result<Device> {
+        Ok(match self {
+            DeviceKind::Auto if candle_core::utils::metal_is_available() => Device::new_metal(0)?,
+            DeviceKind::Auto if candle_core::utils::cuda_is_available() => Device::new_cuda(0)?,
+            DeviceKind::Auto | DeviceKind::Cpu => Device::Cpu,
+            DeviceKind::Metal => Device::new_metal(0)?,
+            DeviceKind::Cuda => Device::new_cuda(0)?,
+        })
+    }
+}
+
+impl FromStr for DeviceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <Self as clap::ValueEnum>::from_str(s, true)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <Self as clap::ValueEnum>::from_str(s, true)
+    }
+}
+
+/// How the server is run. Each layer overrides the one before it: the defaults here, a
+/// TOML file, `EIDOLON_*` environment variables and finally command line flags.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub listen: SocketAddr,
+    pub device: DeviceKind,
+    /// Keeps `IndexImages` embeddings in memory for `SearchByText`/`SearchByImage`.
+    pub in_memory_index: bool,
+    /// The hex color transparent images are composited onto.
+    pub image_background: String,
+    /// The CLIP or SigLIP dual encoder.
+    pub model: ModelSettings,
+    pub caption: CaptionSettings,
+    pub batch: BatchSettings,
+    pub threads: ThreadSettings,
+    pub limits: LimitSettings,
+    pub log: LogSettings,
+}
+
+/// A model on the Hugging Face hub, or in a local directory when `path` is set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptionSettings {
+    /// Loads the BLIP model behind `Caption`.
+    pub enabled: bool,
+    pub model: ModelSettings,
+}
+
+/// How the streaming RPCs group requests before running the model.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchSettings {
+    /// Images per forward pass.
+    pub image_size: usize,
+    /// Texts per forward pass.
+    pub text_size: usize,
+    /// How long a stream may go quiet before a partial batch is embedded.
+    pub timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThreadSettings {
+    /// Async runtime workers; one per CPU when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workers: Option<usize>,
+    /// Threads the models' CPU kernels use; one per CPU when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compute: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitSettings {
+    /// The largest width or height an image may decode to.
+    pub max_image_dimension: u32,
+    /// The most memory decoding one image may allocate, in bytes.
+    pub max_image_alloc: u64,
+    /// The largest encoded image accepted, in bytes.
+    pub max_image_bytes: usize,
+    /// Extensions of the formats accepted, e.g. `["jpg", "png"]`.
+    pub image_formats: Vec<String>,
+    /// The largest request message the server decodes, in bytes.
+    pub max_message_bytes: usize,
+    /// Requests served at once on each connection; unlimited when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_requests: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    /// A `tracing` filter, e.g. `info` or `Eidolon=debug,h2=warn`.
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        let Rgb([r, g, b]) = DEFAULT_BACKGROUND;
+        Self {
+            listen: SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 50051)),
+            device: DeviceKind::default(),
+            in_memory_index: false,
+            image_background: format!("{:02x}{:02x}{:02x}", r, g, b),
+            model: ModelSettings::default(),
+            caption: CaptionSettings::default(),
+            batch: BatchSettings::default(),
+            threads: ThreadSettings::default(),
+            limits: LimitSettings::default(),
+            log: LogSettings::default(),
+        }
+    }
+}
+
+impl Default for BatchSettings {
+    fn default() -> Self {
+        let batching = Batching::default();
+        Self {
+            image_size: batching.image_batch_size,
+            text_size: batching.text_batch_size,
+            timeout_ms: batching.timeout.as_millis() as u64,
+        }
+    }
+}
+
+/// Room a request message needs beyond its image bytes, for the rest of its fields.
+const MESSAGE_OVERHEAD_BYTES: usize = 1024 * 1024;
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        let limits = DecodeLimits::default();
+        Self {
+            max_image_dimension: limits.max_width.max(limits.max_height),
+            max_image_alloc: limits.max_alloc,
+            max_image_bytes: limits.max_encoded_bytes,
+            image_formats: limits
+                .allowed_formats
+                .iter()
+                .map(|format| format.extensions_str()[0].to_string())
+                .collect(),
+            // Large enough for the largest image the limits accept.
+            max_message_bytes: limits.max_encoded_bytes + MESSAGE_OVERHEAD_BYTES,
+            max_concurrent_requests: None,
+        }
+    }
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::default(),
+        }
+    }
+}
+
+impl ModelSettings {
+    pub fn source(&self, default: ModelSource) -> ModelSource {
+        ModelSource::with_overrides(default, self.id.as_deref(), self.revision.as_deref(), self.path.as_deref())
+    }
+
+    /// Sets a new model id, dropping a revision meant for the previous one.
+    fn set_id(&mut self, id: String) {
+        self.id = Some(id);
+        self.revision = None;
+    }
+
+    /// Fills in the id and revision `source` resolves to, for `--print-config`.
+    fn resolve(&mut self, default: ModelSource) {
+        let source = self.source(default);
+        self.id = Some(source.model_id);
+        self.revision = Some(source.revision);
+    }
+}
+
+/// Flags for `serve`. Anything left unset keeps the value from the file or environment.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct ServeArgs {
+    /// A TOML settings file; `EIDOLON_CONFIG` when not given.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Prints the resolved settings as TOML and exits.
+    #[arg(long)]
+    pub print_config: bool,
+    /// The address to listen on, e.g. `0.0.0.0:50051`.
+    #[arg(long)]
+    pub listen: Option<SocketAddr>,
+    #[arg(long, value_enum)]
+    pub device: Option<DeviceKind>,
+    /// The dual encoder's model id on the Hugging Face hub.
+    #[arg(long)]
+    pub model: Option<String>,
+    #[arg(long)]
+    pub model_revision: Option<String>,
+    /// A local directory holding the dual encoder's files.
+    #[arg(long)]
+    pub model_path: Option<PathBuf>,
+    /// Loads the captioning model.
+    #[arg(long)]
+    pub captioning: bool,
+    /// The captioning model's id on the Hugging Face hub; implies `--captioning`.
+    #[arg(long)]
+    pub caption_model: Option<String>,
+    #[arg(long)]
+    pub in_memory_index: bool,
+    #[arg(long)]
+    pub image_batch_size: Option<usize>,
+    #[arg(long)]
+    pub text_batch_size: Option<usize>,
+    #[arg(long)]
+    pub batch_timeout_ms: Option<u64>,
+    #[arg(long)]
+    pub worker_threads: Option<usize>,
+    #[arg(long)]
+    pub compute_threads: Option<usize>,
+    #[arg(long)]
+    pub max_image_dimension: Option<u32>,
+    #[arg(long)]
+    pub max_image_bytes: Option<usize>,
+    #[arg(long)]
+    pub max_message_bytes: Option<usize>,
+    #[arg(long)]
+    pub max_concurrent_requests: Option<usize>,
+    #[arg(long)]
+    pub log_level: Option<String>,
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+}
+
+/// Parses an environment variable, treating an empty value as unset.
+fn env<T>(name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(name) {
+        Ok(value) if !value.is_empty() => value
+            .parse()
+            .map(Some)
+            .map_err(|e| E::msg(format!("Invalid {}={:?}: {}", name, value, e))),
+        _ => Ok(None),
+    }
+}
+
+/// Reads a boolean environment variable, `1` or `true` meaning on.
+fn env_flag(name: &str) -> Option<bool> {
+    std::env::var(name)
+        .ok()
+        .filter(|value| !value.is_empty())
+        .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+}
+
+impl Settings {
+    /// Resolves the settings for `args`, reading the file it or `EIDOLON_CONFIG` names.
+    pub fn load(args: &ServeArgs) -> Result<Self> {
+        let file = match &args.config {
+            Some(path) => Some(path.clone()),
+            None => env::<PathBuf>("EIDOLON_CONFIG")?,
+        };
+        let mut settings = match file {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        settings.apply_env()?;
+        settings.apply_args(args);
+        settings.validate()?;
+        settings.model.resolve(ModelSource::default());
+        if settings.caption.enabled {
+            settings.caption.model.resolve(ModelSource::default_captioner());
+        }
+        Ok(settings)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| E::msg(format!("Cannot read {}: {}", path.display(), e)))?;
+        toml::from_str(&text).map_err(|e| E::msg(format!("Invalid settings in {}: {}", path.display(), e)))
+    }
+
+    /// Reads `EIDOLON_LISTEN` (or just the port from `PORT`), `EIDOLON_DEVICE`,
+    /// `EIDOLON_IN_MEMORY_INDEX`, `EIDOLON_IMAGE_BACKGROUND`, `EIDOLON_MODEL_{ID,REVISION,PATH}`,
+    /// `EIDOLON_CAPTIONING`, `EIDOLON_CAPTION_MODEL_{ID,REVISION,PATH}` (an id or path turns
+    /// captioning on), `EIDOLON_{IMAGE,TEXT}_BATCH_SIZE`, `EIDOLON_BATCH_TIMEOUT_MS`,
+    /// `EIDOLON_{WORKER,COMPUTE}_THREADS`, `EIDOLON_MAX_IMAGE_{DIMENSION,ALLOC,BYTES}`,
+    /// `EIDOLON_IMAGE_FORMATS` (comma-separated extensions), `EIDOLON_MAX_MESSAGE_BYTES`,
+    /// `EIDOLON_MAX_CONCURRENT_REQUESTS`, `EIDOLON_LOG` and `EIDOLON_LOG_FORMAT`.
+    fn apply_env(&mut self) -> Result<()> {
+        if let Some(port) = env("PORT")? {
+            self.listen.set_port(port);
+        }
+        if let Some(listen) = env("EIDOLON_LISTEN")? {
+            self.listen = listen;
+        }
+        if let Some(device) = env("EIDOLON_DEVICE")? {
+            self.device = device;
+        }
+        if let Some(enabled) = env_flag("EIDOLON_IN_MEMORY_INDEX") {
+            self.in_memory_index = enabled;
+        }
+        if let Some(background) = env("EIDOLON_IMAGE_BACKGROUND")? {
+            self.image_background = background;
+        }
+
+        if let Some(id) = env("EIDOLON_MODEL_ID")? {
+            self.model.set_id(id);
+        }
+        if let Some(revision) = env("EIDOLON_MODEL_REVISION")? {
+            self.model.revision = Some(revision);
+        }
+        if let Some(path) = env("EIDOLON_MODEL_PATH")? {
+            self.model.path = Some(path);
+        }
+        if let Some(enabled) = env_flag("EIDOLON_CAPTIONING") {
+            self.caption.enabled = enabled;
+        }
+        if let Some(id) = env("EIDOLON_CAPTION_MODEL_ID")? {
+            self.caption.model.set_id(id);
+            self.caption.enabled = true;
+        }
+        if let Some(revision) = env("EIDOLON_CAPTION_MODEL_REVISION")? {
+            self.caption.model.revision = Some(revision);
+        }
+        if let Some(path) = env("EIDOLON_CAPTION_MODEL_PATH")? {
+            self.caption.model.path = Some(path);
+            self.caption.enabled = true;
+        }
+
+        if let Some(size) = env("EIDOLON_IMAGE_BATCH_SIZE")? {
+            self.batch.image_size = size;
+        }
+        if let Some(size) = env("EIDOLON_TEXT_BATCH_SIZE")? {
+            self.batch.text_size = size;
+        }
+        if let Some(timeout_ms) = env("EIDOLON_BATCH_TIMEOUT_MS")? {
+            self.batch.timeout_ms = timeout_ms;
+        }
+        if let Some(workers) = env("EIDOLON_WORKER_THREADS")? {
+            self.threads.workers = Some(workers);
+        }
+        if let Some(compute) = env("EIDOLON_COMPUTE_THREADS")? {
+            self.threads.compute = Some(compute);
+        }
+
+        if let Some(dimension) = env("EIDOLON_MAX_IMAGE_DIMENSION")? {
+            self.limits.max_image_dimension = dimension;
+        }
+        if let Some(alloc) = env("EIDOLON_MAX_IMAGE_ALLOC")? {
+            self.limits.max_image_alloc = alloc;
+        }
+        if let Some(bytes) = env("EIDOLON_MAX_IMAGE_BYTES")? {
+            self.limits.max_image_bytes = bytes;
+        }
+        if let Some(formats) = env::<String>("EIDOLON_IMAGE_FORMATS")? {
+            self.limits.image_formats = formats
+                .split(',')
+                .map(str::trim)
+                .filter(|extension| !extension.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Some(bytes) = env("EIDOLON_MAX_MESSAGE_BYTES")? {
+            self.limits.max_message_bytes = bytes;
+        }
+        if let Some(requests) = env("EIDOLON_MAX_CONCURRENT_REQUESTS")? {
+            self.limits.max_concurrent_requests = Some(requests);
+        }
+
+        if let Some(level) = env("EIDOLON_LOG")? {
+            self.log.level = level;
+        }
+        if let Some(format) = env("EIDOLON_LOG_FORMAT")? {
+            self.log.format = format;
+        }
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &ServeArgs) {
+        if let Some(listen) = args.listen {
+            self.listen = listen;
+        }
+        if let Some(device) = args.device {
+            self.device = device;
+        }
+        if args.in_memory_index {
+            self.in_memory_index = true;
+        }
+        if let Some(id) = &args.model {
+            self.model.set_id(id.clone());
+        }
+        if let Some(revision) = &args.model_revision {
+            self.model.revision = Some(revision.clone());
+        }
+        if let Some(path) = &args.model_path {
+            self.model.path = Some(path.clone());
+        }
+        if args.captioning {
+            self.caption.enabled = true;
+        }
+        if let Some(id) = &args.caption_model {
+            self.caption.model.set_id(id.clone());
+            self.caption.enabled = true;
+        }
+        if let Some(size) = args.image_batch_size {
+            self.batch.image_size = size;
+        }
+        if let Some(size) = args.text_batch_size {
+            self.batch.text_size = size;
+        }
+        if let Some(timeout_ms) = args.batch_timeout_ms {
+            self.batch.timeout_ms = timeout_ms;
+        }
+        if let Some(workers) = args.worker_threads {
+            self.threads.workers = Some(workers);
+        }
+        if let Some(compute) = args.compute_threads {
+            self.threads.compute = Some(compute);
+        }
+        if let Some(dimension) = args.max_image_dimension {
+            self.limits.max_image_dimension = dimension;
+        }
+        if let Some(bytes) = args.max_image_bytes {
+            self.limits.max_image_bytes = bytes;
+        }
+        if let Some(bytes) = args.max_message_bytes {
+            self.limits.max_message_bytes = bytes;
+        }
+        if let Some(requests) = args.max_concurrent_requests {
+            self.limits.max_concurrent_requests = Some(requests);
+        }
+        if let Some(level) = &args.log_level {
+            self.log.level = level.clone();
+        }
+        if let Some(format) = args.log_format {
+            self.log.format = format;
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.batch.image_size == 0 || self.batch.text_size == 0 {
+            return Err(E::msg("Batch sizes must be at least 1"));
+        }
+        if self.threads.workers == Some(0) || self.threads.compute == Some(0) {
+            return Err(E::msg("Thread counts must be at least 1"));
+        }
+        if self.limits.max_concurrent_requests == Some(0) {
+            return Err(E::msg("limits.max_concurrent_requests must be at least 1"));
+        }
+        let needed = self.limits.max_image_bytes.saturating_add(MESSAGE_OVERHEAD_BYTES);
+        if self.limits.max_message_bytes < needed {
+            return Err(E::msg(format!(
+                "limits.max_message_bytes must be at least {} to fit an image of limits.max_image_bytes",
+                needed
+            )));
+        }
+        self.decode_limits()?;
+        self.background()?;
+        tracing_subscriber::EnvFilter::try_new(&self.log.level)
+            .map_err(|e| E::msg(format!("Invalid log level {:?}: {}", self.log.level, e)))?;
+        Ok(())
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    pub fn model_source(&self) -> ModelSource {
+        self.model.source(ModelSource::default())
+    }
+
+    pub fn captioner_source(&self) -> Option<ModelSource> {
+        self.caption
+            .enabled
+            .then(|| self.caption.model.source(ModelSource::default_captioner()))
+    }
+
+    pub fn decode_limits(&self) -> Result<DecodeLimits> {
+        Ok(DecodeLimits {
+            max_width: self.limits.max_image_dimension,
+            max_height: self.limits.max_image_dimension,
+            max_alloc: self.limits.max_image_alloc,
+            max_encoded_bytes: self.limits.max_image_bytes,
+            allowed_formats: parse_formats(&self.limits.image_formats.join(","))?,
+        })
+    }
+
+    pub fn background(&self) -> Result<Rgb<u8>> {
+        parse_hex_color(&self.image_background)
+    }
+
+    pub fn batching(&self) -> Batching {
+        Batching {
+            image_batch_size: self.batch.image_size,
+            text_batch_size: self.batch.text_size,
+            timeout: Duration::from_millis(self.batch.timeout_ms),
+        }
+    }
+
+    /// Installs the global `tracing` subscriber.
+    pub fn init_logging(&self) -> Result<()> {
+        let filter = tracing_subscriber::EnvFilter::try_new(&self.log.level)?;
+        let builder = tracing_subscriber::fmt().with_env_filter(filter);
+        match self.log.format {
+            LogFormat::Text => builder.try_init(),
+            LogFormat::Json => builder.json().try_init(),
+        }
+        .map_err(E::msg)
+    }
+
+    /// Sets `RAYON_NUM_THREADS`, which candle's CPU kernels read for their thread count.
+    /// Must run before any other thread starts.
+    pub fn apply_compute_threads(&self) {
+        if let Some(compute) = self.threads.compute {
+            // SAFETY: called from `main` while the process is still single-threaded.
+            unsafe { std::env::set_var("RAYON_NUM_THREADS", compute.to_string()) };
+        }
+    }
+
+    pub fn runtime(&self) -> Result<tokio::runtime::Runtime> {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.enable_all();
+        if let Some(workers) = self.threads.workers {
+            builder.worker_threads(workers);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_messages_fit_the_largest_image() {
+        let settings = Settings::default();
+        assert!(settings.limits.max_message_bytes > settings.limits.max_image_bytes);
+        assert!(settings.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_messages_smaller_than_images() {
+        let mut settings = Settings::default();
+        settings.limits.max_message_bytes = 4 * 1024 * 1024;
+        assert!(settings.validate().is_err());
+
+        settings.limits.max_image_bytes = 2 * 1024 * 1024;
+        assert!(settings.validate().is_ok());
+    }
+}
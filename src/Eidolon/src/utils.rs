@@ -3,3 +3,104 @@ use candle_core::Tensor;
 pub fn normalize_l2(v: &Tensor) -> candle_core::error::Result<Tensor> {
     v.broadcast_div(&v.sqr()?.sum_keepdim(1)?.sqrt()?)
 }
+
+/// Cosine similarity between two vectors. Returns 0.0 if either vector has zero length.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+/// Softmax over `scores` after multiplying each by `scale`.
+pub fn softmax(scores: &[f32], scale: f32) -> Vec<f32> {
+    let max = scores
+        .iter()
+        .map(|s| s * scale)
+        .fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<f32> = scores.iter().map(|s| (s * scale - max).exp()).collect();
+    let sum: f32 = exps.iter().sum();
+    exps.into_iter().map(|e| e / sum).collect()
+}
+
+/// Averages vectors and rescales the result to unit length.
+pub fn mean_normalized(vectors: &[Vec<f32>]) -> Vec<f32> {
+    let Some(dim) = vectors.first().map(Vec::len) else {
+        return vec![];
+    };
+    let mut mean = vec![0f32; dim];
+    for vector in vectors {
+        for (m, v) in mean.iter_mut().zip(vector) {
+            *m += v;
+        }
+    }
+    let norm = mean.iter().map(|m| m * m).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        mean.iter_mut().for_each(|m| *m /= norm);
+    }
+    mean
+}
+
+/// Sums vectors scaled by their weights and rescales the result to unit length.
+pub fn weighted_mean_normalized(vectors: &[(&[f32], f32)]) -> Vec<f32> {
+    let Some(dim) = vectors.first().map(|(vector, _)| vector.len()) else {
+        return vec![];
+    };
+    let mut mean = vec![0f32; dim];
+    for (vector, weight) in vectors {
+        for (m, v) in mean.iter_mut().zip(vector.iter()) {
+            *m += v * weight;
+        }
+    }
+    let norm = mean.iter().map(|m| m * m).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        mean.iter_mut().for_each(|m| *m /= norm);
+    }
+    mean
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cosine_similarity_ignores_length() {
+        assert!((cosine_similarity(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]).abs() < 1e-6);
+        assert!((cosine_similarity(&[3.0, 4.0], &[4.0, 3.0]) - 0.96).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 2.0]), 0.0);
+    }
+
+    #[test]
+    fn softmax_scales_before_normalizing() {
+        let probabilities = softmax(&[0.3, 0.2, 0.1], 1.0);
+        assert!((probabilities.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        assert!(probabilities[0] > probabilities[1] && probabilities[1] > probabilities[2]);
+
+        // With CLIP's scale of 100 a 0.1 lead in cosine is a near certain pick.
+        let sharp = softmax(&[0.3, 0.2], 100.0);
+        let expected = 1.0 / (1.0 + (-10f32).exp());
+        assert!((sharp[0] - expected).abs() < 1e-6);
+        assert!((sharp[1] - (1.0 - expected)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn weighted_mean_is_unit_length() {
+        let mean = weighted_mean_normalized(&[(&[1.0, 0.0], 3.0), (&[0.0, 1.0], 4.0)]);
+        assert_eq!(mean, [0.6, 0.8]);
+
+        // Zero weights, or weights that cancel out, leave a zero vector rather than NaNs.
+        assert_eq!(weighted_mean_normalized(&[(&[1.0, 0.0], 0.0)]), [0.0, 0.0]);
+        assert_eq!(weighted_mean_normalized(&[(&[1.0, 1.0], 1.0), (&[1.0, 1.0], -1.0)]), [0.0, 0.0]);
+        assert!(weighted_mean_normalized(&[]).is_empty());
+    }
+
+    #[test]
+    fn softmax_does_not_overflow_on_large_logits() {
+        let probabilities = softmax(&[1.0, 1.0], 1000.0);
+        assert_eq!(probabilities, [0.5, 0.5]);
+    }
+}
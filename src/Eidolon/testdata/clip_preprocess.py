@@ -0,0 +1,78 @@
+"""Writes clip_preprocess.json, the reference tensors for processor.rs's fixture test.
+
+Each case runs a small synthetic image through transformers' CLIPImageProcessor with
+the case's preprocessor_config.json settings. Regenerate after adding a case:
+
+    pip install transformers pillow numpy
+    python testdata/clip_preprocess.py
+"""
+
+import json
+from pathlib import Path
+
+import numpy as np
+import transformers
+from PIL import Image
+from transformers import CLIPImageProcessor
+
+
+def gradient(width, height):
+    """An image whose channels vary independently, so misplaced pixels show up."""
+    y, x = np.mgrid[0:height, 0:width]
+    red = (x * 255 // max(width - 1, 1)).astype(np.uint8)
+    green = (y * 255 // max(height - 1, 1)).astype(np.uint8)
+    blue = ((x * 37 + y * 91) % 256).astype(np.uint8)
+    return np.stack([red, green, blue], axis=-1)
+
+
+CLIP = {
+    "size": {"shortest_edge": 8},
+    "crop_size": {"height": 8, "width": 8},
+}
+SIGLIP = {
+    "size": {"height": 8, "width": 8},
+    "do_center_crop": False,
+    "image_mean": [0.5, 0.5, 0.5],
+    "image_std": [0.5, 0.5, 0.5],
+}
+
+CASES = [
+    ("bicubic_downscale_landscape", 24, 16, {**CLIP, "resample": 3}),
+    ("bicubic_downscale_portrait", 13, 29, {**CLIP, "resample": 3}),
+    ("bicubic_upscale", 5, 3, {**CLIP, "resample": 3}),
+    ("bilinear_downscale", 24, 16, {**CLIP, "resample": 2}),
+    ("nearest_upscale", 4, 2, {**CLIP, "resample": 0}),
+    ("lanczos_downscale", 24, 16, {**CLIP, "resample": 1}),
+    ("siglip_square_resize", 20, 12, {**SIGLIP, "resample": 2}),
+    ("crop_without_resize", 12, 10, {**CLIP, "do_resize": False}),
+    ("pad_small_image", 5, 3, {**CLIP, "do_resize": False}),
+]
+
+
+def main():
+    cases = []
+    for name, width, height, config in CASES:
+        pixels = gradient(width, height)
+        processor = CLIPImageProcessor(**config)
+        output = processor(images=Image.fromarray(pixels), return_tensors="np")
+        values = output["pixel_values"][0].astype(np.float32)
+        cases.append(
+            {
+                "name": name,
+                "width": width,
+                "height": height,
+                "pixels": pixels.flatten().tolist(),
+                "config": config,
+                "shape": list(values.shape),
+                "values": [round(float(v), 6) for v in values.flatten()],
+            }
+        )
+
+    fixture = {"transformers": transformers.__version__, "cases": cases}
+    path = Path(__file__).with_name("clip_preprocess.json")
+    path.write_text(json.dumps(fixture) + "\n")
+    print(f"Wrote {len(cases)} cases to {path}")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,124 @@
+//! Measures recall@k of the HNSW index against exact search on synthetic clustered data.
+//!
+//! Run with `cargo bench --bench recall`. `RECALL_VECTORS`, `RECALL_DIMENSION` and
+//! `RECALL_QUERIES` change the data set size.
+
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use Engram::hnsw::{Hnsw, HnswParams, Metric};
+
+const K: usize = 10;
+const CLUSTERS: usize = 64;
+const EF_SEARCH: [usize; 5] = [16, 32, 64, 128, 256];
+
+struct Rng(u64);
+
+impl Rng {
+    fn next_f32(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        ((self.0 >> 40) as f32 + 0.5) / (1u64 << 24) as f32
+    }
+
+    /// A standard normal sample, by Box-Muller.
+    fn gaussian(&mut self) -> f32 {
+        let (u, v) = (self.next_f32(), self.next_f32());
+        (-2.0 * u.ln()).sqrt() * (std::f32::consts::TAU * v).cos()
+    }
+}
+
+fn env_or(name: &str, default: usize) -> usize {
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// Points scattered around random cluster centres, closer to real embeddings than
+/// uniform noise.
+fn clustered(rng: &mut Rng, count: usize, dimension: usize) -> Vec<Vec<f32>> {
+    let centres: Vec<Vec<f32>> = (0..CLUSTERS)
+        .map(|_| (0..dimension).map(|_| rng.gaussian()).collect())
+        .collect();
+    (0..count)
+        .map(|i| {
+            let centre = &centres[i % CLUSTERS];
+            centre.iter().map(|c| c + 0.5 * rng.gaussian()).collect()
+        })
+        .collect()
+}
+
+/// Mean recall@K and mean query latency at the index's current ef_search.
+fn measure(index: &Hnsw, queries: &[Vec<f32>], truth: &[HashSet<u32>]) -> (f64, Duration) {
+    let mut found = 0;
+    let mut elapsed = Duration::ZERO;
+    for (query, truth) in queries.iter().zip(truth) {
+        let start = Instant::now();
+        let hits = index.search(query, K).unwrap();
+        elapsed += start.elapsed();
+        found += hits.iter().filter(|hit| truth.contains(&hit.id)).count();
+    }
+    let recall = found as f64 / (queries.len() * K) as f64;
+    (recall, elapsed / queries.len() as u32)
+}
+
+fn ground_truth(index: &Hnsw, queries: &[Vec<f32>]) -> (Vec<HashSet<u32>>, Duration) {
+    let start = Instant::now();
+    let truth = queries
+        .iter()
+        .map(|query| index.exact_search(query, K).unwrap().iter().map(|hit| hit.id).collect())
+        .collect();
+    (truth, start.elapsed() / queries.len() as u32)
+}
+
+fn report(label: &str, index: &mut Hnsw, queries: &[Vec<f32>]) {
+    let (truth, exact) = ground_truth(index, queries);
+    println!("  {} (exact search {:?}/query)", label, exact);
+    for ef in EF_SEARCH {
+        index.set_ef_search(ef);
+        let (recall, latency) = measure(index, queries, &truth);
+        println!("    ef={:<4} recall@{}={:.4} {:?}/query", ef, K, recall, latency);
+    }
+}
+
+fn main() {
+    let count = env_or("RECALL_VECTORS", 20_000);
+    let dimension = env_or("RECALL_DIMENSION", 128);
+    let query_count = env_or("RECALL_QUERIES", 200);
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let data = clustered(&mut rng, count, dimension);
+    let queries = clustered(&mut rng, query_count, dimension);
+    println!("{} vectors, {} dimensions, {} queries", count, dimension, query_count);
+
+    for metric in [Metric::Cosine, Metric::Dot, Metric::L2] {
+        let params = HnswParams {
+            metric,
+            ..HnswParams::default()
+        };
+        println!("{:?} (m={}, ef_construction={})", metric, params.m, params.ef_construction);
+
+        let start = Instant::now();
+        let mut index = Hnsw::new(dimension, params);
+        for vector in &data {
+            index.insert(vector).unwrap();
+        }
+        println!("  built in {:?}", start.elapsed());
+        report("fresh", &mut index, &queries);
+
+        for id in (0..count as u32).step_by(10) {
+            index.delete(id).unwrap();
+        }
+        report("10% deleted", &mut index, &queries);
+
+        let start = Instant::now();
+        index.compact();
+        println!("  compacted in {:?}", start.elapsed());
+        report("compacted", &mut index, &queries);
+
+        let path = std::env::temp_dir().join(format!("engram-recall-{:?}.hnsw", metric));
+        index.save(&path).unwrap();
+        let start = Instant::now();
+        let mut loaded = Hnsw::load(&path).unwrap();
+        println!("  snapshot loaded in {:?} (mapped: {})", start.elapsed(), loaded.is_mapped());
+        report("loaded", &mut loaded, &queries);
+        std::fs::remove_file(&path).ok();
+    }
+}
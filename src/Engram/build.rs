@@ -0,0 +1,10 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Use the vendored protoc unless PROTOC points at another one.
+    let mut config = prost_build::Config::new();
+    if std::env::var_os("PROTOC").is_none() {
+        config.protoc_executable(protoc_bin_vendored::protoc_bin_path()?);
+    }
+    tonic_prost_build::configure().compile_with_config(config, &["proto/product.proto"], &["proto"])?;
+
+    Ok(())
+}
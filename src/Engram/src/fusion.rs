@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+
+/// How the rankings of the dense and lexical legs are combined.
+#[derive(Debug, Clone, Copy)]
+pub enum Fusion {
+    /// Reciprocal rank fusion: each leg contributes `1 / (k + rank)`, ignoring raw scores.
+    Rrf { k: f32 },
+    /// Each leg's scores are min-max normalized over its candidates, then blended.
+    /// `dense_weight` is the dense leg's share; the lexical leg gets the rest.
+    Weighted { dense_weight: f32 },
+}
+
+impl Default for Fusion {
+    fn default() -> Self {
+        Fusion::Rrf { k: 60.0 }
+    }
+}
+
+/// Where a document placed in one leg, for debugging fused rankings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LegHit {
+    /// 1-based position in the leg's ranking.
+    pub rank: usize,
+    /// The leg's own score: similarity for dense, BM25 for lexical.
+    pub score: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FusedHit {
+    pub doc: u32,
+    pub score: f32,
+    pub dense: Option<LegHit>,
+    pub lexical: Option<LegHit>,
+}
+
+/// Merges two rankings, each best first, into the `limit` best documents.
+pub fn fuse(dense: &[(u32, f32)], lexical: &[(u32, f32)], fusion: Fusion, limit: usize) -> Vec<FusedHit> {
+    let mut hits: HashMap<u32, FusedHit> = HashMap::new();
+    for (ranking, is_dense) in [(dense, true), (lexical, false)] {
+        for (index, &(doc, score)) in ranking.iter().enumerate() {
+            let hit = hits.entry(doc).or_insert(FusedHit {
+                doc,
+                score: 0.0,
+                dense: None,
+                lexical: None,
+            });
+            let leg = Some(LegHit { rank: index + 1, score });
+            if is_dense {
+                hit.dense = leg;
+            } else {
+                hit.lexical = leg;
+            }
+        }
+    }
+
+    let dense_range = score_range(dense);
+    let lexical_range = score_range(lexical);
+    for hit in hits.values_mut() {
+        hit.score = match fusion {
+            Fusion::Rrf { k } => [hit.dense, hit.lexical]
+                .iter()
+                .flatten()
+                .map(|leg| 1.0 / (k + leg.rank as f32))
+                .sum(),
+            Fusion::Weighted { dense_weight } => {
+                let dense = hit.dense.map_or(0.0, |leg| normalize(leg.score, dense_range));
+                let lexical = hit.lexical.map_or(0.0, |leg| normalize(leg.score, lexical_range));
+                dense_weight * dense + (1.0 - dense_weight) * lexical
+            }
+        };
+    }
+
+    let mut hits: Vec<FusedHit> = hits.into_values().collect();
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.doc.cmp(&b.doc)));
+    hits.truncate(limit);
+    hits
+}
+
+fn score_range(ranking: &[(u32, f32)]) -> (f32, f32) {
+    ranking
+        .iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &(_, score)| {
+            (min.min(score), max.max(score))
+        })
+}
+
+/// Scales a score into [0, 1] over its leg; a leg whose scores are all equal maps to 1.
+fn normalize(score: f32, (min, max): (f32, f32)) -> f32 {
+    if max > min {
+        (score - min) / (max - min)
+    } else {
+        1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(hits: &[FusedHit]) -> Vec<u32> {
+        hits.iter().map(|hit| hit.doc).collect()
+    }
+
+    #[test]
+    fn rrf_sums_reciprocal_ranks() {
+        let dense = [(1, 0.9), (2, 0.8), (3, 0.1)];
+        let lexical = [(3, 12.0), (4, 6.0)];
+        let hits = fuse(&dense, &lexical, Fusion::Rrf { k: 60.0 }, 10);
+        assert_eq!(order(&hits), [3, 1, 2, 4]);
+        assert!((hits[0].score - (1.0 / 63.0 + 1.0 / 61.0)).abs() < 1e-7);
+        assert!((hits[1].score - 1.0 / 61.0).abs() < 1e-7);
+        assert_eq!(order(&fuse(&dense, &lexical, Fusion::default(), 2)), [3, 1]);
+    }
+
+    #[test]
+    fn rrf_breaks_ties_by_document() {
+        // Each document is first in one leg and second in the other.
+        let hits = fuse(&[(7, 0.9), (5, 0.2)], &[(5, 3.0), (7, 1.0)], Fusion::default(), 10);
+        assert_eq!(order(&hits), [5, 7]);
+        assert_eq!(hits[0].score, hits[1].score);
+
+        let hits = fuse(&[(9, 0.5)], &[(2, 8.0)], Fusion::default(), 10);
+        assert_eq!(order(&hits), [2, 9]);
+    }
+
+    #[test]
+    fn weighted_fusion_blends_normalized_scores() {
+        let dense = [(1, 0.9), (2, 0.5), (3, 0.1)];
+        let lexical = [(3, 10.0), (1, 5.0), (4, 0.0)];
+        let hits = fuse(&dense, &lexical, Fusion::Weighted { dense_weight: 0.75 }, 10);
+        assert_eq!(order(&hits), [1, 2, 3, 4]);
+        let scores: Vec<f32> = hits.iter().map(|hit| hit.score).collect();
+        let expected = [0.75 + 0.125, 0.375, 0.25, 0.0];
+        for (score, expected) in scores.iter().zip(expected) {
+            assert!((score - expected).abs() < 1e-6, "{:?}", scores);
+        }
+    }
+
+    #[test]
+    fn weighted_fusion_with_an_empty_leg() {
+        let lexical = [(4, 3.0), (6, 2.0), (8, 1.0)];
+        let hits = fuse(&[], &lexical, Fusion::Weighted { dense_weight: 0.7 }, 10);
+        assert_eq!(order(&hits), [4, 6, 8]);
+        let scores: Vec<f32> = hits.iter().map(|hit| hit.score).collect();
+        for (score, expected) in scores.iter().zip([0.3, 0.15, 0.0]) {
+            assert!((score - expected).abs() < 1e-6, "{:?}", scores);
+        }
+        assert!(hits.iter().all(|hit| hit.dense.is_none()));
+
+        // A leg whose scores are all equal counts in full.
+        let hits = fuse(&[(1, 0.4), (2, 0.4)], &[], Fusion::Weighted { dense_weight: 0.7 }, 10);
+        assert_eq!(order(&hits), [1, 2]);
+        assert!(hits.iter().all(|hit| (hit.score - 0.7).abs() < 1e-6));
+        assert!(fuse(&[], &[], Fusion::default(), 10).is_empty());
+    }
+
+    #[test]
+    fn carries_each_legs_rank_and_score() {
+        let hits = fuse(&[(1, 0.9), (2, 0.8)], &[(2, 7.5), (3, 1.5)], Fusion::default(), 10);
+        let two = hits.iter().find(|hit| hit.doc == 2).unwrap();
+        assert_eq!(two.dense, Some(LegHit { rank: 2, score: 0.8 }));
+        assert_eq!(two.lexical, Some(LegHit { rank: 1, score: 7.5 }));
+        let three = hits.iter().find(|hit| hit.doc == 3).unwrap();
+        assert_eq!(three.dense, None);
+        assert_eq!(three.lexical, Some(LegHit { rank: 2, score: 1.5 }));
+    }
+}
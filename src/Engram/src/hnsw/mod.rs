@@ -0,0 +1,660 @@
+mod metric;
+mod snapshot;
+mod vectors;
+
+pub use metric::{normalize, Metric};
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+use std::fmt;
+
+use vectors::Vectors;
+
+/// Layers are capped so a freak draw from the level distribution can't build a tower.
+const MAX_LEVEL: usize = 16;
+
+/// Tuning knobs for the graph. `m` trades memory and build time for recall, `ef_search`
+/// trades query latency for recall.
+#[derive(Debug, Clone)]
+pub struct HnswParams {
+    /// Links kept per node on the upper layers; layer 0 keeps twice as many.
+    pub m: usize,
+    /// Candidates considered when linking a new node.
+    pub ef_construction: usize,
+    /// Candidates considered per query; raised to `k` when a query asks for more.
+    pub ef_search: usize,
+    pub metric: Metric,
+    /// Seeds the level assignment so builds are reproducible.
+    pub seed: u64,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        Self {
+            m: 16,
+            ef_construction: 200,
+            ef_search: 64,
+            metric: Metric::Cosine,
+            seed: 0x5eed,
+        }
+    }
+}
+
+impl HnswParams {
+    /// Reads `ENGRAM_HNSW_M`, `ENGRAM_HNSW_EF_CONSTRUCTION`, `ENGRAM_HNSW_EF_SEARCH` and
+    /// `ENGRAM_METRIC` (`cosine`, `dot` or `l2`).
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut params = Self::default();
+        if let Ok(value) = std::env::var("ENGRAM_HNSW_M") {
+            params.m = value.parse()?;
+        }
+        if let Ok(value) = std::env::var("ENGRAM_HNSW_EF_CONSTRUCTION") {
+            params.ef_construction = value.parse()?;
+        }
+        if let Ok(value) = std::env::var("ENGRAM_HNSW_EF_SEARCH") {
+            params.ef_search = value.parse()?;
+        }
+        if let Ok(value) = std::env::var("ENGRAM_METRIC") {
+            params.metric = value.parse().map_err(anyhow::Error::msg)?;
+        }
+        if params.m < 2 || params.ef_construction == 0 || params.ef_search == 0 {
+            return Err(anyhow::Error::msg(
+                "HNSW needs m of at least 2 and non-zero ef parameters",
+            ));
+        }
+        Ok(params)
+    }
+}
+
+#[derive(Debug)]
+pub enum HnswError {
+    EmptyVector,
+    DimensionMismatch { expected: usize, actual: usize },
+    /// Cosine distance is undefined for a zero vector.
+    ZeroVector,
+    UnknownNode(u32),
+    Io(std::io::Error),
+    Corrupt(String),
+}
+
+impl fmt::Display for HnswError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HnswError::EmptyVector => write!(f, "Vector cannot be empty"),
+            HnswError::DimensionMismatch { expected, actual } => write!(
+                f,
+                "Vector has {} dimensions but the index holds {}-dimensional vectors",
+                actual, expected
+            ),
+            HnswError::ZeroVector => write!(f, "Vector cannot be all zeros under cosine distance"),
+            HnswError::UnknownNode(id) => write!(f, "No node with id {}", id),
+            HnswError::Io(e) => write!(f, "Snapshot I/O failed: {}", e),
+            HnswError::Corrupt(e) => write!(f, "Snapshot is corrupt: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HnswError {}
+
+impl From<std::io::Error> for HnswError {
+    fn from(e: std::io::Error) -> Self {
+        HnswError::Io(e)
+    }
+}
+
+/// A search hit: the node id assigned on insertion and its distance to the query.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Neighbor {
+    pub id: u32,
+    pub distance: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate {
+    distance: f32,
+    id: u32,
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then_with(|| self.id.cmp(&other.id))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A small deterministic generator for level assignment.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A uniform sample from (0, 1].
+    fn next_f64(&mut self) -> f64 {
+        ((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// The live vectors of a graph, copied out by `Hnsw::rebuild` to build a compacted graph from.
+pub struct Rebuild {
+    params: HnswParams,
+    dimension: usize,
+    /// Nodes in the source graph, including tombstones.
+    nodes: usize,
+    live: Vec<u32>,
+    vectors: Vec<f32>,
+}
+
+impl Rebuild {
+    /// Builds the compacted graph, returning it with the new id of every node of the source
+    /// graph at the time of the copy, or `None` for the deleted ones.
+    pub fn build(self) -> (Hnsw, Vec<Option<u32>>) {
+        let mut rebuilt = Hnsw::new(self.dimension, self.params);
+        let mut remap = vec![None; self.nodes];
+        for (position, &id) in self.live.iter().enumerate() {
+            let start = position * self.dimension;
+            remap[id as usize] = Some(rebuilt.add(&self.vectors[start..start + self.dimension]));
+        }
+        (rebuilt, remap)
+    }
+}
+
+/// A Hierarchical Navigable Small World graph (Malkov & Yashunin) over fixed-dimension
+/// vectors. Nodes are numbered in insertion order. Deleted nodes stay in the graph as
+/// tombstones that queries route through but never return, until `compact` rebuilds it.
+pub struct Hnsw {
+    params: HnswParams,
+    dimension: usize,
+    vectors: Vectors,
+    /// Per node, its links on each layer it lives on, bottom layer first.
+    links: Vec<Vec<Vec<u32>>>,
+    deleted: Vec<bool>,
+    deleted_count: usize,
+    entry: Option<u32>,
+    rng: SplitMix64,
+}
+
+impl Hnsw {
+    pub fn new(dimension: usize, params: HnswParams) -> Self {
+        let rng = SplitMix64(params.seed);
+        Self {
+            params,
+            dimension,
+            vectors: Vectors::Owned(vec![]),
+            links: vec![],
+            deleted: vec![],
+            deleted_count: 0,
+            entry: None,
+            rng,
+        }
+    }
+
+    pub fn params(&self) -> &HnswParams {
+        &self.params
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    /// Nodes in the graph, including tombstones.
+    pub fn len(&self) -> usize {
+        self.links.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.links.is_empty()
+    }
+
+    pub fn live_count(&self) -> usize {
+        self.len() - self.deleted_count
+    }
+
+    pub fn deleted_count(&self) -> usize {
+        self.deleted_count
+    }
+
+    pub fn is_deleted(&self, id: u32) -> bool {
+        self.deleted.get(id as usize).copied().unwrap_or(true)
+    }
+
+    /// Whether the vectors are still served from a mapped snapshot.
+    pub fn is_mapped(&self) -> bool {
+        self.vectors.is_mapped()
+    }
+
+    pub fn set_ef_search(&mut self, ef_search: usize) {
+        self.params.ef_search = ef_search.max(1);
+    }
+
+    /// The stored vector of a node, normalized if the metric is cosine.
+    pub fn vector(&self, id: u32) -> &[f32] {
+        let start = id as usize * self.dimension;
+        &self.vectors.as_slice()[start..start + self.dimension]
+    }
+
+    /// Checks a vector against the index and returns it as stored.
+    pub fn prepare(&self, vector: &[f32]) -> Result<Vec<f32>, HnswError> {
+        if vector.is_empty() {
+            return Err(HnswError::EmptyVector);
+        }
+        if vector.len() != self.dimension {
+            return Err(HnswError::DimensionMismatch {
+                expected: self.dimension,
+                actual: vector.len(),
+            });
+        }
+        if self.params.metric == Metric::Cosine {
+            if vector.iter().all(|&x| x == 0.0) {
+                return Err(HnswError::ZeroVector);
+            }
+            return Ok(normalize(vector));
+        }
+        Ok(vector.to_vec())
+    }
+
+    /// Adds a vector and returns its node id.
+    pub fn insert(&mut self, vector: &[f32]) -> Result<u32, HnswError> {
+        let vector = self.prepare(vector)?;
+        Ok(self.add(&vector))
+    }
+
+    /// Marks a node deleted, returning false if it already was.
+    pub fn delete(&mut self, id: u32) -> Result<bool, HnswError> {
+        let deleted = self
+            .deleted
+            .get_mut(id as usize)
+            .ok_or(HnswError::UnknownNode(id))?;
+        if *deleted {
+            return Ok(false);
+        }
+        *deleted = true;
+        self.deleted_count += 1;
+        Ok(true)
+    }
+
+    /// The `k` live nodes nearest to `query`, nearest first.
+    pub fn search(&self, query: &[f32], k: usize) -> Result<Vec<Neighbor>, HnswError> {
+        self.search_filtered(query, k, |_| true)
+    }
+
+    /// Like `search`, but only returns nodes `accept` allows. Rejected nodes are still
+    /// traversed, so the graph stays connected however selective the filter is.
+    pub fn search_filtered(
+        &self,
+        query: &[f32],
+        k: usize,
+        accept: impl Fn(u32) -> bool,
+    ) -> Result<Vec<Neighbor>, HnswError> {
+        let query = self.prepare(query)?;
+        let Some(entry) = self.entry.filter(|_| k > 0) else {
+            return Ok(vec![]);
+        };
+
+        let mut entry_points = self.descend(&query, entry, 0);
+        let ef = self.params.ef_search.max(k);
+        let accept = |id: u32| !self.deleted[id as usize] && accept(id);
+        entry_points = self.search_layer(&query, &entry_points, ef, 0, &accept);
+        Ok(entry_points
+            .into_iter()
+            .take(k)
+            .map(|c| Neighbor {
+                id: c.id,
+                distance: c.distance,
+            })
+            .collect())
+    }
+
+    /// Exhaustively finds the `k` live nodes nearest to `query`, for measuring recall.
+    pub fn exact_search(&self, query: &[f32], k: usize) -> Result<Vec<Neighbor>, HnswError> {
+        let query = self.prepare(query)?;
+        let mut nearest = BinaryHeap::with_capacity(k + 1);
+        for id in 0..self.len() as u32 {
+            if self.deleted[id as usize] {
+                continue;
+            }
+            nearest.push(Candidate {
+                distance: self.params.metric.distance(&query, self.vector(id)),
+                id,
+            });
+            if nearest.len() > k {
+                nearest.pop();
+            }
+        }
+        Ok(nearest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|c| Neighbor {
+                id: c.id,
+                distance: c.distance,
+            })
+            .collect())
+    }
+
+    /// Rebuilds the graph from the live nodes, dropping tombstones. Returns the new id of
+    /// every old node, or `None` for the deleted ones.
+    pub fn compact(&mut self) -> Vec<Option<u32>> {
+        let (rebuilt, remap) = self.rebuild().build();
+        *self = rebuilt;
+        remap
+    }
+
+    /// Copies out the live vectors so a compacted graph can be built from them while this
+    /// one stays in use. Changes made here in the meantime are carried over by `catch_up`.
+    pub fn rebuild(&self) -> Rebuild {
+        let live: Vec<u32> = (0..self.len() as u32)
+            .filter(|&id| !self.deleted[id as usize])
+            .collect();
+        let mut vectors = Vec::with_capacity(live.len() * self.dimension);
+        for &id in &live {
+            vectors.extend_from_slice(self.vector(id));
+        }
+        Rebuild {
+            params: self.params.clone(),
+            dimension: self.dimension,
+            nodes: self.len(),
+            live,
+            vectors,
+        }
+    }
+
+    /// Applies the inserts and deletes made here since `rebuild` to a graph built from it,
+    /// extending `remap` to every current node.
+    pub fn catch_up(&self, rebuilt: &mut Hnsw, remap: &mut Vec<Option<u32>>) {
+        for (old, new) in remap.iter_mut().enumerate() {
+            if self.deleted[old] {
+                if let Some(new) = new.take() {
+                    // Every id in the remap was assigned by the rebuilt graph.
+                    let _ = rebuilt.delete(new);
+                }
+            }
+        }
+        for id in remap.len() as u32..self.len() as u32 {
+            let new = (!self.deleted[id as usize]).then(|| rebuilt.add(self.vector(id)));
+            remap.push(new);
+        }
+    }
+
+    fn add(&mut self, vector: &[f32]) -> u32 {
+        let id = self.links.len() as u32;
+        let level = self.random_level();
+        self.vectors.push(vector);
+        self.links.push(vec![vec![]; level + 1]);
+        self.deleted.push(false);
+
+        let Some(entry) = self.entry else {
+            self.entry = Some(id);
+            return id;
+        };
+        let top = self.top_level();
+        let mut entry_points = self.descend(vector, entry, level + 1);
+        for layer in (0..=level.min(top)).rev() {
+            let found = self.search_layer(vector, &entry_points, self.params.ef_construction, layer, &|_| true);
+            let selected = self.select_neighbors(&found, self.params.m);
+            for &neighbor in &selected {
+                self.connect(neighbor, id, layer);
+            }
+            self.links[id as usize][layer] = selected;
+            entry_points = found;
+        }
+        if level > top {
+            self.entry = Some(id);
+        }
+        id
+    }
+
+    fn top_level(&self) -> usize {
+        self.entry.map_or(0, |entry| self.links[entry as usize].len() - 1)
+    }
+
+    fn random_level(&mut self) -> usize {
+        let multiplier = 1.0 / (self.params.m.max(2) as f64).ln();
+        ((-self.rng.next_f64().ln() * multiplier) as usize).min(MAX_LEVEL)
+    }
+
+    fn max_links(&self, layer: usize) -> usize {
+        if layer == 0 {
+            self.params.m * 2
+        } else {
+            self.params.m
+        }
+    }
+
+    /// Greedily walks down from the top layer to `bottom`, returning the closest node found.
+    fn descend(&self, query: &[f32], entry: u32, bottom: usize) -> Vec<Candidate> {
+        let mut entry_points = vec![Candidate {
+            distance: self.params.metric.distance(query, self.vector(entry)),
+            id: entry,
+        }];
+        for layer in (bottom..=self.top_level()).rev() {
+            entry_points = self.search_layer(query, &entry_points, 1, layer, &|_| true);
+        }
+        entry_points
+    }
+
+    /// Best-first search of one layer, returning up to `ef` accepted nodes nearest first.
+    fn search_layer(
+        &self,
+        query: &[f32],
+        entry_points: &[Candidate],
+        ef: usize,
+        layer: usize,
+        accept: &dyn Fn(u32) -> bool,
+    ) -> Vec<Candidate> {
+        let mut visited: HashSet<u32> = entry_points.iter().map(|c| c.id).collect();
+        let mut candidates: BinaryHeap<Reverse<Candidate>> = entry_points.iter().copied().map(Reverse).collect();
+        let mut results: BinaryHeap<Candidate> = entry_points.iter().copied().filter(|c| accept(c.id)).collect();
+        while results.len() > ef {
+            results.pop();
+        }
+
+        while let Some(Reverse(current)) = candidates.pop() {
+            if results.len() >= ef && results.peek().is_some_and(|worst| current.distance > worst.distance) {
+                break;
+            }
+            for &id in &self.links[current.id as usize][layer] {
+                if !visited.insert(id) {
+                    continue;
+                }
+                let distance = self.params.metric.distance(query, self.vector(id));
+                if results.len() >= ef && results.peek().is_some_and(|worst| distance >= worst.distance) {
+                    continue;
+                }
+                let candidate = Candidate { distance, id };
+                candidates.push(Reverse(candidate));
+                if accept(id) {
+                    results.push(candidate);
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+        results.into_sorted_vec()
+    }
+
+    /// Picks up to `m` of the sorted candidates, skipping any closer to an already picked
+    /// neighbor than to the base node so links spread out in different directions.
+    fn select_neighbors(&self, candidates: &[Candidate], m: usize) -> Vec<u32> {
+        let mut selected: Vec<Candidate> = Vec::with_capacity(m);
+        for &candidate in candidates {
+            if selected.len() >= m {
+                break;
+            }
+            let diverse = selected.iter().all(|picked| {
+                self.params.metric.distance(self.vector(candidate.id), self.vector(picked.id)) > candidate.distance
+            });
+            if diverse {
+                selected.push(candidate);
+            }
+        }
+        selected.into_iter().map(|c| c.id).collect()
+    }
+
+    /// Links `node` to `new`, re-selecting its neighbors if that overflows the layer's limit.
+    fn connect(&mut self, node: u32, new: u32, layer: usize) {
+        let max_links = self.max_links(layer);
+        let links = &mut self.links[node as usize][layer];
+        links.push(new);
+        if links.len() <= max_links {
+            return;
+        }
+
+        let base = self.vector(node);
+        let mut candidates: Vec<Candidate> = self.links[node as usize][layer]
+            .iter()
+            .map(|&id| Candidate {
+                distance: self.params.metric.distance(base, self.vector(id)),
+                id,
+            })
+            .collect();
+        candidates.sort();
+        self.links[node as usize][layer] = self.select_neighbors(&candidates, max_links);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Uniform vectors in [-1, 1) from a seeded generator.
+    pub(super) fn random_vectors(count: usize, dimension: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut rng = SplitMix64(seed);
+        (0..count)
+            .map(|_| (0..dimension).map(|_| rng.next_f64() as f32 * 2.0 - 1.0).collect())
+            .collect()
+    }
+
+    pub(super) fn build(vectors: &[Vec<f32>], metric: Metric) -> Hnsw {
+        let params = HnswParams {
+            m: 8,
+            ef_construction: 64,
+            ef_search: 32,
+            metric,
+            ..HnswParams::default()
+        };
+        let mut index = Hnsw::new(vectors[0].len(), params);
+        for vector in vectors {
+            index.insert(vector).unwrap();
+        }
+        index
+    }
+
+    fn ids(neighbors: &[Neighbor]) -> Vec<u32> {
+        neighbors.iter().map(|neighbor| neighbor.id).collect()
+    }
+
+    #[test]
+    fn recall_matches_exact_search() {
+        let vectors = random_vectors(1000, 16, 1);
+        let queries = random_vectors(50, 16, 2);
+        for metric in [Metric::Cosine, Metric::Dot, Metric::L2] {
+            let index = build(&vectors, metric);
+            let mut found = 0;
+            for query in &queries {
+                let exact = ids(&index.exact_search(query, 10).unwrap());
+                let approximate = ids(&index.search(query, 10).unwrap());
+                found += approximate.iter().filter(|id| exact.contains(id)).count();
+            }
+            let recall = found as f32 / (queries.len() * 10) as f32;
+            assert!(recall >= 0.95, "{:?} recall@10 is {}", metric, recall);
+        }
+    }
+
+    #[test]
+    fn returns_hits_nearest_first() {
+        let index = build(&random_vectors(200, 8, 3), Metric::L2);
+        let hits = index.search(&random_vectors(1, 8, 4)[0], 20).unwrap();
+        assert_eq!(hits.len(), 20);
+        assert!(hits.windows(2).all(|pair| pair[0].distance <= pair[1].distance));
+        assert!(index.search(&[0.5; 8], 0).unwrap().is_empty());
+        assert_eq!(index.search(&[0.5; 8], 500).unwrap().len(), 200);
+    }
+
+    #[test]
+    fn deleted_nodes_are_never_returned() {
+        let vectors = random_vectors(300, 8, 5);
+        let mut index = build(&vectors, Metric::Cosine);
+        assert_eq!(index.search(&vectors[42], 1).unwrap()[0].id, 42);
+
+        assert!(index.delete(42).unwrap());
+        assert!(!index.delete(42).unwrap());
+        assert!(matches!(index.delete(300), Err(HnswError::UnknownNode(300))));
+        assert!(!ids(&index.search(&vectors[42], 300).unwrap()).contains(&42));
+        assert!(!ids(&index.exact_search(&vectors[42], 300).unwrap()).contains(&42));
+        assert_eq!((index.live_count(), index.deleted_count()), (299, 1));
+    }
+
+    #[test]
+    fn compaction_drops_tombstones_and_remaps_ids() {
+        let vectors = random_vectors(100, 8, 6);
+        let mut index = build(&vectors, Metric::L2);
+        for id in (0..100).step_by(3) {
+            index.delete(id).unwrap();
+        }
+        let remap = index.compact();
+        assert_eq!(remap.len(), 100);
+        assert_eq!((index.len(), index.deleted_count()), (66, 0));
+        for (old, new) in remap.iter().enumerate() {
+            match new {
+                None => assert!(old % 3 == 0),
+                Some(new) => {
+                    assert!(old % 3 != 0);
+                    assert_eq!(index.vector(*new), &vectors[old][..]);
+                    assert_eq!(index.search(&vectors[old], 1).unwrap()[0].id, *new);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn catch_up_applies_changes_made_during_a_rebuild() {
+        let vectors = random_vectors(50, 4, 7);
+        let mut index = build(&vectors[..40], Metric::L2);
+        index.delete(0).unwrap();
+        let rebuild = index.rebuild();
+        index.delete(1).unwrap();
+        for vector in &vectors[40..] {
+            index.insert(vector).unwrap();
+        }
+        index.delete(45).unwrap();
+
+        let (mut rebuilt, mut remap) = rebuild.build();
+        index.catch_up(&mut rebuilt, &mut remap);
+        assert_eq!(remap.len(), 50);
+        assert_eq!((remap[0], remap[1], remap[45]), (None, None, None));
+        assert_eq!(rebuilt.live_count(), 47);
+        for (old, new) in remap.iter().enumerate() {
+            if let Some(new) = new {
+                assert_eq!(rebuilt.vector(*new), &vectors[old][..]);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_vectors() {
+        let mut index = Hnsw::new(3, HnswParams::default());
+        assert!(matches!(index.insert(&[]), Err(HnswError::EmptyVector)));
+        assert!(matches!(
+            index.insert(&[1.0, 0.0]),
+            Err(HnswError::DimensionMismatch { expected: 3, actual: 2 })
+        ));
+        assert!(matches!(index.insert(&[0.0; 3]), Err(HnswError::ZeroVector)));
+        assert!(index.is_empty());
+        assert!(index.search(&[1.0, 0.0, 0.0], 5).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,283 @@
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use super::vectors::Vectors;
+use super::{Hnsw, HnswError, HnswParams, Metric, SplitMix64};
+
+const MAGIC: &[u8; 8] = b"ENGRAMHN";
+const VERSION: u32 = 1;
+/// The fixed header is padded so the vectors that follow start f32-aligned in the map.
+const HEADER_LEN: usize = 64;
+const NO_ENTRY: u64 = u64::MAX;
+
+// Layout, all little-endian:
+//   header      magic, version, metric, dimension, m, ef_construction, ef_search (u32s),
+//               node count, entry node, seed (u64s), zero padding to HEADER_LEN
+//   vectors     count * dimension f32s
+//   tombstones  count bytes
+//   links       per node: a layer-count byte, then per layer a u32 length and its node ids
+
+impl Hnsw {
+    /// Writes the index to `path`, through a temporary file renamed into place so readers
+    /// never see a partial snapshot and existing maps of the old file stay valid.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), HnswError> {
+        let path = path.as_ref();
+        let temporary = temporary_path(path);
+        let mut out = BufWriter::new(File::create(&temporary)?);
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        for value in [
+            VERSION,
+            self.params.metric.code(),
+            self.dimension as u32,
+            self.params.m as u32,
+            self.params.ef_construction as u32,
+            self.params.ef_search as u32,
+        ] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [
+            self.len() as u64,
+            self.entry.map_or(NO_ENTRY, u64::from),
+            self.params.seed,
+        ] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+        header.resize(HEADER_LEN, 0);
+        out.write_all(&header)?;
+
+        for value in self.vectors.as_slice() {
+            out.write_all(&value.to_le_bytes())?;
+        }
+        let tombstones: Vec<u8> = self.deleted.iter().map(|&deleted| deleted as u8).collect();
+        out.write_all(&tombstones)?;
+        for layers in &self.links {
+            out.write_all(&[layers.len() as u8])?;
+            for links in layers {
+                out.write_all(&(links.len() as u32).to_le_bytes())?;
+                for id in links {
+                    out.write_all(&id.to_le_bytes())?;
+                }
+            }
+        }
+
+        let file = out.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        std::fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    /// Maps a snapshot written by `save`. The vectors are read in place from the map until
+    /// the first insert; the graph links are copied into memory.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, HnswError> {
+        let file = File::open(path)?;
+        // SAFETY: snapshots are only ever replaced by renaming a new file over them, never
+        // written in place, so the mapped bytes don't change underneath us.
+        let map = unsafe { Mmap::map(&file)? };
+        let mut reader = Reader { bytes: &map, position: 0 };
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(corrupt("not an Engram HNSW snapshot"));
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(corrupt(format!("unsupported version {}", version)));
+        }
+        let metric = Metric::from_code(reader.u32()?).ok_or_else(|| corrupt("unknown metric"))?;
+        let dimension = reader.u32()? as usize;
+        let m = reader.u32()? as usize;
+        let ef_construction = reader.u32()? as usize;
+        let ef_search = reader.u32()? as usize;
+        let count = reader.u64()? as usize;
+        let entry = reader.u64()?;
+        let seed = reader.u64()?;
+        reader.position = HEADER_LEN;
+        if dimension == 0 || m < 2 {
+            return Err(corrupt("invalid parameters"));
+        }
+
+        let vectors_len = count
+            .checked_mul(dimension)
+            .ok_or_else(|| corrupt("vector count overflows"))?;
+        let vectors_offset = reader.position;
+        let vector_bytes = reader.take(vectors_len.checked_mul(4).ok_or_else(|| corrupt("vector count overflows"))?)?;
+        let in_place = cfg!(target_endian = "little")
+            && (map.as_ptr() as usize + vectors_offset).is_multiple_of(std::mem::align_of::<f32>());
+        let owned_vectors = (!in_place).then(|| {
+            vector_bytes
+                .chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect::<Vec<f32>>()
+        });
+
+        let deleted: Vec<bool> = reader.take(count)?.iter().map(|&b| b != 0).collect();
+        let mut links = Vec::with_capacity(count);
+        for _ in 0..count {
+            let layer_count = reader.take(1)?[0] as usize;
+            if layer_count == 0 {
+                return Err(corrupt("node without layers"));
+            }
+            let mut layers = Vec::with_capacity(layer_count);
+            for _ in 0..layer_count {
+                let len = reader.u32()? as usize;
+                let layer = (0..len)
+                    .map(|_| {
+                        let id = reader.u32()?;
+                        if id as usize >= count {
+                            return Err(corrupt(format!("link to missing node {}", id)));
+                        }
+                        Ok(id)
+                    })
+                    .collect::<Result<Vec<u32>, HnswError>>()?;
+                layers.push(layer);
+            }
+            links.push(layers);
+        }
+        let entry = match entry {
+            NO_ENTRY if count == 0 => None,
+            id if (id as usize) < count => Some(id as u32),
+            _ => return Err(corrupt("invalid entry node")),
+        };
+
+        let vectors = match owned_vectors {
+            Some(values) => Vectors::Owned(values),
+            None => Vectors::Mapped {
+                map,
+                offset: vectors_offset,
+                len: vectors_len,
+            },
+        };
+        let deleted_count = deleted.iter().filter(|&&deleted| deleted).count();
+        Ok(Self {
+            params: HnswParams {
+                m,
+                ef_construction,
+                ef_search,
+                metric,
+                seed,
+            },
+            dimension,
+            vectors,
+            links,
+            deleted,
+            deleted_count,
+            entry,
+            // Continue from a different point in the sequence than the original build.
+            rng: SplitMix64(seed ^ count as u64),
+        })
+    }
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+fn corrupt(message: impl Into<String>) -> HnswError {
+    HnswError::Corrupt(message.into())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], HnswError> {
+        let end = self
+            .position
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| corrupt("unexpected end of file"))?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, HnswError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, HnswError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{build, random_vectors};
+    use super::*;
+
+    fn snapshot_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("engram-hnsw-{}-{}.hnsw", name, std::process::id()))
+    }
+
+    #[test]
+    fn round_trips_through_a_mapped_snapshot() {
+        let vectors = random_vectors(200, 12, 8);
+        let mut index = build(&vectors, Metric::Cosine);
+        index.delete(7).unwrap();
+        let path = snapshot_path("round-trip");
+        index.save(&path).unwrap();
+
+        let mut loaded = Hnsw::load(&path).unwrap();
+        assert!(loaded.is_mapped());
+        assert_eq!((loaded.dimension(), loaded.len()), (12, 200));
+        assert!(loaded.is_deleted(7));
+        assert_eq!(loaded.params().metric, Metric::Cosine);
+        assert_eq!((loaded.params().m, loaded.params().ef_search), (8, 32));
+        for query in random_vectors(10, 12, 9) {
+            assert_eq!(loaded.search(&query, 10).unwrap(), index.search(&query, 10).unwrap());
+        }
+
+        // Growing the index copies the vectors out of the map.
+        let id = loaded.insert(&vectors[0]).unwrap();
+        assert!(!loaded.is_mapped());
+        assert_eq!(loaded.search(&vectors[0], 2).unwrap().len(), 2);
+        assert_eq!(id, 200);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_truncated_and_corrupt_snapshots() {
+        let index = build(&random_vectors(50, 4, 10), Metric::L2);
+        let path = snapshot_path("corrupt");
+        index.save(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+
+        let mut cases: Vec<(&str, Vec<u8>)> = vec![
+            ("unexpected end of file", bytes[..bytes.len() - 3].to_vec()),
+            ("unexpected end of file", bytes[..HEADER_LEN + 10].to_vec()),
+            ("unexpected end of file", bytes[..20].to_vec()),
+        ];
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        cases.push(("not an Engram HNSW snapshot", magic));
+        let mut version = bytes.clone();
+        version[8] = 9;
+        cases.push(("unsupported version", version));
+        let mut metric = bytes.clone();
+        metric[12] = 7;
+        cases.push(("unknown metric", metric));
+        // The first link of node 0 sits after the vectors, the tombstones, the layer count
+        // and the link count.
+        let mut link = bytes.clone();
+        let first_link = HEADER_LEN + 50 * 4 * 4 + 50 + 1 + 4;
+        link[first_link..first_link + 4].copy_from_slice(&1000u32.to_le_bytes());
+        cases.push(("link to missing node 1000", link));
+
+        for (expected, bytes) in cases {
+            std::fs::write(&path, bytes).unwrap();
+            match Hnsw::load(&path) {
+                Err(HnswError::Corrupt(message)) => assert!(message.contains(expected), "{}", message),
+                Err(e) => panic!("expected {:?}, got {}", expected, e),
+                Ok(_) => panic!("expected {:?}, but the snapshot loaded", expected),
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
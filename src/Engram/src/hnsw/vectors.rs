@@ -0,0 +1,34 @@
+use memmap2::Mmap;
+
+/// The flat array of node vectors, either owned or borrowed straight from a mapped
+/// snapshot. Mapped vectors are copied into memory the first time the index grows.
+pub(crate) enum Vectors {
+    Owned(Vec<f32>),
+    Mapped { map: Mmap, offset: usize, len: usize },
+}
+
+impl Vectors {
+    pub(crate) fn as_slice(&self) -> &[f32] {
+        match self {
+            Vectors::Owned(values) => values,
+            // SAFETY: `load` only builds this variant on little-endian targets after checking
+            // that `offset` is f32-aligned within the map and that `len` floats fit in it.
+            Vectors::Mapped { map, offset, len } => unsafe {
+                std::slice::from_raw_parts(map.as_ptr().add(*offset) as *const f32, *len)
+            },
+        }
+    }
+
+    pub(crate) fn push(&mut self, vector: &[f32]) {
+        if let Vectors::Mapped { .. } = self {
+            *self = Vectors::Owned(self.as_slice().to_vec());
+        }
+        if let Vectors::Owned(values) = self {
+            values.extend_from_slice(vector);
+        }
+    }
+
+    pub(crate) fn is_mapped(&self) -> bool {
+        matches!(self, Vectors::Mapped { .. })
+    }
+}
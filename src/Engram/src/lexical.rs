@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+
+/// BM25's term-frequency saturation and length normalization.
+#[derive(Debug, Clone, Copy)]
+pub struct Bm25Params {
+    pub k1: f32,
+    pub b: f32,
+}
+
+impl Default for Bm25Params {
+    fn default() -> Self {
+        Self { k1: 1.2, b: 0.75 }
+    }
+}
+
+/// Lowercased alphanumeric terms. Words joined by `-`, `_`, `.` or `/`, as SKUs and model
+/// numbers often are, also yield the joined word with the separators dropped, so
+/// `AB-1234`, `ab_1234` and `AB1234` all match each other.
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut terms = vec![];
+    for word in text.split(|c: char| !(c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))) {
+        let parts: Vec<String> = word
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|part| !part.is_empty())
+            .map(str::to_lowercase)
+            .collect();
+        if parts.len() > 1 {
+            terms.push(parts.concat());
+        }
+        terms.extend(parts);
+    }
+    terms
+}
+
+/// An inverted index over short documents keyed by `u32`, scored with Okapi BM25.
+#[derive(Debug, Default)]
+pub struct Bm25Index {
+    params: Bm25Params,
+    /// Per term, the documents containing it and how often.
+    postings: HashMap<String, HashMap<u32, u32>>,
+    /// Per document, its terms and its length in terms.
+    documents: HashMap<u32, (Vec<String>, u32)>,
+    total_length: u64,
+}
+
+impl Bm25Index {
+    pub fn new(params: Bm25Params) -> Self {
+        Self {
+            params,
+            ..Self::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    /// Indexes a document, replacing any previous text under the same key.
+    pub fn insert(&mut self, doc: u32, text: &str) {
+        self.remove(doc);
+        let tokens = tokenize(text);
+        let length = tokens.len() as u32;
+        let mut counts: HashMap<String, u32> = HashMap::new();
+        for token in tokens {
+            *counts.entry(token).or_default() += 1;
+        }
+        for (term, count) in &counts {
+            self.postings.entry(term.clone()).or_default().insert(doc, *count);
+        }
+        self.documents.insert(doc, (counts.into_keys().collect(), length));
+        self.total_length += length as u64;
+    }
+
+    pub fn remove(&mut self, doc: u32) -> bool {
+        let Some((terms, length)) = self.documents.remove(&doc) else {
+            return false;
+        };
+        for term in terms {
+            if let Some(docs) = self.postings.get_mut(&term) {
+                docs.remove(&doc);
+                if docs.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+        self.total_length -= length as u64;
+        true
+    }
+
+    /// Renumbers documents after the vector index is compacted, dropping those mapped to `None`.
+    pub fn remap(&mut self, remap: &[Option<u32>]) {
+        let new_key = |doc: u32| remap.get(doc as usize).copied().flatten();
+        for docs in self.postings.values_mut() {
+            *docs = docs
+                .drain()
+                .filter_map(|(doc, count)| Some((new_key(doc)?, count)))
+                .collect();
+        }
+        self.postings.retain(|_, docs| !docs.is_empty());
+        self.documents = self
+            .documents
+            .drain()
+            .filter_map(|(doc, entry)| Some((new_key(doc)?, entry)))
+            .collect();
+        self.total_length = self.documents.values().map(|(_, length)| *length as u64).sum();
+    }
+
+    /// The `k` best-scoring documents for `query` that `accept` allows, best first. Documents
+    /// sharing no term with the query are never returned.
+    pub fn search_filtered(&self, query: &str, k: usize, accept: impl Fn(u32) -> bool) -> Vec<(u32, f32)> {
+        if self.documents.is_empty() || k == 0 {
+            return vec![];
+        }
+        let mut terms = tokenize(query);
+        terms.sort();
+        terms.dedup();
+
+        let count = self.documents.len() as f32;
+        let average_length = self.total_length as f32 / count;
+        let Bm25Params { k1, b } = self.params;
+        let mut scores: HashMap<u32, f32> = HashMap::new();
+        for term in &terms {
+            let Some(docs) = self.postings.get(term) else {
+                continue;
+            };
+            let idf = (1.0 + (count - docs.len() as f32 + 0.5) / (docs.len() as f32 + 0.5)).ln();
+            for (&doc, &frequency) in docs {
+                let frequency = frequency as f32;
+                let length = self.documents[&doc].1 as f32;
+                let norm = k1 * (1.0 - b + b * length / average_length.max(1.0));
+                *scores.entry(doc).or_default() += idf * frequency * (k1 + 1.0) / (frequency + norm);
+            }
+        }
+
+        let mut hits: Vec<(u32, f32)> = scores.into_iter().filter(|&(doc, _)| accept(doc)).collect();
+        hits.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        hits.truncate(k);
+        hits
+    }
+
+    pub fn search(&self, query: &str, k: usize) -> Vec<(u32, f32)> {
+        self.search_filtered(query, k, |_| true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(documents: &[&str]) -> Bm25Index {
+        let mut index = Bm25Index::default();
+        for (doc, text) in documents.iter().enumerate() {
+            index.insert(doc as u32, text);
+        }
+        index
+    }
+
+    fn docs(hits: &[(u32, f32)]) -> Vec<u32> {
+        hits.iter().map(|&(doc, _)| doc).collect()
+    }
+
+    #[test]
+    fn splits_skus_into_parts_and_the_joined_word() {
+        assert_eq!(tokenize("AB-1234 Desk lamp"), ["ab1234", "ab", "1234", "desk", "lamp"]);
+        assert_eq!(tokenize("v2.0/beta, ok"), ["v20beta", "v2", "0", "beta", "ok"]);
+        assert!(tokenize(" -- ").is_empty());
+    }
+
+    #[test]
+    fn scores_with_bm25() {
+        let index = index(&["red lamp", "blue lamp", "red chair"]);
+        // "blue" is in one of three documents, each of the average length, so its score
+        // is just its IDF.
+        let blue = (1.0f32 + (3.0 - 1.0 + 0.5) / (1.0 + 0.5)).ln();
+        let hits = index.search("blue", 10);
+        assert_eq!(docs(&hits), [1]);
+        assert!((hits[0].1 - blue).abs() < 1e-6, "{} != {}", hits[0].1, blue);
+
+        // A term in two documents is worth less than one in a single document.
+        let lamp = index.search("lamp", 10);
+        assert_eq!(docs(&lamp), [0, 1]);
+        assert!(lamp[0].1 < blue);
+        assert_eq!(lamp[0].1, lamp[1].1);
+        assert_eq!(docs(&index.search("blue lamp", 10)), [1, 0]);
+        assert!(index.search("sofa", 10).is_empty());
+    }
+
+    #[test]
+    fn normalizes_by_document_length() {
+        let index = index(&[
+            "lamp with a long description of its shade base and cable",
+            "lamp",
+            "lamp lamp lamp with a shade",
+        ]);
+        let hits = index.search("lamp", 10);
+        assert_eq!(docs(&hits), [2, 1, 0]);
+
+        // Without length normalization, the short and the long document tie.
+        let mut flat = Bm25Index::new(Bm25Params { k1: 1.2, b: 0.0 });
+        flat.insert(0, "lamp with a long description of its shade base and cable");
+        flat.insert(1, "lamp");
+        let hits = flat.search("lamp", 10);
+        assert_eq!(hits[0].1, hits[1].1);
+    }
+
+    #[test]
+    fn ranks_an_exact_sku_match_first() {
+        let index = index(&[
+            "AB 1234 replacement bulb for lamps",
+            "Desk lamp AB-1234",
+            "AB-12345 floor lamp",
+            "Lamp shade 1234",
+        ]);
+        for query in ["ab1234", "AB-1234", "ab_1234"] {
+            assert_eq!(index.search(query, 10)[0].0, 1, "{}", query);
+        }
+    }
+
+    #[test]
+    fn removes_replaces_and_remaps_documents() {
+        let mut index = index(&["red lamp", "blue lamp", "red chair"]);
+        index.insert(1, "green sofa");
+        assert!(index.search("blue", 10).is_empty());
+        assert_eq!(docs(&index.search("sofa", 10)), [1]);
+        assert!(index.remove(0));
+        assert!(!index.remove(0));
+        assert_eq!(docs(&index.search("red", 10)), [2]);
+
+        index.remap(&[None, Some(0), Some(1)]);
+        assert_eq!(index.len(), 2);
+        assert_eq!(docs(&index.search("sofa chair", 10)), [0, 1]);
+        assert_eq!(docs(&index.search_filtered("sofa chair", 10, |doc| doc == 1)), [1]);
+        assert_eq!(index.search("sofa chair", 1).len(), 1);
+    }
+}
@@ -0,0 +1,7 @@
+// The crate is named after the service, like its siblings.
+#![allow(non_snake_case)]
+
+pub mod fusion;
+pub mod hnsw;
+pub mod lexical;
+pub mod search;
@@ -0,0 +1,76 @@
+use std::sync::{Arc, Mutex, RwLock};
+use tonic::transport::Server;
+use Engram::search::proto::ProductSearchServer;
+use Engram::search::service::ProductSearchService;
+use Engram::hnsw::HnswParams;
+use Engram::search::store::ProductStore;
+use Glyph::embedder::model::EmbeddingModel;
+
+/// Glyph's default model, so vectors produced by Glyph can be searched directly.
+const DEFAULT_TEXT_MODEL: &str = "BAAI/bge-base-en-v1.5";
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // An empty ENGRAM_TEXT_MODEL disables text queries and vectorless upserts.
+    let model_id = std::env::var("ENGRAM_TEXT_MODEL").unwrap_or_else(|_| DEFAULT_TEXT_MODEL.to_string());
+    let text_model = if model_id.is_empty() {
+        println!("No text model configured; text search is disabled.");
+        None
+    } else {
+        println!("Initializing text model {}...", model_id);
+        let model = EmbeddingModel::new(&model_id)?;
+        println!(
+            "Model loaded successfully on device: {:?}.",
+            model.device.location()
+        );
+        Some(Arc::new(Mutex::new(model)))
+    };
+
+    // With ENGRAM_SNAPSHOT_DIR set, the index is loaded from it on startup and saved back
+    // to it on shutdown.
+    let params = HnswParams::from_env()?;
+    let dimension = match std::env::var("ENGRAM_DIMENSION") {
+        Ok(value) if !value.is_empty() => Some(value.parse::<usize>()?),
+        _ => None,
+    };
+    let snapshot_dir = std::env::var("ENGRAM_SNAPSHOT_DIR").ok().filter(|dir| !dir.is_empty());
+    let store = match &snapshot_dir {
+        Some(dir) => ProductStore::load(dir, params, dimension)?,
+        None => match dimension {
+            Some(dimension) => ProductStore::with_dimension(params, dimension),
+            None => ProductStore::new(params),
+        },
+    };
+    println!("Product store holds {} products.", store.len());
+    let store = Arc::new(RwLock::new(store));
+
+    let search_service = ProductSearchService {
+        store: store.clone(),
+        text_model,
+    };
+
+    let addr = std::env::var("ENGRAM_ADDR")
+        .unwrap_or_else(|_| "[::1]:50052".to_string())
+        .parse()?;
+    println!("gRPC ProductSearchServer listening on {}", addr);
+
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    health_reporter
+        .set_serving::<ProductSearchServer<ProductSearchService>>()
+        .await;
+
+    Server::builder()
+        .add_service(ProductSearchServer::new(search_service))
+        .add_service(health_service)
+        .serve_with_shutdown(addr, async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await?;
+
+    if let Some(dir) = snapshot_dir {
+        println!("Saving product store to {}...", dir);
+        store.read().unwrap().save(&dir)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,155 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A typed payload value stored with a product.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+/// Named fields stored alongside a product for filtering, e.g. category, price, stock
+/// status and tenant.
+pub type Payload = BTreeMap<String, Value>;
+
+/// Bounds on a numeric field; unset bounds are open.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Range {
+    pub gt: Option<f64>,
+    pub gte: Option<f64>,
+    pub lt: Option<f64>,
+    pub lte: Option<f64>,
+}
+
+impl Range {
+    fn contains(&self, value: f64) -> bool {
+        self.gt.is_none_or(|bound| value > bound)
+            && self.gte.is_none_or(|bound| value >= bound)
+            && self.lt.is_none_or(|bound| value < bound)
+            && self.lte.is_none_or(|bound| value <= bound)
+    }
+}
+
+/// A boolean expression over payload fields. Conditions on a field the product lacks,
+/// or holds a value of another type in, don't match.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Equals { field: String, value: Value },
+    Range { field: String, range: Range },
+    /// Matches when the field equals any of the values.
+    AnyOf { field: String, values: Vec<Value> },
+    /// Matches when every filter does; an empty list matches everything.
+    All(Vec<Filter>),
+    /// Matches when some filter does; an empty list matches nothing.
+    Any(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    pub fn matches(&self, payload: &Payload) -> bool {
+        match self {
+            Filter::Equals { field, value } => payload.get(field) == Some(value),
+            Filter::Range { field, range } => match payload.get(field) {
+                Some(Value::Number(number)) => range.contains(*number),
+                _ => false,
+            },
+            Filter::AnyOf { field, values } => payload
+                .get(field)
+                .is_some_and(|value| values.contains(value)),
+            Filter::All(filters) => filters.iter().all(|filter| filter.matches(payload)),
+            Filter::Any(filters) => filters.iter().any(|filter| filter.matches(payload)),
+            Filter::Not(filter) => !filter.matches(payload),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload() -> Payload {
+        Payload::from([
+            ("category".to_string(), Value::String("lamps".to_string())),
+            ("price".to_string(), Value::Number(49.5)),
+            ("in_stock".to_string(), Value::Bool(true)),
+        ])
+    }
+
+    fn equals(field: &str, value: Value) -> Filter {
+        Filter::Equals {
+            field: field.to_string(),
+            value,
+        }
+    }
+
+    fn price(range: Range) -> Filter {
+        Filter::Range {
+            field: "price".to_string(),
+            range,
+        }
+    }
+
+    #[test]
+    fn matches_equality() {
+        let payload = payload();
+        assert!(equals("category", Value::String("lamps".to_string())).matches(&payload));
+        assert!(!equals("category", Value::String("chairs".to_string())).matches(&payload));
+        assert!(equals("in_stock", Value::Bool(true)).matches(&payload));
+        assert!(equals("price", Value::Number(49.5)).matches(&payload));
+        // Missing fields and values of another type don't match.
+        assert!(!equals("tenant", Value::String("acme".to_string())).matches(&payload));
+        assert!(!equals("price", Value::String("49.5".to_string())).matches(&payload));
+    }
+
+    #[test]
+    fn matches_ranges() {
+        let payload = payload();
+        let range = |gt, gte, lt, lte| price(Range { gt, gte, lt, lte });
+        assert!(range(None, None, None, None).matches(&payload));
+        assert!(range(Some(40.0), None, Some(50.0), None).matches(&payload));
+        assert!(range(None, Some(49.5), None, Some(49.5)).matches(&payload));
+        assert!(!range(Some(49.5), None, None, None).matches(&payload));
+        assert!(!range(None, None, Some(49.5), None).matches(&payload));
+        assert!(!range(None, Some(50.0), None, None).matches(&payload));
+        let category = Filter::Range {
+            field: "category".to_string(),
+            range: Range::default(),
+        };
+        assert!(!category.matches(&payload));
+    }
+
+    #[test]
+    fn matches_any_of() {
+        let payload = payload();
+        let any_of = |values: Vec<Value>| Filter::AnyOf {
+            field: "category".to_string(),
+            values,
+        };
+        let lamps = Value::String("lamps".to_string());
+        let chairs = Value::String("chairs".to_string());
+        assert!(any_of(vec![chairs.clone(), lamps]).matches(&payload));
+        assert!(!any_of(vec![chairs]).matches(&payload));
+        assert!(!any_of(vec![]).matches(&payload));
+    }
+
+    #[test]
+    fn combines_filters() {
+        let payload = payload();
+        let lamps = equals("category", Value::String("lamps".to_string()));
+        let cheap = price(Range {
+            lt: Some(20.0),
+            ..Range::default()
+        });
+        assert!(!Filter::All(vec![lamps.clone(), cheap.clone()]).matches(&payload));
+        assert!(Filter::Any(vec![lamps.clone(), cheap.clone()]).matches(&payload));
+        assert!(Filter::All(vec![lamps.clone(), Filter::Not(Box::new(cheap.clone()))]).matches(&payload));
+        assert!(!Filter::Not(Box::new(lamps)).matches(&payload));
+        assert!(Filter::All(vec![]).matches(&payload));
+        assert!(!Filter::Any(vec![]).matches(&payload));
+        // A missing field fails its condition, so its negation matches.
+        let tenant = equals("tenant", Value::String("acme".to_string()));
+        assert!(Filter::Not(Box::new(tenant)).matches(&payload));
+    }
+}
@@ -0,0 +1,4 @@
+pub mod filter;
+pub mod proto;
+pub mod service;
+pub mod store;
@@ -0,0 +1,5 @@
+pub mod product {
+    tonic::include_proto!("product");
+}
+pub use product::*;
+pub use product::product_search_server::{ProductSearch, ProductSearchServer};
@@ -0,0 +1,493 @@
+use crate::fusion::{Fusion, LegHit};
+use crate::search::filter::{Filter, Payload, Range, Value};
+use crate::search::proto::{
+    self, field_condition, filter, hybrid_search_request, payload_value, DeleteProductsRequest,
+    DeleteProductsResponse, HybridProduct, HybridSearchRequest, HybridSearchResponse, LegScore,
+    PayloadValue, Product, ProductSearch, ProductSearchRequest, ProductSearchResponse,
+    ProductTextSearchRequest, UpsertProductsRequest, UpsertProductsResponse,
+};
+use crate::search::store::{
+    product_text, HybridOptions, ProductRecord, ProductStore, StoreError, StoredProduct,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use tonic::{Request, Response, Status};
+use Glyph::embedder::model::EmbeddingModel;
+
+pub struct ProductSearchService {
+    pub store: Arc<RwLock<ProductStore>>,
+    /// Glyph's text model, run in-process for text queries and vectorless upserts.
+    pub text_model: Option<Arc<Mutex<EmbeddingModel>>>,
+}
+
+/// Results returned when a search doesn't say, and the most it may ask for.
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 1000;
+
+fn limit(requested: i32) -> usize {
+    if requested <= 0 {
+        DEFAULT_LIMIT
+    } else {
+        (requested as usize).min(MAX_LIMIT)
+    }
+}
+
+fn store_status(e: StoreError) -> Status {
+    Status::invalid_argument(e.to_string())
+}
+
+/// How deeply filter expressions may nest.
+const MAX_FILTER_DEPTH: usize = 32;
+
+fn value_from_proto(value: PayloadValue) -> Result<Value, Status> {
+    match value.kind {
+        Some(payload_value::Kind::StringValue(value)) => Ok(Value::String(value)),
+        Some(payload_value::Kind::NumberValue(value)) => Ok(Value::Number(value)),
+        Some(payload_value::Kind::BoolValue(value)) => Ok(Value::Bool(value)),
+        None => Err(Status::invalid_argument("Payload value cannot be empty")),
+    }
+}
+
+fn value_to_proto(value: &Value) -> PayloadValue {
+    let kind = match value {
+        Value::String(value) => payload_value::Kind::StringValue(value.clone()),
+        Value::Number(value) => payload_value::Kind::NumberValue(*value),
+        Value::Bool(value) => payload_value::Kind::BoolValue(*value),
+    };
+    PayloadValue { kind: Some(kind) }
+}
+
+fn payload_from_proto(payload: HashMap<String, PayloadValue>) -> Result<Payload, Status> {
+    payload
+        .into_iter()
+        .map(|(field, value)| Ok((field, value_from_proto(value)?)))
+        .collect()
+}
+
+fn filter_from_proto(filter: proto::Filter, depth: usize) -> Result<Filter, Status> {
+    if depth > MAX_FILTER_DEPTH {
+        return Err(Status::invalid_argument(format!(
+            "Filters cannot nest deeper than {} levels",
+            MAX_FILTER_DEPTH
+        )));
+    }
+    let list = |list: proto::FilterList| -> Result<Vec<Filter>, Status> {
+        list.filters
+            .into_iter()
+            .map(|filter| filter_from_proto(filter, depth + 1))
+            .collect()
+    };
+    match filter.expression {
+        Some(filter::Expression::Condition(condition)) => {
+            let field = condition.field;
+            if field.is_empty() {
+                return Err(Status::invalid_argument("Filter field cannot be empty"));
+            }
+            match condition.r#match {
+                Some(field_condition::Match::Equals(value)) => Ok(Filter::Equals {
+                    field,
+                    value: value_from_proto(value)?,
+                }),
+                Some(field_condition::Match::Range(range)) => Ok(Filter::Range {
+                    field,
+                    range: Range {
+                        gt: range.gt,
+                        gte: range.gte,
+                        lt: range.lt,
+                        lte: range.lte,
+                    },
+                }),
+                Some(field_condition::Match::AnyOf(values)) => Ok(Filter::AnyOf {
+                    field,
+                    values: values
+                        .values
+                        .into_iter()
+                        .map(value_from_proto)
+                        .collect::<Result<_, _>>()?,
+                }),
+                None => Err(Status::invalid_argument(format!(
+                    "Condition on {} has no match",
+                    field
+                ))),
+            }
+        }
+        Some(filter::Expression::All(filters)) => Ok(Filter::All(list(filters)?)),
+        Some(filter::Expression::Any(filters)) => Ok(Filter::Any(list(filters)?)),
+        Some(filter::Expression::Not(filter)) => {
+            Ok(Filter::Not(Box::new(filter_from_proto(*filter, depth + 1)?)))
+        }
+        None => Err(Status::invalid_argument("Filter has no expression")),
+    }
+}
+
+fn request_filter(filter: Option<proto::Filter>) -> Result<Option<Filter>, Status> {
+    filter.map(|filter| filter_from_proto(filter, 0)).transpose()
+}
+
+fn product(id: &str, product: &StoredProduct, score: f32) -> Product {
+    Product {
+        id: id.to_string(),
+        name: product.name.clone(),
+        description: product.description.clone(),
+        score,
+        payload: product
+            .payload
+            .iter()
+            .map(|(field, value)| (field.clone(), value_to_proto(value)))
+            .collect(),
+    }
+}
+
+fn search_response(
+    store: &ProductStore,
+    query: &[f32],
+    limit: usize,
+    filter: Option<&Filter>,
+) -> Result<ProductSearchResponse, Status> {
+    let products = store
+        .search(query, limit, filter)
+        .map_err(store_status)?
+        .into_iter()
+        .map(|(id, stored, score)| product(id, stored, score))
+        .collect();
+    Ok(ProductSearchResponse { products })
+}
+
+/// Candidates each leg of a hybrid search contributes when the request doesn't say.
+const DEFAULT_CANDIDATES: usize = 100;
+
+fn hybrid_options(request: &HybridSearchRequest) -> Result<HybridOptions, Status> {
+    let fusion = match request.fusion {
+        None => Fusion::default(),
+        Some(hybrid_search_request::Fusion::Rrf(ref rrf)) if rrf.k < 0.0 => {
+            return Err(Status::invalid_argument("RRF k cannot be negative"));
+        }
+        Some(hybrid_search_request::Fusion::Rrf(ref rrf)) if rrf.k == 0.0 => Fusion::default(),
+        Some(hybrid_search_request::Fusion::Rrf(ref rrf)) => Fusion::Rrf { k: rrf.k },
+        Some(hybrid_search_request::Fusion::Weighted(ref weighted)) => {
+            if !(0.0..=1.0).contains(&weighted.dense_weight) {
+                return Err(Status::invalid_argument("Dense weight must be between 0 and 1"));
+            }
+            Fusion::Weighted {
+                dense_weight: weighted.dense_weight,
+            }
+        }
+    };
+    let candidates = if request.candidates <= 0 {
+        DEFAULT_CANDIDATES
+    } else {
+        (request.candidates as usize).min(MAX_LIMIT)
+    };
+    Ok(HybridOptions { fusion, candidates })
+}
+
+fn leg_score(leg: Option<LegHit>) -> Option<LegScore> {
+    leg.map(|leg| LegScore {
+        rank: leg.rank as i32,
+        score: leg.score,
+    })
+}
+
+impl ProductSearchService {
+    /// Runs `f` on the blocking pool under the store's read lock, so graph traversal doesn't
+    /// stall the async runtime.
+    async fn read_store<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&ProductStore) -> T + Send + 'static,
+    ) -> Result<T, Status> {
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || f(&store.read().unwrap()))
+            .await
+            .map_err(|e| Status::internal(format!("Task join error: {}", e)))
+    }
+
+    /// Runs `f` on the blocking pool under the store's write lock, then starts a compaction
+    /// if the change left enough tombstones behind.
+    async fn write_store<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut ProductStore) -> T + Send + 'static,
+    ) -> Result<T, Status> {
+        let store = self.store.clone();
+        let (result, rebuild) = tokio::task::spawn_blocking(move || {
+            let mut store = store.write().unwrap();
+            let result = f(&mut store);
+            (result, store.start_compaction())
+        })
+        .await
+        .map_err(|e| Status::internal(format!("Task join error: {}", e)))?;
+
+        // The new graph is built without holding the store, so searches and writes carry
+        // on; only swapping it in takes the write lock.
+        if let Some(rebuild) = rebuild {
+            let store = self.store.clone();
+            tokio::task::spawn_blocking(move || {
+                let (rebuilt, remap) = rebuild.build();
+                store.write().unwrap().finish_compaction(rebuilt, remap);
+            });
+        }
+        Ok(result)
+    }
+
+    fn text_model(&self) -> Result<Arc<Mutex<EmbeddingModel>>, Status> {
+        self.text_model
+            .clone()
+            .ok_or_else(|| Status::failed_precondition("No text model is loaded on this server"))
+    }
+
+    /// Embeds a single query with the text model, off the async runtime.
+    async fn embed_query(&self, query: String) -> Result<Vec<f32>, Status> {
+        let model = self.text_model()?;
+        tokio::task::spawn_blocking(move || model.lock().unwrap().embed_batch(&[query]))
+            .await
+            .map_err(|e| Status::internal(format!("Task join error: {}", e)))?
+            .map_err(|e| Status::internal(format!("Embedding generation failed: {}", e)))?
+            .pop()
+            .ok_or_else(|| Status::internal("Model returned no embedding"))
+    }
+}
+
+#[tonic::async_trait]
+impl ProductSearch for ProductSearchService {
+    async fn search_products(
+        &self,
+        request: Request<ProductSearchRequest>,
+    ) -> Result<Response<ProductSearchResponse>, Status> {
+        let request = request.into_inner();
+        let filter = request_filter(request.filter)?;
+        let response = self
+            .read_store(move |store| {
+                search_response(store, &request.vector, limit(request.limit), filter.as_ref())
+            })
+            .await??;
+        Ok(Response::new(response))
+    }
+
+    async fn search_products_by_text(
+        &self,
+        request: Request<ProductTextSearchRequest>,
+    ) -> Result<Response<ProductSearchResponse>, Status> {
+        let request = request.into_inner();
+        if request.query.is_empty() {
+            return Err(Status::invalid_argument("Query cannot be empty"));
+        }
+
+        let filter = request_filter(request.filter)?;
+        let embedding = self.embed_query(request.query).await?;
+        let response = self
+            .read_store(move |store| {
+                search_response(store, &embedding, limit(request.limit), filter.as_ref())
+            })
+            .await??;
+        Ok(Response::new(response))
+    }
+
+    async fn hybrid_search_products(
+        &self,
+        request: Request<HybridSearchRequest>,
+    ) -> Result<Response<HybridSearchResponse>, Status> {
+        let request = request.into_inner();
+        if request.query.is_empty() && request.vector.is_empty() {
+            return Err(Status::invalid_argument("A query or a vector is required"));
+        }
+        let options = hybrid_options(&request)?;
+        let filter = request_filter(request.filter)?;
+        let vector = if request.vector.is_empty() {
+            self.embed_query(request.query.clone()).await?
+        } else {
+            request.vector
+        };
+
+        let response = self
+            .read_store(move |store| {
+                let products = store
+                    .hybrid_search(&vector, &request.query, limit(request.limit), options, filter.as_ref())
+                    .map_err(store_status)?
+                    .into_iter()
+                    .map(|hit| HybridProduct {
+                        product: Some(product(hit.id, hit.product, hit.score)),
+                        dense: leg_score(hit.dense),
+                        lexical: leg_score(hit.lexical),
+                    })
+                    .collect();
+                Ok::<_, Status>(HybridSearchResponse { products })
+            })
+            .await??;
+        Ok(Response::new(response))
+    }
+
+    async fn upsert_products(
+        &self,
+        request: Request<UpsertProductsRequest>,
+    ) -> Result<Response<UpsertProductsResponse>, Status> {
+        let products = request.into_inner().products;
+        if let Some(product) = products.iter().find(|p| p.id.is_empty()) {
+            return Err(Status::invalid_argument(format!(
+                "Product id cannot be empty (name: {:?})",
+                product.name
+            )));
+        }
+
+        // Embed the products that came without a vector in one batch.
+        let missing: Vec<String> = products
+            .iter()
+            .filter(|p| p.vector.is_empty())
+            .map(|p| product_text(&p.name, &p.description))
+            .collect();
+        if missing.iter().any(String::is_empty) {
+            return Err(Status::invalid_argument(
+                "Products without a vector need a name or description to embed",
+            ));
+        }
+        let mut embeddings = if missing.is_empty() {
+            vec![].into_iter()
+        } else {
+            let model = self.text_model()?;
+            tokio::task::spawn_blocking(move || model.lock().unwrap().embed_batch(&missing))
+                .await
+                .map_err(|e| Status::internal(format!("Task join error: {}", e)))?
+                .map_err(|e| Status::internal(format!("Embedding generation failed: {}", e)))?
+                .into_iter()
+        };
+
+        let records = products
+            .into_iter()
+            .map(|p| {
+                let vector = if p.vector.is_empty() {
+                    embeddings.next().unwrap_or_default()
+                } else {
+                    p.vector
+                };
+                let record = ProductRecord {
+                    name: p.name,
+                    description: p.description,
+                    vector,
+                    payload: payload_from_proto(p.payload)?,
+                };
+                Ok((p.id, record))
+            })
+            .collect::<Result<_, Status>>()?;
+        let upserted = self
+            .write_store(move |store| store.upsert(records))
+            .await?
+            .map_err(store_status)?;
+
+        Ok(Response::new(UpsertProductsResponse {
+            upserted: upserted as i32,
+        }))
+    }
+
+    async fn delete_products(
+        &self,
+        request: Request<DeleteProductsRequest>,
+    ) -> Result<Response<DeleteProductsResponse>, Status> {
+        let ids = request.into_inner().ids;
+        let deleted = self.write_store(move |store| store.delete(&ids)).await?;
+        Ok(Response::new(DeleteProductsResponse {
+            deleted: deleted as i32,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::{FieldCondition, FilterList, NumberRange, PayloadValues};
+
+    fn string(value: &str) -> PayloadValue {
+        PayloadValue {
+            kind: Some(payload_value::Kind::StringValue(value.to_string())),
+        }
+    }
+
+    fn condition(field: &str, r#match: field_condition::Match) -> proto::Filter {
+        proto::Filter {
+            expression: Some(filter::Expression::Condition(FieldCondition {
+                field: field.to_string(),
+                r#match: Some(r#match),
+            })),
+        }
+    }
+
+    fn not(filter: proto::Filter) -> proto::Filter {
+        proto::Filter {
+            expression: Some(filter::Expression::Not(Box::new(filter))),
+        }
+    }
+
+    #[test]
+    fn converts_filters() {
+        let range = NumberRange {
+            gte: Some(10.0),
+            lt: Some(20.0),
+            ..NumberRange::default()
+        };
+        let filter = proto::Filter {
+            expression: Some(filter::Expression::All(FilterList {
+                filters: vec![
+                    condition("category", field_condition::Match::Equals(string("lamps"))),
+                    condition("price", field_condition::Match::Range(range)),
+                    not(proto::Filter {
+                        expression: Some(filter::Expression::Any(FilterList {
+                            filters: vec![condition(
+                                "tenant",
+                                field_condition::Match::AnyOf(PayloadValues {
+                                    values: vec![string("a"), string("b")],
+                                }),
+                            )],
+                        })),
+                    }),
+                ],
+            })),
+        };
+        let expected = Filter::All(vec![
+            Filter::Equals {
+                field: "category".to_string(),
+                value: Value::String("lamps".to_string()),
+            },
+            Filter::Range {
+                field: "price".to_string(),
+                range: Range {
+                    gte: Some(10.0),
+                    lt: Some(20.0),
+                    ..Range::default()
+                },
+            },
+            Filter::Not(Box::new(Filter::Any(vec![Filter::AnyOf {
+                field: "tenant".to_string(),
+                values: vec![Value::String("a".to_string()), Value::String("b".to_string())],
+            }]))),
+        ]);
+        assert_eq!(request_filter(Some(filter)).unwrap(), Some(expected));
+        assert_eq!(request_filter(None).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_incomplete_filters() {
+        let empty_field = condition("", field_condition::Match::Equals(string("lamps")));
+        let no_match = proto::Filter {
+            expression: Some(filter::Expression::Condition(FieldCondition {
+                field: "category".to_string(),
+                r#match: None,
+            })),
+        };
+        let empty_value = condition("category", field_condition::Match::Equals(PayloadValue { kind: None }));
+        for filter in [empty_field, no_match, empty_value, proto::Filter { expression: None }] {
+            let status = request_filter(Some(filter)).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        }
+    }
+
+    #[test]
+    fn caps_filter_depth() {
+        let nested = |depth: usize| {
+            (0..depth).fold(
+                condition("category", field_condition::Match::Equals(string("lamps"))),
+                |filter, _| not(filter),
+            )
+        };
+        assert!(request_filter(Some(nested(MAX_FILTER_DEPTH))).is_ok());
+        let status = request_filter(Some(nested(MAX_FILTER_DEPTH + 1))).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(status.message().contains("32 levels"), "{}", status.message());
+    }
+}
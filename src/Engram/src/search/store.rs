@@ -0,0 +1,743 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::fusion::{fuse, Fusion, LegHit};
+use crate::hnsw::{Hnsw, HnswError, HnswParams, Rebuild};
+use crate::lexical::Bm25Index;
+use crate::search::filter::{Filter, Payload};
+
+/// A product as sent by clients, with the vector it is indexed under.
+#[derive(Debug, Clone)]
+pub struct ProductRecord {
+    pub name: String,
+    pub description: String,
+    pub vector: Vec<f32>,
+    pub payload: Payload,
+}
+
+/// The text a product is embedded from when it arrives without a vector, and that the
+/// lexical index holds for it.
+pub fn product_text(name: &str, description: &str) -> String {
+    match (name.is_empty(), description.is_empty()) {
+        (false, false) => format!("{}\n{}", name, description),
+        (false, true) => name.to_string(),
+        _ => description.to_string(),
+    }
+}
+
+/// A product as stored; its vector lives in the index under `node`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredProduct {
+    pub name: String,
+    pub description: String,
+    #[serde(default)]
+    pub payload: Payload,
+    node: u32,
+}
+
+/// Why a vector or snapshot was rejected.
+#[derive(Debug)]
+pub enum StoreError {
+    Index(HnswError),
+    Snapshot(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Index(e) => write!(f, "{}", e),
+            StoreError::Snapshot(e) => write!(f, "Product snapshot is invalid: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<HnswError> for StoreError {
+    fn from(e: HnswError) -> Self {
+        StoreError::Index(e)
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(e: std::io::Error) -> Self {
+        StoreError::Index(HnswError::Io(e))
+    }
+}
+
+/// Options for a hybrid search.
+#[derive(Debug, Clone, Copy)]
+pub struct HybridOptions {
+    pub fusion: Fusion,
+    /// How many candidates each leg contributes to the fusion.
+    pub candidates: usize,
+}
+
+/// A hybrid search result, with where it placed in each leg.
+pub struct HybridHit<'a> {
+    pub id: &'a str,
+    pub product: &'a StoredProduct,
+    pub score: f32,
+    pub dense: Option<LegHit>,
+    pub lexical: Option<LegHit>,
+}
+
+/// A snapshot directory holds numbered generation directories, each with an index file and
+/// a product file, and a file naming the current generation.
+const CURRENT_FILE: &str = "CURRENT";
+const GENERATION_PREFIX: &str = "generation-";
+const INDEX_FILE: &str = "index.hnsw";
+const PRODUCTS_FILE: &str = "products.jsonl";
+
+#[derive(Serialize, Deserialize)]
+struct ProductLine {
+    id: String,
+    #[serde(flatten)]
+    product: StoredProduct,
+}
+
+/// Products keyed by id and searched through an HNSW index, with their text in a BM25
+/// index keyed by the same node ids. Unless a dimension is configured, the first vector
+/// stored fixes it for all later ones.
+pub struct ProductStore {
+    params: HnswParams,
+    /// The dimension every vector must have, if configured rather than fixed by the first
+    /// upsert.
+    dimension: Option<usize>,
+    /// Built on the first upsert, once the dimension is known.
+    index: Option<Hnsw>,
+    lexical: Bm25Index,
+    products: HashMap<String, StoredProduct>,
+    /// The product id of every live node.
+    ids: Vec<Option<String>>,
+    /// The share of tombstones, relative to live nodes, that triggers a compaction.
+    compact_ratio: f32,
+    /// Set between `start_compaction` and `finish_compaction`.
+    compacting: AtomicBool,
+}
+
+impl Default for ProductStore {
+    fn default() -> Self {
+        Self::new(HnswParams::default())
+    }
+}
+
+impl ProductStore {
+    pub fn new(params: HnswParams) -> Self {
+        Self {
+            params,
+            dimension: None,
+            index: None,
+            lexical: Bm25Index::default(),
+            products: HashMap::new(),
+            ids: vec![],
+            compact_ratio: 0.25,
+            compacting: AtomicBool::new(false),
+        }
+    }
+
+    /// A store whose vectors must all have `dimension` components.
+    pub fn with_dimension(params: HnswParams, dimension: usize) -> Self {
+        Self {
+            dimension: Some(dimension),
+            ..Self::new(params)
+        }
+    }
+
+    /// The dimension of the stored vectors, once known.
+    pub fn dimension(&self) -> Option<usize> {
+        self.index.as_ref().map(Hnsw::dimension).or(self.dimension)
+    }
+
+    pub fn len(&self) -> usize {
+        self.products.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.products.is_empty()
+    }
+
+    /// Inserts or replaces a batch of products. Nothing is stored if any vector is rejected.
+    pub fn upsert(&mut self, products: Vec<(String, ProductRecord)>) -> Result<usize, StoreError> {
+        let Some(first) = products.first() else {
+            return Ok(0);
+        };
+        // Validate against a fresh index if there is none yet, so a rejected batch doesn't
+        // fix the dimension.
+        let dimension = self.dimension.unwrap_or(first.1.vector.len());
+        let fresh;
+        let index = match &self.index {
+            Some(index) => index,
+            None => {
+                fresh = Hnsw::new(dimension, self.params.clone());
+                &fresh
+            }
+        };
+        for (_, product) in &products {
+            index.prepare(&product.vector)?;
+        }
+
+        let params = &self.params;
+        let index = self
+            .index
+            .get_or_insert_with(|| Hnsw::new(dimension, params.clone()));
+
+        let upserted = products.len();
+        for (id, product) in products {
+            let node = index.insert(&product.vector)?;
+            if let Some(previous) = self.products.get(&id) {
+                index.delete(previous.node)?;
+                self.lexical.remove(previous.node);
+                self.ids[previous.node as usize] = None;
+            }
+            self.lexical.insert(node, &product_text(&product.name, &product.description));
+            self.ids.push(Some(id.clone()));
+            let stored = StoredProduct {
+                name: product.name,
+                description: product.description,
+                payload: product.payload,
+                node,
+            };
+            self.products.insert(id, stored);
+        }
+        Ok(upserted)
+    }
+
+    /// Removes products by id, returning how many were present.
+    pub fn delete(&mut self, ids: &[String]) -> usize {
+        let Some(index) = self.index.as_mut() else {
+            return 0;
+        };
+        let mut deleted = 0;
+        for id in ids {
+            if let Some(product) = self.products.remove(id) {
+                // The node belongs to a stored product, so it is known to the index.
+                let _ = index.delete(product.node);
+                self.lexical.remove(product.node);
+                self.ids[product.node as usize] = None;
+                deleted += 1;
+            }
+        }
+        deleted
+    }
+
+    /// Whether the product at a node passes `filter`, checked while the indexes are traversed
+    /// so filtered searches still fill their limit.
+    fn accepts(&self, node: u32, filter: Option<&Filter>) -> bool {
+        let Some(id) = self.ids[node as usize].as_deref() else {
+            return false;
+        };
+        filter.is_none_or(|filter| filter.matches(&self.products[id].payload))
+    }
+
+    /// The `limit` products most similar to `query` that pass `filter`, best first.
+    pub fn search(
+        &self,
+        query: &[f32],
+        limit: usize,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<(&str, &StoredProduct, f32)>, StoreError> {
+        let Some(index) = self.index.as_ref() else {
+            return Ok(vec![]);
+        };
+        let metric = index.params().metric;
+        Ok(index
+            .search_filtered(query, limit, |node| self.accepts(node, filter))?
+            .into_iter()
+            .filter_map(|neighbor| {
+                let id = self.ids[neighbor.id as usize].as_deref()?;
+                Some((id, &self.products[id], metric.score(neighbor.distance)))
+            })
+            .collect())
+    }
+
+    /// Runs a dense search for `vector` and a BM25 search for `text` side by side and fuses
+    /// their rankings. Either query may be empty to skip its leg. Both legs only retrieve
+    /// products that pass `filter`.
+    pub fn hybrid_search(
+        &self,
+        vector: &[f32],
+        text: &str,
+        limit: usize,
+        options: HybridOptions,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<HybridHit<'_>>, StoreError> {
+        let Some(index) = self.index.as_ref() else {
+            return Ok(vec![]);
+        };
+        let candidates = options.candidates.max(limit);
+        let metric = index.params().metric;
+        let accept = |node| self.accepts(node, filter);
+        let (dense, lexical) = std::thread::scope(|scope| {
+            let lexical = scope.spawn(|| self.lexical.search_filtered(text, candidates, accept));
+            let dense = if vector.is_empty() {
+                Ok(vec![])
+            } else {
+                index.search_filtered(vector, candidates, accept)
+            };
+            (dense, lexical.join().expect("lexical search panicked"))
+        });
+        let dense: Vec<(u32, f32)> = dense?
+            .into_iter()
+            .map(|neighbor| (neighbor.id, metric.score(neighbor.distance)))
+            .collect();
+
+        Ok(fuse(&dense, &lexical, options.fusion, limit)
+            .into_iter()
+            .filter_map(|hit| {
+                let id = self.ids[hit.doc as usize].as_deref()?;
+                Some(HybridHit {
+                    id,
+                    product: &self.products[id],
+                    score: hit.score,
+                    dense: hit.dense,
+                    lexical: hit.lexical,
+                })
+            })
+            .collect())
+    }
+
+    /// Whether tombstones make up a large enough share of the index to rebuild it.
+    pub fn needs_compaction(&self) -> bool {
+        self.index.as_ref().is_some_and(|index| {
+            index.deleted_count() as f32 > index.live_count() as f32 * self.compact_ratio
+        })
+    }
+
+    /// Copies out the live vectors for a compaction if one is needed and none is under way.
+    /// The caller builds the new graph from them without holding the store, then hands it to
+    /// `finish_compaction`.
+    pub fn start_compaction(&self) -> Option<Rebuild> {
+        if !self.needs_compaction() || self.compacting.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        self.index.as_ref().map(Hnsw::rebuild)
+    }
+
+    /// Swaps in a graph built from `start_compaction`'s copy, after applying the upserts and
+    /// deletes made since.
+    pub fn finish_compaction(&mut self, mut rebuilt: Hnsw, mut remap: Vec<Option<u32>>) {
+        self.compacting.store(false, Ordering::Release);
+        let Some(index) = self.index.as_mut() else {
+            return;
+        };
+        index.catch_up(&mut rebuilt, &mut remap);
+        *index = rebuilt;
+        self.lexical.remap(&remap);
+        let mut ids = vec![None; index.len()];
+        for (old, new) in remap.into_iter().enumerate() {
+            let (Some(new), Some(id)) = (new, self.ids[old].take()) else {
+                continue;
+            };
+            if let Some(product) = self.products.get_mut(&id) {
+                product.node = new;
+            }
+            ids[new as usize] = Some(id);
+        }
+        self.ids = ids;
+    }
+
+    /// Compacts the index in place if needed, for callers that hold the store exclusively.
+    pub fn compact_if_needed(&mut self) {
+        if let Some(rebuild) = self.start_compaction() {
+            let (rebuilt, remap) = rebuild.build();
+            self.finish_compaction(rebuilt, remap);
+        }
+    }
+
+    /// Writes a new snapshot generation into `dir`, which is created if needed. The products
+    /// and the index go into a fresh generation directory that only becomes current once
+    /// both are complete, when the `CURRENT` file naming it is replaced by a rename. A crash
+    /// mid-save leaves the previous generation current; older generations are removed after
+    /// the switch.
+    pub fn save(&self, dir: impl AsRef<Path>) -> Result<(), StoreError> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let Some(index) = self.index.as_ref() else {
+            return Ok(());
+        };
+
+        let number = match current_generation(dir)? {
+            Some(name) => generation_number(&name)
+                .ok_or_else(|| StoreError::Snapshot(format!("unexpected generation {}", name)))?,
+            None => 0,
+        } + 1;
+        let name = format!("{}{}", GENERATION_PREFIX, number);
+        // A leftover from a save that crashed before its switch.
+        let temporary = dir.join(format!("{}.tmp", name));
+        if temporary.exists() {
+            std::fs::remove_dir_all(&temporary)?;
+        }
+        std::fs::create_dir(&temporary)?;
+
+        let mut out = BufWriter::new(File::create(temporary.join(PRODUCTS_FILE))?);
+        for (id, product) in &self.products {
+            let line = ProductLine {
+                id: id.clone(),
+                product: product.clone(),
+            };
+            serde_json::to_writer(&mut out, &line).map_err(|e| StoreError::Snapshot(e.to_string()))?;
+            out.write_all(b"\n")?;
+        }
+        let file = out.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        index.save(temporary.join(INDEX_FILE))?;
+        sync_dir(&temporary)?;
+        std::fs::rename(&temporary, dir.join(&name))?;
+
+        let pointer = dir.join(format!("{}.tmp", CURRENT_FILE));
+        let mut file = File::create(&pointer)?;
+        file.write_all(name.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&pointer, dir.join(CURRENT_FILE))?;
+        sync_dir(dir)?;
+
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let old = path
+                .file_name()
+                .and_then(|file| file.to_str())
+                .is_some_and(|file| file != name && generation_number(file).is_some());
+            if old {
+                // Best effort: a loaded store may still map the old index, which some
+                // platforms refuse to delete. The next save tries again.
+                let _ = std::fs::remove_dir_all(&path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the current generation saved with `save`, mapping its index. An empty or
+    /// missing directory yields an empty store with the given parameters. The snapshot must
+    /// use the configured metric and, if one is given, dimension; the configured `ef_search`
+    /// replaces the saved one, while the graph keeps the `m` it was built with.
+    pub fn load(
+        dir: impl AsRef<Path>,
+        params: HnswParams,
+        dimension: Option<usize>,
+    ) -> Result<Self, StoreError> {
+        let dir = dir.as_ref();
+        let mut store = Self::new(params);
+        store.dimension = dimension;
+        let Some(name) = current_generation(dir)? else {
+            return Ok(store);
+        };
+        if generation_number(&name).is_none() {
+            return Err(StoreError::Snapshot(format!("unexpected generation {}", name)));
+        }
+        let generation = dir.join(name);
+        let mut index = Hnsw::load(generation.join(INDEX_FILE))?;
+        if index.params().metric != store.params.metric {
+            return Err(StoreError::Snapshot(format!(
+                "it uses {:?} distance but {:?} is configured",
+                index.params().metric,
+                store.params.metric
+            )));
+        }
+        if let Some(dimension) = dimension.filter(|&dimension| dimension != index.dimension()) {
+            return Err(StoreError::Snapshot(format!(
+                "it holds {}-dimensional vectors but {} dimensions are configured",
+                index.dimension(),
+                dimension
+            )));
+        }
+        index.set_ef_search(store.params.ef_search);
+        store.ids = vec![None; index.len()];
+
+        for line in BufReader::new(File::open(generation.join(PRODUCTS_FILE))?).lines() {
+            let line: ProductLine =
+                serde_json::from_str(&line?).map_err(|e| StoreError::Snapshot(e.to_string()))?;
+            let node = line.product.node;
+            let usable = !index.is_deleted(node)
+                && store.ids.get(node as usize).is_some_and(Option::is_none);
+            if !usable {
+                return Err(StoreError::Snapshot(format!(
+                    "product {} points at an unusable node {}",
+                    line.id, node
+                )));
+            }
+            store.ids[node as usize] = Some(line.id.clone());
+            let text = product_text(&line.product.name, &line.product.description);
+            store.lexical.insert(node, &text);
+            store.products.insert(line.id, line.product);
+        }
+        if store.products.len() != index.live_count() {
+            return Err(StoreError::Snapshot(format!(
+                "{} products for {} live nodes",
+                store.products.len(),
+                index.live_count()
+            )));
+        }
+        store.index = Some(index);
+        Ok(store)
+    }
+}
+
+/// The generation named by `dir`'s `CURRENT` file, if it has one.
+fn current_generation(dir: &Path) -> Result<Option<String>, StoreError> {
+    match std::fs::read_to_string(dir.join(CURRENT_FILE)) {
+        Ok(name) => Ok(Some(name.trim().to_string())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The number of a generation directory name, or `None` for any other name.
+fn generation_number(name: &str) -> Option<u64> {
+    name.strip_prefix(GENERATION_PREFIX)?.parse().ok()
+}
+
+/// Flushes a directory's entries, so renames into it survive a crash.
+fn sync_dir(dir: &Path) -> Result<(), StoreError> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hnsw::Metric;
+    use crate::search::filter::Value;
+
+    fn product(name: &str, vector: Vec<f32>) -> ProductRecord {
+        ProductRecord {
+            name: name.to_string(),
+            description: String::new(),
+            vector,
+            payload: Payload::new(),
+        }
+    }
+
+    fn options() -> HybridOptions {
+        HybridOptions {
+            fusion: Fusion::default(),
+            candidates: 10,
+        }
+    }
+
+    fn snapshot_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("engram-store-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn two_products() -> ProductStore {
+        let mut store = ProductStore::new(HnswParams::default());
+        store
+            .upsert(vec![
+                ("a".to_string(), product("lamp", vec![1.0, 0.0])),
+                ("b".to_string(), product("chair", vec![0.0, 1.0])),
+            ])
+            .unwrap();
+        store
+    }
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut files: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn saves_whole_generations() {
+        let dir = snapshot_dir("generations");
+        let store = two_products();
+        store.save(&dir).unwrap();
+        // Saving again switches to a new generation and drops the old one.
+        store.save(&dir).unwrap();
+        assert_eq!(entries(&dir), [CURRENT_FILE, "generation-2"]);
+        assert_eq!(entries(&dir.join("generation-2")), [INDEX_FILE, PRODUCTS_FILE]);
+
+        let loaded = ProductStore::load(&dir, HnswParams::default(), None).unwrap();
+        assert_eq!(loaded.len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_crashed_save_leaves_the_previous_generation_current() {
+        let dir = snapshot_dir("crash");
+        let mut store = two_products();
+        store.save(&dir).unwrap();
+
+        // A save that died after writing part of its products, before the switch.
+        let partial = dir.join("generation-2.tmp");
+        std::fs::create_dir(&partial).unwrap();
+        std::fs::write(partial.join(PRODUCTS_FILE), "{\"id\":\"c\",\"na").unwrap();
+        let loaded = ProductStore::load(&dir, HnswParams::default(), None).unwrap();
+        assert_eq!(loaded.len(), 2);
+
+        store.delete(&["a".to_string()]);
+        store.save(&dir).unwrap();
+        assert_eq!(entries(&dir), [CURRENT_FILE, "generation-2"]);
+        let loaded = ProductStore::load(&dir, HnswParams::default(), None).unwrap();
+        assert_eq!(loaded.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_products_pointing_outside_the_index() {
+        let dir = snapshot_dir("bounds");
+        two_products().save(&dir).unwrap();
+        let products = dir.join("generation-1").join(PRODUCTS_FILE);
+        let text = std::fs::read_to_string(&products).unwrap();
+        std::fs::write(&products, text.replace("\"node\":1", "\"node\":99")).unwrap();
+
+        let error = ProductStore::load(&dir, HnswParams::default(), None).err().unwrap();
+        assert!(matches!(error, StoreError::Snapshot(_)), "{}", error);
+        assert!(error.to_string().contains("node 99"), "{}", error);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn loads_with_the_configured_parameters() {
+        let dir = snapshot_dir("params");
+        two_products().save(&dir).unwrap();
+
+        let params = HnswParams {
+            ef_search: 7,
+            ..HnswParams::default()
+        };
+        let loaded = ProductStore::load(&dir, params, Some(2)).unwrap();
+        assert_eq!(loaded.index.as_ref().unwrap().params().ef_search, 7);
+        assert_eq!(loaded.dimension(), Some(2));
+
+        let l2 = HnswParams {
+            metric: Metric::L2,
+            ..HnswParams::default()
+        };
+        let error = ProductStore::load(&dir, l2, None).err().unwrap();
+        assert!(error.to_string().contains("L2"), "{}", error);
+        let error = ProductStore::load(&dir, HnswParams::default(), Some(3)).err().unwrap();
+        assert!(error.to_string().contains("3 dimensions"), "{}", error);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_configured_dimension_applies_before_the_first_upsert() {
+        let mut store = ProductStore::with_dimension(HnswParams::default(), 3);
+        assert!(store.upsert(vec![("a".to_string(), product("lamp", vec![1.0, 0.0]))]).is_err());
+        assert!(store.is_empty());
+        store.upsert(vec![("a".to_string(), product("lamp", vec![1.0, 0.0, 0.0]))]).unwrap();
+        assert_eq!(store.dimension(), Some(3));
+    }
+
+    #[test]
+    fn compaction_carries_over_changes_made_while_it_builds() {
+        let mut store = ProductStore::new(HnswParams::default());
+        let products = (0..8)
+            .map(|i| {
+                let angle = i as f32 * 0.2;
+                (format!("p{}", i), product(&format!("item {}", i), vec![angle.cos(), angle.sin()]))
+            })
+            .collect();
+        store.upsert(products).unwrap();
+        store.delete(&["p0".to_string(), "p1".to_string(), "p2".to_string()]);
+        let rebuild = store.start_compaction().unwrap();
+        // Only one compaction runs at a time.
+        assert!(store.start_compaction().is_none());
+
+        store.delete(&["p3".to_string()]);
+        store
+            .upsert(vec![
+                ("p4".to_string(), product("moved", vec![-1.0, 0.0])),
+                ("new".to_string(), product("new", vec![0.0, -1.0])),
+            ])
+            .unwrap();
+        let (rebuilt, remap) = rebuild.build();
+        store.finish_compaction(rebuilt, remap);
+
+        let index = store.index.as_ref().unwrap();
+        assert_eq!(index.live_count(), store.len());
+        assert_eq!(store.len(), 5);
+        for (id, product) in &store.products {
+            assert_eq!(store.ids[product.node as usize].as_deref(), Some(id.as_str()));
+        }
+        let hits = store.search(&[-1.0, 0.0], 1, None).unwrap();
+        assert_eq!((hits[0].0, hits[0].1.name.as_str()), ("p4", "moved"));
+        let hits = store.hybrid_search(&[], "new", 5, options(), None).unwrap();
+        assert_eq!(hits.iter().map(|hit| hit.id).collect::<Vec<_>>(), ["new"]);
+        // The deletes made during the build are tombstones in the new graph.
+        assert_eq!(store.index.as_ref().unwrap().deleted_count(), 2);
+    }
+
+    #[test]
+    fn hybrid_hits_report_both_legs() {
+        let mut store = ProductStore::new(HnswParams::default());
+        store
+            .upsert(vec![
+                ("sku".to_string(), product("Desk lamp AB-1234", vec![0.0, 1.0])),
+                ("near".to_string(), product("Floor lamp", vec![1.0, 0.1])),
+                ("far".to_string(), product("Chair", vec![-1.0, 0.0])),
+            ])
+            .unwrap();
+        let hits = store.hybrid_search(&[1.0, 0.0], "ab1234", 3, options(), None).unwrap();
+        let ids: Vec<&str> = hits.iter().map(|hit| hit.id).collect();
+        // Second in the dense leg and first in the lexical one beats first in the dense leg alone.
+        assert_eq!(ids, ["sku", "near", "far"]);
+
+        let sku = &hits[0];
+        assert_eq!(sku.dense.map(|leg| leg.rank), Some(2));
+        assert!(sku.dense.unwrap().score.abs() < 1e-6);
+        assert_eq!(sku.lexical.map(|leg| leg.rank), Some(1));
+        assert!(sku.lexical.unwrap().score > 0.0);
+        assert_eq!(hits[1].lexical, None);
+        assert_eq!(hits[1].dense.map(|leg| leg.rank), Some(1));
+
+        // Without a vector, only the lexical leg runs.
+        let hits = store.hybrid_search(&[], "ab1234", 3, options(), None).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].id, hits[0].dense), ("sku", None));
+    }
+
+    #[test]
+    fn selective_filters_still_fill_the_limit() {
+        let mut store = ProductStore::new(HnswParams::default());
+        let products = (0..2000)
+            .map(|i| {
+                let angle = i as f32 * 0.01;
+                let mut record = product(&format!("lamp {}", i), vec![angle.cos(), angle.sin(), 0.5]);
+                // Three percent of the products are on sale.
+                let sale = i % 33 == 0;
+                record.payload.insert("sale".to_string(), Value::Bool(sale));
+                (format!("p{}", i), record)
+            })
+            .collect();
+        store.upsert(products).unwrap();
+        let on_sale = Filter::Equals {
+            field: "sale".to_string(),
+            value: Value::Bool(true),
+        };
+        let is_on_sale = |product: &StoredProduct| product.payload["sale"] == Value::Bool(true);
+
+        let hits = store.search(&[1.0, 0.0, 0.5], 25, Some(&on_sale)).unwrap();
+        assert_eq!(hits.len(), 25);
+        assert!(hits.iter().all(|&(_, product, _)| is_on_sale(product)));
+        // The dense leg returns the same products an exact filtered search would.
+        let index = store.index.as_ref().unwrap();
+        let exact: Vec<&str> = index
+            .exact_search(&[1.0, 0.0, 0.5], 2000)
+            .unwrap()
+            .into_iter()
+            .filter_map(|neighbor| store.ids[neighbor.id as usize].as_deref())
+            .filter(|id| is_on_sale(&store.products[*id]))
+            .take(25)
+            .collect();
+        let ids: Vec<&str> = hits.iter().map(|&(id, _, _)| id).collect();
+        assert_eq!(ids, exact);
+
+        let hits = store.hybrid_search(&[], "lamp", 25, options(), Some(&on_sale)).unwrap();
+        assert_eq!(hits.len(), 25);
+        assert!(hits.iter().all(|hit| is_on_sale(hit.product)));
+    }
+}
@@ -1,11 +1,20 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Compile the helloworld service
-    tonic_prost_build::compile_protos("proto/helloworld.proto")?;
+    // Use the vendored protoc unless PROTOC points at another one.
+    let mut config = prost_build::Config::new();
+    if std::env::var_os("PROTOC").is_none() {
+        config.protoc_executable(protoc_bin_vendored::protoc_bin_path()?);
+    }
 
-    // Compile the health service
-    tonic_prost_build::compile_protos("proto/health.proto")?;
-
-    tonic_prost_build::compile_protos("proto/embedding.proto")?;
+    // The helloworld and health services, and the embedding service itself.
+    tonic_prost_build::configure().compile_with_config(
+        config,
+        &[
+            "proto/helloworld.proto",
+            "proto/health.proto",
+            "proto/embedding.proto",
+        ],
+        &["proto"],
+    )?;
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,291 @@
+use anyhow::{Error as E, Result};
+use arrow_array::{Array, RecordBatch, StringArray};
+use arrow_schema::DataType;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ProjectionMask;
+use serde_json::Value;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum InputFormat {
+    Csv,
+    Jsonl,
+    Parquet,
+}
+
+impl InputFormat {
+    /// Infers the format from a file extension.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+        match extension.to_ascii_lowercase().as_str() {
+            "csv" => Ok(InputFormat::Csv),
+            "jsonl" | "ndjson" => Ok(InputFormat::Jsonl),
+            "parquet" => Ok(InputFormat::Parquet),
+            _ => Err(E::msg(format!(
+                "Can't infer the input format of {}; pass --input-format",
+                path.display()
+            ))),
+        }
+    }
+}
+
+/// One input row. Either field is `None` when the row lacks it, and the row is skipped.
+pub struct Row {
+    pub id: Option<String>,
+    pub text: Option<String>,
+}
+
+pub type Rows = Box<dyn Iterator<Item = Result<Row>>>;
+
+/// Counts the rows `read_rows` will yield, for progress reporting.
+pub fn count_rows(path: &Path, format: InputFormat) -> Result<u64> {
+    match format {
+        InputFormat::Csv => Ok(csv::Reader::from_path(path)?.records().count() as u64),
+        InputFormat::Jsonl => Ok(BufReader::new(File::open(path)?).lines().count() as u64),
+        InputFormat::Parquet => {
+            let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+            Ok(builder.metadata().file_metadata().num_rows() as u64)
+        }
+    }
+}
+
+/// Reads the id and text column of every row, in file order.
+pub fn read_rows(path: &Path, format: InputFormat, id_column: &str, text_column: &str) -> Result<Rows> {
+    match format {
+        InputFormat::Csv => read_csv(path, id_column, text_column),
+        InputFormat::Jsonl => read_jsonl(path, id_column, text_column),
+        InputFormat::Parquet => read_parquet(path, id_column, text_column),
+    }
+}
+
+fn read_csv(path: &Path, id_column: &str, text_column: &str) -> Result<Rows> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|header| header == name)
+            .ok_or_else(|| E::msg(format!("CSV has no column named {}", name)))
+    };
+    let (id_index, text_index) = (column(id_column)?, column(text_column)?);
+
+    let field = |record: &csv::StringRecord, index: usize| {
+        record.get(index).filter(|value| !value.is_empty()).map(str::to_string)
+    };
+    Ok(Box::new(reader.into_records().map(move |record| {
+        let record = record?;
+        Ok(Row {
+            id: field(&record, id_index),
+            text: field(&record, text_index),
+        })
+    })))
+}
+
+fn read_jsonl(path: &Path, id_column: &str, text_column: &str) -> Result<Rows> {
+    let (id_column, text_column) = (id_column.to_string(), text_column.to_string());
+    let lines = BufReader::new(File::open(path)?).lines();
+    Ok(Box::new(lines.enumerate().map(move |(index, line)| {
+        let line = line?;
+        if line.trim().is_empty() {
+            return Ok(Row { id: None, text: None });
+        }
+        let object: Value = serde_json::from_str(&line)
+            .map_err(|e| E::msg(format!("Line {} is not valid JSON: {}", index + 1, e)))?;
+        Ok(Row {
+            id: json_field(&object, &id_column),
+            text: json_field(&object, &text_column),
+        })
+    })))
+}
+
+/// A scalar field as a string; missing, null and empty fields, objects and arrays are `None`.
+fn json_field(object: &Value, name: &str) -> Option<String> {
+    match object.get(name)? {
+        Value::String(value) if !value.is_empty() => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn read_parquet(path: &Path, id_column: &str, text_column: &str) -> Result<Rows> {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+    for name in [id_column, text_column] {
+        if builder.schema().field_with_name(name).is_err() {
+            return Err(E::msg(format!("Parquet file has no column named {}", name)));
+        }
+    }
+    let mask = ProjectionMask::columns(builder.parquet_schema(), [id_column, text_column]);
+    let reader = builder.with_projection(mask).build()?;
+
+    let (id_column, text_column) = (id_column.to_string(), text_column.to_string());
+    Ok(Box::new(reader.flat_map(move |batch| {
+        let rows = batch
+            .map_err(E::from)
+            .and_then(|batch| parquet_rows(&batch, &id_column, &text_column));
+        match rows {
+            Ok(rows) => rows.into_iter().map(Ok).collect::<Vec<_>>(),
+            Err(e) => vec![Err(e)],
+        }
+    })))
+}
+
+fn parquet_rows(batch: &RecordBatch, id_column: &str, text_column: &str) -> Result<Vec<Row>> {
+    let strings = |name: &str| -> Result<StringArray> {
+        let column = batch
+            .column_by_name(name)
+            .ok_or_else(|| E::msg(format!("Parquet file has no column named {}", name)))?;
+        let column = arrow_cast::cast(column, &DataType::Utf8)?;
+        Ok(column.as_any().downcast_ref::<StringArray>().expect("cast to Utf8").clone())
+    };
+    let (ids, texts) = (strings(id_column)?, strings(text_column)?);
+    let value = |array: &StringArray, index: usize| {
+        (array.is_valid(index) && !array.value(index).is_empty()).then(|| array.value(index).to_string())
+    };
+    Ok((0..batch.num_rows())
+        .map(|index| Row {
+            id: value(&ids, index),
+            text: value(&texts, index),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::Int64Array;
+    use arrow_schema::{Field, Schema};
+    use parquet::arrow::ArrowWriter;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn input_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("glyph-input-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn rows(path: &Path, format: InputFormat) -> Vec<(Option<String>, Option<String>)> {
+        read_rows(path, format, "id", "text")
+            .unwrap()
+            .map(|row| {
+                let row = row.unwrap();
+                (row.id, row.text)
+            })
+            .collect()
+    }
+
+    fn row(id: Option<&str>, text: Option<&str>) -> (Option<String>, Option<String>) {
+        (id.map(str::to_string), text.map(str::to_string))
+    }
+
+    #[test]
+    fn infers_formats_from_extensions() {
+        assert_eq!(InputFormat::from_path(Path::new("a.CSV")).unwrap(), InputFormat::Csv);
+        assert_eq!(InputFormat::from_path(Path::new("a.ndjson")).unwrap(), InputFormat::Jsonl);
+        assert_eq!(InputFormat::from_path(Path::new("a.parquet")).unwrap(), InputFormat::Parquet);
+        assert!(InputFormat::from_path(Path::new("a.txt")).is_err());
+    }
+
+    #[test]
+    fn reads_csv() {
+        let path = input_file(
+            "rows.csv",
+            b"text,id,price\n\"Desk lamp, brass\",1,20\nno id,,5\n,3,7\n42 chairs,0042,9\n",
+        );
+        assert_eq!(count_rows(&path, InputFormat::Csv).unwrap(), 4);
+        assert_eq!(
+            rows(&path, InputFormat::Csv),
+            [
+                row(Some("1"), Some("Desk lamp, brass")),
+                row(None, Some("no id")),
+                row(Some("3"), None),
+                row(Some("0042"), Some("42 chairs")),
+            ]
+        );
+
+        let Err(error) = read_rows(&path, InputFormat::Csv, "sku", "text") else {
+            panic!("read a CSV without the id column");
+        };
+        assert!(error.to_string().contains("no column named sku"), "{}", error);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_jsonl() {
+        let path = input_file(
+            "rows.jsonl",
+            br#"{"id": "a", "text": "Desk lamp"}
+{"id": 42, "text": "Chair"}
+{"id": 1.5, "text": null}
+
+{"id": "", "text": "no id"}
+{"text": "missing id", "other": 1}
+{"id": true, "text": ["not", "a", "string"]}
+"#,
+        );
+        assert_eq!(count_rows(&path, InputFormat::Jsonl).unwrap(), 7);
+        assert_eq!(
+            rows(&path, InputFormat::Jsonl),
+            [
+                row(Some("a"), Some("Desk lamp")),
+                row(Some("42"), Some("Chair")),
+                row(Some("1.5"), None),
+                row(None, None),
+                row(None, Some("no id")),
+                row(None, Some("missing id")),
+                row(Some("true"), None),
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        let path = input_file("invalid.jsonl", b"{\"id\": \"a\", \"text\": \"ok\"}\n{\"id\": \n");
+        let results: Vec<Result<Row>> = read_rows(&path, InputFormat::Jsonl, "id", "text").unwrap().collect();
+        assert!(results[0].is_ok());
+        let Err(error) = &results[1] else {
+            panic!("read invalid JSON");
+        };
+        assert!(error.to_string().contains("Line 2"), "{}", error);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_parquet() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, true),
+            Field::new("text", DataType::Utf8, true),
+            Field::new("extra", DataType::Utf8, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int64Array::from(vec![Some(7), None, Some(9), Some(10)])),
+                Arc::new(StringArray::from(vec![Some("Desk lamp"), Some("no id"), None, Some("")])),
+                Arc::new(StringArray::from(vec![Some("x"); 4])),
+            ],
+        )
+        .unwrap();
+        let path = std::env::temp_dir().join(format!("glyph-input-{}-rows.parquet", std::process::id()));
+        let mut writer = ArrowWriter::try_new(File::create(&path).unwrap(), schema, None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        assert_eq!(count_rows(&path, InputFormat::Parquet).unwrap(), 4);
+        assert_eq!(
+            rows(&path, InputFormat::Parquet),
+            [
+                row(Some("7"), Some("Desk lamp")),
+                row(None, Some("no id")),
+                row(Some("9"), None),
+                row(Some("10"), None),
+            ]
+        );
+        let Err(error) = read_rows(&path, InputFormat::Parquet, "id", "body") else {
+            panic!("read a Parquet file without the text column");
+        };
+        assert!(error.to_string().contains("no column named body"), "{}", error);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
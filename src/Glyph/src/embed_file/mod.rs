@@ -0,0 +1,307 @@
+pub mod input;
+pub mod output;
+
+use anyhow::{Error as E, Result};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::embed_file::input::{count_rows, read_rows, InputFormat};
+use crate::embed_file::output::{merge, write_part, OutputFormat};
+use crate::embedder::model::EmbeddingModel;
+use crate::settings::{ServeArgs, Settings};
+
+/// Embeds the text column of a CSV, JSONL or Parquet file offline.
+#[derive(Debug, Clone, clap::Args)]
+pub struct EmbedFileArgs {
+    /// The file to embed.
+    pub input: PathBuf,
+    /// Where to write the vectors.
+    #[arg(short, long)]
+    pub output: PathBuf,
+    /// Inferred from the input extension when not given.
+    #[arg(long, value_enum)]
+    pub input_format: Option<InputFormat>,
+    /// Inferred from the output extension when not given.
+    #[arg(long, value_enum)]
+    pub output_format: Option<OutputFormat>,
+    #[arg(long, default_value = "id")]
+    pub id_column: String,
+    #[arg(long, default_value = "text")]
+    pub text_column: String,
+    /// Overrides the model from the settings.
+    #[arg(long)]
+    pub model: Option<String>,
+    /// Texts embedded per forward pass; overrides `batch.size` from the settings.
+    #[arg(long)]
+    pub batch_size: Option<usize>,
+    /// Input rows between checkpoints; an interrupted job resumes from the last one.
+    #[arg(long, default_value_t = 4096)]
+    pub checkpoint_rows: usize,
+    /// Discards the checkpoints of a previous run instead of resuming from them.
+    #[arg(long)]
+    pub restart: bool,
+    /// The server's TOML settings file, for the model, device and batch size;
+    /// `GLYPH_CONFIG` when not given.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+}
+
+/// What a set of checkpoints was produced from, so a resumed job can't mix in parts
+/// embedded from another input, an input that changed since, or with another model.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Job {
+    input: PathBuf,
+    /// The input's size and modification time in nanoseconds since the epoch. Checkpoints
+    /// written before these were recorded read as zero, and so as a different job.
+    #[serde(default)]
+    input_len: u64,
+    #[serde(default)]
+    input_modified: u64,
+    id_column: String,
+    text_column: String,
+    model: String,
+}
+
+const JOB_FILE: &str = "job.json";
+
+/// A checkpointed part covering input rows `start..end`.
+struct Part {
+    path: PathBuf,
+    start: u64,
+    end: u64,
+}
+
+impl Part {
+    fn new(dir: &Path, start: u64, end: u64) -> Self {
+        let path = dir.join(format!("part-{:012}-{:012}.parquet", start, end));
+        Self { path, start, end }
+    }
+
+    fn parse(path: PathBuf) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        let (start, end) = name.strip_prefix("part-")?.strip_suffix(".parquet")?.split_once('-')?;
+        let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+        Some(Self { path, start, end })
+    }
+}
+
+/// The directory checkpoints are kept in while the job runs: `<output>.parts`.
+fn parts_dir(output: &Path) -> PathBuf {
+    let mut dir = output.as_os_str().to_os_string();
+    dir.push(".parts");
+    PathBuf::from(dir)
+}
+
+/// Opens the checkpoint directory, returning the parts already written, in order.
+fn resume(dir: &Path, job: &Job, restart: bool) -> Result<Vec<Part>> {
+    if restart && dir.exists() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    std::fs::create_dir_all(dir)?;
+    let job_path = dir.join(JOB_FILE);
+    if job_path.exists() {
+        let previous: Job = serde_json::from_slice(&std::fs::read(&job_path)?)?;
+        if previous != *job {
+            return Err(E::msg(format!(
+                "{} holds checkpoints of a different job ({:?}); pass --restart to discard them",
+                dir.display(),
+                previous
+            )));
+        }
+    } else {
+        std::fs::write(&job_path, serde_json::to_vec_pretty(job)?)?;
+    }
+
+    let mut parts = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        if let Some(part) = Part::parse(entry?.path()) {
+            parts.push(part);
+        }
+    }
+    parts.sort_by_key(|part| part.start);
+    // Keep the contiguous run from the start; anything after a gap is redone.
+    let mut next = 0;
+    parts.retain(|part| {
+        let contiguous = part.start == next;
+        if contiguous {
+            next = part.end;
+        }
+        contiguous
+    });
+    Ok(parts)
+}
+
+struct Pending {
+    ids: Vec<String>,
+    texts: Vec<String>,
+    vectors: Vec<Vec<f32>>,
+}
+
+impl Pending {
+    fn embed(&mut self, model: &EmbeddingModel) -> Result<()> {
+        if !self.texts.is_empty() {
+            self.vectors.extend(model.embed_batch(&self.texts)?);
+            self.texts.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Runs the job, resuming from checkpoints under `<output>.parts` if there are any.
+pub fn run(args: EmbedFileArgs) -> Result<()> {
+    let input_format = match args.input_format {
+        Some(format) => format,
+        None => InputFormat::from_path(&args.input)?,
+    };
+    let output_format = match args.output_format {
+        Some(format) => format,
+        None => OutputFormat::from_path(&args.output)?,
+    };
+    let settings = Settings::load(&ServeArgs {
+        config: args.config.clone(),
+        model: args.model.clone(),
+        batch_size: args.batch_size,
+        ..ServeArgs::default()
+    })?;
+    settings.apply_compute_threads();
+    let batch_size = settings.batch.size;
+    let checkpoint_rows = args.checkpoint_rows.max(1) as u64;
+
+    let input = std::fs::metadata(&args.input)?;
+    let job = Job {
+        input: std::fs::canonicalize(&args.input)?,
+        input_len: input.len(),
+        input_modified: input
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64),
+        id_column: args.id_column.clone(),
+        text_column: args.text_column.clone(),
+        model: settings.model.clone(),
+    };
+    let dir = parts_dir(&args.output);
+    let mut parts = resume(&dir, &job, args.restart)?;
+    let resume_from = parts.last().map_or(0, |part| part.end);
+
+    let total = count_rows(&args.input, input_format)?;
+    if resume_from > 0 {
+        println!("Resuming after {} of {} rows.", resume_from, total);
+    }
+    let model = EmbeddingModel::with_device(&settings.model, settings.device.device()?)?;
+    println!("Model loaded successfully on device: {:?}.", model.device.location());
+
+    let progress = ProgressBar::new(total);
+    progress.set_style(
+        ProgressStyle::with_template("{bar:40} {pos}/{len} rows, {per_sec}, ETA {eta}")
+            .expect("valid progress template"),
+    );
+    progress.set_position(resume_from);
+
+    let mut pending = Pending {
+        ids: vec![],
+        texts: vec![],
+        vectors: vec![],
+    };
+    let mut part_start = resume_from;
+    let mut consumed = resume_from;
+    let mut skipped = 0u64;
+    let rows = read_rows(&args.input, input_format, &args.id_column, &args.text_column)?;
+    for row in rows.skip(resume_from as usize) {
+        let row = row?;
+        consumed += 1;
+        match (row.id, row.text) {
+            (Some(id), Some(text)) => {
+                pending.ids.push(id);
+                pending.texts.push(text);
+            }
+            _ => skipped += 1,
+        }
+        if pending.texts.len() >= batch_size {
+            pending.embed(&model)?;
+        }
+        if consumed - part_start >= checkpoint_rows {
+            pending.embed(&model)?;
+            parts.push(checkpoint(&dir, part_start, consumed, &mut pending)?);
+            part_start = consumed;
+        }
+        progress.set_position(consumed);
+    }
+    if consumed > part_start {
+        pending.embed(&model)?;
+        parts.push(checkpoint(&dir, part_start, consumed, &mut pending)?);
+    }
+    progress.finish();
+
+    let paths: Vec<PathBuf> = parts.into_iter().map(|part| part.path).collect();
+    let embedded = merge(&paths, &args.output, output_format)?;
+    std::fs::remove_dir_all(&dir)?;
+
+    println!(
+        "Wrote {} embeddings to {}; skipped {} rows without an id or text{}.",
+        embedded,
+        args.output.display(),
+        skipped,
+        if resume_from > 0 { " since resuming" } else { "" }
+    );
+    Ok(())
+}
+
+fn checkpoint(dir: &Path, start: u64, end: u64, pending: &mut Pending) -> Result<Part> {
+    let part = Part::new(dir, start, end);
+    write_part(&part.path, &pending.ids, &pending.vectors)?;
+    pending.ids.clear();
+    pending.vectors.clear();
+    Ok(part)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(input_len: u64, input_modified: u64) -> Job {
+        Job {
+            input: PathBuf::from("/data/products.csv"),
+            input_len,
+            input_modified,
+            id_column: "id".to_string(),
+            text_column: "text".to_string(),
+            model: "BAAI/bge-base-en-v1.5".to_string(),
+        }
+    }
+
+    #[test]
+    fn resumes_only_the_same_input() {
+        let dir = std::env::temp_dir().join(format!("glyph-embed-file-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(resume(&dir, &job(100, 7), false).unwrap().is_empty());
+        std::fs::write(Part::new(&dir, 0, 10).path, b"").unwrap();
+        std::fs::write(Part::new(&dir, 20, 30).path, b"").unwrap();
+
+        let parts = resume(&dir, &job(100, 7), false).unwrap();
+        assert_eq!(parts.iter().map(|part| (part.start, part.end)).collect::<Vec<_>>(), [(0, 10)]);
+
+        let Err(error) = resume(&dir, &job(101, 7), false) else {
+            panic!("resumed checkpoints of a changed input");
+        };
+        assert!(error.to_string().contains("--restart"));
+        assert!(resume(&dir, &job(100, 8), false).is_err());
+
+        assert!(resume(&dir, &job(101, 8), true).unwrap().is_empty());
+        assert!(resume(&dir, &job(101, 8), false).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn treats_checkpoints_without_input_stamps_as_another_job() {
+        let previous: Job = serde_json::from_value(serde_json::json!({
+            "input": "/data/products.csv",
+            "id_column": "id",
+            "text_column": "text",
+            "model": "BAAI/bge-base-en-v1.5",
+        }))
+        .unwrap();
+        assert_eq!(previous, job(0, 0));
+        assert_ne!(previous, job(100, 7));
+    }
+}
@@ -0,0 +1,353 @@
+use anyhow::{Error as E, Result};
+use arrow_array::{Array, FixedSizeListArray, Float32Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// An `id` string column and an `embedding` fixed-size float list column.
+    Parquet,
+    /// A float32 `(rows, dimension)` array, plus the ids one per line in `<stem>.ids.txt`.
+    Npy,
+    /// One `{"id": ..., "embedding": [...]}` object per line.
+    Jsonl,
+}
+
+impl OutputFormat {
+    /// Infers the format from a file extension.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+        match extension.to_ascii_lowercase().as_str() {
+            "parquet" => Ok(OutputFormat::Parquet),
+            "npy" => Ok(OutputFormat::Npy),
+            "jsonl" | "ndjson" => Ok(OutputFormat::Jsonl),
+            _ => Err(E::msg(format!(
+                "Can't infer the output format of {}; pass --output-format",
+                path.display()
+            ))),
+        }
+    }
+}
+
+fn schema(dimension: usize) -> SchemaRef {
+    let item = Arc::new(Field::new("item", DataType::Float32, false));
+    Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Utf8, false),
+        Field::new("embedding", DataType::FixedSizeList(item, dimension as i32), false),
+    ]))
+}
+
+fn to_batch(ids: &[String], vectors: &[Vec<f32>]) -> Result<RecordBatch> {
+    let dimension = vectors.first().map_or(0, Vec::len);
+    let values = Float32Array::from_iter_values(vectors.iter().flatten().copied());
+    let item = Arc::new(Field::new("item", DataType::Float32, false));
+    let embeddings = FixedSizeListArray::try_new(item, dimension as i32, Arc::new(values), None)?;
+    Ok(RecordBatch::try_new(
+        schema(dimension),
+        vec![Arc::new(StringArray::from_iter_values(ids)), Arc::new(embeddings)],
+    )?)
+}
+
+/// Writes to `<path>.tmp` and renames it into place, so an interrupted write never
+/// leaves a file that looks complete.
+fn write_atomically(path: &Path, write: impl FnOnce(File) -> Result<()>) -> Result<()> {
+    let mut temporary = path.as_os_str().to_os_string();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    write(File::create(&temporary)?)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// Writes one checkpointed part of the job as Parquet.
+pub fn write_part(path: &Path, ids: &[String], vectors: &[Vec<f32>]) -> Result<()> {
+    let batch = to_batch(ids, vectors)?;
+    write_atomically(path, |file| {
+        let mut writer = ArrowWriter::try_new(file, batch.schema(), None)?;
+        writer.write(&batch)?;
+        writer.close()?;
+        Ok(())
+    })
+}
+
+/// The number of rows and the vector dimension of a part.
+fn part_shape(path: &Path) -> Result<(usize, usize)> {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+    let rows = builder.metadata().file_metadata().num_rows() as usize;
+    let dimension = match builder.schema().field_with_name("embedding")?.data_type() {
+        DataType::FixedSizeList(_, dimension) => *dimension as usize,
+        other => return Err(E::msg(format!("Part has an unexpected embedding type {}", other))),
+    };
+    Ok((rows, dimension))
+}
+
+fn read_part(path: &Path) -> Result<impl Iterator<Item = Result<(StringArray, Float32Array)>>> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?.build()?;
+    Ok(reader.map(|batch| {
+        let batch = batch?;
+        let ids = batch.column(0).as_any().downcast_ref::<StringArray>().cloned();
+        let embeddings = batch.column(1).as_any().downcast_ref::<FixedSizeListArray>();
+        let values = embeddings.and_then(|e| e.values().as_any().downcast_ref::<Float32Array>().cloned());
+        ids.zip(values)
+            .ok_or_else(|| E::msg("Part does not have the expected columns"))
+    }))
+}
+
+/// Concatenates the parts, in order, into the final output and returns the number of rows
+/// written. Parts whose rows were all skipped have no vectors, and no dimension, so they
+/// are left out; the others must agree on the dimension.
+pub fn merge(parts: &[PathBuf], output: &Path, format: OutputFormat) -> Result<usize> {
+    let mut dimension = None;
+    let mut rows = 0;
+    let mut nonempty = vec![];
+    for part in parts {
+        let (part_rows, part_dimension) = part_shape(part)?;
+        if part_rows == 0 {
+            continue;
+        }
+        if dimension.is_some_and(|dimension| dimension != part_dimension) {
+            return Err(E::msg(format!(
+                "{} holds {}-dimensional vectors but earlier parts hold {}",
+                part.display(),
+                part_dimension,
+                dimension.unwrap_or_default()
+            )));
+        }
+        dimension = Some(part_dimension);
+        rows += part_rows;
+        nonempty.push(part.clone());
+    }
+    write_output(&nonempty, output, format, rows, dimension.unwrap_or(0))?;
+    Ok(rows)
+}
+
+fn write_output(parts: &[PathBuf], output: &Path, format: OutputFormat, rows: usize, dimension: usize) -> Result<()> {
+    match format {
+        OutputFormat::Parquet => write_atomically(output, |file| {
+            let mut writer = ArrowWriter::try_new(file, schema(dimension), None)?;
+            for part in parts {
+                let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(part)?)?.build()?;
+                for batch in reader {
+                    writer.write(&batch?)?;
+                }
+            }
+            writer.close()?;
+            Ok(())
+        }),
+        OutputFormat::Npy => {
+            write_atomically(&ids_path(output), |file| {
+                let mut out = BufWriter::new(file);
+                for part in parts {
+                    for batch in read_part(part)? {
+                        for id in batch?.0.iter().flatten() {
+                            if id.contains('\n') {
+                                return Err(E::msg(format!("Id {:?} contains a newline", id)));
+                            }
+                            writeln!(out, "{}", id)?;
+                        }
+                    }
+                }
+                Ok(out.flush()?)
+            })?;
+            write_atomically(output, |file| {
+                let mut out = BufWriter::new(file);
+                out.write_all(&npy_header(rows, dimension))?;
+                for part in parts {
+                    for batch in read_part(part)? {
+                        for value in batch?.1.values() {
+                            out.write_all(&value.to_le_bytes())?;
+                        }
+                    }
+                }
+                Ok(out.flush()?)
+            })
+        }
+        OutputFormat::Jsonl => write_atomically(output, |file| {
+            let mut out = BufWriter::new(file);
+            for part in parts {
+                for batch in read_part(part)? {
+                    let (ids, values) = batch?;
+                    for (index, id) in ids.iter().enumerate() {
+                        let start = index * dimension;
+                        let line = serde_json::json!({
+                            "id": id,
+                            "embedding": &values.values()[start..start + dimension],
+                        });
+                        serde_json::to_writer(&mut out, &line)?;
+                        out.write_all(b"\n")?;
+                    }
+                }
+            }
+            Ok(out.flush()?)
+        }),
+    }
+}
+
+/// Where the ids of an `.npy` output go: `vectors.npy` gets `vectors.ids.txt`.
+pub fn ids_path(output: &Path) -> PathBuf {
+    output.with_extension("ids.txt")
+}
+
+/// A version 1.0 `.npy` header for a little-endian float32 C-order matrix, padded so the
+/// data starts 64-byte aligned.
+fn npy_header(rows: usize, dimension: usize) -> Vec<u8> {
+    let dict = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+        rows, dimension
+    );
+    let unpadded = 10 + dict.len() + 1;
+    let padding = (64 - unpadded % 64) % 64;
+    let mut header = b"\x93NUMPY\x01\x00".to_vec();
+    header.extend_from_slice(&((dict.len() + padding + 1) as u16).to_le_bytes());
+    header.extend_from_slice(dict.as_bytes());
+    header.extend(std::iter::repeat_n(b' ', padding));
+    header.push(b'\n');
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("glyph-output-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    /// Three parts of 3-dimensional vectors, the middle one with every row skipped.
+    fn parts(dir: &Path) -> Vec<PathBuf> {
+        let paths: Vec<PathBuf> = (0..3).map(|i| dir.join(format!("part-{}.parquet", i))).collect();
+        write_part(&paths[0], &ids(&["a", "b"]), &[vec![1.0, 2.0, 3.0], vec![4.0, 5.0, 6.0]]).unwrap();
+        write_part(&paths[1], &[], &[]).unwrap();
+        write_part(&paths[2], &ids(&["c"]), &[vec![7.0, 8.0, 9.0]]).unwrap();
+        paths
+    }
+
+    #[test]
+    fn npy_headers_are_aligned() {
+        for (rows, dimension) in [(0, 0), (3, 5), (1, 768), (123_456_789, 4096)] {
+            let header = npy_header(rows, dimension);
+            assert_eq!(header.len() % 64, 0);
+            assert_eq!(&header[..8], b"\x93NUMPY\x01\x00");
+            let len = u16::from_le_bytes([header[8], header[9]]) as usize;
+            assert_eq!(10 + len, header.len());
+            let dict = std::str::from_utf8(&header[10..]).unwrap();
+            assert!(dict.starts_with("{'descr': '<f4', 'fortran_order': False,"), "{}", dict);
+            assert!(dict.contains(&format!("'shape': ({}, {}), }}", rows, dimension)), "{}", dict);
+            assert!(dict.ends_with(" \n"));
+        }
+    }
+
+    #[test]
+    fn records_part_shapes() {
+        let dir = scratch("shapes");
+        let paths = parts(&dir);
+        assert_eq!(part_shape(&paths[0]).unwrap(), (2, 3));
+        assert_eq!(part_shape(&paths[1]).unwrap(), (0, 0));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merges_parts_into_parquet() {
+        let dir = scratch("parquet");
+        let output = dir.join("vectors.parquet");
+        assert_eq!(merge(&parts(&dir), &output, OutputFormat::Parquet).unwrap(), 3);
+
+        let mut merged_ids = vec![];
+        let mut merged_values = vec![];
+        for batch in read_part(&output).unwrap() {
+            let (ids, values) = batch.unwrap();
+            merged_ids.extend(ids.iter().flatten().map(str::to_string));
+            merged_values.extend(values.values().iter().copied());
+        }
+        assert_eq!(merged_ids, ["a", "b", "c"]);
+        assert_eq!(merged_values, [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0]);
+        assert_eq!(part_shape(&output).unwrap(), (3, 3));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merges_parts_into_npy() {
+        let dir = scratch("npy");
+        let output = dir.join("vectors.npy");
+        assert_eq!(merge(&parts(&dir), &output, OutputFormat::Npy).unwrap(), 3);
+
+        let bytes = std::fs::read(&output).unwrap();
+        let header = npy_header(3, 3);
+        assert_eq!(&bytes[..header.len()], &header[..]);
+        let values: Vec<f32> = bytes[header.len()..]
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(values, [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0]);
+        assert_eq!(ids_path(&output), dir.join("vectors.ids.txt"));
+        assert_eq!(std::fs::read_to_string(ids_path(&output)).unwrap(), "a\nb\nc\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merges_parts_into_jsonl() {
+        let dir = scratch("jsonl");
+        let output = dir.join("vectors.jsonl");
+        assert_eq!(merge(&parts(&dir), &output, OutputFormat::Jsonl).unwrap(), 3);
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                serde_json::json!({"id": "a", "embedding": [1.0, 2.0, 3.0]}),
+                serde_json::json!({"id": "b", "embedding": [4.0, 5.0, 6.0]}),
+                serde_json::json!({"id": "c", "embedding": [7.0, 8.0, 9.0]}),
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merges_jobs_where_every_row_was_skipped() {
+        let dir = scratch("skipped");
+        let part = dir.join("part.parquet");
+        write_part(&part, &[], &[]).unwrap();
+        let output = dir.join("vectors.npy");
+        assert_eq!(merge(&[part], &output, OutputFormat::Npy).unwrap(), 0);
+        assert_eq!(std::fs::read(&output).unwrap(), npy_header(0, 0));
+        assert_eq!(std::fs::read_to_string(ids_path(&output)).unwrap(), "");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_parts_of_different_dimensions() {
+        let dir = scratch("dimensions");
+        let (first, second) = (dir.join("first.parquet"), dir.join("second.parquet"));
+        write_part(&first, &ids(&["a"]), &[vec![1.0, 2.0]]).unwrap();
+        write_part(&second, &ids(&["b"]), &[vec![1.0, 2.0, 3.0]]).unwrap();
+        let output = dir.join("vectors.jsonl");
+        let error = merge(&[first, second], &output, OutputFormat::Jsonl).unwrap_err();
+        assert!(error.to_string().contains("3-dimensional"), "{}", error);
+        assert!(!output.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_ids_with_newlines_in_npy_output() {
+        let dir = scratch("newline");
+        let part = dir.join("part.parquet");
+        write_part(&part, &ids(&["a\nb"]), &[vec![1.0]]).unwrap();
+        assert!(merge(&[part], &dir.join("vectors.npy"), OutputFormat::Npy).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 pub mod model;
 pub mod service;
 pub mod proto;
+pub mod qdrant;
 
@@ -2,9 +2,9 @@ use candle_core::{Device, Tensor};
 use candle_nn::VarBuilder;
 use candle_transformers::models::bert::{BertModel, Config, DTYPE};
 use hf_hub::{api::sync::Api, Repo, RepoType};
-use std::sync::Arc;
 use tokenizers::Tokenizer;
 use anyhow::{Error as E, Result};
+use crate::settings::DeviceKind;
 use crate::utils::normalize_l2;
 
 pub struct EmbeddingModel {
@@ -15,11 +15,10 @@ pub struct EmbeddingModel {
 
 impl EmbeddingModel {
     pub fn new(model_id: &str) -> Result<Self> {
-        let device = if candle_core::utils::metal_is_available() {
-            Device::new_metal(0)?
-        } else {
-            Device::Cpu
-        };
+        Self::with_device(model_id, DeviceKind::Auto.device()?)
+    }
+
+    pub fn with_device(model_id: &str, device: Device) -> Result<Self> {
         let api = Api::new()?;
         let repo = api.repo(Repo::new(model_id.to_string(), RepoType::Model));
 
@@ -0,0 +1,526 @@
+use anyhow::Result;
+use prost_types::value::Kind;
+use serde_json::{json, Map, Value};
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use uuid::Uuid;
+
+/// The longest a single retry waits, however many attempts came before it.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Where and how embeddings from `IndexTexts` are written to Qdrant.
+#[derive(Debug, Clone)]
+pub struct QdrantConfig {
+    /// The REST endpoint, e.g. `http://localhost:6333`.
+    pub url: String,
+    pub collection: String,
+    pub api_key: Option<String>,
+    /// Points sent per upsert request.
+    pub batch_size: usize,
+    /// Retries after a failed request; the delay doubles from `retry_backoff` each time, up
+    /// to 30 seconds.
+    pub max_retries: u32,
+    pub retry_backoff: Duration,
+    pub timeout: Duration,
+    /// Creates the collection with cosine distance on the first write if it doesn't exist.
+    pub create_collection: bool,
+}
+
+impl QdrantConfig {
+    pub fn new(url: &str, collection: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            collection: collection.to_string(),
+            api_key: None,
+            batch_size: 64,
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(200),
+            timeout: Duration::from_secs(10),
+            create_collection: true,
+        }
+    }
+
+    /// Reads `GLYPH_QDRANT_URL` and `GLYPH_QDRANT_COLLECTION` (default `documents`), plus the
+    /// optional `GLYPH_QDRANT_API_KEY`, `GLYPH_QDRANT_BATCH_SIZE`, `GLYPH_QDRANT_MAX_RETRIES`
+    /// and `GLYPH_QDRANT_CREATE_COLLECTION`. Returns `None` when no URL is set.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(url) = std::env::var("GLYPH_QDRANT_URL").ok().filter(|url| !url.is_empty()) else {
+            return Ok(None);
+        };
+        let collection = std::env::var("GLYPH_QDRANT_COLLECTION").unwrap_or_else(|_| "documents".to_string());
+        let mut config = Self::new(&url, &collection);
+        config.api_key = std::env::var("GLYPH_QDRANT_API_KEY").ok().filter(|key| !key.is_empty());
+        if let Ok(value) = std::env::var("GLYPH_QDRANT_BATCH_SIZE") {
+            config.batch_size = value.parse::<usize>()?.max(1);
+        }
+        if let Ok(value) = std::env::var("GLYPH_QDRANT_MAX_RETRIES") {
+            config.max_retries = value.parse()?;
+        }
+        if let Ok(value) = std::env::var("GLYPH_QDRANT_CREATE_COLLECTION") {
+            config.create_collection = value != "0" && !value.eq_ignore_ascii_case("false");
+        }
+        Ok(Some(config))
+    }
+}
+
+/// One embedding to store, keyed by the client's document id.
+pub struct Point {
+    pub document_id: String,
+    pub vector: Vec<f32>,
+    pub payload: Map<String, Value>,
+}
+
+#[derive(Debug)]
+enum SinkError {
+    /// Qdrant refused the request; retrying it won't help.
+    Rejected(String),
+    /// The request failed in a way that may pass on retry, and did not.
+    Unavailable(String),
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkError::Rejected(e) => write!(f, "Qdrant rejected the upsert: {}", e),
+            SinkError::Unavailable(e) => write!(f, "Qdrant is unavailable: {}", e),
+        }
+    }
+}
+
+/// Upserts embeddings into a Qdrant collection over its REST API.
+pub struct QdrantSink {
+    config: QdrantConfig,
+    client: reqwest::Client,
+    collection_ready: OnceCell<()>,
+}
+
+impl QdrantSink {
+    pub fn new(config: QdrantConfig) -> Result<Self> {
+        let client = reqwest::Client::builder().timeout(config.timeout).build()?;
+        Ok(Self {
+            config,
+            client,
+            collection_ready: OnceCell::new(),
+        })
+    }
+
+    /// Upserts the points in batches and acknowledges each one, in order. A batch Qdrant
+    /// rejects outright is retried point by point, so one bad point doesn't fail the rest.
+    pub async fn upsert(&self, points: &[Point]) -> Vec<Result<(), String>> {
+        let Some(first) = points.first() else {
+            return vec![];
+        };
+        if let Err(e) = self.ensure_collection(first.vector.len()).await {
+            return points.iter().map(|_| Err(e.to_string())).collect();
+        }
+
+        let mut acks = Vec::with_capacity(points.len());
+        for batch in points.chunks(self.config.batch_size) {
+            match self.upsert_batch(batch).await {
+                Ok(()) => acks.extend(batch.iter().map(|_| Ok(()))),
+                Err(SinkError::Rejected(_)) if batch.len() > 1 => {
+                    for point in batch {
+                        let ack = self.upsert_batch(std::slice::from_ref(point)).await;
+                        acks.push(ack.map_err(|e| e.to_string()));
+                    }
+                }
+                Err(e) => acks.extend(batch.iter().map(|_| Err(e.to_string()))),
+            }
+        }
+        acks
+    }
+
+    async fn upsert_batch(&self, points: &[Point]) -> Result<(), SinkError> {
+        let points: Vec<Value> = points
+            .iter()
+            .map(|point| {
+                let mut payload = point.payload.clone();
+                payload
+                    .entry("document_id")
+                    .or_insert_with(|| Value::String(point.document_id.clone()));
+                json!({
+                    "id": point_id(&point.document_id),
+                    "vector": point.vector,
+                    "payload": payload,
+                })
+            })
+            .collect();
+        let url = format!("{}/collections/{}/points?wait=true", self.config.url, self.config.collection);
+        self.send(reqwest::Method::PUT, &url, Some(&json!({ "points": points }))).await
+    }
+
+    async fn ensure_collection(&self, dimension: usize) -> Result<(), SinkError> {
+        if !self.config.create_collection {
+            return Ok(());
+        }
+        self.collection_ready
+            .get_or_try_init(|| async {
+                let url = format!("{}/collections/{}", self.config.url, self.config.collection);
+                match self.send(reqwest::Method::GET, &url, None).await {
+                    Ok(()) => return Ok(()),
+                    // Usually a 404; any other refusal will show up again on the create.
+                    Err(SinkError::Rejected(_)) => {}
+                    Err(e) => return Err(e),
+                }
+                let body = json!({ "vectors": { "size": dimension, "distance": "Cosine" } });
+                self.send(reqwest::Method::PUT, &url, Some(&body)).await
+            })
+            .await
+            .map(|_| ())
+    }
+
+    fn request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, url);
+        match &self.config.api_key {
+            Some(key) => request.header("api-key", key),
+            None => request,
+        }
+    }
+
+    /// Sends a request, retrying with exponential backoff on connection errors, 429s and 5xxs.
+    async fn send(&self, method: reqwest::Method, url: &str, body: Option<&Value>) -> Result<(), SinkError> {
+        let mut attempt = 0;
+        loop {
+            let request = self.request(method.clone(), url);
+            let request = match body {
+                Some(body) => request.json(body),
+                None => request,
+            };
+            let error = match request.send().await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) => {
+                    let status = response.status();
+                    let text = response.text().await.unwrap_or_default();
+                    let message = format!("{} {}", status, text);
+                    if !(status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS) {
+                        return Err(SinkError::Rejected(message));
+                    }
+                    message
+                }
+                Err(e) => e.to_string(),
+            };
+            if attempt >= self.config.max_retries {
+                return Err(SinkError::Unavailable(error));
+            }
+            tokio::time::sleep(self.backoff(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    /// The delay before retry `attempt + 1`: `retry_backoff` doubled `attempt` times, capped
+    /// at `MAX_BACKOFF` rather than overflowing.
+    fn backoff(&self, attempt: u32) -> Duration {
+        2u32.checked_pow(attempt)
+            .and_then(|factor| self.config.retry_backoff.checked_mul(factor))
+            .map_or(MAX_BACKOFF, |delay| delay.min(MAX_BACKOFF))
+    }
+}
+
+/// Qdrant only accepts unsigned integers and UUIDs as point ids, so other document ids
+/// are mapped to a UUID derived from them. The original id is kept in the payload.
+fn point_id(document_id: &str) -> Value {
+    if let Ok(id) = document_id.parse::<u64>() {
+        return json!(id);
+    }
+    let uuid = Uuid::parse_str(document_id)
+        .unwrap_or_else(|_| Uuid::new_v5(&Uuid::NAMESPACE_OID, document_id.as_bytes()));
+    json!(uuid.to_string())
+}
+
+/// Converts a request's protobuf `Struct` payload into a JSON object.
+pub fn payload_to_json(payload: Option<prost_types::Struct>) -> Map<String, Value> {
+    payload
+        .map(|payload| {
+            payload
+                .fields
+                .into_iter()
+                .map(|(field, value)| (field, value_to_json(value)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn value_to_json(value: prost_types::Value) -> Value {
+    match value.kind {
+        None | Some(Kind::NullValue(_)) => Value::Null,
+        // Protobuf numbers are all doubles; whole ones go out as integers so Qdrant's
+        // integer indexes and match conditions apply to them.
+        Some(Kind::NumberValue(number)) if number.fract() == 0.0 && number.abs() < 2f64.powi(53) => {
+            json!(number as i64)
+        }
+        Some(Kind::NumberValue(number)) => json!(number),
+        Some(Kind::StringValue(text)) => Value::String(text),
+        Some(Kind::BoolValue(flag)) => Value::Bool(flag),
+        Some(Kind::StructValue(object)) => Value::Object(payload_to_json(Some(object))),
+        Some(Kind::ListValue(list)) => Value::Array(list.values.into_iter().map(value_to_json).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+
+    struct Recorded {
+        method: String,
+        path: String,
+        body: Value,
+    }
+
+    type Respond = dyn Fn(usize, &Recorded) -> u16 + Send + Sync;
+
+    /// A minimal HTTP/1.1 server on 127.0.0.1 standing in for Qdrant. It records every
+    /// request and answers with the status `respond` picks, given how many came before.
+    struct FakeQdrant {
+        url: String,
+        requests: Arc<Mutex<Vec<Recorded>>>,
+    }
+
+    impl FakeQdrant {
+        fn start(respond: impl Fn(usize, &Recorded) -> u16 + Send + Sync + 'static) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(vec![]));
+            let respond: Arc<Respond> = Arc::new(respond);
+            let recorded = requests.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let (recorded, respond) = (recorded.clone(), respond.clone());
+                    std::thread::spawn(move || serve(stream, &recorded, &*respond));
+                }
+            });
+            Self { url, requests }
+        }
+
+        fn config(&self) -> QdrantConfig {
+            let mut config = QdrantConfig::new(&self.url, "docs");
+            config.retry_backoff = Duration::from_millis(1);
+            config
+        }
+
+        fn requests(&self) -> Vec<(String, String, Value)> {
+            let requests = self.requests.lock().unwrap();
+            requests
+                .iter()
+                .map(|r| (r.method.clone(), r.path.clone(), r.body.clone()))
+                .collect()
+        }
+
+        /// The document ids sent in each points upsert, in order.
+        fn upserts(&self) -> Vec<Vec<String>> {
+            self.requests()
+                .into_iter()
+                .filter(|(_, path, _)| path.contains("/points"))
+                .map(|(_, _, body)| document_ids(&body))
+                .collect()
+        }
+    }
+
+    fn serve(stream: TcpStream, recorded: &Mutex<Vec<Recorded>>, respond: &Respond) {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            let mut parts = line.split_whitespace();
+            let (method, path) = (parts.next().unwrap().to_string(), parts.next().unwrap().to_string());
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let request = Recorded {
+                method,
+                path,
+                body: serde_json::from_slice(&body).unwrap_or(Value::Null),
+            };
+            let status = {
+                let mut recorded = recorded.lock().unwrap();
+                let status = respond(recorded.len(), &request);
+                recorded.push(request);
+                status
+            };
+            let text = r#"{"status":"ok"}"#;
+            let response = format!(
+                "HTTP/1.1 {} Fake\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                status,
+                text.len(),
+                text
+            );
+            if writer.write_all(response.as_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn document_ids(body: &Value) -> Vec<String> {
+        body["points"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|point| point["payload"]["document_id"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn point(document_id: &str) -> Point {
+        Point {
+            document_id: document_id.to_string(),
+            vector: vec![0.6, 0.8],
+            payload: Map::new(),
+        }
+    }
+
+    fn points(ids: &[&str]) -> Vec<Point> {
+        ids.iter().map(|id| point(id)).collect()
+    }
+
+    fn is_points(request: &Recorded) -> bool {
+        request.path.contains("/points")
+    }
+
+    #[tokio::test]
+    async fn batches_points_and_acknowledges_each() {
+        let qdrant = FakeQdrant::start(|_, _| 200);
+        let mut config = qdrant.config();
+        config.batch_size = 2;
+        let sink = QdrantSink::new(config).unwrap();
+
+        let acks = sink.upsert(&points(&["a", "b", "c", "d", "e"])).await;
+        assert_eq!(acks, vec![Ok(()); 5]);
+        assert_eq!(qdrant.upserts(), [vec!["a", "b"], vec!["c", "d"], vec!["e"]]);
+        let requests = qdrant.requests();
+        assert_eq!((requests[0].0.as_str(), requests[0].1.as_str()), ("GET", "/collections/docs"));
+        assert_eq!(requests[1].0, "PUT");
+        assert_eq!(requests[1].1, "/collections/docs/points?wait=true");
+
+        // The collection is only checked once per sink.
+        sink.upsert(&points(&["f"])).await;
+        assert_eq!(qdrant.requests().iter().filter(|r| r.0 == "GET").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let qdrant = FakeQdrant::start(|count, request| match (count, is_points(request)) {
+            (0, _) => 200,
+            (1 | 2, true) => 503,
+            _ => 200,
+        });
+        let sink = QdrantSink::new(qdrant.config()).unwrap();
+        assert_eq!(sink.upsert(&points(&["a", "b"])).await, vec![Ok(()); 2]);
+        assert_eq!(qdrant.upserts().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let qdrant = FakeQdrant::start(|_, request| if is_points(request) { 500 } else { 200 });
+        let mut config = qdrant.config();
+        config.max_retries = 2;
+        let sink = QdrantSink::new(config).unwrap();
+
+        let acks = sink.upsert(&points(&["a", "b"])).await;
+        assert_eq!(acks.len(), 2);
+        assert!(acks.iter().all(|ack| ack.as_ref().unwrap_err().contains("unavailable")));
+        assert_eq!(qdrant.upserts().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_single_points_when_a_batch_is_rejected() {
+        let qdrant = FakeQdrant::start(|_, request| {
+            if !is_points(request) {
+                return 200;
+            }
+            match document_ids(&request.body).as_slice() {
+                [id] if id == "bad" => 400,
+                [_] => 200,
+                _ => 400,
+            }
+        });
+        let sink = QdrantSink::new(qdrant.config()).unwrap();
+
+        let acks = sink.upsert(&points(&["a", "bad", "c"])).await;
+        assert_eq!(acks[0], Ok(()));
+        assert!(acks[1].as_ref().unwrap_err().contains("rejected"));
+        assert_eq!(acks[2], Ok(()));
+        assert_eq!(qdrant.upserts(), [vec!["a", "bad", "c"], vec!["a"], vec!["bad"], vec!["c"]]);
+    }
+
+    #[tokio::test]
+    async fn creates_a_missing_collection_after_retrying_the_lookup() {
+        let qdrant = FakeQdrant::start(|count, request| match (count, request.method.as_str()) {
+            (0, _) => 502,
+            (_, "GET") => 404,
+            _ => 200,
+        });
+        let sink = QdrantSink::new(qdrant.config()).unwrap();
+        assert_eq!(sink.upsert(&points(&["a"])).await, vec![Ok(())]);
+
+        let requests = qdrant.requests();
+        let methods: Vec<&str> = requests.iter().map(|r| r.0.as_str()).collect();
+        assert_eq!(methods, ["GET", "GET", "PUT", "PUT"]);
+        assert_eq!(requests[2].1, "/collections/docs");
+        assert_eq!(requests[2].2, json!({ "vectors": { "size": 2, "distance": "Cosine" } }));
+    }
+
+    #[tokio::test]
+    async fn passes_payloads_through_with_the_document_id() {
+        let qdrant = FakeQdrant::start(|_, _| 200);
+        let mut config = qdrant.config();
+        config.create_collection = false;
+        let sink = QdrantSink::new(config).unwrap();
+
+        let mut tagged = point("7");
+        tagged.payload = json!({ "color": "red", "stock": 3 }).as_object().unwrap().clone();
+        let mut named = point("sku-1");
+        named.payload = json!({ "document_id": "kept" }).as_object().unwrap().clone();
+        assert_eq!(sink.upsert(&[tagged, named]).await, vec![Ok(()); 2]);
+
+        let requests = qdrant.requests();
+        assert_eq!(requests.len(), 1);
+        let sent = &requests[0].2["points"];
+        assert_eq!(sent[0]["id"], json!(7));
+        assert_eq!(sent[0]["vector"], json!([0.6f32, 0.8f32]));
+        assert_eq!(sent[0]["payload"], json!({ "color": "red", "stock": 3, "document_id": "7" }));
+        assert_eq!(sent[1]["payload"], json!({ "document_id": "kept" }));
+    }
+
+    #[test]
+    fn maps_document_ids_to_point_ids() {
+        assert_eq!(point_id("42"), json!(42));
+        let uuid = "67E55044-10B1-426F-9247-BB680E5FE0C8";
+        assert_eq!(point_id(uuid), json!(uuid.to_lowercase()));
+
+        let derived = point_id("sku-1");
+        let derived = derived.as_str().unwrap();
+        assert!(Uuid::parse_str(derived).is_ok());
+        assert_eq!(point_id("sku-1"), json!(derived));
+        assert_ne!(point_id("sku-2"), json!(derived));
+        // Negative and oversized numbers aren't valid unsigned ids either.
+        assert!(point_id("-1").is_string());
+        assert!(point_id("18446744073709551616").is_string());
+    }
+
+    #[test]
+    fn caps_the_retry_backoff() {
+        let sink = QdrantSink::new(QdrantConfig::new("http://localhost:6333", "docs")).unwrap();
+        assert_eq!(sink.backoff(0), Duration::from_millis(200));
+        assert_eq!(sink.backoff(3), Duration::from_millis(1600));
+        assert_eq!(sink.backoff(20), MAX_BACKOFF);
+        assert_eq!(sink.backoff(40), MAX_BACKOFF);
+        assert_eq!(sink.backoff(u32::MAX), MAX_BACKOFF);
+    }
+}
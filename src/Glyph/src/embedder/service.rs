@@ -1,8 +1,10 @@
 use crate::embedder::model::EmbeddingModel;
 use crate::embedder::proto::{
     embedder_server::Embedder, EmbedSingleRequest, EmbedSingleResponse, Embedding, IndexRequest,
-    IndexResponse,
+    IndexResponse, SimilarityRequest, SimilarityResponse,
 };
+use crate::embedder::qdrant::{payload_to_json, Point, QdrantSink};
+use crate::utils::cosine_similarity;
 use futures::Stream;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
@@ -11,9 +13,16 @@ use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamExt;
 use tonic::{Request, Response, Status, Streaming};
+use tracing::{error, warn};
 
 pub struct EmbedderService {
     pub model: Arc<Mutex<EmbeddingModel>>,
+    /// Where `IndexTexts` stores embeddings, if anywhere.
+    pub sink: Option<Arc<QdrantSink>>,
+    /// Texts `IndexTexts` embeds per forward pass, and how long the stream may go quiet
+    /// before a partial batch is embedded.
+    pub batch_size: usize,
+    pub batch_timeout: Duration,
 }
 
 type IndexTextsStream = Pin<Box<dyn Stream<Item = Result<IndexResponse, Status>> + Send>>;
@@ -21,6 +30,77 @@ type IndexTextsStream = Pin<Box<dyn Stream<Item = Result<IndexResponse, Status>>
 struct Batch {
     document_ids: Vec<String>,
     texts: Vec<String>,
+    payloads: Vec<Option<prost_types::Struct>>,
+}
+
+/// A batch after embedding, waiting to be stored and acknowledged.
+struct EmbeddedBatch {
+    batch: Batch,
+    embeddings: anyhow::Result<Vec<Vec<f32>>>,
+}
+
+/// Stores a batch's embeddings in the sink, if there is one, and builds its responses.
+async fn acknowledge(sink: Option<&QdrantSink>, embedded: EmbeddedBatch) -> Vec<IndexResponse> {
+    let EmbeddedBatch { batch, embeddings } = embedded;
+    let embeddings = match embeddings {
+        Ok(embeddings) => embeddings,
+        Err(e) => {
+            error!("Batch embedding failed: {:?}", e);
+            return batch
+                .document_ids
+                .into_iter()
+                .map(|document_id| IndexResponse {
+                    document_id,
+                    success: false,
+                    ..Default::default()
+                })
+                .collect();
+        }
+    };
+
+    let points: Vec<Point> = batch
+        .document_ids
+        .into_iter()
+        .zip(embeddings)
+        .zip(batch.payloads)
+        .map(|((document_id, vector), payload)| Point {
+            document_id,
+            vector,
+            payload: payload_to_json(payload),
+        })
+        .collect();
+    let acks = match sink {
+        Some(sink) => sink.upsert(&points).await.into_iter().map(Some).collect(),
+        None => vec![None; points.len()],
+    };
+    points
+        .into_iter()
+        .zip(acks)
+        .map(|(point, ack)| IndexResponse {
+            document_id: point.document_id,
+            embedding: Some(Embedding { values: point.vector }),
+            success: true,
+            stored: matches!(ack, Some(Ok(()))),
+            sink_error: ack.and_then(Result::err).unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Most candidates one `Similarity` call may score, since they're embedded in one batch.
+const MAX_CANDIDATES: usize = 1000;
+
+fn validate_similarity(source: &str, candidates: &[String]) -> Result<(), Status> {
+    if source.is_empty() {
+        return Err(Status::invalid_argument("Source text cannot be empty"));
+    }
+    if candidates.len() > MAX_CANDIDATES {
+        return Err(Status::invalid_argument(format!(
+            "At most {} candidates may be scored at once, got {}",
+            MAX_CANDIDATES,
+            candidates.len()
+        )));
+    }
+    Ok(())
 }
 
 #[tonic::async_trait]
@@ -55,7 +135,7 @@ impl Embedder for EmbedderService {
                 Ok(Response::new(reply))
             }
             Err(e) => {
-                eprintln!("Failed to generate embedding: {:?}", e);
+                error!("Failed to generate embedding: {:?}", e);
                 Err(Status::internal("Failed to generate embedding."))
             }
         }
@@ -69,89 +149,96 @@ impl Embedder for EmbedderService {
     ) -> Result<Response<Self::IndexTextsStream>, Status> {
         let mut request_stream = request.into_inner();
         let model = self.model.clone();
+        let sink = self.sink.clone();
+        let (batch_size, batch_timeout) = (self.batch_size, self.batch_timeout);
 
         // The batch_tx channel has a small buffer. If the model worker can't keep up,
         // this channel will fill up, and the `send` call will wait, creating backpressure.
         let (batch_tx, mut batch_rx) = mpsc::channel::<Batch>(4); // Small buffer for backpressure
+        let (embedded_tx, mut embedded_rx) = mpsc::channel::<EmbeddedBatch>(4);
         let (response_tx, response_rx) = mpsc::channel(32);
 
         // Spawn a dedicated worker task to process batches.
-        // This task receives batches, runs the model, and sends results back.
+        // This task receives batches, runs the model, and hands the results on in order.
         tokio::spawn(async move {
             while let Some(batch) = batch_rx.recv().await {
                 let model_clone = model.clone();
-                let response_tx_clone = response_tx.clone();
 
-                tokio::task::spawn_blocking(move || {
+                let embedded = tokio::task::spawn_blocking(move || {
                     let model_guard = model_clone.lock().expect("Mutex lock failed");
                     let embeddings = model_guard.embed_batch(&batch.texts);
-
-                    match embeddings {
-                        Ok(embeddings) => {
-                            for (i, doc_id) in batch.document_ids.iter().enumerate() {
-                                let response = IndexResponse {
-                                    document_id: doc_id.clone(),
-                                    embedding: embeddings.get(i).map(|v| Embedding { values: v.clone() }),
-                                    success: true,
-                                };
-                                if response_tx_clone.blocking_send(Ok(response)).is_err() {
-                                    break; // Client disconnected
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Batch embedding failed: {:?}", e);
-                            for doc_id in batch.document_ids {
-                                let response = IndexResponse {
-                                    document_id: doc_id,
-                                    embedding: None,
-                                    success: false,
-                                };
-                                if response_tx_clone.blocking_send(Ok(response)).is_err() {
-                                    break; // Client disconnected
-                                }
-                            }
+                    EmbeddedBatch { batch, embeddings }
+                })
+                .await;
+                match embedded {
+                    Ok(embedded) => {
+                        if embedded_tx.send(embedded).await.is_err() {
+                            break; // Client disconnected
                         }
                     }
-                });
+                    Err(e) => {
+                        error!("Embedding task failed: {}", e);
+                        break;
+                    }
+                }
             }
         });
 
-        // Spawn a task to read from the client stream and create batches.
+        // Spawn a task that stores embedded batches in the sink while the next batch is
+        // being embedded, then acknowledges each document to the client.
         tokio::spawn(async move {
-            const BATCH_SIZE: usize = 32;
-            const BATCH_TIMEOUT: Duration = Duration::from_millis(500);
+            while let Some(embedded) = embedded_rx.recv().await {
+                for response in acknowledge(sink.as_deref(), embedded).await {
+                    if response_tx.send(Ok(response)).await.is_err() {
+                        return; // Client disconnected
+                    }
+                }
+            }
+        });
 
-            let mut batch_ids = Vec::with_capacity(BATCH_SIZE);
-            let mut batch_texts = Vec::with_capacity(BATCH_SIZE);
+        // Spawn a task to read from the client stream and create batches.
+        tokio::spawn(async move {
+            let mut batch_ids = Vec::with_capacity(batch_size);
+            let mut batch_texts = Vec::with_capacity(batch_size);
+            let mut batch_payloads = Vec::with_capacity(batch_size);
 
             loop {
-                match tokio::time::timeout(BATCH_TIMEOUT, request_stream.next()).await {
+                match tokio::time::timeout(batch_timeout, request_stream.next()).await {
                     // Message received from stream
                     Ok(Some(Ok(req))) => {
                         batch_ids.push(req.document_id);
                         batch_texts.push(req.text);
+                        batch_payloads.push(req.payload);
 
-                        if batch_ids.len() >= BATCH_SIZE {
-                            let batch = Batch { document_ids: batch_ids, texts: batch_texts };
+                        if batch_ids.len() >= batch_size {
+                            let batch = Batch {
+                                document_ids: batch_ids,
+                                texts: batch_texts,
+                                payloads: batch_payloads,
+                            };
                             if batch_tx.send(batch).await.is_err() {
                                 break; // Worker task died
                             }
-                            batch_ids = Vec::with_capacity(BATCH_SIZE);
-                            batch_texts = Vec::with_capacity(BATCH_SIZE);
+                            batch_ids = Vec::with_capacity(batch_size);
+                            batch_texts = Vec::with_capacity(batch_size);
+                            batch_payloads = Vec::with_capacity(batch_size);
                         }
                     }
                     // Stream ended or timed out
                     Ok(None) | Err(_) => {
                         if !batch_ids.is_empty() {
-                            let batch = Batch { document_ids: batch_ids, texts: batch_texts };
+                            let batch = Batch {
+                                document_ids: batch_ids,
+                                texts: batch_texts,
+                                payloads: batch_payloads,
+                            };
                             let _ = batch_tx.send(batch).await; // Send final batch
                         }
                         break; // End of stream
                     }
                     // Client stream error
                     Ok(Some(Err(e))) => {
-                        eprintln!("Client stream error: {}", e);
+                        warn!("Client stream error: {}", e);
                         break;
                     }
                 }
@@ -162,4 +249,63 @@ impl Embedder for EmbedderService {
         let output_stream = ReceiverStream::new(response_rx);
         Ok(Response::new(Box::pin(output_stream) as Self::IndexTextsStream))
     }
-}
\ No newline at end of file
+
+    async fn similarity(
+        &self,
+        request: Request<SimilarityRequest>,
+    ) -> Result<Response<SimilarityResponse>, Status> {
+        let SimilarityRequest { source, candidates } = request.into_inner();
+        validate_similarity(&source, &candidates)?;
+        if candidates.is_empty() {
+            return Ok(Response::new(SimilarityResponse { scores: vec![] }));
+        }
+
+        let model = self.model.clone();
+
+        // Embed the source and all candidates in a single batch.
+        let mut texts = Vec::with_capacity(candidates.len() + 1);
+        texts.push(source);
+        texts.extend(candidates);
+
+        let embeddings = tokio::task::spawn_blocking(move || {
+            let model_guard = model.lock().expect("Mutex lock failed");
+            model_guard.embed_batch(&texts)
+        })
+            .await
+            .map_err(|e| Status::internal(format!("Task join error: {}", e)))?
+            .map_err(|e| {
+                error!("Failed to generate embeddings: {:?}", e);
+                Status::internal("Failed to generate embeddings.")
+            })?;
+
+        let (source_embedding, candidate_embeddings) = embeddings
+            .split_first()
+            .ok_or_else(|| Status::internal("Model returned no embedding"))?;
+        let scores = candidate_embeddings
+            .iter()
+            .map(|candidate| cosine_similarity(source_embedding, candidate))
+            .collect();
+
+        Ok(Response::new(SimilarityResponse { scores }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_too_many_similarity_candidates() {
+        let candidates = vec!["candidate".to_string(); MAX_CANDIDATES + 1];
+        let status = validate_similarity("source", &candidates).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(validate_similarity("source", &candidates[..MAX_CANDIDATES]).is_ok());
+        assert!(validate_similarity("source", &[]).is_ok());
+    }
+
+    #[test]
+    fn rejects_an_empty_similarity_source() {
+        let status = validate_similarity("", &["candidate".to_string()]).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+}
@@ -1,2 +1,7 @@
+// The crate is named after the service, like its siblings.
+#![allow(non_snake_case)]
+
+pub mod embed_file;
 pub mod embedder;
-pub mod utils;
\ No newline at end of file
+pub mod settings;
+pub mod utils;
@@ -1,15 +1,56 @@
+use clap::{Parser, Subcommand};
 use std::sync::{Arc, Mutex};
 use tonic::transport::Server;
+use tracing::info;
+use Glyph::embed_file::EmbedFileArgs;
 use Glyph::embedder::model::EmbeddingModel;
 use Glyph::embedder::proto::embedder_server::EmbedderServer;
+use Glyph::embedder::qdrant::{QdrantConfig, QdrantSink};
 use Glyph::embedder::service::EmbedderService;
+use Glyph::settings::{ServeArgs, Settings};
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Initializing model and device...");
+#[derive(Parser)]
+#[command(about = "Text embedding gRPC server", args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    serve: ServeArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Runs the gRPC server (the default).
+    Serve(ServeArgs),
+    /// Embeds a CSV, JSONL or Parquet file and writes the vectors to disk.
+    EmbedFile(EmbedFileArgs),
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::EmbedFile(args)) => Ok(Glyph::embed_file::run(args)?),
+        Some(Command::Serve(args)) => serve(&args),
+        None => serve(&cli.serve),
+    }
+}
+
+fn serve(args: &ServeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let settings = Settings::load(args)?;
+    if args.print_config {
+        print!("{}", settings.to_toml()?);
+        return Ok(());
+    }
+    settings.init_logging()?;
+    settings.apply_compute_threads();
+    settings.runtime()?.block_on(run(settings))
+}
+
+async fn run(settings: Settings) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Initializing model {} and device...", settings.model);
     // Initialize the embedding model.
-    let model = EmbeddingModel::new("BAAI/bge-base-en-v1.5")?;
-    println!(
+    let model = EmbeddingModel::with_device(&settings.model, settings.device.device()?)?;
+    info!(
         "Model loaded successfully on device: {:?}.",
         model.device.location()
     );
@@ -17,13 +58,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Wrap the model in a standard Mutex and an Arc for safe, shared access across threads.
     let shared_model = Arc::new(Mutex::new(model));
 
+    // Optionally store IndexTexts embeddings in Qdrant, configured through GLYPH_QDRANT_*.
+    let sink = match QdrantConfig::from_env()? {
+        Some(config) => {
+            info!(
+                "Storing indexed embeddings in Qdrant collection {} at {}.",
+                config.collection, config.url
+            );
+            Some(Arc::new(QdrantSink::new(config)?))
+        }
+        None => None,
+    };
+
     // Create the service instance, passing the shared model.
     let embedder_service = EmbedderService {
         model: shared_model,
+        sink,
+        batch_size: settings.batch.size,
+        batch_timeout: settings.batch_timeout(),
     };
 
-    let addr = "[::1]:50051".parse()?;
-    println!("gRPC EmbedderServer listening on {}", addr);
+    let addr = settings.listen;
+    info!("gRPC EmbedderServer listening on {}", addr);
 
     // Set up the gRPC health checking service.
     let (health_reporter, health_service) = tonic_health::server::health_reporter();
@@ -32,8 +88,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .await;
 
     // Build and run the gRPC server.
-    Server::builder()
-        .add_service(EmbedderServer::new(embedder_service))
+    let mut server = Server::builder();
+    if let Some(limit) = settings.limits.max_concurrent_requests {
+        server = server.concurrency_limit_per_connection(limit);
+    }
+    server
+        .add_service(
+            EmbedderServer::new(embedder_service)
+                .max_decoding_message_size(settings.limits.max_message_bytes),
+        )
         .add_service(health_service)
         .serve(addr)
         .await?;
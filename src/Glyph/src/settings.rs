@@ -0,0 +1,445 @@
+use anyhow::{Error as E, Result};
+use candle_core::Device;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Where the model runs. `auto` prefers Metal, then CUDA, then the CPU.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    #[default]
+    Auto,
+    Cpu,
+    Metal,
+    Cuda,
+}
+
+impl DeviceKind {
+    pub fn device(self) -> Result<Device> {
+        Ok(match self {
+            DeviceKind::Auto if candle_core::utils::metal_is_available() => Device::new_metal(0)?,
+            DeviceKind::Auto if candle_core::utils::cuda_is_available() => Device::new_cuda(0)?,
+            DeviceKind::Auto | DeviceKind::Cpu => Device::Cpu,
+            DeviceKind::Metal => Device::new_metal(0)?,
+            DeviceKind::Cuda => Device::new_cuda(0)?,
+        })
+    }
+}
+
+impl FromStr for DeviceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <Self as clap::ValueEnum>::from_str(s, true)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <Self as clap::ValueEnum>::from_str(s, true)
+    }
+}
+
+/// How the server is run. Each layer overrides the one before it: the defaults here, a
+/// TOML file, `GLYPH_*` environment variables and finally command line flags.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub listen: SocketAddr,
+    /// The Hugging Face model id of the BERT embedding model.
+    pub model: String,
+    pub device: DeviceKind,
+    pub batch: BatchSettings,
+    pub threads: ThreadSettings,
+    pub limits: LimitSettings,
+    pub log: LogSettings,
+}
+
+/// How `IndexTexts` groups the stream before running the model.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchSettings {
+    /// Texts per forward pass.
+    pub size: usize,
+    /// How long the stream may go quiet before a partial batch is embedded.
+    pub timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThreadSettings {
+    /// Async runtime workers; one per CPU when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workers: Option<usize>,
+    /// Threads the model's CPU kernels use; one per CPU when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compute: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitSettings {
+    /// The largest request message the server decodes, in bytes.
+    pub max_message_bytes: usize,
+    /// Requests served at once on each connection; unlimited when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_requests: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    /// A `tracing` filter, e.g. `info` or `Glyph=debug,h2=warn`.
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 50051)),
+            model: "BAAI/bge-base-en-v1.5".to_string(),
+            device: DeviceKind::default(),
+            batch: BatchSettings::default(),
+            threads: ThreadSettings::default(),
+            limits: LimitSettings::default(),
+            log: LogSettings::default(),
+        }
+    }
+}
+
+impl Default for BatchSettings {
+    fn default() -> Self {
+        Self {
+            size: 32,
+            timeout_ms: 500,
+        }
+    }
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        Self {
+            // tonic's own default.
+            max_message_bytes: 4 * 1024 * 1024,
+            max_concurrent_requests: None,
+        }
+    }
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::default(),
+        }
+    }
+}
+
+/// Flags for `serve`. Anything left unset keeps the value from the file or environment.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct ServeArgs {
+    /// A TOML settings file; `GLYPH_CONFIG` when not given.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Prints the resolved settings as TOML and exits.
+    #[arg(long)]
+    pub print_config: bool,
+    /// The address to listen on, e.g. `0.0.0.0:50051`.
+    #[arg(long)]
+    pub listen: Option<SocketAddr>,
+    #[arg(long)]
+    pub model: Option<String>,
+    #[arg(long, value_enum)]
+    pub device: Option<DeviceKind>,
+    #[arg(long)]
+    pub batch_size: Option<usize>,
+    #[arg(long)]
+    pub batch_timeout_ms: Option<u64>,
+    #[arg(long)]
+    pub worker_threads: Option<usize>,
+    #[arg(long)]
+    pub compute_threads: Option<usize>,
+    #[arg(long)]
+    pub max_message_bytes: Option<usize>,
+    #[arg(long)]
+    pub max_concurrent_requests: Option<usize>,
+    #[arg(long)]
+    pub log_level: Option<String>,
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+}
+
+/// Parses an environment variable, treating an empty value as unset.
+fn env<T>(name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(name) {
+        Ok(value) if !value.is_empty() => value
+            .parse()
+            .map(Some)
+            .map_err(|e| E::msg(format!("Invalid {}={:?}: {}", name, value, e))),
+        _ => Ok(None),
+    }
+}
+
+impl Settings {
+    /// Resolves the settings for `args`, reading the file it or `GLYPH_CONFIG` names.
+    pub fn load(args: &ServeArgs) -> Result<Self> {
+        let file = match &args.config {
+            Some(path) => Some(path.clone()),
+            None => env::<PathBuf>("GLYPH_CONFIG")?,
+        };
+        let mut settings = match file {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        settings.apply_env()?;
+        settings.apply_args(args);
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| E::msg(format!("Cannot read {}: {}", path.display(), e)))?;
+        toml::from_str(&text).map_err(|e| E::msg(format!("Invalid settings in {}: {}", path.display(), e)))
+    }
+
+    /// Reads `GLYPH_LISTEN` (or just the port from `PORT`), `GLYPH_MODEL`, `GLYPH_DEVICE`,
+    /// `GLYPH_BATCH_SIZE`, `GLYPH_BATCH_TIMEOUT_MS`, `GLYPH_WORKER_THREADS`,
+    /// `GLYPH_COMPUTE_THREADS`, `GLYPH_MAX_MESSAGE_BYTES`, `GLYPH_MAX_CONCURRENT_REQUESTS`,
+    /// `GLYPH_LOG` and `GLYPH_LOG_FORMAT`.
+    fn apply_env(&mut self) -> Result<()> {
+        if let Some(port) = env("PORT")? {
+            self.listen.set_port(port);
+        }
+        if let Some(listen) = env("GLYPH_LISTEN")? {
+            self.listen = listen;
+        }
+        if let Some(model) = env("GLYPH_MODEL")? {
+            self.model = model;
+        }
+        if let Some(device) = env("GLYPH_DEVICE")? {
+            self.device = device;
+        }
+        if let Some(size) = env("GLYPH_BATCH_SIZE")? {
+            self.batch.size = size;
+        }
+        if let Some(timeout_ms) = env("GLYPH_BATCH_TIMEOUT_MS")? {
+            self.batch.timeout_ms = timeout_ms;
+        }
+        if let Some(workers) = env("GLYPH_WORKER_THREADS")? {
+            self.threads.workers = Some(workers);
+        }
+        if let Some(compute) = env("GLYPH_COMPUTE_THREADS")? {
+            self.threads.compute = Some(compute);
+        }
+        if let Some(bytes) = env("GLYPH_MAX_MESSAGE_BYTES")? {
+            self.limits.max_message_bytes = bytes;
+        }
+        if let Some(requests) = env("GLYPH_MAX_CONCURRENT_REQUESTS")? {
+            self.limits.max_concurrent_requests = Some(requests);
+        }
+        if let Some(level) = env("GLYPH_LOG")? {
+            self.log.level = level;
+        }
+        if let Some(format) = env("GLYPH_LOG_FORMAT")? {
+            self.log.format = format;
+        }
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &ServeArgs) {
+        if let Some(listen) = args.listen {
+            self.listen = listen;
+        }
+        if let Some(model) = &args.model {
+            self.model = model.clone();
+        }
+        if let Some(device) = args.device {
+            self.device = device;
+        }
+        if let Some(size) = args.batch_size {
+            self.batch.size = size;
+        }
+        if let Some(timeout_ms) = args.batch_timeout_ms {
+            self.batch.timeout_ms = timeout_ms;
+        }
+        if let Some(workers) = args.worker_threads {
+            self.threads.workers = Some(workers);
+        }
+        if let Some(compute) = args.compute_threads {
+            self.threads.compute = Some(compute);
+        }
+        if let Some(bytes) = args.max_message_bytes {
+            self.limits.max_message_bytes = bytes;
+        }
+        if let Some(requests) = args.max_concurrent_requests {
+            self.limits.max_concurrent_requests = Some(requests);
+        }
+        if let Some(level) = &args.log_level {
+            self.log.level = level.clone();
+        }
+        if let Some(format) = args.log_format {
+            self.log.format = format;
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.model.is_empty() {
+            return Err(E::msg("model cannot be empty"));
+        }
+        if self.batch.size == 0 {
+            return Err(E::msg("batch.size must be at least 1"));
+        }
+        if self.threads.workers == Some(0) || self.threads.compute == Some(0) {
+            return Err(E::msg("Thread counts must be at least 1"));
+        }
+        if self.limits.max_concurrent_requests == Some(0) {
+            return Err(E::msg("limits.max_concurrent_requests must be at least 1"));
+        }
+        tracing_subscriber::EnvFilter::try_new(&self.log.level)
+            .map_err(|e| E::msg(format!("Invalid log level {:?}: {}", self.log.level, e)))?;
+        Ok(())
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    pub fn batch_timeout(&self) -> Duration {
+        Duration::from_millis(self.batch.timeout_ms)
+    }
+
+    /// Installs the global `tracing` subscriber.
+    pub fn init_logging(&self) -> Result<()> {
+        let filter = tracing_subscriber::EnvFilter::try_new(&self.log.level)?;
+        let builder = tracing_subscriber::fmt().with_env_filter(filter);
+        match self.log.format {
+            LogFormat::Text => builder.try_init(),
+            LogFormat::Json => builder.json().try_init(),
+        }
+        .map_err(E::msg)
+    }
+
+    /// Sets `RAYON_NUM_THREADS`, which candle's CPU kernels read for their thread count.
+    /// Must run before any other thread starts.
+    pub fn apply_compute_threads(&self) {
+        if let Some(compute) = self.threads.compute {
+            std::env::set_var("RAYON_NUM_THREADS", compute.to_string());
+        }
+    }
+
+    pub fn runtime(&self) -> Result<tokio::runtime::Runtime> {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.enable_all();
+        if let Some(workers) = self.threads.workers {
+            builder.worker_threads(workers);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VARIABLES: [&str; 5] = ["GLYPH_CONFIG", "GLYPH_LISTEN", "GLYPH_MODEL", "GLYPH_BATCH_SIZE", "PORT"];
+
+    fn settings_file(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("glyph-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    // Kept in one test since the environment is shared by every test thread.
+    #[test]
+    fn layers_defaults_file_environment_and_flags() {
+        let clear = || VARIABLES.iter().for_each(|name| std::env::remove_var(name));
+        clear();
+        let file = settings_file(
+            "layers",
+            "model = \"from/file\"\nlisten = \"127.0.0.1:7000\"\n\n[batch]\nsize = 8\ntimeout_ms = 50\n",
+        );
+
+        let settings = Settings::load(&ServeArgs::default()).unwrap();
+        assert_eq!(settings.model, "BAAI/bge-base-en-v1.5");
+        assert_eq!(settings.batch.size, 32);
+
+        let args = ServeArgs {
+            config: Some(file.clone()),
+            ..ServeArgs::default()
+        };
+        let settings = Settings::load(&args).unwrap();
+        assert_eq!(settings.model, "from/file");
+        assert_eq!(settings.batch.size, 8);
+        assert_eq!(settings.batch.timeout_ms, 50);
+        assert_eq!(settings.limits.max_message_bytes, 4 * 1024 * 1024);
+
+        std::env::set_var("GLYPH_MODEL", "from/env");
+        std::env::set_var("GLYPH_BATCH_SIZE", "16");
+        std::env::set_var("PORT", "8080");
+        let settings = Settings::load(&args).unwrap();
+        assert_eq!(settings.model, "from/env");
+        assert_eq!(settings.batch.size, 16);
+        assert_eq!(settings.batch.timeout_ms, 50);
+        // PORT replaces only the port of the address from the file.
+        assert_eq!(settings.listen, "127.0.0.1:8080".parse().unwrap());
+
+        std::env::set_var("GLYPH_LISTEN", "0.0.0.0:9000");
+        let settings = Settings::load(&args).unwrap();
+        assert_eq!(settings.listen, "0.0.0.0:9000".parse().unwrap());
+
+        let flags = ServeArgs {
+            model: Some("from/flag".to_string()),
+            batch_size: Some(4),
+            listen: Some("[::1]:9100".parse().unwrap()),
+            ..args.clone()
+        };
+        let settings = Settings::load(&flags).unwrap();
+        assert_eq!(settings.model, "from/flag");
+        assert_eq!(settings.batch.size, 4);
+        assert_eq!(settings.listen, "[::1]:9100".parse().unwrap());
+
+        // GLYPH_CONFIG names the file when --config doesn't.
+        std::env::set_var("GLYPH_CONFIG", &file);
+        std::env::remove_var("GLYPH_MODEL");
+        let settings = Settings::load(&ServeArgs::default()).unwrap();
+        assert_eq!(settings.model, "from/file");
+
+        std::env::set_var("PORT", "not-a-port");
+        let error = Settings::load(&ServeArgs::default()).unwrap_err();
+        assert!(error.to_string().contains("PORT"));
+
+        clear();
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        let unknown = settings_file("unknown", "modle = \"typo\"\n");
+        assert!(Settings::from_file(&unknown).is_err());
+        std::fs::remove_file(&unknown).unwrap();
+
+        let mut settings = Settings::default();
+        settings.batch.size = 0;
+        assert!(settings.validate().is_err());
+    }
+}
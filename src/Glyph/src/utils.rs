@@ -3,3 +3,40 @@ use candle_core::Tensor;
 pub fn normalize_l2(v: &Tensor) -> candle_core::error::Result<Tensor> {
     v.broadcast_div(&v.sqr()?.sum_keepdim(1)?.sqrt()?)
 }
+
+/// Cosine similarity between two vectors. Returns 0.0 if either vector has zero length.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cosine_similarity_ignores_length() {
+        assert!((cosine_similarity(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 1.0], &[-2.0, -2.0]) + 1.0).abs() < 1e-6);
+        assert!((cosine_similarity(&[3.0, 4.0], &[4.0, 3.0]) - 0.96).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cosine_similarity_of_a_zero_vector_is_zero() {
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 2.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0, 2.0], &[0.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn normalize_l2_scales_each_row_to_unit_length() {
+        let v = Tensor::new(&[[3f32, 4.0], [0.0, 2.0]], &candle_core::Device::Cpu).unwrap();
+        let normalized = normalize_l2(&v).unwrap().to_vec2::<f32>().unwrap();
+        assert_eq!(normalized, [[0.6, 0.8], [0.0, 1.0]]);
+    }
+}